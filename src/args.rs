@@ -1,16 +1,160 @@
-use clap::Parser;
+use clap::{ArgEnum, ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand, ValueSource};
 use lazy_static::lazy_static;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
 use std::convert::Infallible;
 use std::fmt;
 use std::net::IpAddr;
 use std::str::FromStr;
+use std::sync::{Arc, RwLock};
 
 lazy_static! {
-    pub static ref ARGS: Args = Args::parse();
+    pub static ref ARGS: Settings = Settings::new(
+        // at startup, clap prints the help, the version and usage errors and exits
+        Args::from_matches(&Args::command().get_matches()).unwrap_or_else(|error| {
+            eprintln!("Invalid configuration: {}", error);
+            std::process::exit(2);
+        }),
+    );
 }
 
-#[derive(Parser, Debug, Clone, Serialize)]
+/// Name of the optional config file in the data directory.
+pub const CONFIG_FILE: &str = "microbin.toml";
+
+/// Settings that are only read at startup, so they are not changed when the
+/// configuration is reloaded.
+const RESTART_REQUIRED: [&str; 19] = [
+    "auth_basic_username",
+    "auth_basic_password",
+    "auth_basic_htpasswd",
+    "port",
+    "bind",
+    "json_db",
+    "database_url",
+    "public_path",
+    "short_path",
+    "threads",
+    "data_dir",
+    "hash_ids",
+    "list_server",
+    "disable_telemetry",
+    "attachment_store",
+    "s3_endpoint",
+    "s3_bucket",
+    "s3_region",
+    "s3_path_style",
+];
+
+pub const EXPIRATIONS: [&str; 7] = ["1min", "10min", "1hour", "24hour", "3days", "1week", "never"];
+const BURN_AFTER_READS: [u16; 6] = [0, 1, 10, 100, 1000, 10000];
+const EVICTION_POLICIES: [&str; 3] = ["none", "oldest", "least_read"];
+const ATTACHMENT_STORES: [&str; 3] = ["filesystem", "s3", "sqlite"];
+
+/// Settings that admins can change on the settings page. They are stored in
+/// the database and take precedence over flags, environment and config file.
+pub const OVERRIDABLE: [&str; 18] = [
+    "title",
+    "footer_text",
+    "hide_footer",
+    "hide_header",
+    "hide_logo",
+    "wide",
+    "qr",
+    "highlightsyntax",
+    "show_read_stats",
+    "editable",
+    "enable_readonly",
+    "enable_burn_after",
+    "default_burn_after",
+    "default_expiry",
+    "eternal_pasta",
+    "no_file_upload",
+    "max_file_size_encrypted_mb",
+    "max_file_size_unencrypted_mb",
+];
+
+/// The current settings. Requests take a snapshot with `get`, which stays
+/// the same until they drop it even when the settings change meanwhile.
+pub struct Settings {
+    /// Settings from flags, environment and config file
+    loaded: RwLock<Args>,
+    /// Values set on the admin settings page
+    overrides: RwLock<BTreeMap<String, Value>>,
+    current: RwLock<Arc<Args>>,
+}
+
+impl Settings {
+    fn new(args: Args) -> Settings {
+        Settings {
+            loaded: RwLock::new(args.clone()),
+            overrides: RwLock::new(BTreeMap::new()),
+            current: RwLock::new(Arc::new(args)),
+        }
+    }
+
+    /// The current settings.
+    pub fn get(&self) -> Arc<Args> {
+        self.current.read().unwrap().clone()
+    }
+
+    /// The settings without the overrides from the admin settings page.
+    pub fn loaded(&self) -> Args {
+        self.loaded.read().unwrap().clone()
+    }
+
+    pub fn overrides(&self) -> BTreeMap<String, Value> {
+        self.overrides.read().unwrap().clone()
+    }
+
+    /// Replaces the overrides from the admin settings page. If they are
+    /// invalid, the current settings are kept.
+    pub fn set_overrides(&self, overrides: BTreeMap<String, Value>) -> Result<(), String> {
+        let args = self.loaded.read().unwrap().with_overrides(&overrides)?;
+        *self.overrides.write().unwrap() = overrides;
+        *self.current.write().unwrap() = Arc::new(args);
+        Ok(())
+    }
+
+    /// Reloads the config file, environment and flags, and applies the
+    /// settings that can change without a restart. On errors the current
+    /// settings are kept.
+    pub fn reload(&self) {
+        let loaded = match Args::load() {
+            Ok(loaded) => loaded,
+            Err(error) => {
+                log::error!("Keeping the current configuration: {}", error);
+                return;
+            }
+        };
+
+        let current = serde_json::to_value(self.loaded()).unwrap();
+        let mut reloaded = serde_json::to_value(&loaded).unwrap();
+        for key in RESTART_REQUIRED {
+            if current[key] != reloaded[key] {
+                log::warn!("Changing {} requires a restart, keeping the current value", key);
+                reloaded[key] = current[key].clone();
+            }
+        }
+
+        let result = serde_json::from_value::<Args>(reloaded)
+            .map_err(|e| e.to_string())
+            .and_then(|loaded| {
+                let args = loaded.with_overrides(&self.overrides())?;
+                Ok((loaded, args))
+            });
+        match result {
+            Ok((loaded, args)) => {
+                *self.loaded.write().unwrap() = loaded;
+                *self.current.write().unwrap() = Arc::new(args);
+                log::info!("Configuration reloaded");
+            }
+            Err(error) => log::error!("Keeping the current configuration: {}", error),
+        }
+    }
+}
+
+#[derive(Parser, Debug, Clone, Serialize, Deserialize)]
 #[clap(author, version, about, long_about = None)]
 pub struct Args {
     #[clap(long, env = "MICROBIN_BASIC_AUTH_USERNAME")]
@@ -19,6 +163,9 @@ pub struct Args {
     #[clap(long, env = "MICROBIN_BASIC_AUTH_PASSWORD")]
     pub auth_basic_password: Option<String>,
 
+    #[clap(long, env = "MICROBIN_BASIC_AUTH_HTPASSWD")]
+    pub auth_basic_htpasswd: Option<String>,
+
     #[clap(long, env = "MICROBIN_ADMIN_USERNAME", default_value = "admin")]
     pub auth_admin_username: String,
 
@@ -146,9 +293,297 @@ pub struct Args {
         default_value_t = 2048
     )]
     pub max_file_size_unencrypted_mb: usize,
+
+    #[clap(long, env = "MICROBIN_ENABLE_METRICS")]
+    pub enable_metrics: bool,
+
+    #[clap(long, env = "MICROBIN_METRICS_TOKEN")]
+    pub metrics_token: Option<String>,
+
+    #[clap(long, env = "MICROBIN_MIN_FREE_DISK_MB", default_value_t = 100)]
+    pub min_free_disk_mb: u64,
+
+    #[clap(long, env = "MICROBIN_STORAGE_LIMIT_MB", default_value_t = 0)]
+    pub storage_limit_mb: u64,
+
+    #[clap(long, env = "MICROBIN_QUOTA_PER_IP_MB", default_value_t = 0)]
+    pub quota_per_ip_mb: u64,
+
+    #[clap(long, env = "MICROBIN_QUOTA_PER_USER_MB", default_value_t = 0)]
+    pub quota_per_user_mb: u64,
+
+    #[clap(long, env = "MICROBIN_EVICTION", default_value = "none")]
+    pub eviction: String,
+
+    #[clap(long, env = "MICROBIN_DISABLE_VIEW_LOG")]
+    pub disable_view_log: bool,
+
+    #[clap(long, env = "MICROBIN_VIEW_LOG_RETENTION_DAYS", default_value_t = 30)]
+    pub view_log_retention_days: u16,
+
+    #[clap(long, env = "MICROBIN_VIEW_LOG_IP")]
+    pub view_log_ip: bool,
+
+    #[clap(long, env = "MICROBIN_WEBHOOK_URLS")]
+    pub webhook_urls: Option<String>,
+
+    #[clap(long, env = "MICROBIN_WEBHOOK_SECRET")]
+    pub webhook_secret: Option<String>,
+
+    #[clap(long, env = "MICROBIN_ATTACHMENT_STORE", default_value = "filesystem")]
+    pub attachment_store: String,
+
+    #[clap(long, env = "MICROBIN_S3_ENDPOINT")]
+    pub s3_endpoint: Option<String>,
+
+    #[clap(long, env = "MICROBIN_S3_BUCKET")]
+    pub s3_bucket: Option<String>,
+
+    #[clap(long, env = "MICROBIN_S3_REGION", default_value = "us-east-1")]
+    pub s3_region: String,
+
+    #[clap(long, env = "MICROBIN_S3_ACCESS_KEY")]
+    pub s3_access_key: Option<String>,
+
+    #[clap(long, env = "MICROBIN_S3_SECRET_KEY")]
+    pub s3_secret_key: Option<String>,
+
+    #[clap(long, env = "MICROBIN_S3_PATH_STYLE")]
+    pub s3_path_style: bool,
+
+    #[clap(long, env = "MICROBIN_DATABASE_URL")]
+    pub database_url: Option<String>,
+
+    #[clap(subcommand)]
+    #[serde(skip)]
+    pub command: Option<Command>,
+}
+
+/// Maintenance commands, which run instead of the server.
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    /// Write all pastas, collections, settings and attachments to a backup archive
+    Export {
+        /// Path of the archive to create, like microbin-backup.tar.gz
+        file: String,
+    },
+    /// Restore a backup archive into an empty data directory
+    Import {
+        /// Path of the archive to restore
+        file: String,
+    },
+    /// Copy all pastas, collections and settings from one database backend to the other
+    MigrateDb {
+        #[clap(long, arg_enum)]
+        from: Backend,
+
+        #[clap(long, arg_enum)]
+        to: Backend,
+
+        /// Replace the contents of a target database that is not empty
+        #[clap(long)]
+        force: bool,
+    },
+}
+
+/// Database backends of the `migrate-db` command.
+#[derive(ArgEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    Json,
+    Sqlite,
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Backend::Json => write!(f, "JSON"),
+            Backend::Sqlite => write!(f, "SQLite"),
+        }
+    }
 }
 
 impl Args {
+    /// Parses the flags and environment variables and fills in everything
+    /// they leave at its default from the config file in the data directory.
+    /// Unlike at startup, errors of the flags and environment are returned,
+    /// not printed before exiting.
+    pub fn load() -> Result<Args, String> {
+        let matches = Args::command()
+            .try_get_matches()
+            .map_err(|e| e.to_string())?;
+        Args::from_matches(&matches)
+    }
+
+    fn from_matches(matches: &ArgMatches) -> Result<Args, String> {
+        let args = Args::from_arg_matches(matches).map_err(|e| e.to_string())?;
+        let command = args.command.clone();
+
+        let path = format!("{}/{}", args.data_dir, CONFIG_FILE);
+        let args = match std::fs::read_to_string(&path) {
+            Ok(contents) => {
+                let file: toml::value::Table = toml::from_str(&contents)
+                    .map_err(|e| format!("Failed to parse {}: {}", path, e))?;
+                args.merge(&file, matches)
+                    .map_err(|e| format!("{}: {}", path, e))?
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => args,
+            Err(e) => return Err(format!("Failed to read {}: {}", path, e)),
+        };
+
+        args.validate()?;
+        // the command is not a setting, so it does not survive the merge
+        Ok(Args { command, ..args })
+    }
+
+    /// Takes the values of the config file for all settings that were not
+    /// given as a flag or environment variable.
+    fn merge(self, file: &toml::value::Table, matches: &ArgMatches) -> Result<Args, String> {
+        let mut merged = serde_json::to_value(&self).unwrap();
+        let keys: Vec<String> = merged.as_object().unwrap().keys().cloned().collect();
+
+        for (key, value) in file {
+            if !keys.contains(key) {
+                return Err(format!("unknown setting `{}`", key));
+            }
+            if key == "data_dir" {
+                return Err(String::from(
+                    "`data_dir` can not be set in the config file inside of it",
+                ));
+            }
+            // clap names the arguments after their flags, in kebab case
+            if matches!(
+                matches.value_source(key.replace('_', "-").as_str()),
+                Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+            ) {
+                continue;
+            }
+
+            let value = serde_json::to_value(value).map_err(|e| e.to_string())?;
+            // try the key on its own first, to name it in the error message
+            let mut single = serde_json::to_value(&self).unwrap();
+            single[key.as_str()] = value.clone();
+            serde_json::from_value::<Args>(single)
+                .map_err(|e| format!("invalid value for `{}`: {}", key, e))?;
+
+            merged[key.as_str()] = value;
+        }
+
+        serde_json::from_value(merged).map_err(|e| e.to_string())
+    }
+
+    fn with_overrides(&self, overrides: &BTreeMap<String, Value>) -> Result<Args, String> {
+        let mut merged = serde_json::to_value(self).unwrap();
+        for (key, value) in overrides {
+            if !OVERRIDABLE.contains(&key.as_str()) {
+                return Err(format!("{} can not be changed on the settings page", key));
+            }
+            merged[key.as_str()] = value.clone();
+        }
+
+        let args: Args = serde_json::from_value(merged).map_err(|e| e.to_string())?;
+        args.validate()?;
+        Ok(args)
+    }
+
+    /// Checks settings that clap can not check on its own.
+    pub fn validate(&self) -> Result<(), String> {
+        if !EXPIRATIONS.contains(&self.default_expiry.as_str()) {
+            return Err(format!(
+                "default_expiry must be one of {}, not `{}`",
+                EXPIRATIONS.join(", "),
+                self.default_expiry
+            ));
+        }
+        if !BURN_AFTER_READS.contains(&self.default_burn_after) {
+            return Err(format!(
+                "default_burn_after must be one of {}, not `{}`",
+                BURN_AFTER_READS.map(|n| n.to_string()).join(", "),
+                self.default_burn_after
+            ));
+        }
+        if !EVICTION_POLICIES.contains(&self.eviction.as_str()) {
+            return Err(format!(
+                "eviction must be one of {}, not `{}`",
+                EVICTION_POLICIES.join(", "),
+                self.eviction
+            ));
+        }
+        if self.database_url.is_some() {
+            if !cfg!(feature = "postgres") {
+                return Err(String::from(
+                    "database_url requires a build with the postgres feature",
+                ));
+            }
+            if self.json_db {
+                return Err(String::from("json_db and database_url can not be used together"));
+            }
+        }
+        if !ATTACHMENT_STORES.contains(&self.attachment_store.as_str()) {
+            return Err(format!(
+                "attachment_store must be one of {}, not `{}`",
+                ATTACHMENT_STORES.join(", "),
+                self.attachment_store
+            ));
+        }
+        if self.attachment_store == "s3" {
+            for (key, value) in [
+                ("s3_endpoint", &self.s3_endpoint),
+                ("s3_bucket", &self.s3_bucket),
+                ("s3_access_key", &self.s3_access_key),
+                ("s3_secret_key", &self.s3_secret_key),
+            ] {
+                if value.as_deref().unwrap_or("").is_empty() {
+                    return Err(format!("{} is required for the s3 attachment store", key));
+                }
+            }
+            let endpoint = self.s3_endpoint.as_deref().unwrap_or("");
+            if !endpoint.starts_with("http://") && !endpoint.starts_with("https://") {
+                return Err(format!(
+                    "s3_endpoint must start with http:// or https://, not `{}`",
+                    endpoint
+                ));
+            }
+        }
+        if self.attachment_store == "sqlite" {
+            if cfg!(not(feature = "default")) {
+                return Err(String::from(
+                    "the sqlite attachment store requires a build with SQLite support",
+                ));
+            }
+            if self.json_db || self.database_url.is_some() {
+                return Err(String::from(
+                    "the sqlite attachment store requires the SQLite database",
+                ));
+            }
+        }
+        if let Some(urls) = &self.webhook_urls {
+            let urls: Vec<&str> = urls.split(',').map(str::trim).filter(|url| !url.is_empty()).collect();
+            if let Some(url) = urls
+                .iter()
+                .find(|url| !url.starts_with("http://") && !url.starts_with("https://"))
+            {
+                return Err(format!("webhook URL {} must start with http:// or https://", url));
+            }
+            if !urls.is_empty() && self.webhook_secret.as_deref().unwrap_or("").is_empty() {
+                return Err(String::from("webhook_secret is required to sign webhook payloads"));
+            }
+        }
+        if self.threads == 0 {
+            return Err(String::from("threads must be at least 1"));
+        }
+        for (key, url) in [("public_path", &self.public_path), ("short_path", &self.short_path)] {
+            if let Some(url) = url {
+                if !url.0.starts_with("http://") && !url.0.starts_with("https://") {
+                    return Err(format!(
+                        "{} must start with http:// or https://, not `{}`",
+                        key, url
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
     pub fn public_path_as_str(&self) -> String {
         if self.public_path.is_some() {
             self.public_path.as_ref().unwrap().to_string()
@@ -171,6 +606,7 @@ impl Args {
         Args {
             auth_basic_username: None,
             auth_basic_password: None,
+            auth_basic_htpasswd: None,
             auth_admin_username: String::from(""),
             auth_admin_password: String::from(""),
             editable: self.editable,
@@ -211,13 +647,41 @@ impl Args {
             max_file_size_encrypted_mb: self.max_file_size_encrypted_mb,
             max_file_size_unencrypted_mb: self.max_file_size_unencrypted_mb,
             disable_update_checking: self.disable_update_checking,
+            enable_metrics: self.enable_metrics,
+            metrics_token: None,
+            min_free_disk_mb: self.min_free_disk_mb,
+            storage_limit_mb: self.storage_limit_mb,
+            quota_per_ip_mb: self.quota_per_ip_mb,
+            quota_per_user_mb: self.quota_per_user_mb,
+            eviction: self.eviction,
+            disable_view_log: self.disable_view_log,
+            view_log_retention_days: self.view_log_retention_days,
+            view_log_ip: self.view_log_ip,
+            webhook_urls: self.webhook_urls,
+            webhook_secret: None,
+            attachment_store: self.attachment_store,
+            s3_endpoint: self.s3_endpoint,
+            s3_bucket: self.s3_bucket,
+            s3_region: self.s3_region,
+            s3_access_key: None,
+            s3_secret_key: None,
+            s3_path_style: self.s3_path_style,
+            database_url: None,
+            command: None,
         }
     }
 }
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(from = "String")]
 pub struct PublicUrl(pub String);
 
+impl From<String> for PublicUrl {
+    fn from(s: String) -> Self {
+        PublicUrl::from_str(&s).unwrap_or_else(|e| match e {})
+    }
+}
+
 impl fmt::Display for PublicUrl {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{}", self.0)
@@ -232,3 +696,40 @@ impl FromStr for PublicUrl {
         Ok(PublicUrl(uri))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn load_from(flags: &[&str], file: &str) -> Result<Args, String> {
+        let matches = Args::command().get_matches_from(flags);
+        let args = Args::from_arg_matches(&matches).unwrap();
+        args.merge(&toml::from_str(file).unwrap(), &matches)
+    }
+
+    #[test]
+    fn test_flags_override_config_file() {
+        let args = load_from(
+            &["microbin", "--title", "Flag"],
+            "title = \"File\"\nqr = true\nport = 9000",
+        )
+        .unwrap();
+        assert_eq!(args.title.as_deref(), Some("Flag"));
+        assert!(args.qr);
+        assert_eq!(args.port, 9000);
+    }
+
+    #[test]
+    fn test_invalid_config_file() {
+        assert_eq!(
+            load_from(&["microbin"], "titel = \"x\"").unwrap_err(),
+            "unknown setting `titel`"
+        );
+        assert!(load_from(&["microbin"], "port = \"high\"")
+            .unwrap_err()
+            .starts_with("invalid value for `port`"));
+
+        let args = load_from(&["microbin"], "default_expiry = \"2days\"").unwrap();
+        assert!(args.validate().unwrap_err().starts_with("default_expiry must be one of"));
+    }
+}
@@ -0,0 +1,26 @@
+use serde::{Deserialize, Serialize};
+
+use crate::args::ARGS;
+use crate::util::animalnumbers::to_animal_names;
+use crate::util::hashids::to_hashids;
+
+/// A named bundle of pastas that can be shared under a single URL.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Collection {
+    pub id: u64,
+    pub name: String,
+    /// Ids of the bundled pastas in the order they are shown. Pastas that
+    /// expired or were removed since are skipped when showing the collection.
+    pub pasta_ids: Vec<u64>,
+    pub created: i64,
+}
+
+impl Collection {
+    pub fn id_as_animals(&self) -> String {
+        if ARGS.get().hash_ids {
+            to_hashids(self.id)
+        } else {
+            to_animal_names(self.id)
+        }
+    }
+}
@@ -1,29 +1,143 @@
 use crate::args::{Args, ARGS};
-use crate::pasta::Pasta;
-use crate::util::misc::remove_expired;
+use crate::collection::Collection;
+use crate::endpoints::create::expiration_to_timestamp;
+use crate::util::{auth, backup, store};
+use crate::util::db::{self, delete, delete_collection, insert_collection, read_admin_totp, update};
+use crate::util::listing::{ListQuery, Page};
+use crate::util::metrics;
+use crate::util::misc::{remove_attachment, remove_expired, unused_id};
+use crate::util::totp::{self, AdminTotp};
 use crate::util::version::{fetch_latest_version, Version, CURRENT_VERSION};
-use crate::AppState;
+use crate::util::webhooks::{self, Delivery, Event};
+use crate::{AppState, Pasta};
+use actix_files::NamedFile;
 use actix_multipart::Multipart;
-use actix_web::{get, post, web, Error, HttpResponse};
+use actix_web::error::ErrorInternalServerError;
+use actix_web::http::header::{ContentDisposition, DispositionParam, DispositionType};
+use actix_web::{get, post, web, Error, HttpRequest, HttpResponse};
 use askama::Template;
+use chrono::Local;
 use futures::TryStreamExt;
+use serde::Deserialize;
+use std::fs::File;
+use std::io::{Seek, SeekFrom};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 #[derive(Template)]
 #[template(path = "admin.html")]
 struct AdminTemplate<'a> {
-    pastas: &'a Vec<Pasta>,
+    page: &'a Page<'a>,
+    query: &'a ListQuery,
+    uploads: usize,
+    collections: &'a Vec<Collection>,
     args: &'a Args,
     status: &'a String,
     version_string: &'a String,
     message: &'a String,
     update: &'a Option<Version>,
+    totp: &'a Option<AdminTotp>,
+    webhook_urls: &'a Vec<String>,
+    deliveries: &'a Vec<Delivery>,
+}
+
+#[derive(Deserialize)]
+pub struct AdminQuery {
+    status: Option<String>,
+    count: Option<usize>,
 }
 
 #[get("/admin")]
-pub async fn get_admin() -> Result<HttpResponse, Error> {
-    return Ok(HttpResponse::Found()
-        .append_header(("Location", format!("{}/auth_admin", ARGS.public_path_as_str())))
-        .finish());
+pub async fn get_admin(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    query: web::Query<AdminQuery>,
+    list_query: web::Query<ListQuery>,
+) -> Result<HttpResponse, Error> {
+    if !auth::is_admin(&req, &data.db).await {
+        return Ok(HttpResponse::Found()
+            .append_header(("Location", format!("{}/auth_admin", ARGS.get().public_path_as_str())))
+            .finish());
+    }
+
+    let update;
+
+    if !ARGS.get().disable_update_checking {
+        let latest_version_res = fetch_latest_version().await;
+        if latest_version_res.is_ok() {
+            let latest_version = latest_version_res.unwrap();
+            if latest_version.newer_than_current() {
+                update = Some(latest_version);
+            } else {
+                update = None;
+            }
+        } else {
+            update = None;
+        }
+    } else {
+        update = None;
+    }
+
+    let db = data.db.clone();
+    let totp = web::block(move || read_admin_totp(&db)).await?;
+
+    data.pastas.reload().await;
+    data.collections.reload().await;
+    let mut pastas = data.pastas.lock().unwrap();
+
+    remove_expired(&data.db, &mut pastas);
+
+    let page = list_query.apply(pastas.iter());
+
+    // todo status report more sophisticated
+    let mut status = "OK";
+    let mut message = String::from("");
+
+    if ARGS.get().public_path.is_none() {
+        status = "WARNING";
+        message = String::from("Warning: No public URL set with --public-path parameter. QR code and URL Copying functions have been disabled");
+    }
+
+    if ARGS.get().auth_admin_username == "admin" && ARGS.get().auth_admin_password == "m1cr0b1n" {
+        status = "WARNING";
+        message = String::from("Warning: You are using the default admin login details. This is a security risk, please change them.");
+    }
+
+    match query.status.as_deref() {
+        Some("totp_incorrect") => {
+            message = String::from(
+                "Incorrect authentication code, two-factor authentication is still enabled.",
+            )
+        }
+        Some("bulk") => {
+            message = format!("Updated {} upload(s).", query.count.unwrap_or(0));
+        }
+        Some("collection") => {
+            message = format!(
+                "Created a collection of {} upload(s).",
+                query.count.unwrap_or(0)
+            );
+        }
+        _ => {}
+    }
+
+    Ok(HttpResponse::Ok().content_type("text/html; charset=utf-8").body(
+        AdminTemplate {
+            page: &page,
+            query: &list_query,
+            uploads: pastas.len(),
+            collections: &data.collections.lock().unwrap(),
+            args: &ARGS.get(),
+            status: &String::from(status),
+            version_string: &format!("{}", CURRENT_VERSION.long_title),
+            message: &message,
+            update: &update,
+            totp: &totp,
+            webhook_urls: &webhooks::urls(),
+            deliveries: &webhooks::deliveries(),
+        }
+        .render()
+        .unwrap(),
+    ))
 }
 
 #[post("/admin")]
@@ -33,6 +147,7 @@ pub async fn post_admin(
 ) -> Result<HttpResponse, Error> {
     let mut username = String::from("");
     let mut password = String::from("");
+    let mut code = String::from("");
 
     while let Some(mut field) = payload.try_next().await? {
         if field.name() == Some("username") {
@@ -43,64 +158,311 @@ pub async fn post_admin(
             while let Some(chunk) = field.try_next().await? {
                 password.push_str(std::str::from_utf8(&chunk).unwrap().to_string().as_str());
             }
+        } else if field.name() == Some("code") {
+            while let Some(chunk) = field.try_next().await? {
+                code.push_str(std::str::from_utf8(&chunk).unwrap().to_string().as_str());
+            }
         }
     }
 
-    if username != ARGS.auth_admin_username || password != ARGS.auth_admin_password {
+    let db = data.db.clone();
+    if username != ARGS.get().auth_admin_username
+        || password != ARGS.get().auth_admin_password
+        || !web::block(move || totp::verify_login(&db, &code)).await?
+    {
+        metrics::failed_auth("admin");
         return Ok(HttpResponse::Found()
-            .append_header(("Location", format!("{}/auth_admin/incorrect", ARGS.public_path_as_str())))
+            .append_header(("Location", format!("{}/auth_admin/incorrect", ARGS.get().public_path_as_str())))
             .finish());
     }
 
-    let mut pastas = data.pastas.lock().unwrap();
+    Ok(HttpResponse::Found()
+        .cookie(auth::start_admin_session(&data.db).await?)
+        .append_header(("Location", format!("{}/admin", ARGS.get().public_path_as_str())))
+        .finish())
+}
+
+#[get("/admin/logout")]
+pub async fn admin_logout(req: HttpRequest, data: web::Data<AppState>) -> HttpResponse {
+    HttpResponse::Found()
+        .cookie(auth::end_admin_session(&req, &data.db).await)
+        .append_header(("Location", format!("{}/auth_admin", ARGS.get().public_path_as_str())))
+        .finish()
+}
 
-    remove_expired(&mut pastas);
+/// Downloads a backup of the whole instance, the same archive as
+/// `microbin export` writes.
+#[get("/admin/export")]
+pub async fn get_admin_export(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, Error> {
+    if !auth::is_admin(&req, &data.db).await {
+        return Ok(HttpResponse::Found()
+            .append_header(("Location", format!("{}/auth_admin", ARGS.get().public_path_as_str())))
+            .finish());
+    }
 
-    // sort pastas in reverse-chronological order of creation time
-    pastas.sort_by(|a, b| b.created.cmp(&a.created));
+    // the archive can be larger than memory, so it is built in an unlinked
+    // file in the data directory
+    let path = store::local_path(&format!(".export-{}", rand::random::<u64>()));
+    let mut file = File::options()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(&path)?;
+    let _ = std::fs::remove_file(&path);
 
-    // todo status report more sophisticated
-    let mut status = "OK";
-    let mut message = "";
+    data.pastas.reload().await;
+    data.collections.reload().await;
+    data.setting_changes.reload().await;
 
-    if ARGS.public_path.is_none() {
-        status = "WARNING";
-        message = "Warning: No public URL set with --public-path parameter. QR code and URL Copying functions have been disabled"
+    // the records are copied under the locks, which are released before the
+    // attachments are read into the archive
+    let (pastas, collections, setting_changes) = {
+        let pastas = data.pastas.lock().unwrap();
+        let collections = data.collections.lock().unwrap();
+        let setting_changes = data.setting_changes.lock().unwrap();
+        (pastas.clone(), collections.clone(), setting_changes.clone())
+    };
+
+    let db = data.db.clone();
+    let mut file = web::block(move || {
+        let totp = read_admin_totp(&db);
+        backup::export(
+            &db,
+            &mut file,
+            &pastas,
+            &collections,
+            &setting_changes,
+            totp.as_ref(),
+        )
+        .map(|_| file)
+    })
+    .await?
+    .map_err(|e| {
+        log::error!("Failed to export the backup: {}", e);
+        ErrorInternalServerError(e)
+    })?;
+    file.seek(SeekFrom::Start(0))?;
+
+    let name = format!("microbin-backup-{}.tar.gz", Local::now().format("%Y-%m-%d"));
+    let response = NamedFile::from_file(file, &name)?.set_content_disposition(ContentDisposition {
+        disposition: DispositionType::Attachment,
+        parameters: vec![DispositionParam::Filename(name.clone())],
+    });
+    Ok(response.into_response(&req))
+}
+
+/// Applies one moderation action to every selected pasta at once.
+#[post("/admin/bulk")]
+pub async fn post_admin_bulk(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    mut payload: Multipart,
+) -> Result<HttpResponse, Error> {
+    if !auth::is_admin(&req, &data.db).await {
+        return Ok(HttpResponse::Found()
+            .append_header(("Location", format!("{}/auth_admin", ARGS.get().public_path_as_str())))
+            .finish());
     }
 
-    if ARGS.auth_admin_username == "admin" && ARGS.auth_admin_password == "m1cr0b1n" {
-        status = "WARNING";
-        message = "Warning: You are using the default admin login details. This is a security risk, please change them."
+    let mut ids: Vec<u64> = Vec::new();
+    let mut action = String::from("");
+    let mut expiration = String::from("");
+    let mut collection_name = String::from("");
+
+    while let Some(mut field) = payload.try_next().await? {
+        let mut value = String::from("");
+        while let Some(chunk) = field.try_next().await? {
+            value.push_str(std::str::from_utf8(&chunk).unwrap().to_string().as_str());
+        }
+
+        match field.name() {
+            Some("id") => {
+                if let Ok(id) = value.parse() {
+                    ids.push(id);
+                }
+            }
+            Some("action") => action = value,
+            Some("expiration") => expiration = value,
+            Some("collection_name") => collection_name = value,
+            _ => {}
+        }
     }
 
-    let update;
+    let timenow: i64 = match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(n) => n.as_secs(),
+        Err(_) => {
+            log::error!("SystemTime before UNIX EPOCH!");
+            0
+        }
+    } as i64;
 
-    if !ARGS.disable_update_checking {
-        let latest_version_res = fetch_latest_version().await;
-        if latest_version_res.is_ok() {
-            let latest_version = latest_version_res.unwrap();
-            if latest_version.newer_than_current() {
-                update = Some(latest_version);
-            } else {
-                update = None;
+    data.pastas.reload().await;
+
+    if action == "collect" {
+        let pasta_ids: Vec<u64> = {
+            let pastas = data.pastas.lock().unwrap();
+            ids.into_iter()
+                .filter(|id| pastas.iter().any(|p| p.id == *id))
+                .collect()
+        };
+        let count = pasta_ids.len();
+
+        if count > 0 {
+            data.collections.reload().await;
+            let write = {
+                let mut collections = data.collections.lock().unwrap();
+                let name = collection_name.trim();
+                let collection = Collection {
+                    id: unused_id(|id| collections.iter().any(|c| c.id == id)),
+                    name: if name.is_empty() {
+                        String::from("Untitled collection")
+                    } else {
+                        name.to_string()
+                    },
+                    pasta_ids,
+                    created: timenow,
+                };
+                collections.push(collection.clone());
+                db::write(&data.db, collections, move |db, collections| {
+                    insert_collection(db, collections, Some(&collection))
+                })
+            };
+            write.await?;
+            log::info!("Admin created a collection of {} upload(s)", count);
+        }
+
+        return Ok(HttpResponse::Found()
+            .append_header((
+                "Location",
+                format!(
+                    "{}/admin?status=collection&count={}",
+                    ARGS.get().public_path_as_str(),
+                    count
+                ),
+            ))
+            .finish());
+    }
+
+    let (count, write) = {
+        let mut pastas = data.pastas.lock().unwrap();
+
+        let mut count = 0;
+        // the database is written once all the pastas are changed
+        let mut removed: Vec<Pasta> = Vec::new();
+        let mut purged: Vec<Pasta> = Vec::new();
+        let mut updated: Vec<Pasta> = Vec::new();
+
+        for id in ids {
+            let Some(i) = pastas.iter().position(|p| p.id == id) else {
+                continue;
+            };
+
+            // a pasta without an attachment, text or text files has nothing
+            // left to show, so purging its attachment removes it completely
+            let remove = action == "delete"
+                || (action == "purge_attachments"
+                    && pastas[i].file.is_some()
+                    && pastas[i].content.is_empty()
+                    && pastas[i].files.is_empty());
+
+            if remove {
+                webhooks::notify(Event::Deleted, &pastas[i]);
+                removed.push(pastas.remove(i));
+                count += 1;
+                continue;
             }
-        } else {
-            update = None;
+
+            if action == "purge_attachments" && pastas[i].file.is_some() {
+                purged.push(pastas[i].clone());
+            }
+
+            let pasta = &mut pastas[i];
+            match action.as_str() {
+                "expiration" => pasta.expiration = expiration_to_timestamp(&expiration, timenow),
+                "clear_burn_after" => pasta.burn_after_reads = 0,
+                "make_private" => pasta.private = true,
+                // encrypted pastas are always private
+                "make_public" if !pasta.encrypt_server && !pasta.encrypt_client => {
+                    pasta.private = false
+                }
+                "make_editable" => pasta.editable = true,
+                "make_uneditable" => pasta.editable = false,
+                "purge_attachments" if pasta.file.is_some() => pasta.file = None,
+                _ => continue,
+            }
+
+            updated.push(pastas[i].clone());
+            count += 1;
         }
-    } else {
-        update = None;
+
+        let write = db::write(&data.db, pastas, move |db, pastas| {
+            let others = pastas.map_or(&[][..], Vec::as_slice);
+            for pasta in &removed {
+                delete(db, pastas, Some(pasta.id));
+                remove_attachment(db, pasta, others);
+            }
+            for pasta in &purged {
+                remove_attachment(db, pasta, others);
+            }
+            for pasta in &updated {
+                update(db, pastas, Some(pasta));
+            }
+        });
+        (count, write)
+    };
+    write.await?;
+
+    log::info!("Admin applied bulk action {} to {} upload(s)", action, count);
+
+    Ok(HttpResponse::Found()
+        .append_header((
+            "Location",
+            format!(
+                "{}/admin?status=bulk&count={}",
+                ARGS.get().public_path_as_str(),
+                count
+            ),
+        ))
+        .finish())
+}
+
+#[post("/admin/collection/{id}/delete")]
+pub async fn post_admin_delete_collection(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    id: web::Path<u64>,
+) -> HttpResponse {
+    if !auth::is_admin(&req, &data.db).await {
+        return HttpResponse::Found()
+            .append_header(("Location", format!("{}/auth_admin", ARGS.get().public_path_as_str())))
+            .finish();
     }
 
-    Ok(HttpResponse::Ok().content_type("text/html; charset=utf-8").body(
-        AdminTemplate {
-            pastas: &pastas,
-            args: &ARGS,
-            status: &String::from(status),
-            version_string: &format!("{}", CURRENT_VERSION.long_title),
-            message: &String::from(message),
-            update: &update,
+    let id = id.into_inner();
+    data.collections.reload().await;
+    let write = {
+        let mut collections = data.collections.lock().unwrap();
+
+        let index = collections.iter().position(|c| c.id == id);
+        index.map(|index| {
+            // the bundled pastas stay, only the collection itself goes away
+            collections.remove(index);
+            db::write(&data.db, collections, move |db, collections| {
+                delete_collection(db, collections, Some(id))
+            })
+        })
+    };
+
+    if let Some(write) = write {
+        if let Err(e) = write.await {
+            return HttpResponse::from_error(e);
         }
-        .render()
-        .unwrap(),
-    ))
+    }
+
+    HttpResponse::Found()
+        .append_header(("Location", format!("{}/admin", ARGS.get().public_path_as_str())))
+        .finish()
 }
@@ -0,0 +1,225 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::args::{Args, ARGS, EXPIRATIONS, OVERRIDABLE};
+use crate::setting::{self, display_value, SettingChange};
+use crate::util::auth;
+use crate::util::db::{self, insert_setting_change};
+use crate::AppState;
+use actix_multipart::Multipart;
+use actix_web::{get, post, web, Error, HttpRequest, HttpResponse};
+use askama::Template;
+use futures::TryStreamExt;
+use serde::Deserialize;
+use serde_json::Value;
+
+/// Number of changes shown in the history on the settings page.
+const HISTORY_LENGTH: usize = 50;
+
+/// One row of the settings table.
+struct SettingRow {
+    key: &'static str,
+    /// "select", "number" or "text", picks the input shown
+    kind: &'static str,
+    /// Choices of a select
+    options: Vec<&'static str>,
+    value: String,
+    from_database: bool,
+    environment_value: String,
+}
+
+#[derive(Template)]
+#[template(path = "admin_settings.html")]
+struct AdminSettingsTemplate<'a> {
+    args: &'a Args,
+    rows: &'a Vec<SettingRow>,
+    history: &'a Vec<SettingChange>,
+    status: &'a String,
+}
+
+#[derive(Deserialize)]
+pub struct SettingsQuery {
+    status: Option<String>,
+}
+
+fn redirect_to_login() -> HttpResponse {
+    HttpResponse::Found()
+        .append_header(("Location", format!("{}/auth_admin", ARGS.get().public_path_as_str())))
+        .finish()
+}
+
+fn redirect_with_status(status: &str) -> HttpResponse {
+    HttpResponse::Found()
+        .append_header((
+            "Location",
+            format!("{}/admin/settings?status={}", ARGS.get().public_path_as_str(), status),
+        ))
+        .finish()
+}
+
+impl SettingRow {
+    fn new(key: &'static str, current: &Value, loaded: &Value, from_database: bool) -> SettingRow {
+        let (kind, options) = match &loaded[key] {
+            Value::Bool(_) => ("select", vec!["true", "false"]),
+            Value::Number(_) => ("number", Vec::new()),
+            _ if key == "default_expiry" => ("select", EXPIRATIONS.to_vec()),
+            _ => ("text", Vec::new()),
+        };
+        SettingRow {
+            key,
+            kind,
+            options,
+            value: display_value(&current[key]),
+            from_database,
+            environment_value: display_value(&loaded[key]),
+        }
+    }
+
+    fn is_value(&self, option: &str) -> bool {
+        self.value == option
+    }
+}
+
+#[get("/admin/settings")]
+pub async fn get_admin_settings(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    query: web::Query<SettingsQuery>,
+) -> HttpResponse {
+    if !auth::is_admin(&req, &data.db).await {
+        return redirect_to_login();
+    }
+
+    data.setting_changes.reload().await;
+    setting::apply(&data.setting_changes.lock().unwrap());
+
+    let current = serde_json::to_value(&*ARGS.get()).unwrap();
+    let loaded = serde_json::to_value(ARGS.loaded()).unwrap();
+    let overrides = ARGS.overrides();
+
+    let rows: Vec<SettingRow> = OVERRIDABLE
+        .iter()
+        .map(|key| SettingRow::new(key, &current, &loaded, overrides.contains_key(*key)))
+        .collect();
+
+    let history: Vec<SettingChange> = data
+        .setting_changes
+        .lock()
+        .unwrap()
+        .iter()
+        .rev()
+        .take(HISTORY_LENGTH)
+        .cloned()
+        .collect();
+
+    HttpResponse::Ok().content_type("text/html; charset=utf-8").body(
+        AdminSettingsTemplate {
+            args: &ARGS.get(),
+            rows: &rows,
+            history: &history,
+            status: &query.status.clone().unwrap_or_default(),
+        }
+        .render()
+        .unwrap(),
+    )
+}
+
+/// Sets or, with the reset action, removes the override of one setting.
+#[post("/admin/settings")]
+pub async fn post_admin_settings(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    mut payload: Multipart,
+) -> Result<HttpResponse, Error> {
+    if !auth::is_admin(&req, &data.db).await {
+        return Ok(redirect_to_login());
+    }
+
+    let mut key = String::from("");
+    let mut value = String::from("");
+    let mut action = String::from("");
+
+    while let Some(mut field) = payload.try_next().await? {
+        let target = match field.name() {
+            Some("key") => &mut key,
+            Some("value") => &mut value,
+            Some("action") => &mut action,
+            _ => continue,
+        };
+        while let Some(chunk) = field.try_next().await? {
+            target.push_str(std::str::from_utf8(&chunk).unwrap().to_string().as_str());
+        }
+    }
+
+    if !OVERRIDABLE.contains(&key.as_str()) {
+        return Ok(redirect_with_status("invalid"));
+    }
+
+    let new_value = if action == "reset" {
+        None
+    } else {
+        let loaded = serde_json::to_value(ARGS.loaded()).unwrap();
+        let value = value.trim();
+        let parsed = match &loaded[key.as_str()] {
+            Value::Bool(_) => value.parse::<bool>().map(Value::from).ok(),
+            Value::Number(_) => value.parse::<u64>().map(Value::from).ok(),
+            // optional text settings are unset by leaving them empty
+            Value::Null if value.is_empty() => Some(Value::Null),
+            _ => Some(Value::from(value)),
+        };
+        match parsed {
+            Some(parsed) => Some(parsed),
+            None => return Ok(redirect_with_status("invalid")),
+        }
+    };
+
+    // start from the changes made on other instances sharing the database
+    data.setting_changes.reload().await;
+    setting::apply(&data.setting_changes.lock().unwrap());
+
+    let mut overrides = ARGS.overrides();
+    match &new_value {
+        Some(new_value) => overrides.insert(key.clone(), new_value.clone()),
+        None => overrides.remove(&key),
+    };
+    if let Err(error) = ARGS.set_overrides(overrides) {
+        log::warn!("Rejected setting change of {}: {}", key, error);
+        return Ok(redirect_with_status("invalid"));
+    }
+
+    let changed_at: i64 = match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(n) => n.as_secs(),
+        Err(_) => {
+            log::error!("SystemTime before UNIX EPOCH!");
+            0
+        }
+    } as i64;
+
+    let changed_by = match req.connection_info().realip_remote_addr() {
+        Some(address) => format!("{} ({})", ARGS.get().auth_admin_username, address),
+        None => ARGS.get().auth_admin_username.clone(),
+    };
+
+    let change = SettingChange {
+        key,
+        value: new_value.map(|value| value.to_string()),
+        changed_by,
+        changed_at,
+    };
+    log::info!(
+        "Setting {} changed to {} by {}",
+        change.key,
+        change.value_as_str(),
+        change.changed_by
+    );
+
+    let write = {
+        let mut setting_changes = data.setting_changes.lock().unwrap();
+        setting_changes.push(change.clone());
+        db::write(&data.db, setting_changes, move |db, changes| {
+            insert_setting_change(db, changes, Some(&change))
+        })
+    };
+    write.await?;
+
+    Ok(redirect_with_status("saved"))
+}
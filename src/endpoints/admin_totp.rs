@@ -0,0 +1,156 @@
+use crate::args::{Args, ARGS};
+use crate::util::auth;
+use crate::util::db::{read_admin_totp, update_admin_totp};
+use crate::util::misc::string_to_qr_svg;
+use crate::util::totp::{self, AdminTotp};
+use crate::AppState;
+use actix_multipart::Multipart;
+use actix_web::{get, post, web, Error, HttpRequest, HttpResponse};
+use askama::Template;
+use futures::TryStreamExt;
+
+#[derive(Template)]
+#[template(path = "admin_totp.html", escape = "none")]
+struct AdminTotpTemplate<'a> {
+    args: &'a Args,
+    status: &'a String,
+    secret: &'a String,
+    qr: &'a String,
+    recovery_codes: &'a Vec<String>,
+}
+
+fn redirect_to_login() -> HttpResponse {
+    HttpResponse::Found()
+        .append_header(("Location", format!("{}/auth_admin", ARGS.get().public_path_as_str())))
+        .finish()
+}
+
+fn setup_page(secret: &str, status: &str) -> HttpResponse {
+    HttpResponse::Ok().content_type("text/html; charset=utf-8").body(
+        AdminTotpTemplate {
+            args: &ARGS.get(),
+            status: &String::from(status),
+            secret: &String::from(secret),
+            qr: &string_to_qr_svg(&totp::otpauth_url(secret)),
+            recovery_codes: &Vec::new(),
+        }
+        .render()
+        .unwrap(),
+    )
+}
+
+#[get("/admin/totp")]
+pub async fn get_admin_totp(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, Error> {
+    if !auth::is_admin(&req, &data.db).await {
+        return Ok(redirect_to_login());
+    }
+
+    let db = data.db.clone();
+    if web::block(move || totp::enabled(&db)).await? {
+        return Ok(HttpResponse::Found()
+            .append_header(("Location", format!("{}/admin", ARGS.get().public_path_as_str())))
+            .finish());
+    }
+
+    Ok(setup_page(&totp::new_secret(), ""))
+}
+
+#[post("/admin/totp")]
+pub async fn post_admin_totp(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    mut payload: Multipart,
+) -> Result<HttpResponse, Error> {
+    if !auth::is_admin(&req, &data.db).await {
+        return Ok(redirect_to_login());
+    }
+
+    let mut secret = String::from("");
+    let mut code = String::from("");
+
+    while let Some(mut field) = payload.try_next().await? {
+        if field.name() == Some("secret") {
+            while let Some(chunk) = field.try_next().await? {
+                secret.push_str(std::str::from_utf8(&chunk).unwrap().to_string().as_str());
+            }
+        } else if field.name() == Some("code") {
+            while let Some(chunk) = field.try_next().await? {
+                code.push_str(std::str::from_utf8(&chunk).unwrap().to_string().as_str());
+            }
+        }
+    }
+
+    // the admin proves their authenticator app is set up correctly before
+    // we start requiring it
+    let Some(last_step) = totp::check_code(&secret, &code, 0) else {
+        return Ok(setup_page(&secret, "incorrect"));
+    };
+
+    let (recovery_codes, hashes) = totp::new_recovery_codes();
+    let db = data.db.clone();
+    web::block(move || {
+        update_admin_totp(&db, Some(&AdminTotp {
+            secret,
+            recovery_codes: hashes,
+            last_step,
+        }))
+    })
+    .await?;
+    log::info!("Two-factor authentication enabled for the admin account");
+
+    Ok(HttpResponse::Ok().content_type("text/html; charset=utf-8").body(
+        AdminTotpTemplate {
+            args: &ARGS.get(),
+            status: &String::from("enabled"),
+            secret: &String::from(""),
+            qr: &String::from(""),
+            recovery_codes: &recovery_codes,
+        }
+        .render()
+        .unwrap(),
+    ))
+}
+
+#[post("/admin/totp/disable")]
+pub async fn post_admin_totp_disable(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    mut payload: Multipart,
+) -> Result<HttpResponse, Error> {
+    if !auth::is_admin(&req, &data.db).await {
+        return Ok(redirect_to_login());
+    }
+
+    let mut code = String::from("");
+
+    while let Some(mut field) = payload.try_next().await? {
+        if field.name() == Some("code") {
+            while let Some(chunk) = field.try_next().await? {
+                code.push_str(std::str::from_utf8(&chunk).unwrap().to_string().as_str());
+            }
+        }
+    }
+
+    let db = data.db.clone();
+    if let Some(admin_totp) = web::block(move || read_admin_totp(&db)).await? {
+        if totp::check_code(&admin_totp.secret, &code, admin_totp.last_step).is_none() {
+            return Ok(HttpResponse::Found()
+                .append_header((
+                    "Location",
+                    format!("{}/admin?status=totp_incorrect", ARGS.get().public_path_as_str()),
+                ))
+                .finish());
+        }
+
+        let db = data.db.clone();
+        web::block(move || update_admin_totp(&db, None)).await?;
+        log::info!("Two-factor authentication disabled for the admin account");
+    }
+
+    Ok(HttpResponse::Found()
+        .append_header(("Location", format!("{}/admin", ARGS.get().public_path_as_str())))
+        .finish())
+}
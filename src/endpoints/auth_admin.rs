@@ -1,4 +1,6 @@
 use crate::args::{Args, ARGS};
+use crate::util::totp;
+use crate::AppState;
 use actix_web::{get, web, HttpResponse};
 use askama::Template;
 
@@ -7,14 +9,19 @@ use askama::Template;
 struct AuthAdmin<'a> {
     args: &'a Args,
     status: String,
+    totp: bool,
 }
 
 #[get("/auth_admin")]
-pub async fn auth_admin() -> HttpResponse {
+pub async fn auth_admin(data: web::Data<AppState>) -> HttpResponse {
+    let db = data.db.clone();
+    let totp = web::block(move || totp::enabled(&db)).await.unwrap_or_default();
+
     return HttpResponse::Ok().content_type("text/html; charset=utf-8").body(
         AuthAdmin {
-            args: &ARGS,
+            args: &ARGS.get(),
             status: String::from(""),
+            totp,
         }
         .render()
         .unwrap(),
@@ -22,13 +29,19 @@ pub async fn auth_admin() -> HttpResponse {
 }
 
 #[get("/auth_admin/{status}")]
-pub async fn auth_admin_with_status(param: web::Path<String>) -> HttpResponse {
+pub async fn auth_admin_with_status(
+    data: web::Data<AppState>,
+    param: web::Path<String>,
+) -> HttpResponse {
     let status = param.into_inner();
+    let db = data.db.clone();
+    let totp = web::block(move || totp::enabled(&db)).await.unwrap_or_default();
 
     return HttpResponse::Ok().content_type("text/html; charset=utf-8").body(
         AuthAdmin {
-            args: &ARGS,
+            args: &ARGS.get(),
             status,
+            totp,
         }
         .render()
         .unwrap(),
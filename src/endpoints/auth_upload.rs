@@ -20,22 +20,23 @@ struct AuthPasta<'a> {
 
 #[get("/auth/{id}")]
 pub async fn auth_upload(data: web::Data<AppState>, id: web::Path<String>) -> HttpResponse {
-    // get access to the pasta collection
-    let mut pastas = data.pastas.lock().unwrap();
-
-    remove_expired(&mut pastas);
-
-    let intern_id = if ARGS.hash_ids {
+    let intern_id = if ARGS.get().hash_ids {
         hashid_to_u64(&id).unwrap_or(0)
     } else {
         to_u64(&id).unwrap_or(0)
     };
 
+    // get access to the pasta collection
+    data.pastas.reload_id(intern_id).await;
+    let mut pastas = data.pastas.lock().unwrap();
+
+    remove_expired(&data.db, &mut pastas);
+
     for (_i, pasta) in pastas.iter().enumerate() {
         if pasta.id == intern_id {
             return HttpResponse::Ok().content_type("text/html; charset=utf-8").body(
                 AuthPasta {
-                    args: &ARGS,
+                    args: &ARGS.get(),
                     id: id.into_inner(),
                     status: String::from(""),
                     encrypted_key: pasta.encrypted_key.to_owned().unwrap_or_default(),
@@ -50,7 +51,7 @@ pub async fn auth_upload(data: web::Data<AppState>, id: web::Path<String>) -> Ht
 
     HttpResponse::Ok()
         .content_type("text/html; charset=utf-8")
-        .body(ErrorTemplate { args: &ARGS }.render().unwrap())
+        .body(ErrorTemplate { args: &ARGS.get() }.render().unwrap())
 }
 
 #[get("/auth/{id}/{status}")]
@@ -58,24 +59,25 @@ pub async fn auth_upload_with_status(
     data: web::Data<AppState>,
     param: web::Path<(String, String)>,
 ) -> HttpResponse {
-    // get access to the pasta collection
-    let mut pastas = data.pastas.lock().unwrap();
-
-    remove_expired(&mut pastas);
-
     let (id, status) = param.into_inner();
 
-    let intern_id = if ARGS.hash_ids {
+    let intern_id = if ARGS.get().hash_ids {
         hashid_to_u64(&id).unwrap_or(0)
     } else {
         to_u64(&id).unwrap_or(0)
     };
 
+    // get access to the pasta collection
+    data.pastas.reload_id(intern_id).await;
+    let mut pastas = data.pastas.lock().unwrap();
+
+    remove_expired(&data.db, &mut pastas);
+
     for (_i, pasta) in pastas.iter().enumerate() {
         if pasta.id == intern_id {
             return HttpResponse::Ok().content_type("text/html; charset=utf-8").body(
                 AuthPasta {
-                    args: &ARGS,
+                    args: &ARGS.get(),
                     id,
                     status,
                     encrypted_key: pasta.encrypted_key.to_owned().unwrap_or_default(),
@@ -90,27 +92,28 @@ pub async fn auth_upload_with_status(
 
     HttpResponse::Ok()
         .content_type("text/html; charset=utf-8")
-        .body(ErrorTemplate { args: &ARGS }.render().unwrap())
+        .body(ErrorTemplate { args: &ARGS.get() }.render().unwrap())
 }
 
 #[get("/auth_raw/{id}")]
 pub async fn auth_raw_pasta(data: web::Data<AppState>, id: web::Path<String>) -> HttpResponse {
-    // get access to the pasta collection
-    let mut pastas = data.pastas.lock().unwrap();
-
-    remove_expired(&mut pastas);
-
-    let intern_id = if ARGS.hash_ids {
+    let intern_id = if ARGS.get().hash_ids {
         hashid_to_u64(&id).unwrap_or(0)
     } else {
         to_u64(&id).unwrap_or(0)
     };
 
+    // get access to the pasta collection
+    data.pastas.reload_id(intern_id).await;
+    let mut pastas = data.pastas.lock().unwrap();
+
+    remove_expired(&data.db, &mut pastas);
+
     for (_i, pasta) in pastas.iter().enumerate() {
         if pasta.id == intern_id {
             return HttpResponse::Ok().content_type("text/html; charset=utf-8").body(
                 AuthPasta {
-                    args: &ARGS,
+                    args: &ARGS.get(),
                     id: id.into_inner(),
                     status: String::from(""),
                     encrypted_key: pasta.encrypted_key.to_owned().unwrap_or_default(),
@@ -125,7 +128,7 @@ pub async fn auth_raw_pasta(data: web::Data<AppState>, id: web::Path<String>) ->
 
     HttpResponse::Ok()
         .content_type("text/html; charset=utf-8")
-        .body(ErrorTemplate { args: &ARGS }.render().unwrap())
+        .body(ErrorTemplate { args: &ARGS.get() }.render().unwrap())
 }
 
 #[get("/auth_raw/{id}/{status}")]
@@ -133,24 +136,25 @@ pub async fn auth_raw_pasta_with_status(
     data: web::Data<AppState>,
     param: web::Path<(String, String)>,
 ) -> HttpResponse {
-    // get access to the pasta collection
-    let mut pastas = data.pastas.lock().unwrap();
-
-    remove_expired(&mut pastas);
-
     let (id, status) = param.into_inner();
 
-    let intern_id = if ARGS.hash_ids {
+    let intern_id = if ARGS.get().hash_ids {
         hashid_to_u64(&id).unwrap_or(0)
     } else {
         to_u64(&id).unwrap_or(0)
     };
 
+    // get access to the pasta collection
+    data.pastas.reload_id(intern_id).await;
+    let mut pastas = data.pastas.lock().unwrap();
+
+    remove_expired(&data.db, &mut pastas);
+
     for (_i, pasta) in pastas.iter().enumerate() {
         if pasta.id == intern_id {
             return HttpResponse::Ok().content_type("text/html; charset=utf-8").body(
                 AuthPasta {
-                    args: &ARGS,
+                    args: &ARGS.get(),
                     id,
                     status,
                     encrypted_key: pasta.encrypted_key.to_owned().unwrap_or_default(),
@@ -165,27 +169,28 @@ pub async fn auth_raw_pasta_with_status(
 
     HttpResponse::Ok()
         .content_type("text/html; charset=utf-8")
-        .body(ErrorTemplate { args: &ARGS }.render().unwrap())
+        .body(ErrorTemplate { args: &ARGS.get() }.render().unwrap())
 }
 
 #[get("/auth_edit_private/{id}")]
 pub async fn auth_edit_private(data: web::Data<AppState>, id: web::Path<String>) -> HttpResponse {
-    // get access to the pasta collection
-    let mut pastas = data.pastas.lock().unwrap();
-
-    remove_expired(&mut pastas);
-
-    let intern_id = if ARGS.hash_ids {
+    let intern_id = if ARGS.get().hash_ids {
         hashid_to_u64(&id).unwrap_or(0)
     } else {
         to_u64(&id).unwrap_or(0)
     };
 
+    // get access to the pasta collection
+    data.pastas.reload_id(intern_id).await;
+    let mut pastas = data.pastas.lock().unwrap();
+
+    remove_expired(&data.db, &mut pastas);
+
     for (_, pasta) in pastas.iter().enumerate() {
         if pasta.id == intern_id {
             return HttpResponse::Ok().content_type("text/html; charset=utf-8").body(
                 AuthPasta {
-                    args: &ARGS,
+                    args: &ARGS.get(),
                     id: id.into_inner(),
                     status: String::from(""),
                     encrypted_key: pasta.encrypted_key.to_owned().unwrap_or_default(),
@@ -200,7 +205,7 @@ pub async fn auth_edit_private(data: web::Data<AppState>, id: web::Path<String>)
 
     HttpResponse::Ok()
         .content_type("text/html; charset=utf-8")
-        .body(ErrorTemplate { args: &ARGS }.render().unwrap())
+        .body(ErrorTemplate { args: &ARGS.get() }.render().unwrap())
 }
 
 #[get("/auth_edit_private/{id}/{status}")]
@@ -208,24 +213,25 @@ pub async fn auth_edit_private_with_status(
     data: web::Data<AppState>,
     param: web::Path<(String, String)>,
 ) -> HttpResponse {
-    // get access to the pasta collection
-    let mut pastas = data.pastas.lock().unwrap();
-
-    remove_expired(&mut pastas);
-
     let (id, status) = param.into_inner();
 
-    let intern_id = if ARGS.hash_ids {
+    let intern_id = if ARGS.get().hash_ids {
         hashid_to_u64(&id).unwrap_or(0)
     } else {
         to_u64(&id).unwrap_or(0)
     };
 
+    // get access to the pasta collection
+    data.pastas.reload_id(intern_id).await;
+    let mut pastas = data.pastas.lock().unwrap();
+
+    remove_expired(&data.db, &mut pastas);
+
     for (_i, pasta) in pastas.iter().enumerate() {
         if pasta.id == intern_id {
             return HttpResponse::Ok().content_type("text/html; charset=utf-8").body(
                 AuthPasta {
-                    args: &ARGS,
+                    args: &ARGS.get(),
                     id,
                     status,
                     encrypted_key: pasta.encrypted_key.to_owned().unwrap_or_default(),
@@ -240,27 +246,28 @@ pub async fn auth_edit_private_with_status(
 
     HttpResponse::Ok()
         .content_type("text/html; charset=utf-8")
-        .body(ErrorTemplate { args: &ARGS }.render().unwrap())
+        .body(ErrorTemplate { args: &ARGS.get() }.render().unwrap())
 }
 
 #[get("/auth_file/{id}")]
 pub async fn auth_file(data: web::Data<AppState>, id: web::Path<String>) -> HttpResponse {
-    // get access to the pasta collection
-    let mut pastas = data.pastas.lock().unwrap();
-
-    remove_expired(&mut pastas);
-
-    let intern_id = if ARGS.hash_ids {
+    let intern_id = if ARGS.get().hash_ids {
         hashid_to_u64(&id).unwrap_or(0)
     } else {
         to_u64(&id).unwrap_or(0)
     };
 
+    // get access to the pasta collection
+    data.pastas.reload_id(intern_id).await;
+    let mut pastas = data.pastas.lock().unwrap();
+
+    remove_expired(&data.db, &mut pastas);
+
     for (_, pasta) in pastas.iter().enumerate() {
         if pasta.id == intern_id {
             return HttpResponse::Ok().content_type("text/html; charset=utf-8").body(
                 AuthPasta {
-                    args: &ARGS,
+                    args: &ARGS.get(),
                     id: id.into_inner(),
                     status: String::from(""),
                     encrypted_key: pasta.encrypted_key.to_owned().unwrap_or_default(),
@@ -275,7 +282,7 @@ pub async fn auth_file(data: web::Data<AppState>, id: web::Path<String>) -> Http
 
     HttpResponse::Ok()
         .content_type("text/html; charset=utf-8")
-        .body(ErrorTemplate { args: &ARGS }.render().unwrap())
+        .body(ErrorTemplate { args: &ARGS.get() }.render().unwrap())
 }
 
 #[get("/auth_file/{id}/{status}")]
@@ -283,24 +290,25 @@ pub async fn auth_file_with_status(
     data: web::Data<AppState>,
     param: web::Path<(String, String)>,
 ) -> HttpResponse {
-    // get access to the pasta collection
-    let mut pastas = data.pastas.lock().unwrap();
-
-    remove_expired(&mut pastas);
-
     let (id, status) = param.into_inner();
 
-    let intern_id = if ARGS.hash_ids {
+    let intern_id = if ARGS.get().hash_ids {
         hashid_to_u64(&id).unwrap_or(0)
     } else {
         to_u64(&id).unwrap_or(0)
     };
 
+    // get access to the pasta collection
+    data.pastas.reload_id(intern_id).await;
+    let mut pastas = data.pastas.lock().unwrap();
+
+    remove_expired(&data.db, &mut pastas);
+
     for (_i, pasta) in pastas.iter().enumerate() {
         if pasta.id == intern_id {
             return HttpResponse::Ok().content_type("text/html; charset=utf-8").body(
                 AuthPasta {
-                    args: &ARGS,
+                    args: &ARGS.get(),
                     id,
                     status,
                     encrypted_key: pasta.encrypted_key.to_owned().unwrap_or_default(),
@@ -315,27 +323,28 @@ pub async fn auth_file_with_status(
 
     HttpResponse::Ok()
         .content_type("text/html; charset=utf-8")
-        .body(ErrorTemplate { args: &ARGS }.render().unwrap())
+        .body(ErrorTemplate { args: &ARGS.get() }.render().unwrap())
 }
 
 #[get("/auth_remove_private/{id}")]
 pub async fn auth_remove_private(data: web::Data<AppState>, id: web::Path<String>) -> HttpResponse {
-    // get access to the pasta collection
-    let mut pastas = data.pastas.lock().unwrap();
-
-    remove_expired(&mut pastas);
-
-    let intern_id = if ARGS.hash_ids {
+    let intern_id = if ARGS.get().hash_ids {
         hashid_to_u64(&id).unwrap_or(0)
     } else {
         to_u64(&id).unwrap_or(0)
     };
 
+    // get access to the pasta collection
+    data.pastas.reload_id(intern_id).await;
+    let mut pastas = data.pastas.lock().unwrap();
+
+    remove_expired(&data.db, &mut pastas);
+
     for (_, pasta) in pastas.iter().enumerate() {
         if pasta.id == intern_id {
             return HttpResponse::Ok().content_type("text/html; charset=utf-8").body(
                 AuthPasta {
-                    args: &ARGS,
+                    args: &ARGS.get(),
                     id: id.into_inner(),
                     status: String::from(""),
                     encrypted_key: pasta.encrypted_key.to_owned().unwrap_or_default(),
@@ -350,7 +359,7 @@ pub async fn auth_remove_private(data: web::Data<AppState>, id: web::Path<String
 
     HttpResponse::Ok()
         .content_type("text/html; charset=utf-8")
-        .body(ErrorTemplate { args: &ARGS }.render().unwrap())
+        .body(ErrorTemplate { args: &ARGS.get() }.render().unwrap())
 }
 
 #[get("/auth_remove_private/{id}/{status}")]
@@ -358,24 +367,25 @@ pub async fn auth_remove_private_with_status(
     data: web::Data<AppState>,
     param: web::Path<(String, String)>,
 ) -> HttpResponse {
-    // get access to the pasta collection
-    let mut pastas = data.pastas.lock().unwrap();
-
-    remove_expired(&mut pastas);
-
     let (id, status) = param.into_inner();
 
-    let intern_id = if ARGS.hash_ids {
+    let intern_id = if ARGS.get().hash_ids {
         hashid_to_u64(&id).unwrap_or(0)
     } else {
         to_u64(&id).unwrap_or(0)
     };
 
+    // get access to the pasta collection
+    data.pastas.reload_id(intern_id).await;
+    let mut pastas = data.pastas.lock().unwrap();
+
+    remove_expired(&data.db, &mut pastas);
+
     for (_i, pasta) in pastas.iter().enumerate() {
         if pasta.id == intern_id {
             return HttpResponse::Ok().content_type("text/html; charset=utf-8").body(
                 AuthPasta {
-                    args: &ARGS,
+                    args: &ARGS.get(),
                     id,
                     status,
                     encrypted_key: pasta.encrypted_key.to_owned().unwrap_or_default(),
@@ -390,5 +400,5 @@ pub async fn auth_remove_private_with_status(
 
     HttpResponse::Ok()
         .content_type("text/html; charset=utf-8")
-        .body(ErrorTemplate { args: &ARGS }.render().unwrap())
+        .body(ErrorTemplate { args: &ARGS.get() }.render().unwrap())
 }
@@ -0,0 +1,61 @@
+use actix_web::{get, web, HttpResponse};
+use askama::Template;
+
+use crate::args::{Args, ARGS};
+use crate::collection::Collection;
+use crate::endpoints::errors::ErrorTemplate;
+use crate::pasta::Pasta;
+use crate::util::animalnumbers::to_u64;
+use crate::util::hashids::to_u64 as hashid_to_u64;
+use crate::util::misc::remove_expired;
+use crate::AppState;
+
+#[derive(Template)]
+#[template(path = "collection.html")]
+struct CollectionTemplate<'a> {
+    collection: &'a Collection,
+    pastas: &'a Vec<&'a Pasta>,
+    args: &'a Args,
+}
+
+#[get("/collection/{id}")]
+pub async fn get_collection(data: web::Data<AppState>, id: web::Path<String>) -> HttpResponse {
+    let id = if ARGS.get().hash_ids {
+        hashid_to_u64(&id).unwrap_or(0)
+    } else {
+        to_u64(&id.into_inner()).unwrap_or(0)
+    };
+
+    data.pastas.reload().await;
+    data.collections.reload().await;
+    let mut pastas = data.pastas.lock().unwrap();
+
+    remove_expired(&data.db, &mut pastas);
+
+    let collections = data.collections.lock().unwrap();
+
+    let Some(collection) = collections.iter().find(|c| c.id == id) else {
+        return HttpResponse::Ok()
+            .content_type("text/html; charset=utf-8")
+            .body(ErrorTemplate { args: &ARGS.get() }.render().unwrap());
+    };
+
+    // the page is public, so pastas made private after they were collected
+    // are left out
+    let pastas: Vec<&Pasta> = collection
+        .pasta_ids
+        .iter()
+        .filter_map(|id| pastas.iter().find(|pasta| pasta.id == *id))
+        .filter(|pasta| !pasta.private)
+        .collect();
+
+    HttpResponse::Ok().content_type("text/html; charset=utf-8").body(
+        CollectionTemplate {
+            collection,
+            pastas: &pastas,
+            args: &ARGS.get(),
+        }
+        .render()
+        .unwrap(),
+    )
+}
@@ -1,24 +1,32 @@
-use crate::pasta::PastaFile;
+use crate::pasta::{PastaFile, TextFile};
 use crate::util::animalnumbers::to_animal_names;
-use crate::util::db::insert;
+use crate::util::auth;
+use crate::util::blobs;
+use crate::util::db::{self, insert};
 use crate::util::hashids::to_hashids;
-use crate::util::misc::{encrypt, encrypt_file, is_valid_url};
+use crate::util::metrics;
+use crate::util::misc::{
+    encrypt, encrypt_file, is_valid_url, parse_tags, remove_attachment, unused_id,
+};
+use crate::util::quota;
+use crate::util::store;
+use crate::util::webhooks::{self, Event};
 use crate::{AppState, Pasta, ARGS};
 use actix_multipart::Multipart;
-use actix_web::error::ErrorBadRequest;
-use actix_web::{get, web, Error, HttpResponse, Responder};
+use actix_web::error::{ErrorBadRequest, ErrorConflict, ErrorPayloadTooLarge};
+use actix_web::{get, web, Error, HttpRequest, HttpResponse, Responder};
 use askama::Template;
 use bytesize::ByteSize;
 use futures::TryStreamExt;
 use log::warn;
-use rand::Rng;
+use sha2::{Digest, Sha256};
 use std::io::Write;
 use std::time::{SystemTime, UNIX_EPOCH};
 
 #[derive(Template)]
 #[template(path = "index.html")]
 struct IndexTemplate<'a> {
-    args: &'a ARGS,
+    args: &'a crate::args::Args,
     status: String,
 }
 
@@ -26,7 +34,7 @@ struct IndexTemplate<'a> {
 pub async fn index() -> impl Responder {
     HttpResponse::Ok().content_type("text/html; charset=utf-8").body(
         IndexTemplate {
-            args: &ARGS,
+            args: &ARGS.get(),
             status: String::from(""),
         }
         .render()
@@ -40,7 +48,7 @@ pub async fn index_with_status(param: web::Path<String>) -> HttpResponse {
 
     return HttpResponse::Ok().content_type("text/html; charset=utf-8").body(
         IndexTemplate {
-            args: &ARGS,
+            args: &ARGS.get(),
             status,
         }
         .render()
@@ -57,7 +65,7 @@ pub fn expiration_to_timestamp(expiration: &str, timenow: i64) -> i64 {
         "3days" => timenow + 60 * 60 * 24 * 3,
         "1week" => timenow + 60 * 60 * 24 * 7,
         "never" => {
-            if ARGS.eternal_pasta {
+            if ARGS.get().eternal_pasta {
                 0
             } else {
                 timenow + 60 * 60 * 24 * 7
@@ -75,11 +83,10 @@ pub fn expiration_to_timestamp(expiration: &str, timenow: i64) -> i64 {
 // TODO: form field order might need to be changed. In my testing the attachment 
 // data is nestled between password encryption key etc <21-10-24, dvdsk> 
 pub async fn create(
+    req: HttpRequest,
     data: web::Data<AppState>,
     mut payload: Multipart,
 ) -> Result<HttpResponse, Error> {
-    let mut pastas = data.pastas.lock().unwrap();
-
     let timenow: i64 = match SystemTime::now().duration_since(UNIX_EPOCH) {
         Ok(n) => n.as_secs(),
         Err(_) => {
@@ -89,13 +96,13 @@ pub async fn create(
     } as i64;
 
     let mut new_pasta = Pasta {
-        id: rand::thread_rng().gen::<u16>() as u64,
+        id: 0,
         content: String::from(""),
         file: None,
         extension: String::from(""),
         private: false,
         readonly: false,
-        editable: ARGS.editable,
+        editable: ARGS.get().editable,
         hide_read_count: false,
         encrypt_server: false,
         encrypted_key: Some(String::from("")),
@@ -105,12 +112,33 @@ pub async fn create(
         burn_after_reads: 0,
         last_read: timenow,
         pasta_type: String::from(""),
-        expiration: expiration_to_timestamp(&ARGS.default_expiry, timenow),
+        expiration: expiration_to_timestamp(&ARGS.get().default_expiry, timenow),
+        tags: Vec::new(),
+        files: Vec::new(),
+        uploader: auth::basic_auth_user(&req),
+        uploader_ip: if ARGS.get().quota_per_ip_mb > 0 {
+            req.connection_info().realip_remote_addr().map(String::from)
+        } else {
+            None
+        },
+    };
+
+    // the id names the directory the attachment is staged in, so it is drawn
+    // now and checked again once the pasta is stored. The room left by the
+    // quotas is checked while the attachment streams in, make_room checks
+    // the pasta again when it is complete.
+    let room = {
+        let pastas = data.pastas.lock().unwrap();
+        new_pasta.id = unused_id(|id| pastas.iter().any(|pasta| pasta.id == id));
+        quota::room(&pastas, &new_pasta)
     };
 
     let mut random_key: String = String::from("");
     let mut plain_key: String = String::from("");
     let mut uploader_password = String::from("");
+    let mut upload_sha256: Option<String> = None;
+    let mut file_names: Vec<String> = Vec::new();
+    let mut file_contents: Vec<String> = Vec::new();
 
     while let Some(mut field) = payload.try_next().await? {
         let Some(field_name) = field.name() else {
@@ -213,6 +241,30 @@ pub async fn create(
                 }
                 continue;
             }
+            "tags" => {
+                let mut tags = String::from("");
+                while let Some(chunk) = field.try_next().await? {
+                    tags.push_str(std::str::from_utf8(&chunk).unwrap().to_string().as_str());
+                }
+                new_pasta.tags = parse_tags(&tags);
+                continue;
+            }
+            "file_name" => {
+                let mut file_name = String::from("");
+                while let Some(chunk) = field.try_next().await? {
+                    file_name.push_str(std::str::from_utf8(&chunk).unwrap().to_string().as_str());
+                }
+                file_names.push(file_name);
+                continue;
+            }
+            "file_content" => {
+                let mut file_content = String::from("");
+                while let Some(chunk) = field.try_next().await? {
+                    file_content.push_str(std::str::from_utf8(&chunk).unwrap().to_string().as_str());
+                }
+                file_contents.push(file_content);
+                continue;
+            }
             "syntax_highlight" => {
                 while let Some(chunk) = field.try_next().await? {
                     new_pasta.extension = std::str::from_utf8(&chunk).unwrap().to_string();
@@ -220,7 +272,7 @@ pub async fn create(
                 continue;
             }
             "file" => {
-                if ARGS.no_file_upload {
+                if ARGS.get().no_file_upload {
                     continue;
                 }
 
@@ -242,32 +294,47 @@ pub async fn create(
 
                 std::fs::create_dir_all(format!(
                     "{}/attachments/{}",
-                    ARGS.data_dir,
+                    ARGS.get().data_dir,
                     &new_pasta.id_as_animals()
                 ))
                 .unwrap();
 
                 let filepath = format!(
                     "{}/attachments/{}/{}",
-                    ARGS.data_dir,
+                    ARGS.get().data_dir,
                     &new_pasta.id_as_animals(),
                     &file.name()
                 );
 
                 let mut f = web::block(|| std::fs::File::create(filepath)).await??;
                 let mut size = 0;
+                let mut hasher = Sha256::new();
                 while let Some(chunk) = field.try_next().await? {
                     size += chunk.len();
+                    hasher.update(&chunk);
                     if (new_pasta.encrypt_server
-                        && size > ARGS.max_file_size_encrypted_mb * 1024 * 1024)
-                        || size > ARGS.max_file_size_unencrypted_mb * 1024 * 1024
+                        && size > ARGS.get().max_file_size_encrypted_mb * 1024 * 1024)
+                        || size > ARGS.get().max_file_size_unencrypted_mb * 1024 * 1024
                     {
                         return Err(ErrorBadRequest("File exceeded size limit."));
                     }
+                    if let Some((room, message)) = &room {
+                        if new_pasta.total_size() + size as u64 > *room {
+                            let staged = format!(
+                                "{}/attachments/{}",
+                                ARGS.get().data_dir,
+                                &new_pasta.id_as_animals()
+                            );
+                            drop(f);
+                            let _ = web::block(move || std::fs::remove_dir_all(staged)).await;
+                            return Err(ErrorPayloadTooLarge(message.clone()));
+                        }
+                    }
                     f = web::block(move || f.write_all(&chunk).map(|_| f)).await??;
                 }
 
                 file.size = ByteSize::b(size as u64);
+                upload_sha256 = Some(format!("{:x}", hasher.finalize()));
 
                 new_pasta.file = Some(file);
                 new_pasta.pasta_type = String::from("text");
@@ -278,32 +345,35 @@ pub async fn create(
         }
     }
 
-    if ARGS.readonly && ARGS.uploader_password.is_some() {
-        if uploader_password != ARGS.uploader_password.as_ref().unwrap().to_owned() {
+    if ARGS.get().readonly && ARGS.get().uploader_password.is_some() {
+        if uploader_password != ARGS.get().uploader_password.as_ref().unwrap().to_owned() {
+            metrics::failed_auth("uploader");
             return Ok(HttpResponse::Found()
-                .append_header(("Location", format!("{}/incorrect", ARGS.public_path_as_str())))
+                .append_header(("Location", format!("{}/incorrect", ARGS.get().public_path_as_str())))
                 .finish());
         }
     }
 
-    let id = new_pasta.id;
-
-    if plain_key != *"" && new_pasta.readonly {
-        new_pasta.encrypted_key = Some(encrypt(id.to_string().as_str(), &plain_key));
-    }
+    new_pasta.files = TextFile::from_form(file_names, file_contents);
 
-    if new_pasta.encrypt_server && !new_pasta.readonly && new_pasta.content != *"" {
-        if new_pasta.encrypt_client {
-            new_pasta.content = encrypt(&new_pasta.content, &random_key);
-        } else {
-            new_pasta.content = encrypt(&new_pasta.content, &plain_key);
+    if !new_pasta.files.is_empty() {
+        // only the main content and the attachment are encrypted
+        if new_pasta.encrypt_server || new_pasta.encrypt_client {
+            return Err(ErrorBadRequest(
+                "Uploads with several text files can not be encrypted.",
+            ));
+        }
+        if new_pasta.pasta_type != "text" {
+            new_pasta.pasta_type = String::from("text");
         }
     }
 
-    if new_pasta.file.is_some() && new_pasta.encrypt_server && !new_pasta.readonly {
+    let encrypt_file_content = new_pasta.encrypt_server && !new_pasta.readonly;
+
+    if new_pasta.file.is_some() && encrypt_file_content {
         let filepath = format!(
             "{}/attachments/{}/{}",
-            ARGS.data_dir,
+            ARGS.get().data_dir,
             &new_pasta.id_as_animals(),
             &new_pasta.file.as_ref().unwrap().name()
         );
@@ -314,17 +384,79 @@ pub async fn create(
         }
     }
 
+    // unencrypted attachments go to the content-addressed blob store, the
+    // encrypted ones differ per upload anyway. The store may be remote, so
+    // the upload runs on the blocking thread pool.
+    if let Some(key) = store::attachment_key(&new_pasta) {
+        let staged = store::local_path(&key);
+        let db = data.db.clone();
+        match upload_sha256 {
+            Some(sha256) if !encrypt_file_content => {
+                let blob = sha256.clone();
+                web::block(move || blobs::store(&db, &staged, &blob)).await??;
+                new_pasta.file.as_mut().unwrap().sha256 = Some(sha256);
+            }
+            _ => web::block(move || store::put(&db, &key, &staged)).await??,
+        }
+    }
+
+    let id = new_pasta.id;
+
+    if plain_key != *"" && new_pasta.readonly {
+        new_pasta.encrypted_key = Some(encrypt(id.to_string().as_str(), &plain_key));
+    }
+
+    if new_pasta.encrypt_server && !new_pasta.readonly && new_pasta.content != *"" {
+        if new_pasta.encrypt_client {
+            new_pasta.content = encrypt(&new_pasta.content, &random_key);
+        } else {
+            new_pasta.content = encrypt(&new_pasta.content, &plain_key);
+        }
+    }
+
     let encrypt_server = new_pasta.encrypt_server;
 
-    pastas.push(new_pasta);
+    data.pastas.reload().await;
+    let write = {
+        let mut pastas = data.pastas.lock().unwrap();
+
+        // taken in the meantime by an upload to this or another instance
+        let room = if pastas.iter().any(|pasta| pasta.id == new_pasta.id) {
+            Err(ErrorConflict("Another upload got the same id, please try again."))
+        } else {
+            quota::make_room(&mut pastas, &new_pasta).map_err(ErrorPayloadTooLarge)
+        };
+
+        match room {
+            Ok(evicted) => {
+                metrics::pasta_created(&new_pasta);
+                webhooks::notify(Event::Created, &new_pasta);
+
+                pastas.push(new_pasta.clone());
+
+                Ok(db::write(&data.db, pastas, move |db, pastas| {
+                    quota::delete_evicted(db, &evicted, pastas);
+                    insert(db, pastas, Some(&new_pasta));
+                }))
+            }
+            Err(error) => Err((
+                error,
+                db::write(&data.db, pastas, move |db, pastas| {
+                    remove_attachment(db, &new_pasta, pastas.map_or(&[], Vec::as_slice))
+                }),
+            )),
+        }
+    };
 
-    for (_, pasta) in pastas.iter().enumerate() {
-        if pasta.id == id {
-            insert(Some(&pastas), Some(pasta));
+    match write {
+        Ok(write) => write.await?,
+        Err((error, write)) => {
+            write.await?;
+            return Err(error);
         }
     }
 
-    let slug = if ARGS.hash_ids {
+    let slug = if ARGS.get().hash_ids {
         to_hashids(id)
     } else {
         to_animal_names(id)
@@ -338,7 +470,7 @@ pub async fn create(
         Ok(HttpResponse::Found()
             .append_header((
                 "Location",
-                format!("{}/upload/{}", ARGS.public_path_as_str(), slug),
+                format!("{}/upload/{}", ARGS.get().public_path_as_str(), slug),
             ))
             .finish())
     }
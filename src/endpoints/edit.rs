@@ -1,11 +1,16 @@
 use crate::args::Args;
 use crate::endpoints::errors::ErrorTemplate;
 use crate::util::animalnumbers::to_u64;
-use crate::util::db::update;
+use crate::util::db::{self, update};
 use crate::util::hashids::to_u64 as hashid_to_u64;
-use crate::util::misc::{decrypt, encrypt, remove_expired};
+use crate::util::metrics;
+use crate::util::misc::{decrypt, encrypt, parse_tags, remove_expired};
+use crate::util::quota;
+use crate::util::webhooks::{self, Event};
+use crate::pasta::TextFile;
 use crate::{AppState, Pasta, ARGS};
 use actix_multipart::Multipart;
+use actix_web::error::ErrorPayloadTooLarge;
 use actix_web::{get, post, web, Error, HttpResponse};
 use askama::Template;
 use futures::TryStreamExt;
@@ -21,21 +26,23 @@ struct EditTemplate<'a> {
 
 #[get("/edit/{id}")]
 pub async fn get_edit(data: web::Data<AppState>, id: web::Path<String>) -> HttpResponse {
-    let mut pastas = data.pastas.lock().unwrap();
-
-    let id = if ARGS.hash_ids {
+    let id = if ARGS.get().hash_ids {
         hashid_to_u64(&id).unwrap_or(0)
     } else {
         to_u64(&id.into_inner()).unwrap_or(0)
     };
 
-    remove_expired(&mut pastas);
+    data.pastas.reload_id(id).await;
+
+    let mut pastas = data.pastas.lock().unwrap();
+
+    remove_expired(&data.db, &mut pastas);
 
     for pasta in pastas.iter() {
         if pasta.id == id {
             if !pasta.editable {
                 return HttpResponse::Found()
-                    .append_header(("Location", format!("{}/", ARGS.public_path_as_str())))
+                    .append_header(("Location", format!("{}/", ARGS.get().public_path_as_str())))
                     .finish();
             }
 
@@ -43,7 +50,7 @@ pub async fn get_edit(data: web::Data<AppState>, id: web::Path<String>) -> HttpR
                 return HttpResponse::Found()
                     .append_header((
                         "Location",
-                        format!("{}/auth_edit_private/{}", ARGS.public_path_as_str(), pasta.id_as_animals()),
+                        format!("{}/auth_edit_private/{}", ARGS.get().public_path_as_str(), pasta.id_as_animals()),
                     ))
                     .finish();
             }
@@ -51,7 +58,7 @@ pub async fn get_edit(data: web::Data<AppState>, id: web::Path<String>) -> HttpR
             return HttpResponse::Ok().content_type("text/html; charset=utf-8").body(
                 EditTemplate {
                     pasta,
-                    args: &ARGS,
+                    args: &ARGS.get(),
                     path: &String::from("edit"),
                     status: &String::from(""),
                 }
@@ -63,7 +70,7 @@ pub async fn get_edit(data: web::Data<AppState>, id: web::Path<String>) -> HttpR
 
     HttpResponse::Ok()
         .content_type("text/html; charset=utf-8")
-        .body(ErrorTemplate { args: &ARGS }.render().unwrap())
+        .body(ErrorTemplate { args: &ARGS.get() }.render().unwrap())
 }
 
 #[get("/edit/{id}/{status}")]
@@ -71,23 +78,25 @@ pub async fn get_edit_with_status(
     data: web::Data<AppState>,
     param: web::Path<(String, String)>,
 ) -> HttpResponse {
-    let mut pastas = data.pastas.lock().unwrap();
-
     let (id, status) = param.into_inner();
 
-    let intern_id = if ARGS.hash_ids {
+    let intern_id = if ARGS.get().hash_ids {
         hashid_to_u64(&id).unwrap_or(0)
     } else {
         to_u64(&id).unwrap_or(0)
     };
 
-    remove_expired(&mut pastas);
+    data.pastas.reload_id(intern_id).await;
+
+    let mut pastas = data.pastas.lock().unwrap();
+
+    remove_expired(&data.db, &mut pastas);
 
     for pasta in pastas.iter() {
         if pasta.id == intern_id {
             if !pasta.editable {
                 return HttpResponse::Found()
-                    .append_header(("Location", format!("{}/", ARGS.public_path_as_str())))
+                    .append_header(("Location", format!("{}/", ARGS.get().public_path_as_str())))
                     .finish();
             }
 
@@ -95,7 +104,7 @@ pub async fn get_edit_with_status(
                 return HttpResponse::Found()
                     .append_header((
                         "Location",
-                        format!("{}/auth_edit_private/{}", ARGS.public_path_as_str(), pasta.id_as_animals()),
+                        format!("{}/auth_edit_private/{}", ARGS.get().public_path_as_str(), pasta.id_as_animals()),
                     ))
                     .finish();
             }
@@ -103,7 +112,7 @@ pub async fn get_edit_with_status(
             return HttpResponse::Ok().content_type("text/html; charset=utf-8").body(
                 EditTemplate {
                     pasta,
-                    args: &ARGS,
+                    args: &ARGS.get(),
                     path: &String::from("edit"),
                     status: &status,
                 }
@@ -115,7 +124,7 @@ pub async fn get_edit_with_status(
 
     HttpResponse::Ok()
         .content_type("text/html; charset=utf-8")
-        .body(ErrorTemplate { args: &ARGS }.render().unwrap())
+        .body(ErrorTemplate { args: &ARGS.get() }.render().unwrap())
 }
 
 #[post("/edit_private/{id}")]
@@ -124,10 +133,7 @@ pub async fn post_edit_private(
     id: web::Path<String>,
     mut payload: Multipart,
 ) -> Result<HttpResponse, Error> {
-    // get access to the pasta collection
-    let mut pastas = data.pastas.lock().unwrap();
-
-    let id = if ARGS.hash_ids {
+    let id = if ARGS.get().hash_ids {
         hashid_to_u64(&id).unwrap_or(0)
     } else {
         to_u64(&id.into_inner()).unwrap_or(0)
@@ -143,21 +149,24 @@ pub async fn post_edit_private(
         }
     }
 
-    // remove expired pastas (including this one if needed)
-    remove_expired(&mut pastas);
-
-    // find the index of the pasta in the collection based on u64 id
-    let mut index: usize = 0;
-    let mut found: bool = false;
-    for (i, pasta) in pastas.iter().enumerate() {
-        if pasta.id == id {
-            index = i;
-            found = true;
-            break;
-        }
-    }
+    // get access to the pasta collection
+    data.pastas.reload_id(id).await;
+    let (response, write) = {
+        let mut pastas = data.pastas.lock().unwrap();
+
+        // remove expired pastas (including this one if needed)
+        remove_expired(&data.db, &mut pastas);
+
+        // find the index of the pasta in the collection based on u64 id
+        let Some(index) = pastas
+            .iter()
+            .position(|pasta| pasta.id == id && !pasta.encrypt_client)
+        else {
+            return Ok(HttpResponse::Ok()
+                .content_type("text/html; charset=utf-8")
+                .body(ErrorTemplate { args: &ARGS.get() }.render().unwrap()));
+        };
 
-    if found && !pastas[index].encrypt_client {
         let original_content = pastas[index].content.to_owned();
 
         // decrypt content temporarily
@@ -167,15 +176,14 @@ pub async fn post_edit_private(
                 pastas[index]
                     .content
                     .replace_range(.., res.unwrap().as_str());
-                // save pasta in database
-                update(Some(&pastas), Some(&pastas[index]));
             } else {
+                metrics::failed_auth("pasta");
                 return Ok(HttpResponse::Found()
                     .append_header((
                         "Location",
                         format!(
                             "{}/auth_edit_private/{}/incorrect",
-                            ARGS.public_path_as_str(),
+                            ARGS.get().public_path_as_str(),
                             pastas[index].id_as_animals()
                         ),
                     ))
@@ -187,7 +195,7 @@ pub async fn post_edit_private(
         let response = HttpResponse::Ok().content_type("text/html; charset=utf-8").body(
             EditTemplate {
                 pasta: &pastas[index],
-                args: &ARGS,
+                args: &ARGS.get(),
                 path: &String::from("submit_edit_private"),
                 status: &String::from(""),
             }
@@ -195,15 +203,18 @@ pub async fn post_edit_private(
             .unwrap(),
         );
 
-        if pastas[index].content != original_content {
-            pastas[index].content = original_content;
+        if pastas[index].content == original_content {
+            return Ok(response);
         }
 
-        return Ok(response);
-    }
-    Ok(HttpResponse::Ok()
-        .content_type("text/html; charset=utf-8")
-        .body(ErrorTemplate { args: &ARGS }.render().unwrap()))
+        // save pasta in database
+        let pasta = pastas[index].clone();
+        pastas[index].content = original_content;
+        (response, db::write(&data.db, pastas, move |db, pastas| update(db, pastas, Some(&pasta))))
+    };
+    write.await?;
+
+    Ok(response)
 }
 
 #[post("/submit_edit_private/{id}")]
@@ -212,10 +223,7 @@ pub async fn post_submit_edit_private(
     id: web::Path<String>,
     mut payload: Multipart,
 ) -> Result<HttpResponse, Error> {
-    // get access to the pasta collection
-    let mut pastas = data.pastas.lock().unwrap();
-
-    let id = if ARGS.hash_ids {
+    let id = if ARGS.get().hash_ids {
         hashid_to_u64(&id).unwrap_or(0)
     } else {
         to_u64(&id.into_inner()).unwrap_or(0)
@@ -223,6 +231,7 @@ pub async fn post_submit_edit_private(
 
     let mut password = String::from("");
     let mut new_content = String::from("");
+    let mut new_tags = String::from("");
 
     while let Some(mut field) = payload.try_next().await? {
         if field.name() == Some("content") {
@@ -230,6 +239,11 @@ pub async fn post_submit_edit_private(
                 new_content.push_str(std::str::from_utf8(&chunk).unwrap().to_string().as_str());
             }
         }
+        if field.name() == Some("tags") {
+            while let Some(chunk) = field.try_next().await? {
+                new_tags.push_str(std::str::from_utf8(&chunk).unwrap().to_string().as_str());
+            }
+        }
         if field.name() == Some("password") {
             while let Some(chunk) = field.try_next().await? {
                 password = std::str::from_utf8(&chunk).unwrap().to_string();
@@ -237,32 +251,40 @@ pub async fn post_submit_edit_private(
         }
     }
 
-    // remove expired pastas (including this one if needed)
-    remove_expired(&mut pastas);
-
-    // find the index of the pasta in the collection based on u64 id
-    let mut index: usize = 0;
-    let mut found: bool = false;
-    for (i, pasta) in pastas.iter().enumerate() {
-        if pasta.id == id {
-            index = i;
-            found = true;
-            break;
-        }
-    }
-
-    if found && pastas[index].editable && !pastas[index].encrypt_client {
-        if pastas[index].readonly {
+    // get access to the pasta collection
+    data.pastas.reload_id(id).await;
+    let (location, write) = {
+        let mut pastas = data.pastas.lock().unwrap();
+
+        // remove expired pastas (including this one if needed)
+        remove_expired(&data.db, &mut pastas);
+
+        // find the index of the pasta in the collection based on u64 id
+        let Some(index) = pastas
+            .iter()
+            .position(|pasta| pasta.id == id && pasta.editable && !pasta.encrypt_client)
+        else {
+            return Ok(HttpResponse::Ok()
+                .content_type("text/html; charset=utf-8")
+                .body(ErrorTemplate { args: &ARGS.get() }.render().unwrap()));
+        };
+
+        let location = format!("{}/auth/{}/success", ARGS.get().public_path_as_str(), pastas[index].id_as_animals());
+
+        let write = if pastas[index].readonly {
             let res = decrypt(pastas[index].encrypted_key.as_ref().unwrap(), &password);
             if res.is_ok() {
                 pastas[index]
                     .content
                     .replace_range(.., &encrypt(&new_content, &password));
+                pastas[index].tags = parse_tags(&new_tags);
+                None
             } else {
+                metrics::failed_auth("pasta");
                 return Ok(HttpResponse::Found()
                     .append_header((
                         "Location",
-                        format!("{}/edit/{}/incorrect", ARGS.public_path_as_str(), pastas[index].id_as_animals()),
+                        format!("{}/edit/{}/incorrect", ARGS.get().public_path_as_str(), pastas[index].id_as_animals()),
                     ))
                     .finish());
             }
@@ -272,32 +294,36 @@ pub async fn post_submit_edit_private(
                 pastas[index]
                     .content
                     .replace_range(.., &encrypt(&new_content, &password));
+                pastas[index].tags = parse_tags(&new_tags);
+                webhooks::notify(Event::Edited, &pastas[index]);
                 // save pasta in database
-                update(Some(&pastas), Some(&pastas[index]));
+                let pasta = pastas[index].clone();
+                Some(db::write(&data.db, pastas, move |db, pastas| update(db, pastas, Some(&pasta))))
             } else {
+                metrics::failed_auth("pasta");
                 return Ok(HttpResponse::Found()
                     .append_header((
                         "Location",
                         format!(
                             "{}/auth_edit_private/{}/incorrect",
-                            ARGS.public_path_as_str(),
+                            ARGS.get().public_path_as_str(),
                             pastas[index].id_as_animals()
                         ),
                     ))
                     .finish());
             }
-        }
+        } else {
+            None
+        };
 
-        return Ok(HttpResponse::Found()
-            .append_header((
-                "Location",
-                format!("{}/auth/{}/success", ARGS.public_path_as_str(), pastas[index].id_as_animals()),
-            ))
-            .finish());
+        (location, write)
+    };
+
+    if let Some(write) = write {
+        write.await?;
     }
-    Ok(HttpResponse::Ok()
-        .content_type("text/html; charset=utf-8")
-        .body(ErrorTemplate { args: &ARGS }.render().unwrap()))
+
+    Ok(HttpResponse::Found().append_header(("Location", location)).finish())
 }
 
 #[post("/edit/{id}")]
@@ -306,17 +332,16 @@ pub async fn post_edit(
     id: web::Path<String>,
     mut payload: Multipart,
 ) -> Result<HttpResponse, Error> {
-    let id = if ARGS.hash_ids {
+    let id = if ARGS.get().hash_ids {
         hashid_to_u64(&id).unwrap_or(0)
     } else {
         to_u64(&id.into_inner()).unwrap_or(0)
     };
 
-    let mut pastas = data.pastas.lock().unwrap();
-
-    remove_expired(&mut pastas);
-
     let mut new_content = String::from("");
+    let mut new_tags = String::from("");
+    let mut file_names: Vec<String> = Vec::new();
+    let mut file_contents: Vec<String> = Vec::new();
     let mut password = String::from("");
 
     while let Some(mut field) = payload.try_next().await? {
@@ -325,6 +350,25 @@ pub async fn post_edit(
                 new_content.push_str(std::str::from_utf8(&chunk).unwrap().to_string().as_str());
             }
         }
+        if field.name() == Some("tags") {
+            while let Some(chunk) = field.try_next().await? {
+                new_tags.push_str(std::str::from_utf8(&chunk).unwrap().to_string().as_str());
+            }
+        }
+        if field.name() == Some("file_name") {
+            let mut file_name = String::from("");
+            while let Some(chunk) = field.try_next().await? {
+                file_name.push_str(std::str::from_utf8(&chunk).unwrap().to_string().as_str());
+            }
+            file_names.push(file_name);
+        }
+        if field.name() == Some("file_content") {
+            let mut file_content = String::from("");
+            while let Some(chunk) = field.try_next().await? {
+                file_content.push_str(std::str::from_utf8(&chunk).unwrap().to_string().as_str());
+            }
+            file_contents.push(file_content);
+        }
         if field.name() == Some("password") {
             while let Some(chunk) = field.try_next().await? {
                 password = std::str::from_utf8(&chunk).unwrap().to_string();
@@ -332,55 +376,78 @@ pub async fn post_edit(
         }
     }
 
-    for (i, pasta) in pastas.iter().enumerate() {
-        if pasta.id == id {
-            if pasta.editable && !pasta.encrypt_client {
-                if pastas[i].readonly || pastas[i].encrypt_server {
-                    if password != *"" {
-                        let res = decrypt(pastas[i].encrypted_key.as_ref().unwrap(), &password);
-                        if res.is_ok() {
-                            pastas[i].content.replace_range(.., &new_content);
-                            // save pasta in database
-                            update(Some(&pastas), Some(&pastas[i]));
-                        } else {
-                            return Ok(HttpResponse::Found()
-                                .append_header((
-                                    "Location",
-                                    format!("{}/edit/{}/incorrect", ARGS.public_path_as_str(), pasta.id_as_animals()),
-                                ))
-                                .finish());
-                        }
-                    } else {
-                        return Ok(HttpResponse::Found()
-                            .append_header((
-                                "Location",
-                                format!("{}/edit/{}/incorrect", ARGS.public_path_as_str(), pasta.id_as_animals()),
-                            ))
-                            .finish());
-                    }
-                } else {
-                    pastas[i].content.replace_range(.., &new_content);
-                    // save pasta in database
-                    update(Some(&pastas), Some(&pastas[i]));
-                }
+    let new_files = TextFile::from_form(file_names, file_contents);
+
+    data.pastas.reload_id(id).await;
+
+    let (location, write) = {
+        let mut pastas = data.pastas.lock().unwrap();
 
+        remove_expired(&data.db, &mut pastas);
+
+        let Some(i) = pastas
+            .iter()
+            .position(|pasta| pasta.id == id && pasta.editable && !pasta.encrypt_client)
+        else {
+            return Ok(HttpResponse::Ok()
+                .content_type("text/html; charset=utf-8")
+                .body(ErrorTemplate { args: &ARGS.get() }.render().unwrap()));
+        };
+
+        if pastas[i].readonly || pastas[i].encrypt_server {
+            if password == *""
+                || decrypt(pastas[i].encrypted_key.as_ref().unwrap(), &password).is_err()
+            {
+                metrics::failed_auth("pasta");
                 return Ok(HttpResponse::Found()
                     .append_header((
                         "Location",
-                        format!(
-                            "{}/upload/{}",
-                            ARGS.public_path_as_str(),
-                            pastas[i].id_as_animals()
-                        ),
+                        format!("{}/edit/{}/incorrect", ARGS.get().public_path_as_str(), pastas[i].id_as_animals()),
                     ))
                     .finish());
-            } else {
-                break;
             }
         }
-    }
 
-    Ok(HttpResponse::Ok()
-        .content_type("text/html; charset=utf-8")
-        .body(ErrorTemplate { args: &ARGS }.render().unwrap()))
+        let mut pasta = pastas[i].clone();
+        pasta.content.replace_range(.., &new_content);
+        pasta.tags = parse_tags(&new_tags);
+        pasta.files = new_files;
+
+        // an edit that grows the pasta is held to the quotas like an upload,
+        // with the pasta before the edit taken out of the count
+        let mut evicted = Vec::new();
+        if pasta.total_size() > pastas[i].total_size() {
+            let original = pastas.remove(i);
+            match quota::make_room(&mut pastas, &pasta) {
+                Ok(pastas_evicted) => evicted = pastas_evicted,
+                Err(message) => {
+                    pastas.insert(i, original);
+                    return Err(ErrorPayloadTooLarge(message));
+                }
+            }
+            // evictions may have moved the pastas, which are in the order
+            // they were created
+            let at = pastas
+                .iter()
+                .position(|p| p.created > pasta.created)
+                .unwrap_or(pastas.len());
+            pastas.insert(at, pasta.clone());
+        } else {
+            pastas[i] = pasta.clone();
+        }
+        webhooks::notify(Event::Edited, &pasta);
+
+        let location = format!("{}/upload/{}", ARGS.get().public_path_as_str(), pasta.id_as_animals());
+        // save pasta in database
+        (
+            location,
+            db::write(&data.db, pastas, move |db, pastas| {
+                quota::delete_evicted(db, &evicted, pastas);
+                update(db, pastas, Some(&pasta));
+            }),
+        )
+    };
+    write.await?;
+
+    Ok(HttpResponse::Found().append_header(("Location", location)).finish())
 }
@@ -0,0 +1,170 @@
+use actix_web::{get, web, HttpRequest, HttpResponse};
+use askama::Template;
+use chrono::{TimeZone, Utc};
+
+use crate::args::{Args, ARGS};
+use crate::pasta::Pasta;
+use crate::util::listing::ListQuery;
+use crate::util::misc::remove_expired;
+use crate::util::search::searchable;
+use crate::AppState;
+
+const FEED_LENGTH: usize = 50;
+const SUMMARY_LINES: usize = 10;
+const TITLE_LENGTH: usize = 80;
+
+/// What a feed shows of one pasta.
+struct FeedEntry {
+    title: String,
+    summary: String,
+    slug: String,
+    created: i64,
+}
+
+impl FeedEntry {
+    fn new(pasta: &Pasta) -> FeedEntry {
+        let text = if !pasta.content.is_empty() {
+            pasta.content.as_str()
+        } else {
+            pasta.files.first().map_or("", |f| f.content.as_str())
+        };
+
+        let first_line = text.lines().map(str::trim).find(|l| !l.is_empty());
+        let title = match (first_line, &pasta.file) {
+            (Some(line), _) => line.chars().take(TITLE_LENGTH).collect(),
+            (None, Some(file)) => file.name().to_string(),
+            (None, None) => pasta.id_as_animals(),
+        };
+
+        FeedEntry {
+            title,
+            summary: text
+                .lines()
+                .take(SUMMARY_LINES)
+                .collect::<Vec<&str>>()
+                .join("\n"),
+            slug: pasta.id_as_animals(),
+            created: pasta.created,
+        }
+    }
+
+    fn created_rfc3339(&self) -> String {
+        rfc3339(self.created)
+    }
+
+    fn created_rfc2822(&self) -> String {
+        Utc.timestamp_opt(self.created, 0)
+            .single()
+            .map(|date| date.to_rfc2822())
+            .unwrap_or_default()
+    }
+}
+
+fn rfc3339(timestamp: i64) -> String {
+    Utc.timestamp_opt(timestamp, 0)
+        .single()
+        .map(|date| date.to_rfc3339())
+        .unwrap_or_default()
+}
+
+#[derive(Template)]
+#[template(path = "feed_atom.xml")]
+struct AtomTemplate<'a> {
+    entries: &'a Vec<FeedEntry>,
+    base_url: &'a String,
+    updated: &'a String,
+    args: &'a Args,
+}
+
+#[derive(Template)]
+#[template(path = "feed_rss.xml")]
+struct RssTemplate<'a> {
+    entries: &'a Vec<FeedEntry>,
+    base_url: &'a String,
+    args: &'a Args,
+}
+
+/// Feeds need absolute links. Without a configured public path the address
+/// the feed was requested on is used.
+fn base_url(req: &HttpRequest) -> String {
+    let public_path = ARGS.get().public_path_as_str();
+    if !public_path.is_empty() {
+        return public_path;
+    }
+    let info = req.connection_info();
+    format!("{}://{}", info.scheme(), info.host())
+}
+
+/// The most recent public, unencrypted pastas matching the tag and extension
+/// filters of the query. Burn-after-read pastas are left out like in search,
+/// a feed reader fetching their summary would read them without counting.
+/// None when listing is disabled.
+async fn feed_entries(data: &AppState, query: &ListQuery) -> Option<Vec<FeedEntry>> {
+    if ARGS.get().no_listing || ARGS.get().private {
+        return None;
+    }
+
+    let query = ListQuery {
+        tag: query.tag.clone(),
+        extension: query.extension.clone(),
+        per_page: FEED_LENGTH.to_string(),
+        ..Default::default()
+    };
+
+    data.pastas.reload().await;
+    let mut pastas = data.pastas.lock().unwrap();
+
+    remove_expired(&data.db, &mut pastas);
+
+    let page = query.apply(pastas.iter().filter(|pasta| searchable(pasta)));
+    Some(page.pastas.into_iter().map(FeedEntry::new).collect())
+}
+
+#[get("/feed.atom")]
+pub async fn atom(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    query: web::Query<ListQuery>,
+) -> HttpResponse {
+    let Some(entries) = feed_entries(&data, &query).await else {
+        return HttpResponse::NotFound().finish();
+    };
+
+    let updated = rfc3339(entries.first().map_or(0, |entry| entry.created));
+
+    HttpResponse::Ok()
+        .content_type("application/atom+xml; charset=utf-8")
+        .body(
+            AtomTemplate {
+                entries: &entries,
+                base_url: &base_url(&req),
+                updated: &updated,
+                args: &ARGS.get(),
+            }
+            .render()
+            .unwrap(),
+        )
+}
+
+#[get("/feed.rss")]
+pub async fn rss(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    query: web::Query<ListQuery>,
+) -> HttpResponse {
+    let Some(entries) = feed_entries(&data, &query).await else {
+        return HttpResponse::NotFound().finish();
+    };
+
+    HttpResponse::Ok()
+        .content_type("application/rss+xml; charset=utf-8")
+        .body(
+            RssTemplate {
+                entries: &entries,
+                base_url: &base_url(&req),
+                args: &ARGS.get(),
+            }
+            .render()
+            .unwrap(),
+        )
+}
@@ -1,77 +1,80 @@
-use std::fs::File;
-use std::path::PathBuf;
-
 use crate::args::ARGS;
 use crate::util::auth;
 use crate::util::hashids::to_u64 as hashid_to_u64;
 use crate::util::misc::remove_expired;
+use crate::util::store;
+use crate::util::views;
 use crate::util::{animalnumbers::to_u64, misc::decrypt_file};
 use crate::AppState;
 use actix_multipart::Multipart;
-use actix_web::http::header;
-use actix_web::{get, post, web, Error, HttpResponse};
+use actix_web::error::ErrorInternalServerError;
+use actix_web::{get, post, web, Error, HttpRequest, HttpResponse};
+use std::io;
 
 #[post("/secure_file/{id}")]
 pub async fn post_secure_file(
+    req: HttpRequest,
     data: web::Data<AppState>,
     id: web::Path<String>,
     payload: Multipart,
 ) -> Result<HttpResponse, Error> {
-    // get access to the pasta collection
-    let mut pastas = data.pastas.lock().unwrap();
-
-    let id = if ARGS.hash_ids {
+    let id = if ARGS.get().hash_ids {
         hashid_to_u64(&id).unwrap_or(0)
     } else {
         to_u64(&id.into_inner()).unwrap_or(0)
     };
 
-    // remove expired pastas (including this one if needed)
-    remove_expired(&mut pastas);
-
-    // find the index of the pasta in the collection based on u64 id
-    let mut index: usize = 0;
-    let mut found: bool = false;
-    for (i, pasta) in pastas.iter().enumerate() {
-        if pasta.id == id {
-            index = i;
-            found = true;
-            break;
-        }
-    }
-
     let password = auth::password_from_multipart(payload).await?;
 
-    if found {
-        if let Some(ref pasta_file) = pastas[index].file {
-            let file = File::open(format!(
-                "{}/attachments/{}/data.enc",
-                ARGS.data_dir,
-                pastas[index].id_as_animals()
-            ))?;
-
-            // Not compatible with NamedFile from actix_files (it needs a File
-            // to work therefore secure files do not support streaming
-            let decrypted_data: Vec<u8> = decrypt_file(&password, &file)?;
-
-            // Set the content type based on the file extension
-            let content_type = mime_guess::from_path(&pasta_file.name)
-                .first_or_octet_stream()
-                .to_string();
-
-            // Create a response with the decrypted data
-            let response = HttpResponse::Ok()
-                .content_type(content_type)
-                .append_header((
-                    "Content-Disposition",
-                    format!("attachment; filename=\"{}\"", pasta_file.name()),
-                ))
-                // TODO: make streaming <21-10-24, dvdsk>
-                .body(decrypted_data);
-            return Ok(response);
-        }
-    }
-    Ok(HttpResponse::NotFound().finish())
+    // get access to the pasta collection
+    data.pastas.reload_id(id).await;
+    let found = {
+        let mut pastas = data.pastas.lock().unwrap();
+
+        // remove expired pastas (including this one if needed)
+        remove_expired(&data.db, &mut pastas);
+
+        pastas
+            .iter()
+            .find(|pasta| pasta.id == id && pasta.file.is_some())
+            .cloned()
+    };
+    let Some(pasta) = found else {
+        return Ok(HttpResponse::NotFound().finish());
+    };
+
+    // the store may be remote, so the attachment is read and decrypted on the
+    // blocking thread pool once the pastas are unlocked
+    let key = store::attachment_key(&pasta).unwrap();
+    let db = data.db.clone();
+    let decrypted_data = web::block(move || {
+        let data = store::read(&db, &key)?;
+
+        // Not compatible with NamedFile from actix_files (it needs a File
+        // to work therefore secure files do not support streaming
+        Ok::<_, io::Error>(decrypt_file(&password, data.as_slice()).map_err(|e| e.to_string()))
+    })
+    .await??
+    .map_err(ErrorInternalServerError)?;
+    views::record(&req, &pasta);
+
+    let pasta_file = pasta.file.as_ref().unwrap();
+
+    // Set the content type based on the file extension
+    let content_type = mime_guess::from_path(&pasta_file.name)
+        .first_or_octet_stream()
+        .to_string();
+
+    // Create a response with the decrypted data
+    let response = HttpResponse::Ok()
+        .content_type(content_type)
+        .append_header((
+            "Content-Disposition",
+            format!("attachment; filename=\"{}\"", pasta_file.name()),
+        ))
+        // TODO: make streaming <21-10-24, dvdsk>
+        .body(decrypted_data);
+    Ok(response)
 }
 
 #[get("/file/{id}")]
@@ -80,63 +83,45 @@ pub async fn get_file(
     id: web::Path<String>,
     data: web::Data<AppState>,
 ) -> Result<HttpResponse, Error> {
-    // get access to the pasta collection
-    let mut pastas = data.pastas.lock().unwrap();
-
-    let id_intern = if ARGS.hash_ids {
+    let id_intern = if ARGS.get().hash_ids {
         hashid_to_u64(&id).unwrap_or(0)
     } else {
         to_u64(&id.into_inner()).unwrap_or(0)
     };
 
-    // remove expired pastas (including this one if needed)
-    remove_expired(&mut pastas);
-
-    // find the index of the pasta in the collection based on u64 id
-    let mut index: usize = 0;
-    let mut found: bool = false;
-    for (i, pasta) in pastas.iter().enumerate() {
-        if pasta.id == id_intern {
-            index = i;
-            found = true;
-            break;
+    // don't hold the lock while the file is fetched from the store
+    let (key, name) = {
+        // get access to the pasta collection
+        data.pastas.reload_id(id_intern).await;
+        let mut pastas = data.pastas.lock().unwrap();
+
+        // remove expired pastas (including this one if needed)
+        remove_expired(&data.db, &mut pastas);
+
+        let Some(pasta) = pastas.iter().find(|pasta| pasta.id == id_intern) else {
+            return Ok(HttpResponse::NotFound().finish());
+        };
+        let Some(pasta_file) = &pasta.file else {
+            return Ok(HttpResponse::NotFound().finish());
+        };
+
+        if pasta.encrypt_server {
+            return Ok(HttpResponse::Found()
+                .append_header(("Location", format!("/auth_file/{}", pasta.id_as_animals())))
+                .finish());
         }
-    }
-
-    if found {
-        if let Some(ref pasta_file) = pastas[index].file {
-            if pastas[index].encrypt_server {
-                return Ok(HttpResponse::Found()
-                    .append_header((
-                        "Location",
-                        format!("/auth_file/{}", pastas[index].id_as_animals()),
-                    ))
-                    .finish());
-            }
-
-            // Construct the path to the file
-            let file_path = format!(
-                "{}/attachments/{}/{}",
-                ARGS.data_dir,
-                pastas[index].id_as_animals(),
-                pasta_file.name()
-            );
-            let file_path = PathBuf::from(file_path);
-
-            // This will stream the file and set the content type based on the
-            // file path
-            let file_reponse = actix_files::NamedFile::open(file_path)?;
-            let file_reponse = file_reponse.set_content_disposition(header::ContentDisposition {
-                disposition: header::DispositionType::Attachment,
-                parameters: vec![header::DispositionParam::Filename(
-                    pasta_file.name().to_string(),
-                )],
-            });
-            // This takes care of streaming/seeking using the Range
-            // header in the request.
-            return Ok(file_reponse.into_response(&request));
+
+        // players fetch media in many ranges, only the first one is a view
+        let range = request.headers().get(actix_web::http::header::RANGE);
+        if range.map_or(true, |range| range.as_bytes().starts_with(b"bytes=0-")) {
+            views::record(&request, pasta);
         }
-    }
 
-    Ok(HttpResponse::NotFound().finish())
+        (
+            store::attachment_key(pasta).unwrap(),
+            pasta_file.name().to_string(),
+        )
+    };
+
+    store::serve(&data.db, &request, &key, &name).await
 }
@@ -12,5 +12,5 @@ struct Guide<'a> {
 pub async fn guide() -> HttpResponse {
     HttpResponse::Ok()
         .content_type("text/html; charset=utf-8")
-        .body(Guide { args: &ARGS }.render().unwrap())
+        .body(Guide { args: &ARGS.get() }.render().unwrap())
 }
@@ -0,0 +1,124 @@
+use std::fs;
+
+use actix_web::{get, web, HttpResponse};
+use serde::Serialize;
+
+use crate::args::ARGS;
+use crate::util::db;
+use crate::util::store;
+use crate::AppState;
+
+#[derive(Serialize)]
+struct Check {
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl From<Result<(), String>> for Check {
+    fn from(result: Result<(), String>) -> Check {
+        match result {
+            Ok(()) => Check {
+                ok: true,
+                error: None,
+            },
+            Err(error) => Check {
+                ok: false,
+                error: Some(error),
+            },
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Readiness {
+    status: &'static str,
+    database: Check,
+    data_dir_writable: Check,
+    free_disk: Check,
+    attachment_store: Check,
+}
+
+/// Liveness probe, answers as long as the server is running.
+#[get("/healthz")]
+pub async fn healthz() -> HttpResponse {
+    HttpResponse::Ok().json(serde_json::json!({ "status": "ok" }))
+}
+
+/// Readiness probe, fails with 503 when the database can not be read, the
+/// data directory is not writable, the disk is almost full or the attachment
+/// store can not be reached.
+#[get("/readyz")]
+pub async fn readyz(data: web::Data<AppState>) -> HttpResponse {
+    let checks = web::block(move || {
+        (
+            db::check(&data.db),
+            check_data_dir(),
+            check_free_disk(),
+            store::check(),
+        )
+    })
+    .await;
+    let (database, data_dir_writable, free_disk, attachment_store) = match checks {
+        Ok(results) => results,
+        Err(e) => {
+            let error = Err(e.to_string());
+            (error.clone(), error.clone(), error.clone(), error)
+        }
+    };
+
+    let ready = database.is_ok()
+        && data_dir_writable.is_ok()
+        && free_disk.is_ok()
+        && attachment_store.is_ok();
+    let readiness = Readiness {
+        status: if ready { "ok" } else { "unavailable" },
+        database: database.into(),
+        data_dir_writable: data_dir_writable.into(),
+        free_disk: free_disk.into(),
+        attachment_store: attachment_store.into(),
+    };
+
+    if ready {
+        HttpResponse::Ok().json(readiness)
+    } else {
+        HttpResponse::ServiceUnavailable().json(readiness)
+    }
+}
+
+fn check_data_dir() -> Result<(), String> {
+    let probe = format!("{}/.readyz", ARGS.get().data_dir);
+    fs::write(&probe, b"ok").map_err(|e| format!("{}: {}", ARGS.get().data_dir, e))?;
+    fs::remove_file(&probe).map_err(|e| format!("{}: {}", probe, e))
+}
+
+#[cfg(unix)]
+fn check_free_disk() -> Result<(), String> {
+    use std::ffi::CString;
+
+    if ARGS.get().min_free_disk_mb == 0 {
+        return Ok(());
+    }
+
+    let path = CString::new(ARGS.get().data_dir.as_str()).map_err(|e| e.to_string())?;
+    let mut stats: libc::statvfs = unsafe { std::mem::zeroed() };
+    // SAFETY: path is a valid C string and stats is a properly sized buffer.
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stats) } != 0 {
+        return Err(std::io::Error::last_os_error().to_string());
+    }
+
+    let free_mb = stats.f_bavail as u64 * stats.f_frsize as u64 / 1024 / 1024;
+    if free_mb < ARGS.get().min_free_disk_mb {
+        Err(format!(
+            "{} MB free, at least {} MB required",
+            free_mb, ARGS.get().min_free_disk_mb
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(not(unix))]
+fn check_free_disk() -> Result<(), String> {
+    Ok(())
+}
@@ -2,38 +2,66 @@ use actix_web::{get, web, HttpResponse};
 use askama::Template;
 
 use crate::args::{Args, ARGS};
-use crate::pasta::Pasta;
-use crate::util::misc::remove_expired;
+use crate::collection::Collection;
+use crate::util::listing::{ListQuery, Page};
+use crate::util::misc::{parse_tags, remove_expired};
 use crate::AppState;
 
 #[derive(Template)]
 #[template(path = "list.html")]
 struct ListTemplate<'a> {
-    pastas: &'a Vec<Pasta>,
+    page: &'a Page<'a>,
+    query: &'a ListQuery,
+    collections: &'a Vec<Collection>,
     args: &'a Args,
 }
 
-#[get("/list")]
-pub async fn list(data: web::Data<AppState>) -> HttpResponse {
-    if ARGS.no_listing {
+async fn render_list(data: &AppState, query: &ListQuery) -> HttpResponse {
+    if ARGS.get().no_listing {
         return HttpResponse::Found()
-            .append_header(("Location", format!("{}/", ARGS.public_path_as_str())))
+            .append_header(("Location", format!("{}/", ARGS.get().public_path_as_str())))
             .finish();
     }
 
+    data.pastas.reload().await;
+    data.collections.reload().await;
     let mut pastas = data.pastas.lock().unwrap();
 
-    remove_expired(&mut pastas);
+    remove_expired(&data.db, &mut pastas);
+
+    // private uploads are never listed publicly, whatever the filters say
+    let page = query.apply(pastas.iter().filter(|pasta| !pasta.private));
 
-    // sort pastas in reverse-chronological order of creation time
-    pastas.sort_by(|a, b| b.created.cmp(&a.created));
+    let collections = data.collections.lock().unwrap();
 
     HttpResponse::Ok().content_type("text/html; charset=utf-8").body(
         ListTemplate {
-            pastas: &pastas,
-            args: &ARGS,
+            page: &page,
+            query,
+            collections: &collections,
+            args: &ARGS.get(),
         }
         .render()
         .unwrap(),
     )
 }
+
+#[get("/list")]
+pub async fn list(data: web::Data<AppState>, query: web::Query<ListQuery>) -> HttpResponse {
+    render_list(&data, &query).await
+}
+
+#[get("/tag/{tag}")]
+pub async fn list_tag(
+    data: web::Data<AppState>,
+    tag: web::Path<String>,
+    query: web::Query<ListQuery>,
+) -> HttpResponse {
+    let mut query = query.into_inner();
+    // normalized the same way as tags entered at creation time
+    query.tag = parse_tags(&tag)
+        .into_iter()
+        .next()
+        .unwrap_or_else(|| tag.into_inner());
+    render_list(&data, &query).await
+}
@@ -0,0 +1,36 @@
+use actix_web::{get, web, HttpRequest, HttpResponse};
+
+use crate::args::ARGS;
+use crate::util::metrics;
+use crate::util::misc::remove_expired;
+use crate::AppState;
+
+/// Prometheus scrape endpoint. When a metrics token is configured, scrapers
+/// have to send it as a bearer token.
+#[get("/metrics")]
+pub async fn get_metrics(req: HttpRequest, data: web::Data<AppState>) -> HttpResponse {
+    if !ARGS.get().enable_metrics {
+        return HttpResponse::NotFound().finish();
+    }
+
+    if let Some(token) = ARGS.get().metrics_token.as_ref() {
+        let authorized = req
+            .headers()
+            .get("Authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .is_some_and(|value| value == token);
+        if !authorized {
+            metrics::failed_auth("metrics");
+            return HttpResponse::Unauthorized().finish();
+        }
+    }
+
+    data.pastas.reload().await;
+    let mut pastas = data.pastas.lock().unwrap();
+    remove_expired(&data.db, &mut pastas);
+
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4; charset=utf-8")
+        .body(metrics::render(&pastas))
+}
@@ -3,15 +3,16 @@ use crate::endpoints::errors::ErrorTemplate;
 use crate::pasta::Pasta;
 use crate::util::animalnumbers::to_u64;
 use crate::util::auth;
-use crate::util::db::update;
 use crate::util::hashids::to_u64 as hashid_to_u64;
+use crate::util::metrics;
 use crate::util::misc::remove_expired;
+use crate::util::read_stats;
+use crate::util::views;
 use crate::AppState;
 use actix_multipart::Multipart;
-use actix_web::{get, post, web, Error, HttpResponse};
+use actix_web::{get, post, web, Error, HttpRequest, HttpResponse};
 use askama::Template;
 use magic_crypt::{new_magic_crypt, MagicCryptTrait};
-use std::time::{SystemTime, UNIX_EPOCH};
 
 #[derive(Template)]
 #[template(path = "upload.html", escape = "none")]
@@ -20,382 +21,396 @@ struct PastaTemplate<'a> {
     args: &'a Args,
 }
 
-fn pastaresponse(
+async fn pastaresponse(
+    req: &HttpRequest,
     data: web::Data<AppState>,
     id: web::Path<String>,
     password: String,
 ) -> HttpResponse {
-    // get access to the pasta collection
-    let mut pastas = data.pastas.lock().unwrap();
-
-    let id = if ARGS.hash_ids {
+    let id = if ARGS.get().hash_ids {
         hashid_to_u64(&id).unwrap_or(0)
     } else {
         to_u64(&id.into_inner()).unwrap_or(0)
     };
 
-    // remove expired pastas (including this one if needed)
-    remove_expired(&mut pastas);
-
-    // find the index of the pasta in the collection based on u64 id
-    let mut index: usize = 0;
-    let mut found: bool = false;
-    for (i, pasta) in pastas.iter().enumerate() {
-        if pasta.id == id {
-            index = i;
-            found = true;
-            break;
-        }
-    }
+    // get access to the pasta collection
+    data.pastas.reload_id(id).await;
+    let counting = {
+        let mut pastas = data.pastas.lock().unwrap();
+
+        // remove expired pastas (including this one if needed)
+        remove_expired(&data.db, &mut pastas);
+
+        // find the index of the pasta in the collection based on u64 id,
+        // otherwise send pasta not found error
+        let Some(index) = pastas.iter().position(|pasta| pasta.id == id) else {
+            return HttpResponse::Ok()
+                .content_type("text/html; charset=utf-8")
+                .body(ErrorTemplate { args: &ARGS.get() }.render().unwrap());
+        };
 
-    if found {
         if pastas[index].encrypt_server && password == *"" {
             return HttpResponse::Found()
                 .append_header((
                     "Location",
-                    format!("{}/auth/{}", ARGS.public_path_as_str(), pastas[index].id_as_animals()),
+                    format!("{}/auth/{}", ARGS.get().public_path_as_str(), pastas[index].id_as_animals()),
                 ))
                 .finish();
         }
 
-        // increment read count
-        pastas[index].read_count += 1;
-
-        // save the updated read count
-        update(Some(&pastas), Some(&pastas[index]));
-
-        let original_content = pastas[index].content.to_owned();
-
-        // decrypt content temporarily
-        if password != *"" && !original_content.is_empty() {
-            let res = decrypt(&original_content, &password);
-            if let Ok(..) = res {
-                pastas[index]
-                    .content
-                    .replace_range(.., res.unwrap().as_str());
-            } else {
-                return HttpResponse::Found()
-                    .append_header((
-                        "Location",
-                        format!("{}/auth/{}/incorrect", ARGS.public_path_as_str(), pastas[index].id_as_animals()),
-                    ))
-                    .finish();
-            }
-        }
+        // count the read, the counter is written to the database in batches unless it is
+        // shared, where another instance may have used up the last read in the meantime
+        read_stats::record(&data.db, &data.pastas, pastas, index)
+    };
 
-        // serve pasta in template
-        let response = HttpResponse::Ok().content_type("text/html; charset=utf-8").body(
-            PastaTemplate {
-                pasta: &pastas[index],
-                args: &ARGS,
-            }
-            .render()
-            .unwrap(),
-        );
-
-        if pastas[index].content != original_content {
-            pastas[index].content = original_content;
-        }
+    let Some((mut pastas, index)) = counting.await else {
+        return HttpResponse::Ok()
+            .content_type("text/html; charset=utf-8")
+            .body(ErrorTemplate { args: &ARGS.get() }.render().unwrap());
+    };
+    views::record(req, &pastas[index]);
 
-        // get current unix time in seconds
-        let timenow: i64 = match SystemTime::now().duration_since(UNIX_EPOCH) {
-            Ok(n) => n.as_secs(),
-            Err(_) => {
-                log::error!("SystemTime before UNIX EPOCH!");
-                0
-            }
-        } as i64;
+    let original_content = pastas[index].content.to_owned();
 
-        // update last read time
-        pastas[index].last_read = timenow;
+    // decrypt content temporarily
+    if password != *"" && !original_content.is_empty() {
+        let res = decrypt(&original_content, &password);
+        if let Ok(..) = res {
+            pastas[index]
+                .content
+                .replace_range(.., res.unwrap().as_str());
+        } else {
+            metrics::failed_auth("pasta");
+            return HttpResponse::Found()
+                .append_header((
+                    "Location",
+                    format!("{}/auth/{}/incorrect", ARGS.get().public_path_as_str(), pastas[index].id_as_animals()),
+                ))
+                .finish();
+        }
+    }
 
-        // save the updated read count
-        update(Some(&pastas), Some(&pastas[index]));
+    // serve pasta in template
+    let response = HttpResponse::Ok().content_type("text/html; charset=utf-8").body(
+        PastaTemplate {
+            pasta: &pastas[index],
+            args: &ARGS.get(),
+        }
+        .render()
+        .unwrap(),
+    );
 
-        return response;
+    if pastas[index].content != original_content {
+        pastas[index].content = original_content;
     }
 
-    // otherwise send pasta not found error
-    HttpResponse::Ok()
-        .content_type("text/html; charset=utf-8")
-        .body(ErrorTemplate { args: &ARGS }.render().unwrap())
+    response
 }
 
 #[post("/upload/{id}")]
 pub async fn postpasta(
+    req: HttpRequest,
     data: web::Data<AppState>,
     id: web::Path<String>,
     payload: Multipart,
 ) -> Result<HttpResponse, Error> {
     let password = auth::password_from_multipart(payload).await?;
-    Ok(pastaresponse(data, id, password))
+    Ok(pastaresponse(&req, data, id, password).await)
 }
 
 #[post("/p/{id}")]
 pub async fn postshortpasta(
+    req: HttpRequest,
     data: web::Data<AppState>,
     id: web::Path<String>,
     payload: Multipart,
 ) -> Result<HttpResponse, Error> {
     let password = auth::password_from_multipart(payload).await?;
-    Ok(pastaresponse(data, id, password))
+    Ok(pastaresponse(&req, data, id, password).await)
 }
 
 #[get("/upload/{id}")]
-pub async fn getpasta(data: web::Data<AppState>, id: web::Path<String>) -> HttpResponse {
-    pastaresponse(data, id, String::from(""))
+pub async fn getpasta(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    id: web::Path<String>,
+) -> HttpResponse {
+    pastaresponse(&req, data, id, String::from("")).await
 }
 
 #[get("/p/{id}")]
-pub async fn getshortpasta(data: web::Data<AppState>, id: web::Path<String>) -> HttpResponse {
-    pastaresponse(data, id, String::from(""))
+pub async fn getshortpasta(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    id: web::Path<String>,
+) -> HttpResponse {
+    pastaresponse(&req, data, id, String::from("")).await
 }
 
-fn urlresponse(data: web::Data<AppState>, id: web::Path<String>) -> HttpResponse {
-    // get access to the pasta collection
-    let mut pastas = data.pastas.lock().unwrap();
-
-    let id = if ARGS.hash_ids {
+async fn urlresponse(
+    req: &HttpRequest,
+    data: web::Data<AppState>,
+    id: web::Path<String>,
+) -> HttpResponse {
+    let id = if ARGS.get().hash_ids {
         hashid_to_u64(&id).unwrap_or(0)
     } else {
         to_u64(&id.into_inner()).unwrap_or(0)
     };
 
-    // remove expired pastas (including this one if needed)
-    remove_expired(&mut pastas);
-
-    // find the index of the pasta in the collection based on u64 id
-    let mut index: usize = 0;
-    let mut found: bool = false;
-
-    for (i, pasta) in pastas.iter().enumerate() {
-        if pasta.id == id {
-            index = i;
-            found = true;
-            break;
-        }
-    }
-
-    if found {
-        // increment read count
-        pastas[index].read_count += 1;
-
-        // save the updated read count
-        update(Some(&pastas), Some(&pastas[index]));
+    // get access to the pasta collection
+    data.pastas.reload_id(id).await;
+    let counting = {
+        let mut pastas = data.pastas.lock().unwrap();
 
-        // send redirect if it's a url pasta
-        if pastas[index].pasta_type == "url" {
-            let response = HttpResponse::Found()
-                .append_header(("Location", String::from(&pastas[index].content)))
-                .finish();
+        // remove expired pastas (including this one if needed)
+        remove_expired(&data.db, &mut pastas);
 
-            // get current unix time in seconds
-            let timenow: i64 = match SystemTime::now().duration_since(UNIX_EPOCH) {
-                Ok(n) => n.as_secs(),
-                Err(_) => {
-                    log::error!("SystemTime before UNIX EPOCH!");
-                    0
-                }
-            } as i64;
+        // find the index of the pasta in the collection based on u64 id,
+        // otherwise send pasta not found error
+        let Some(index) = pastas.iter().position(|pasta| pasta.id == id) else {
+            return HttpResponse::Ok()
+                .content_type("text/html; charset=utf-8")
+                .body(ErrorTemplate { args: &ARGS.get() }.render().unwrap());
+        };
 
-            // update last read time
-            pastas[index].last_read = timenow;
+        // count the read, the counter is written to the database in batches unless it is
+        // shared, where another instance may have used up the last read in the meantime
+        read_stats::record(&data.db, &data.pastas, pastas, index)
+    };
 
-            // save the updated read count
-            update(Some(&pastas), Some(&pastas[index]));
+    let Some((pastas, index)) = counting.await else {
+        return HttpResponse::Ok()
+            .content_type("text/html; charset=utf-8")
+            .body(ErrorTemplate { args: &ARGS.get() }.render().unwrap());
+    };
+    views::record(req, &pastas[index]);
 
-            return response;
-        // send error if we're trying to open a non-url pasta as a redirect
-        } else {
-            HttpResponse::Ok()
-                .content_type("text/html; charset=utf-8")
-                .body(ErrorTemplate { args: &ARGS }.render().unwrap());
-        }
+    // send redirect if it's a url pasta
+    if pastas[index].pasta_type == "url" {
+        return HttpResponse::Found()
+            .append_header(("Location", String::from(&pastas[index].content)))
+            .finish();
     }
 
-    // otherwise send pasta not found error
+    // send error if we're trying to open a non-url pasta as a redirect
     HttpResponse::Ok()
         .content_type("text/html; charset=utf-8")
-        .body(ErrorTemplate { args: &ARGS }.render().unwrap())
+        .body(ErrorTemplate { args: &ARGS.get() }.render().unwrap())
 }
 
 #[get("/url/{id}")]
-pub async fn redirecturl(data: web::Data<AppState>, id: web::Path<String>) -> HttpResponse {
-    urlresponse(data, id)
+pub async fn redirecturl(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    id: web::Path<String>,
+) -> HttpResponse {
+    urlresponse(&req, data, id).await
 }
 
 #[get("/u/{id}")]
-pub async fn shortredirecturl(data: web::Data<AppState>, id: web::Path<String>) -> HttpResponse {
-    urlresponse(data, id)
+pub async fn shortredirecturl(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    id: web::Path<String>,
+) -> HttpResponse {
+    urlresponse(&req, data, id).await
 }
 
 #[get("/raw/{id}")]
 pub async fn getrawpasta(
+    req: HttpRequest,
     data: web::Data<AppState>,
     id: web::Path<String>,
 ) -> Result<HttpResponse, Error> {
-    // get access to the pasta collection
-    let mut pastas = data.pastas.lock().unwrap();
-
-    let id = if ARGS.hash_ids {
+    let id = if ARGS.get().hash_ids {
         hashid_to_u64(&id).unwrap_or(0)
     } else {
         to_u64(&id.into_inner()).unwrap_or(0)
     };
 
-    // remove expired pastas (including this one if needed)
-    remove_expired(&mut pastas);
-
-    // find the index of the pasta in the collection based on u64 id
-    let mut index: usize = 0;
-    let mut found: bool = false;
-    for (i, pasta) in pastas.iter().enumerate() {
-        if pasta.id == id {
-            index = i;
-            found = true;
-            break;
-        }
-    }
+    // get access to the pasta collection
+    data.pastas.reload_id(id).await;
+    let counting = {
+        let mut pastas = data.pastas.lock().unwrap();
+
+        // remove expired pastas (including this one if needed)
+        remove_expired(&data.db, &mut pastas);
+
+        // find the index of the pasta in the collection based on u64 id,
+        // otherwise send pasta not found error as raw text
+        let Some(index) = pastas.iter().position(|pasta| pasta.id == id) else {
+            return Ok(HttpResponse::NotFound()
+                .content_type("text/html; charset=utf-8")
+                .body(String::from("Upload not found! :-(")));
+        };
 
-    if found {
         if pastas[index].encrypt_server {
             return Ok(HttpResponse::Found()
                 .append_header((
                     "Location",
-                    format!("{}/auth_raw/{}", ARGS.public_path_as_str(), pastas[index].id_as_animals()),
+                    format!("{}/auth_raw/{}", ARGS.get().public_path_as_str(), pastas[index].id_as_animals()),
                 ))
                 .finish());
         }
 
-        // increment read count
-        pastas[index].read_count += 1;
+        // count the read, the counter is written to the database in batches unless it is
+        // shared, where another instance may have used up the last read in the meantime
+        read_stats::record(&data.db, &data.pastas, pastas, index)
+    };
 
-        // save the updated read count
-        update(Some(&pastas), Some(&pastas[index]));
+    let Some((pastas, index)) = counting.await else {
+        return Ok(HttpResponse::NotFound()
+            .content_type("text/html; charset=utf-8")
+            .body(String::from("Upload not found! :-(")));
+    };
+    views::record(&req, &pastas[index]);
 
-        // get current unix time in seconds
-        let timenow: i64 = match SystemTime::now().duration_since(UNIX_EPOCH) {
-            Ok(n) => n.as_secs(),
-            Err(_) => {
-                log::error!("SystemTime before UNIX EPOCH!");
-                0
-            }
-        } as i64;
+    // send raw content of pasta
+    Ok(HttpResponse::NotFound()
+        .content_type("text/plain; charset=utf-8")
+        .body(pastas[index].content.to_owned()))
+}
 
-        // update last read time
-        pastas[index].last_read = timenow;
+/// Raw content of one of the named text files of a pasta.
+#[get("/raw/{id}/{file}")]
+pub async fn getrawpastafile(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    param: web::Path<(String, String)>,
+) -> HttpResponse {
+    let (id, file_name) = param.into_inner();
 
-        // send raw content of pasta
-        let response = Ok(HttpResponse::NotFound()
-            .content_type("text/plain; charset=utf-8")
-            .body(pastas[index].content.to_owned()));
+    let id = if ARGS.get().hash_ids {
+        hashid_to_u64(&id).unwrap_or(0)
+    } else {
+        to_u64(&id).unwrap_or(0)
+    };
 
-        return response;
-    }
+    // get access to the pasta collection
+    data.pastas.reload_id(id).await;
+    let (content, counting) = {
+        let mut pastas = data.pastas.lock().unwrap();
 
-    // otherwise send pasta not found error as raw text
-    Ok(HttpResponse::NotFound()
-        .content_type("text/html; charset=utf-8")
-        .body(String::from("Upload not found! :-(")))
+        // remove expired pastas (including this one if needed)
+        remove_expired(&data.db, &mut pastas);
+
+        let Some(index) = pastas.iter().position(|pasta| pasta.id == id) else {
+            return HttpResponse::NotFound()
+                .content_type("text/html; charset=utf-8")
+                .body(String::from("Upload not found! :-("));
+        };
+
+        let Some(content) = pastas[index]
+            .files
+            .iter()
+            .find(|file| file.name == file_name)
+            .map(|file| file.content.to_owned())
+        else {
+            return HttpResponse::NotFound()
+                .content_type("text/html; charset=utf-8")
+                .body(String::from("File not found! :-("));
+        };
+
+        // count the read, the counter is written to the database in batches unless it is
+        // shared, where another instance may have used up the last read in the meantime
+        (content, read_stats::record(&data.db, &data.pastas, pastas, index))
+    };
+
+    let Some((pastas, index)) = counting.await else {
+        return HttpResponse::NotFound()
+            .content_type("text/html; charset=utf-8")
+            .body(String::from("Upload not found! :-("));
+    };
+    views::record(&req, &pastas[index]);
+
+    HttpResponse::Ok()
+        .content_type("text/plain; charset=utf-8")
+        .body(content)
 }
 
 #[post("/raw/{id}")]
 pub async fn postrawpasta(
+    req: HttpRequest,
     data: web::Data<AppState>,
     id: web::Path<String>,
     payload: Multipart,
 ) -> Result<HttpResponse, Error> {
     let password = auth::password_from_multipart(payload).await?;
 
-    // get access to the pasta collection
-    let mut pastas = data.pastas.lock().unwrap();
-
-    let id = if ARGS.hash_ids {
+    let id = if ARGS.get().hash_ids {
         hashid_to_u64(&id).unwrap_or(0)
     } else {
         to_u64(&id.into_inner()).unwrap_or(0)
     };
 
-    // remove expired pastas (including this one if needed)
-    remove_expired(&mut pastas);
-
-    // find the index of the pasta in the collection based on u64 id
-    let mut index: usize = 0;
-    let mut found: bool = false;
-    for (i, pasta) in pastas.iter().enumerate() {
-        if pasta.id == id {
-            index = i;
-            found = true;
-            break;
-        }
-    }
+    // get access to the pasta collection
+    data.pastas.reload_id(id).await;
+    let counting = {
+        let mut pastas = data.pastas.lock().unwrap();
+
+        // remove expired pastas (including this one if needed)
+        remove_expired(&data.db, &mut pastas);
+
+        // find the index of the pasta in the collection based on u64 id,
+        // otherwise send pasta not found error as raw text
+        let Some(index) = pastas.iter().position(|pasta| pasta.id == id) else {
+            return Ok(HttpResponse::NotFound()
+                .content_type("text/html; charset=utf-8")
+                .body(String::from("Upload not found! :-(")));
+        };
 
-    if found {
         if pastas[index].encrypt_server && password == *"" {
             return Ok(HttpResponse::Found()
                 .append_header((
                     "Location",
-                    format!("{}/auth/{}", ARGS.public_path_as_str(), pastas[index].id_as_animals()),
+                    format!("{}/auth/{}", ARGS.get().public_path_as_str(), pastas[index].id_as_animals()),
                 ))
                 .finish());
         }
 
-        // increment read count
-        pastas[index].read_count += 1;
-
-        // save the updated read count
-        update(Some(&pastas), Some(&pastas[index]));
-
-        let original_content = pastas[index].content.to_owned();
-
-        // decrypt content temporarily
-        if password != *"" {
-            let res = decrypt(&original_content, &password);
-            if res.is_ok() {
-                pastas[index]
-                    .content
-                    .replace_range(.., res.unwrap().as_str());
-            } else {
-                return Ok(HttpResponse::Found()
-                    .append_header((
-                        "Location",
-                        format!("{}/auth/{}/incorrect", ARGS.public_path_as_str(), pastas[index].id_as_animals()),
-                    ))
-                    .finish());
-            }
-        }
-
-        // get current unix time in seconds
-        let timenow: i64 = match SystemTime::now().duration_since(UNIX_EPOCH) {
-            Ok(n) => n.as_secs(),
-            Err(_) => {
-                log::error!("SystemTime before UNIX EPOCH!");
-                0
-            }
-        } as i64;
-
-        // update last read time
-        pastas[index].last_read = timenow;
-
-        // save the updated read count
-        update(Some(&pastas), Some(&pastas[index]));
+        // count the read, the counter is written to the database in batches unless it is
+        // shared, where another instance may have used up the last read in the meantime
+        read_stats::record(&data.db, &data.pastas, pastas, index)
+    };
 
-        // send raw content of pasta
-        let response = Ok(HttpResponse::NotFound()
+    let Some((mut pastas, index)) = counting.await else {
+        return Ok(HttpResponse::NotFound()
             .content_type("text/html; charset=utf-8")
-            .body(pastas[index].content.to_owned()));
+            .body(String::from("Upload not found! :-(")));
+    };
+    views::record(&req, &pastas[index]);
 
-        if pastas[index].content != original_content {
-            pastas[index].content = original_content;
-        }
+    let original_content = pastas[index].content.to_owned();
 
-        return response;
+    // decrypt content temporarily
+    if password != *"" {
+        let res = decrypt(&original_content, &password);
+        if res.is_ok() {
+            pastas[index]
+                .content
+                .replace_range(.., res.unwrap().as_str());
+        } else {
+            metrics::failed_auth("pasta");
+            return Ok(HttpResponse::Found()
+                .append_header((
+                    "Location",
+                    format!("{}/auth/{}/incorrect", ARGS.get().public_path_as_str(), pastas[index].id_as_animals()),
+                ))
+                .finish());
+        }
     }
 
-    // otherwise send pasta not found error as raw text
-    Ok(HttpResponse::NotFound()
+    // send raw content of pasta
+    let response = Ok(HttpResponse::NotFound()
         .content_type("text/html; charset=utf-8")
-        .body(String::from("Upload not found! :-(")))
+        .body(pastas[index].content.to_owned()));
+
+    if pastas[index].content != original_content {
+        pastas[index].content = original_content;
+    }
+
+    response
 }
 
 fn decrypt(text_str: &str, key_str: &str) -> Result<String, magic_crypt::MagicCryptError> {
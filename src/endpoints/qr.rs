@@ -18,17 +18,18 @@ struct QRTemplate<'a> {
 
 #[get("/qr/{id}")]
 pub async fn getqr(data: web::Data<AppState>, id: web::Path<String>) -> HttpResponse {
-    // get access to the pasta collection
-    let mut pastas = data.pastas.lock().unwrap();
-
-    let u64_id = if ARGS.hash_ids {
+    let u64_id = if ARGS.get().hash_ids {
         hashid_to_u64(&id).unwrap_or(0)
     } else {
         to_u64(&id).unwrap_or(0)
     };
 
+    // get access to the pasta collection
+    data.pastas.reload_id(u64_id).await;
+    let mut pastas = data.pastas.lock().unwrap();
+
     // remove expired pastas (including this one if needed)
-    remove_expired(&mut pastas);
+    remove_expired(&data.db, &mut pastas);
 
     // find the index of the pasta in the collection based on u64 id
     let mut index: usize = 0;
@@ -45,10 +46,10 @@ pub async fn getqr(data: web::Data<AppState>, id: web::Path<String>) -> HttpResp
         // generate the QR code as an SVG - if its a file or text pastas, this will point to the /upload endpoint, otherwise to the /url endpoint, essentially directly taking the user to the url stored in the pasta
         let svg: String = match pastas[index].pasta_type.as_str() {
             "url" => misc::string_to_qr_svg(
-                format!("{}/url/{}", &ARGS.public_path_as_str(), &id).as_str(),
+                format!("{}/url/{}", &ARGS.get().public_path_as_str(), &id).as_str(),
             ),
             _ => misc::string_to_qr_svg(
-                format!("{}/upload/{}", &ARGS.public_path_as_str(), &id).as_str(),
+                format!("{}/upload/{}", &ARGS.get().public_path_as_str(), &id).as_str(),
             ),
         };
 
@@ -57,7 +58,7 @@ pub async fn getqr(data: web::Data<AppState>, id: web::Path<String>) -> HttpResp
             QRTemplate {
                 qr: &svg,
                 pasta: &pastas[index],
-                args: &ARGS,
+                args: &ARGS.get(),
             }
             .render()
             .unwrap(),
@@ -68,5 +69,5 @@ pub async fn getqr(data: web::Data<AppState>, id: web::Path<String>) -> HttpResp
     // send pasta not found error
     HttpResponse::Ok()
         .content_type("text/html; charset=utf-8")
-        .body(ErrorTemplate { args: &ARGS }.render().unwrap())
+        .body(ErrorTemplate { args: &ARGS.get() }.render().unwrap())
 }
@@ -3,79 +3,66 @@ use actix_web::{get, post, web, Error, HttpResponse};
 
 use crate::args::ARGS;
 use crate::endpoints::errors::ErrorTemplate;
-use crate::pasta::PastaFile;
 use crate::util::animalnumbers::to_u64;
 use crate::util::auth;
-use crate::util::db::delete;
+use crate::util::db::{self, delete};
 use crate::util::hashids::to_u64 as hashid_to_u64;
-use crate::util::misc::{decrypt, remove_expired};
+use crate::util::metrics;
+use crate::util::webhooks::{self, Event};
+use crate::util::misc::{decrypt, remove_attachment, remove_expired};
 use crate::AppState;
 use askama::Template;
-use std::fs;
 
 #[get("/remove/{id}")]
 pub async fn remove(data: web::Data<AppState>, id: web::Path<String>) -> HttpResponse {
-    let mut pastas = data.pastas.lock().unwrap();
-
-    let id = if ARGS.hash_ids {
+    let id = if ARGS.get().hash_ids {
         hashid_to_u64(&id).unwrap_or(0)
     } else {
         to_u64(&id.into_inner()).unwrap_or(0)
     };
 
-    for (i, pasta) in pastas.iter().enumerate() {
-        if pasta.id == id {
-            // if it's encrypted or read-only, it needs password to be deleted
-            if pasta.encrypt_server || pasta.readonly {
-                return HttpResponse::Found()
-                    .append_header((
-                        "Location",
-                        format!("{}/auth_remove_private/{}", ARGS.public_path_as_str(), pasta.id_as_animals()),
-                    ))
-                    .finish();
-            }
-
-            // remove the file itself
-            if let Some(PastaFile { name, .. }) = &pasta.file {
-                if fs::remove_file(format!(
-                    "{}/attachments/{}/{}",
-                    ARGS.data_dir,
-                    pasta.id_as_animals(),
-                    name
-                ))
-                .is_err()
-                {
-                    log::error!("Failed to delete file {}!", name)
-                }
-
-                // and remove the containing directory
-                if fs::remove_dir(format!(
-                    "{}/attachments/{}/",
-                    ARGS.data_dir,
-                    pasta.id_as_animals()
-                ))
-                .is_err()
-                {
-                    log::error!("Failed to delete directory {}!", name)
-                }
-            }
+    data.pastas.reload_id(id).await;
+
+    let write = {
+        let mut pastas = data.pastas.lock().unwrap();
 
-            // remove it from in-memory pasta list
-            pastas.remove(i);
+        let Some(i) = pastas.iter().position(|pasta| pasta.id == id) else {
+            remove_expired(&data.db, &mut pastas);
 
-            delete(Some(&pastas), Some(id));
+            return HttpResponse::Ok()
+                .content_type("text/html; charset=utf-8")
+                .body(ErrorTemplate { args: &ARGS.get() }.render().unwrap());
+        };
 
+        // if it's encrypted or read-only, it needs password to be deleted
+        if pastas[i].encrypt_server || pastas[i].readonly {
             return HttpResponse::Found()
-                .append_header(("Location", format!("{}/list", ARGS.public_path_as_str())))
+                .append_header((
+                    "Location",
+                    format!("{}/auth_remove_private/{}", ARGS.get().public_path_as_str(), pastas[i].id_as_animals()),
+                ))
                 .finish();
         }
-    }
 
-    remove_expired(&mut pastas);
+        webhooks::notify(Event::Deleted, &pastas[i]);
+
+        // remove it from in-memory pasta list
+        let pasta = pastas.remove(i);
+
+        // remove it from the database along with the file itself
+        db::write(&data.db, pastas, move |db, pastas| {
+            delete(db, pastas, Some(id));
+            remove_attachment(db, &pasta, pastas.map_or(&[], Vec::as_slice));
+        })
+    };
 
-    HttpResponse::Ok()
-        .content_type("text/html; charset=utf-8")
-        .body(ErrorTemplate { args: &ARGS }.render().unwrap())
+    if let Err(e) = write.await {
+        return HttpResponse::from_error(e);
+    }
+
+    HttpResponse::Found()
+        .append_header(("Location", format!("{}/list", ARGS.get().public_path_as_str())))
+        .finish()
 }
 
 #[post("/remove/{id}")]
@@ -84,92 +71,67 @@ pub async fn post_remove(
     id: web::Path<String>,
     payload: Multipart,
 ) -> Result<HttpResponse, Error> {
-    let id = if ARGS.hash_ids {
+    let id = if ARGS.get().hash_ids {
         hashid_to_u64(&id).unwrap_or(0)
     } else {
         to_u64(&id.into_inner()).unwrap_or(0)
     };
 
-    let mut pastas = data.pastas.lock().unwrap();
+    let password = auth::password_from_multipart(payload).await?;
 
-    remove_expired(&mut pastas);
+    data.pastas.reload_id(id).await;
 
-    let password = auth::password_from_multipart(payload).await?;
+    let write = {
+        let mut pastas = data.pastas.lock().unwrap();
+
+        remove_expired(&data.db, &mut pastas);
 
-    for (i, pasta) in pastas.iter().enumerate() {
-        if pasta.id == id {
-            if pastas[i].readonly || pastas[i].encrypt_server {
-                if password != *"" {
-                    let res = decrypt(pastas[i].content.to_owned().as_str(), &password);
-                    if res.is_ok() {
-                        // remove the file itself
-                        if let Some(PastaFile { name, .. }) = &pasta.file {
-                            if fs::remove_file(format!(
-                                "{}/attachments/{}/{}",
-                                ARGS.data_dir,
-                                pasta.id_as_animals(),
-                                name
-                            ))
-                            .is_err()
-                            {
-                                log::error!("Failed to delete file {}!", name)
-                            }
-
-                            // and remove the containing directory
-                            if fs::remove_dir(format!(
-                                "{}/attachments/{}/",
-                                ARGS.data_dir,
-                                pasta.id_as_animals()
-                            ))
-                            .is_err()
-                            {
-                                log::error!("Failed to delete directory {}!", name)
-                            }
-                        }
-
-                        // remove it from in-memory pasta list
-                        pastas.remove(i);
-
-                        delete(Some(&pastas), Some(id));
-
-                        return Ok(HttpResponse::Found()
-                            .append_header((
-                                "Location",
-                                format!("{}/list", ARGS.public_path_as_str()),
-                            ))
-                            .finish());
-                    } else {
-                        return Ok(HttpResponse::Found()
-                            .append_header((
-                                "Location",
-                                format!("{}/auth_remove_private/{}/incorrect", ARGS.public_path_as_str(), pasta.id_as_animals()),
-                            ))
-                            .finish());
-                    }
-                } else {
-                    return Ok(HttpResponse::Found()
-                        .append_header((
-                            "Location",
-                            format!("{}/auth_remove_private/{}/incorrect", ARGS.public_path_as_str(), pasta.id_as_animals()),
-                        ))
-                        .finish());
-                }
-            }
+        let Some(i) = pastas.iter().position(|pasta| pasta.id == id) else {
+            return Ok(HttpResponse::Ok()
+                .content_type("text/html; charset=utf-8")
+                .body(ErrorTemplate { args: &ARGS.get() }.render().unwrap()));
+        };
 
+        if !pastas[i].readonly && !pastas[i].encrypt_server {
             return Ok(HttpResponse::Found()
                 .append_header((
                     "Location",
                     format!(
                         "{}/upload/{}",
-                        ARGS.public_path_as_str(),
+                        ARGS.get().public_path_as_str(),
                         pastas[i].id_as_animals()
                     ),
                 ))
                 .finish());
         }
-    }
 
-    Ok(HttpResponse::Ok()
-        .content_type("text/html; charset=utf-8")
-        .body(ErrorTemplate { args: &ARGS }.render().unwrap()))
+        if password == *"" || decrypt(pastas[i].content.to_owned().as_str(), &password).is_err() {
+            metrics::failed_auth("pasta");
+            return Ok(HttpResponse::Found()
+                .append_header((
+                    "Location",
+                    format!("{}/auth_remove_private/{}/incorrect", ARGS.get().public_path_as_str(), pastas[i].id_as_animals()),
+                ))
+                .finish());
+        }
+
+        webhooks::notify(Event::Deleted, &pastas[i]);
+
+        // remove it from in-memory pasta list
+        let pasta = pastas.remove(i);
+
+        // remove it from the database along with the file itself
+        db::write(&data.db, pastas, move |db, pastas| {
+            delete(db, pastas, Some(id));
+            remove_attachment(db, &pasta, pastas.map_or(&[], Vec::as_slice));
+        })
+    };
+    write.await?;
+
+    Ok(HttpResponse::Found()
+        .append_header((
+            "Location",
+            format!("{}/list", ARGS.get().public_path_as_str()),
+        ))
+        .finish())
 }
@@ -0,0 +1,59 @@
+use actix_web::{get, web, HttpResponse};
+use askama::Template;
+use serde::Deserialize;
+
+use crate::args::{Args, ARGS};
+use crate::pasta::Pasta;
+use crate::util::db;
+use crate::util::misc::remove_expired;
+use crate::util::search::searchable;
+use crate::AppState;
+
+#[derive(Template)]
+#[template(path = "search.html")]
+struct SearchTemplate<'a> {
+    query: &'a String,
+    results: &'a Vec<(&'a Pasta, String)>,
+    args: &'a Args,
+}
+
+#[derive(Deserialize)]
+pub struct SearchQuery {
+    #[serde(default)]
+    q: String,
+}
+
+#[get("/search")]
+pub async fn search(data: web::Data<AppState>, query: web::Query<SearchQuery>) -> HttpResponse {
+    // searching is a way of listing, so it is disabled along with the list
+    if ARGS.get().no_listing {
+        return HttpResponse::Found()
+            .append_header(("Location", format!("{}/", ARGS.get().public_path_as_str())))
+            .finish();
+    }
+
+    data.pastas.reload().await;
+    let mut pastas = data.pastas.lock().unwrap();
+
+    remove_expired(&data.db, &mut pastas);
+
+    let results: Vec<(&Pasta, String)> = db::search(&data.db, &pastas, &query.q)
+        .into_iter()
+        .filter_map(|hit| {
+            pastas
+                .iter()
+                .find(|pasta| pasta.id == hit.id && searchable(pasta))
+                .map(|pasta| (pasta, hit.snippet))
+        })
+        .collect();
+
+    HttpResponse::Ok().content_type("text/html; charset=utf-8").body(
+        SearchTemplate {
+            query: &query.q,
+            results: &results,
+            args: &ARGS.get(),
+        }
+        .render()
+        .unwrap(),
+    )
+}
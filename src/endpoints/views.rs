@@ -0,0 +1,141 @@
+use crate::args::{Args, ARGS};
+use crate::endpoints::errors::ErrorTemplate;
+use crate::pasta::Pasta;
+use crate::util::animalnumbers::to_u64;
+use crate::util::auth;
+use crate::util::hashids::to_u64 as hashid_to_u64;
+use crate::util::metrics;
+use crate::util::misc::{decrypt, remove_expired};
+use crate::util::views::{self, Day};
+use crate::view::View;
+use crate::AppState;
+use actix_multipart::Multipart;
+use actix_web::{get, post, web, Error, HttpRequest, HttpResponse};
+use askama::Template;
+use chrono::Local;
+
+/// Views listed below the chart, newest first.
+const RECENT_VIEWS: usize = 100;
+
+#[derive(Template)]
+#[template(path = "views.html", escape = "none")]
+struct ViewsTemplate<'a> {
+    pasta: &'a Pasta,
+    args: &'a Args,
+    status: &'a str,
+    authorized: bool,
+    total: usize,
+    days: Vec<Day>,
+    views: Vec<View>,
+}
+
+/// The admin, the basic auth user that uploaded the pasta and, for pastas
+/// with a password, whoever knows it may see who viewed it.
+fn is_owner(req: &HttpRequest, admin: bool, pasta: &Pasta, password: &str) -> bool {
+    if admin
+        || (pasta.uploader.is_some() && auth::basic_auth_user(req) == pasta.uploader)
+    {
+        return true;
+    }
+    if password.is_empty() {
+        return false;
+    }
+    if pasta.readonly {
+        pasta
+            .encrypted_key
+            .as_deref()
+            .and_then(|key| decrypt(key, password).ok())
+            .is_some_and(|id| id == pasta.id.to_string())
+    } else {
+        pasta.encrypt_server && !pasta.content.is_empty() && decrypt(&pasta.content, password).is_ok()
+    }
+}
+
+async fn viewsresponse(
+    req: &HttpRequest,
+    data: web::Data<AppState>,
+    id: web::Path<String>,
+    password: String,
+) -> HttpResponse {
+    let id = if ARGS.get().hash_ids {
+        hashid_to_u64(&id).unwrap_or(0)
+    } else {
+        to_u64(&id.into_inner()).unwrap_or(0)
+    };
+
+    data.pastas.reload_id(id).await;
+
+    let admin = auth::is_admin(req, &data.db).await;
+
+    // the views are read without holding the lock, the pasta is looked up again after
+    let authorized = {
+        let mut pastas = data.pastas.lock().unwrap();
+
+        // remove expired pastas (including this one if needed)
+        remove_expired(&data.db, &mut pastas);
+
+        let Some(pasta) = pastas.iter().find(|pasta| pasta.id == id) else {
+            return HttpResponse::Ok()
+                .content_type("text/html; charset=utf-8")
+                .body(ErrorTemplate { args: &ARGS.get() }.render().unwrap());
+        };
+
+        is_owner(req, admin, pasta, &password)
+    };
+    if !authorized && !password.is_empty() {
+        metrics::failed_auth("pasta");
+    }
+
+    let mut views = if authorized && !ARGS.get().disable_view_log {
+        let db = data.db.clone();
+        web::block(move || views::read(&db, id))
+            .await
+            .unwrap_or_default()
+    } else {
+        Vec::new()
+    };
+
+    let pastas = data.pastas.lock().unwrap();
+    let Some(pasta) = pastas.iter().find(|pasta| pasta.id == id) else {
+        return HttpResponse::Ok()
+            .content_type("text/html; charset=utf-8")
+            .body(ErrorTemplate { args: &ARGS.get() }.render().unwrap());
+    };
+    let days = views::per_day(&views, Local::now().date_naive());
+    let total = views.len();
+    views.truncate(RECENT_VIEWS);
+
+    HttpResponse::Ok().content_type("text/html; charset=utf-8").body(
+        ViewsTemplate {
+            pasta,
+            args: &ARGS.get(),
+            status: if authorized || password.is_empty() { "" } else { "incorrect" },
+            authorized,
+            total,
+            days,
+            views,
+        }
+        .render()
+        .unwrap(),
+    )
+}
+
+#[get("/views/{id}")]
+pub async fn get_views(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    id: web::Path<String>,
+) -> HttpResponse {
+    viewsresponse(&req, data, id, String::from("")).await
+}
+
+#[post("/views/{id}")]
+pub async fn post_views(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    id: web::Path<String>,
+    payload: Multipart,
+) -> Result<HttpResponse, Error> {
+    let password = auth::password_from_multipart(payload).await?;
+    Ok(viewsresponse(&req, data, id, password).await)
+}
@@ -1,13 +1,18 @@
 extern crate core;
 
-use crate::args::ARGS;
+use crate::args::{Command, ARGS};
 use crate::endpoints::{
-    admin, auth_admin, auth_upload, create, edit, errors, file, guide, list,
-    pasta as pasta_endpoint, qr, remove, static_resources,
+    admin, admin_settings, admin_totp, auth_admin, auth_upload, collection as collection_endpoint, create, edit, errors, feed, file, guide, health, list,
+    metrics as metrics_endpoint, pasta as pasta_endpoint, qr, remove, search, static_resources, views as views_endpoint,
 };
 use crate::pasta::Pasta;
-use crate::util::db::read_all;
+use crate::collection::Collection;
+use crate::setting::SettingChange;
+use crate::util::{backup, blobs, read_stats, views};
+use crate::util::db::{is_shared, read_all, read_all_collections, read_setting_changes, Database, Records};
+use crate::util::misc::sweep_expired;
 use crate::util::telemetry::start_telemetry_thread;
+use actix_web::dev::Service;
 use actix_web::middleware::Condition;
 use actix_web::{middleware, web, App, HttpServer};
 use actix_web_httpauth::middleware::HttpAuthentication;
@@ -16,44 +21,78 @@ use env_logger::Builder;
 use log::LevelFilter;
 use std::fs;
 use std::io::Write;
-use std::sync::Mutex;
+use std::time::Instant;
 
 pub mod args;
+pub mod collection;
 pub mod pasta;
+pub mod setting;
+pub mod view;
 
 pub mod util {
     pub mod animalnumbers;
     pub mod auth;
+    pub mod backup;
+    pub mod blobs;
     pub mod db;
     pub mod db_json;
     #[cfg(feature = "default")]
+    pub mod db_migrate;
+    #[cfg(feature = "postgres")]
+    pub mod db_postgres;
+    #[cfg(feature = "default")]
     pub mod db_sqlite;
     pub mod hashids;
+    pub mod htpasswd;
+    pub mod listing;
+    pub mod metrics;
     pub mod misc;
+    pub mod quota;
+    pub mod read_stats;
+    pub mod search;
+    pub mod store;
+    pub mod store_fs;
+    pub mod store_s3;
+    #[cfg(feature = "default")]
+    pub mod store_sqlite;
     pub mod syntaxhighlighter;
     pub mod telemetry;
+    pub mod totp;
     pub mod version;
+    pub mod views;
+    pub mod webhooks;
     pub mod http_client;
 }
 
 pub mod endpoints {
     pub mod admin;
+    pub mod admin_settings;
+    pub mod admin_totp;
     pub mod auth_admin;
     pub mod auth_upload;
+    pub mod collection;
     pub mod create;
     pub mod edit;
     pub mod errors;
+    pub mod feed;
     pub mod file;
     pub mod guide;
+    pub mod health;
     pub mod list;
+    pub mod metrics;
     pub mod pasta;
     pub mod qr;
     pub mod remove;
+    pub mod search;
     pub mod static_resources;
+    pub mod views;
 }
 
 pub struct AppState {
-    pub pastas: Mutex<Vec<Pasta>>,
+    pub db: Database,
+    pub pastas: Records<Pasta>,
+    pub collections: Records<Collection>,
+    pub setting_changes: Records<SettingChange>,
 }
 
 #[actix_web::main]
@@ -71,40 +110,116 @@ async fn main() -> std::io::Result<()> {
         .filter(None, LevelFilter::Info)
         .init();
 
-    log::info!(
-        "MicroBin starting on http://{}:{}",
-        ARGS.bind.to_string(),
-        ARGS.port.to_string()
-    );
-
-    match fs::create_dir_all(format!("{}/public", ARGS.data_dir)) {
+    match fs::create_dir_all(format!("{}/public", ARGS.get().data_dir)) {
         Ok(dir) => dir,
         Err(error) => {
             log::error!(
                 "Couldn't create data directory {}/attachments/: {:?}",
-                ARGS.data_dir,
+                ARGS.get().data_dir,
                 error
             );
             panic!(
                 "Couldn't create data directory {}/attachments/: {:?}",
-                ARGS.data_dir, error
+                ARGS.get().data_dir, error
             );
         }
     };
 
+    if let Some(command) = &ARGS.get().command {
+        let result = match command {
+            Command::Export { file } => backup::export_to_file(&Database::open(), file)
+                .map(|manifest| {
+                    format!(
+                        "Exported {} pastas, {} collections and {} attachments to {}",
+                        manifest.pastas, manifest.collections, manifest.attachments, file
+                    )
+                })
+                .map_err(|e| format!("Export to {} failed: {}", file, e)),
+            Command::Import { file } => backup::import_from_file(&Database::open(), file)
+                .map(|manifest| {
+                    format!(
+                        "Imported {} pastas, {} collections and {} attachments from {}",
+                        manifest.pastas, manifest.collections, manifest.attachments, file
+                    )
+                })
+                .map_err(|e| format!("Import from {} failed: {}", file, e)),
+            #[cfg(feature = "default")]
+            Command::MigrateDb { from, to, force } => util::db_migrate::run(*from, *to, *force)
+                .map(|count| format!("Copied {} pastas from {} to {}", count, from, to))
+                .map_err(|e| format!("Migration from {} to {} failed: {}", from, to, e)),
+            #[cfg(not(feature = "default"))]
+            Command::MigrateDb { .. } => Err(String::from(
+                "migrate-db requires a build with SQLite support",
+            )),
+        };
+        match result {
+            Ok(message) => {
+                log::info!("{}", message);
+                return Ok(());
+            }
+            Err(error) => {
+                log::error!("{}", error);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    log::info!(
+        "MicroBin starting on http://{}:{}",
+        ARGS.get().bind.to_string(),
+        ARGS.get().port.to_string()
+    );
+
+    let db = Database::open();
     let data = web::Data::new(AppState {
-        pastas: Mutex::new(read_all()),
+        pastas: Records::new(&db, read_all),
+        collections: Records::new(&db, read_all_collections),
+        setting_changes: Records::new(&db, read_setting_changes),
+        db,
     });
+    blobs::migrate(&data.db, &mut data.pastas.lock().unwrap());
+
+    setting::apply(&data.setting_changes.lock().unwrap());
 
-    if !ARGS.disable_telemetry {
+    if !ARGS.get().disable_telemetry {
         start_telemetry_thread();
     }
 
+    #[cfg(unix)]
+    actix_web::rt::spawn(async {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(hangup) => hangup,
+            Err(e) => {
+                log::error!("Failed to listen for SIGHUP, configuration reload is disabled: {:?}", e);
+                return;
+            }
+        };
+        while hangup.recv().await.is_some() {
+            ARGS.reload();
+        }
+    });
+
+    // write the read counters and views collected in memory, and sweep the
+    // expired pastas of a shared database, every now and then
+    let flush_data = data.clone();
+    actix_web::rt::spawn(async move {
+        let mut interval = actix_web::rt::time::interval(read_stats::FLUSH_INTERVAL);
+        loop {
+            interval.tick().await;
+            flush(&flush_data).await;
+        }
+    });
+
+    let shutdown_data = data.clone();
     HttpServer::new(move || {
         App::new()
             .app_data(data.clone())
             .wrap(middleware::NormalizePath::trim())
             .service(create::index)
+            .service(health::healthz)
+            .service(health::readyz)
             .service(guide::guide)
             .service(auth_admin::auth_admin)
             .service(auth_upload::auth_file_with_status)
@@ -123,6 +238,7 @@ async fn main() -> std::io::Result<()> {
             .service(pasta_endpoint::getshortpasta)
             .service(pasta_endpoint::postshortpasta)
             .service(pasta_endpoint::getrawpasta)
+            .service(pasta_endpoint::getrawpastafile)
             .service(pasta_endpoint::postrawpasta)
             .service(pasta_endpoint::redirecturl)
             .service(pasta_endpoint::shortredirecturl)
@@ -131,27 +247,106 @@ async fn main() -> std::io::Result<()> {
             .service(edit::post_edit)
             .service(edit::post_edit_private)
             .service(edit::post_submit_edit_private)
+            .service(search::search)
+            .service(list::list_tag)
+            .service(feed::atom)
+            .service(feed::rss)
+            .service(metrics_endpoint::get_metrics)
+            .service(collection_endpoint::get_collection)
             .service(admin::get_admin)
             .service(admin::post_admin)
+            .service(admin::admin_logout)
+            .service(admin::post_admin_bulk)
+            .service(admin::get_admin_export)
+            .service(admin::post_admin_delete_collection)
+            .service(admin_settings::get_admin_settings)
+            .service(admin_settings::post_admin_settings)
+            .service(admin_totp::get_admin_totp)
+            .service(admin_totp::post_admin_totp)
+            .service(admin_totp::post_admin_totp_disable)
             .service(static_resources::static_resources)
             .service(qr::getqr)
             .service(file::get_file)
             .service(file::post_secure_file)
             .service(web::resource("/upload").route(web::post().to(create::create)))
             .default_service(web::route().to(errors::not_found))
-            .wrap(middleware::Logger::default())
+            .wrap(
+                middleware::Logger::default()
+                    .exclude("/healthz")
+                    .exclude("/readyz"),
+            )
+            .wrap_fn(|req, srv| {
+                let started = Instant::now();
+                let response = srv.call(req);
+                async move {
+                    let response = response.await?;
+                    if ARGS.get().enable_metrics {
+                        // label by route pattern to keep the number of series bounded
+                        let route = response
+                            .request()
+                            .match_pattern()
+                            .unwrap_or_else(|| String::from("unmatched"));
+                        util::metrics::observe_request(
+                            response.request().method().as_str(),
+                            &route,
+                            started.elapsed(),
+                        );
+                    }
+                    Ok(response)
+                }
+            })
             .service(remove::remove)
             .service(remove::post_remove)
+            .service(views_endpoint::get_views)
+            .service(views_endpoint::post_views)
             .service(list::list)
             .service(create::index_with_status)
             .wrap(Condition::new(
-                ARGS.auth_basic_username.is_some()
-                    && ARGS.auth_basic_username.as_ref().unwrap().trim() != "",
-                HttpAuthentication::basic(util::auth::auth_validator),
+                util::auth::basic_auth_enabled(),
+                HttpAuthentication::with_fn(util::auth::auth_validator),
             ))
     })
-    .bind((ARGS.bind, ARGS.port))?
-    .workers(ARGS.threads as usize)
+    .bind((ARGS.get().bind, ARGS.get().port))?
+    .workers(ARGS.get().threads as usize)
     .run()
-    .await
+    .await?;
+
+    // and the ones collected since the last time, once all requests are done
+    flush(&shutdown_data).await;
+    Ok(())
+}
+
+/// Writes the read counters and views collected in memory, and removes the
+/// expired pastas from a shared database and applies the settings changed on
+/// other instances. The counters and views are copied out while the pastas
+/// are locked and written on the blocking thread pool, so neither requests
+/// nor the async workers wait for the database.
+async fn flush(data: &web::Data<AppState>) {
+    let (counts, pending_views) = {
+        let pastas = data.pastas.lock().unwrap();
+        let mut counts = read_stats::take_pending(&pastas);
+        // the JSON database only appends a line per counter, which is done
+        // under the lock so the journal can be compacted with the pastas
+        if ARGS.get().json_db && !is_shared() {
+            read_stats::flush(&data.db, &std::mem::take(&mut counts), Some(&pastas));
+        }
+        (counts, views::take_pending(&pastas))
+    };
+    let db = data.db.clone();
+    let result = web::block(move || {
+        read_stats::flush(&db, &counts, None);
+        views::flush(&db, &pending_views);
+        if is_shared() {
+            sweep_expired(&db);
+        }
+    })
+    .await;
+    if let Err(e) = result {
+        log::error!("Failed to write the read counters and views: {}", e);
+    }
+
+    if is_shared() {
+        data.setting_changes.reload().await;
+        setting::apply(&data.setting_changes.lock().unwrap());
+    }
 }
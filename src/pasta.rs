@@ -10,10 +10,15 @@ use crate::util::animalnumbers::to_animal_names;
 use crate::util::hashids::to_hashids;
 use crate::util::syntaxhighlighter::html_highlight;
 
-#[derive(Serialize, Deserialize, PartialEq, Debug, Eq)]
+const MAX_TEXT_FILES: usize = 20;
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Eq, Clone)]
 pub struct PastaFile {
     pub name: String,
     pub size: ByteSize,
+    /// SHA-256 hash of the content, set for unencrypted attachments which are
+    /// kept in the content-addressed blob store
+    pub sha256: Option<String>,
 }
 
 impl PastaFile {
@@ -24,6 +29,7 @@ impl PastaFile {
         Ok(Self {
             name,
             size: ByteSize::b(0),
+            sha256: None,
         })
     }
 
@@ -31,6 +37,10 @@ impl PastaFile {
         &self.name
     }
 
+    pub fn sha256(&self) -> &str {
+        self.sha256.as_deref().unwrap_or("")
+    }
+
     pub fn is_image(&self) -> bool {
         let lowercase_name = self.name.to_lowercase();
         let extensions = [
@@ -53,7 +63,79 @@ impl PastaFile {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// One of several named text files of a gist-style pasta.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct TextFile {
+    pub name: String,
+    pub content: String,
+}
+
+impl TextFile {
+    /// Builds the files of a pasta from the repeated `file_name` and
+    /// `file_content` form fields. Files without both name and content are
+    /// dropped, unnamed files get a generic name and duplicate names are made
+    /// unique so every file has its own raw URL.
+    pub fn from_form(names: Vec<String>, contents: Vec<String>) -> Vec<TextFile> {
+        let mut files: Vec<TextFile> = Vec::new();
+
+        for (name, content) in names.into_iter().zip(contents) {
+            if name.trim().is_empty() && content.is_empty() {
+                continue;
+            }
+            if files.len() == MAX_TEXT_FILES {
+                log::warn!("Dropping text files over the limit of {}", MAX_TEXT_FILES);
+                break;
+            }
+
+            // names end up in raw URLs, so keep them to URL-safe characters
+            let mut name: String = sanitize_filename::sanitize(name.trim())
+                .chars()
+                .map(|c| {
+                    if c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == '_' {
+                        c
+                    } else {
+                        '_'
+                    }
+                })
+                .collect();
+            if name.is_empty() {
+                name = format!("file{}.txt", files.len() + 1);
+            }
+            let mut unique_name = name.clone();
+            let mut n = 2;
+            while files.iter().any(|f| f.name == unique_name) {
+                unique_name = format!("{}-{}", n, name);
+                n += 1;
+            }
+
+            files.push(TextFile {
+                name: unique_name,
+                content,
+            });
+        }
+
+        files
+    }
+
+    pub fn extension(&self) -> &str {
+        self.name.rsplit_once('.').map_or("", |(_, ext)| ext)
+    }
+
+    pub fn content_syntax_highlighted(&self) -> String {
+        html_highlight(&self.content, self.extension())
+    }
+
+    pub fn content_not_highlighted(&self) -> String {
+        html_highlight(&self.content, "txt")
+    }
+
+    /// Content escaped for use inside a textarea
+    pub fn content_html_escaped(&self) -> String {
+        html_escape::encode_text(&self.content).to_string()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Pasta {
     pub id: u64,
     pub content: String,
@@ -72,11 +154,19 @@ pub struct Pasta {
     pub read_count: u64,
     pub burn_after_reads: u64,
     pub pasta_type: String,
+    pub tags: Vec<String>,
+    /// Additional named text files, for sharing several files in one upload
+    pub files: Vec<TextFile>,
+    /// Basic auth user that uploaded the pasta, for per-user quotas
+    pub uploader: Option<String>,
+    /// Address the pasta was uploaded from, only kept when per-IP quotas
+    /// are enabled
+    pub uploader_ip: Option<String>,
 }
 
 impl Pasta {
     pub fn id_as_animals(&self) -> String {
-        if ARGS.hash_ids {
+        if ARGS.get().hash_ids {
             to_hashids(self.id)
         } else {
             to_animal_names(self.id)
@@ -87,12 +177,18 @@ impl Pasta {
         self.file.is_some()
     }
 
-    pub fn total_size_as_string(&self) -> String {
-        let total_size_bytes = if self.has_file() {
-            self.file.as_ref().unwrap().size.as_u64() as usize + self.content.as_bytes().len()
+    /// Size of the text content and the attachment together, in bytes
+    pub fn total_size(&self) -> u64 {
+        let text_size = self.content.len() + self.files.iter().map(|f| f.content.len()).sum::<usize>();
+        if self.has_file() {
+            self.file.as_ref().unwrap().size.as_u64() + text_size as u64
         } else {
-            self.content.as_bytes().len()
-        };
+            text_size as u64
+        }
+    }
+
+    pub fn total_size_as_string(&self) -> String {
+        let total_size_bytes = self.total_size();
 
         if total_size_bytes < 1024 {
             format!("{} B", total_size_bytes)
@@ -260,3 +356,32 @@ impl fmt::Display for Pasta {
         write!(f, "{}", self.content)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_text_files_from_form() {
+        let files = TextFile::from_form(
+            vec![
+                String::from("main.rs"),
+                String::from(""),
+                String::from("main.rs"),
+                String::from("../../etc/pass wd"),
+                String::from(""),
+            ],
+            vec![
+                String::from("fn main() {}"),
+                String::from("notes"),
+                String::from("mod a;"),
+                String::from("x"),
+                String::from(""),
+            ],
+        );
+
+        let names: Vec<&str> = files.iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(names, ["main.rs", "file2.txt", "2-main.rs", "....etcpass_wd"]);
+        assert_eq!(files[0].extension(), "rs");
+    }
+}
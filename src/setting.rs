@@ -0,0 +1,110 @@
+use std::collections::BTreeMap;
+
+use chrono::{Datelike, Local, TimeZone, Timelike};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::args::ARGS;
+
+/// A change an admin made to a setting on the settings page. The overrides
+/// in effect are the last changed value of each setting, so the full history
+/// is kept.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SettingChange {
+    pub key: String,
+    /// JSON encoded value, None when the override was removed again and the
+    /// value from the environment applies
+    pub value: Option<String>,
+    pub changed_by: String,
+    pub changed_at: i64,
+}
+
+impl SettingChange {
+    pub fn value_as_str(&self) -> String {
+        match &self.value {
+            Some(value) => display_value(&serde_json::from_str(value).unwrap_or(Value::Null)),
+            None => String::from("(reset)"),
+        }
+    }
+
+    pub fn changed_at_as_string(&self) -> String {
+        Local
+            .timestamp_opt(self.changed_at, 0)
+            .map(|date| {
+                format!(
+                    "{}-{:02}-{:02} {:02}:{:02}",
+                    date.year(),
+                    date.month(),
+                    date.day(),
+                    date.hour(),
+                    date.minute(),
+                )
+            })
+            .earliest()
+            .unwrap_or_default()
+    }
+}
+
+/// The overrides in effect after all the changes, oldest change first.
+pub fn overrides(changes: &[SettingChange]) -> BTreeMap<String, Value> {
+    let mut overrides = BTreeMap::new();
+    for change in changes {
+        match change
+            .value
+            .as_ref()
+            .and_then(|value| serde_json::from_str(value).ok())
+        {
+            Some(value) => overrides.insert(change.key.clone(), value),
+            None => overrides.remove(&change.key),
+        };
+    }
+    overrides
+}
+
+/// Applies the overrides after all the changes, unless they are in effect
+/// already. With a shared database the changes may have been made on the
+/// admin page of another instance.
+pub fn apply(changes: &[SettingChange]) {
+    let overrides = overrides(changes);
+    if overrides == ARGS.overrides() {
+        return;
+    }
+    if let Err(error) = ARGS.set_overrides(overrides) {
+        log::error!("Ignoring the settings changed on the admin page: {}", error);
+    }
+}
+
+/// Setting value as shown on the settings page.
+pub fn display_value(value: &Value) -> String {
+    match value {
+        Value::Null => String::from("unset"),
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn change(key: &str, value: Option<&str>) -> SettingChange {
+        SettingChange {
+            key: String::from(key),
+            value: value.map(String::from),
+            changed_by: String::from("admin"),
+            changed_at: 0,
+        }
+    }
+
+    #[test]
+    fn test_last_change_wins() {
+        let overrides = overrides(&[
+            change("qr", Some("true")),
+            change("title", Some("\"A\"")),
+            change("title", Some("\"B\"")),
+            change("qr", None),
+        ]);
+        assert_eq!(overrides.len(), 1);
+        assert_eq!(overrides["title"], Value::from("B"));
+    }
+}
@@ -1,30 +1,115 @@
 use actix_multipart::Multipart;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use actix_web::cookie::{Cookie, SameSite};
+use actix_web::error::BlockingError;
 use actix_web::dev::ServiceRequest;
-use actix_web::web::Bytes;
-use actix_web::{error, Error};
+use actix_web::http::header::Header;
+use actix_web::http::Method;
+use actix_web::web::{self, Bytes};
+use actix_web::{error, Error, HttpRequest};
 use actix_web_httpauth::extractors::basic::BasicAuth;
+use actix_web_httpauth::extractors::AuthenticationError;
+use actix_web_httpauth::headers::authorization::{Authorization, Basic as BasicCredentials};
+use actix_web_httpauth::headers::www_authenticate::basic::Basic;
 use futures::TryStreamExt;
+use lazy_static::lazy_static;
+use rand::distributions::{Alphanumeric, DistString};
+use sha2::{Digest, Sha256};
 
 use crate::args::ARGS;
+use crate::util::db::{self, Database};
+use crate::util::htpasswd::{self, Permission};
+use crate::util::metrics;
+
+/// Paths probed by orchestrators, which are reachable without basic auth.
+const PROBE_PATHS: [&str; 2] = ["/healthz", "/readyz"];
 
 pub async fn auth_validator(
     req: ServiceRequest,
-    creds: BasicAuth,
+    creds: Option<BasicAuth>,
 ) -> Result<ServiceRequest, (Error, ServiceRequest)> {
-    match (
-        ARGS.auth_basic_username.as_ref(),
-        ARGS.auth_basic_password.as_ref(),
+    if PROBE_PATHS.contains(&req.path().trim_end_matches('/')) {
+        return Ok(req);
+    }
+
+    let Some(creds) = creds else {
+        return Err((AuthenticationError::new(Basic::default()).into(), req));
+    };
+
+    if let (Some(conf_user), Some(conf_pwd), Some(cred_pwd)) = (
+        ARGS.get().auth_basic_username.as_ref(),
+        ARGS.get().auth_basic_password.as_ref(),
         creds.password(),
     ) {
-        (Some(conf_user), Some(conf_pwd), Some(cred_pwd))
-            if creds.user_id() == conf_user && conf_pwd == cred_pwd =>
-        {
-            Ok(req)
+        if creds.user_id() == conf_user && conf_pwd == cred_pwd {
+            return Ok(req);
+        }
+    }
+
+    if !htpasswd::enabled() {
+        metrics::failed_auth("basic");
+        return Err((error::ErrorBadRequest("Invalid login details."), req));
+    }
+
+    let username = creds.user_id().to_string();
+    let password = creds.password().unwrap_or_default().to_string();
+    let permission = web::block(move || htpasswd::verify(&username, &password))
+        .await
+        .ok()
+        .flatten();
+
+    match permission {
+        Some(Permission::Upload) => Ok(req),
+        Some(Permission::Read) if !is_write_request(&req) => Ok(req),
+        Some(Permission::Read) => Err((
+            error::ErrorForbidden("This account is not allowed to upload or modify uploads."),
+            req,
+        )),
+        None => {
+            metrics::failed_auth("basic");
+            Err((error::ErrorBadRequest("Invalid login details."), req))
         }
-        _ => Err((error::ErrorBadRequest("Invalid login details."), req)),
     }
 }
 
+pub fn basic_auth_enabled() -> bool {
+    (ARGS.get().auth_basic_username.is_some()
+        && ARGS.get().auth_basic_username.as_ref().unwrap().trim() != "")
+        || htpasswd::enabled()
+}
+
+/// The basic auth user that made the request. None when basic auth is
+/// disabled, as the name would not have been checked.
+pub fn basic_auth_user(req: &HttpRequest) -> Option<String> {
+    if !basic_auth_enabled() {
+        return None;
+    }
+    Authorization::<BasicCredentials>::parse(req)
+        .ok()
+        .map(|auth| auth.as_ref().user_id().to_string())
+}
+
+/// Requests that create, change or remove pastas, which read-only basic auth
+/// users are not allowed to make.
+fn is_write_request(req: &ServiceRequest) -> bool {
+    let path = req.path().trim_end_matches('/');
+    let endpoint = path.trim_start_matches('/').split('/').next().unwrap_or("");
+
+    (req.method() == Method::POST && path == "/upload")
+        || matches!(
+            endpoint,
+            "edit"
+                | "edit_private"
+                | "submit_edit_private"
+                | "auth_edit_private"
+                | "remove"
+                | "auth_remove_private"
+        )
+}
+
 pub async fn password_from_multipart(mut payload: Multipart) -> Result<String, Error> {
     let mut password = String::new();
 
@@ -36,3 +121,95 @@ pub async fn password_from_multipart(mut payload: Multipart) -> Result<String, E
     }
     Ok(password)
 }
+
+const ADMIN_SESSION_COOKIE: &str = "microbin_admin_session";
+const ADMIN_SESSION_SECONDS: i64 = 60 * 60;
+
+lazy_static! {
+    // session token -> expiry timestamp, unless the database is shared
+    static ref ADMIN_SESSIONS: Mutex<HashMap<String, i64>> = Mutex::new(HashMap::new());
+}
+
+fn timenow() -> i64 {
+    let timenow: i64 = match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(n) => n.as_secs(),
+        Err(_) => {
+            log::error!("SystemTime before UNIX EPOCH!");
+            0
+        }
+    } as i64;
+    timenow
+}
+
+/// The session tokens are only stored as a hash in the database.
+fn token_sha256(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+/// Starts a new admin session after a successful login and returns the
+/// cookie identifying it. The sessions are kept in memory, or in a shared
+/// database so that all instances using it know them.
+pub async fn start_admin_session(db: &Database) -> Result<Cookie<'static>, BlockingError> {
+    let token = Alphanumeric.sample_string(&mut rand::thread_rng(), 32);
+    let now = timenow();
+
+    if db::is_shared() {
+        let db = db.clone();
+        let token_sha256 = token_sha256(&token);
+        web::block(move || {
+            db::insert_admin_session(&db, &token_sha256, now + ADMIN_SESSION_SECONDS, now)
+        })
+        .await?;
+    } else {
+        let mut sessions = ADMIN_SESSIONS.lock().unwrap();
+        sessions.retain(|_, expiry| *expiry > now);
+        sessions.insert(token.clone(), now + ADMIN_SESSION_SECONDS);
+    }
+
+    Ok(Cookie::build(ADMIN_SESSION_COOKIE, token)
+        .path("/")
+        .http_only(true)
+        .same_site(SameSite::Strict)
+        .max_age(actix_web::cookie::time::Duration::seconds(
+            ADMIN_SESSION_SECONDS,
+        ))
+        .finish())
+}
+
+pub async fn is_admin(req: &HttpRequest, db: &Database) -> bool {
+    let Some(cookie) = req.cookie(ADMIN_SESSION_COOKIE) else {
+        return false;
+    };
+
+    if db::is_shared() {
+        let db = db.clone();
+        let token_sha256 = token_sha256(cookie.value());
+        let now = timenow();
+        return web::block(move || db::admin_session_valid(&db, &token_sha256, now))
+            .await
+            .unwrap_or(false);
+    }
+
+    let sessions = ADMIN_SESSIONS.lock().unwrap();
+    matches!(sessions.get(cookie.value()), Some(expiry) if *expiry > timenow())
+}
+
+/// Ends the admin session of the request, returns a cookie that removes it
+/// from the browser.
+pub async fn end_admin_session(req: &HttpRequest, db: &Database) -> Cookie<'static> {
+    if let Some(cookie) = req.cookie(ADMIN_SESSION_COOKIE) {
+        if db::is_shared() {
+            let db = db.clone();
+            let token_sha256 = token_sha256(cookie.value());
+            if let Err(e) = web::block(move || db::delete_admin_session(&db, &token_sha256)).await {
+                log::error!("Failed to end the admin session: {}", e);
+            }
+        } else {
+            ADMIN_SESSIONS.lock().unwrap().remove(cookie.value());
+        }
+    }
+
+    let mut removal = Cookie::build(ADMIN_SESSION_COOKIE, "").path("/").finish();
+    removal.make_removal();
+    removal
+}
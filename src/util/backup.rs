@@ -0,0 +1,334 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Component, Path};
+
+use chrono::Utc;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::collection::Collection;
+use crate::setting::SettingChange;
+use crate::util::db::{self, Database};
+use crate::util::store;
+use crate::util::totp::AdminTotp;
+use crate::util::version::CURRENT_VERSION;
+use crate::Pasta;
+
+/// Layout version of the archive, increased on incompatible changes.
+const FORMAT: u32 = 1;
+
+const MANIFEST: &str = "manifest.json";
+const PASTAS: &str = "pastas.json";
+const COLLECTIONS: &str = "collections.json";
+const SETTING_CHANGES: &str = "setting_changes.json";
+const ADMIN_TOTP: &str = "admin_totp.json";
+
+/// Directory in the data directory where attachments are unpacked before
+/// they are put into the store.
+const STAGING: &str = "import";
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct Checksum {
+    pub size: u64,
+    pub sha256: String,
+}
+
+/// Written last into the archive. Lists every other entry of the archive with
+/// its size and hash, so a restore can tell a complete archive from a
+/// truncated or altered one.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Manifest {
+    pub format: u32,
+    pub version: String,
+    pub created: i64,
+    pub pastas: usize,
+    pub collections: usize,
+    pub attachments: usize,
+    pub files: BTreeMap<String, Checksum>,
+}
+
+/// Hashes everything read through it.
+struct HashingReader<R> {
+    inner: R,
+    hasher: Sha256,
+    size: u64,
+}
+
+impl<R: Read> HashingReader<R> {
+    fn new(inner: R) -> HashingReader<R> {
+        HashingReader {
+            inner,
+            hasher: Sha256::new(),
+            size: 0,
+        }
+    }
+
+    fn checksum(self) -> Checksum {
+        Checksum {
+            size: self.size,
+            sha256: format!("{:x}", self.hasher.finalize()),
+        }
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        self.size += read as u64;
+        Ok(read)
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn append(
+    archive: &mut tar::Builder<impl Write>,
+    files: &mut BTreeMap<String, Checksum>,
+    path: &str,
+    size: u64,
+    data: impl Read,
+) -> io::Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(size);
+    header.set_mode(0o644);
+    header.set_mtime(Utc::now().timestamp() as u64);
+
+    let mut reader = HashingReader::new(data);
+    archive.append_data(&mut header, path, &mut reader)?;
+    files.insert(path.to_string(), reader.checksum());
+    Ok(())
+}
+
+fn append_json(
+    archive: &mut tar::Builder<impl Write>,
+    files: &mut BTreeMap<String, Checksum>,
+    path: &str,
+    value: &impl Serialize,
+) -> io::Result<()> {
+    let data = serde_json::to_vec_pretty(value)?;
+    append(archive, files, path, data.len() as u64, data.as_slice())
+}
+
+/// Writes the database records and every attachment they reference into a
+/// gzipped tar archive. The records are a snapshot taken by the caller; an
+/// attachment removed along with its pasta after that is left out, importing
+/// restores the pasta without it.
+pub fn export(
+    db: &Database,
+    writer: impl Write,
+    pastas: &[Pasta],
+    collections: &[Collection],
+    setting_changes: &[SettingChange],
+    admin_totp: Option<&AdminTotp>,
+) -> io::Result<Manifest> {
+    let mut archive = tar::Builder::new(GzEncoder::new(writer, Compression::default()));
+    let mut files = BTreeMap::new();
+
+    append_json(&mut archive, &mut files, PASTAS, &pastas)?;
+    append_json(&mut archive, &mut files, COLLECTIONS, &collections)?;
+    append_json(&mut archive, &mut files, SETTING_CHANGES, &setting_changes)?;
+    append_json(&mut archive, &mut files, ADMIN_TOTP, &admin_totp)?;
+
+    // pastas with the same content share a blob, which is stored once
+    let keys: BTreeSet<String> = pastas.iter().filter_map(store::attachment_key).collect();
+    for key in &keys {
+        let file = match store::open(db, key) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                log::warn!("Attachment {} was removed during the export, skipping it", key);
+                continue;
+            }
+            Err(e) => {
+                return Err(io::Error::new(e.kind(), format!("attachment {}: {}", key, e)))
+            }
+        };
+        let size = file.metadata()?.len();
+        append(&mut archive, &mut files, key, size, file)?;
+    }
+
+    let manifest = Manifest {
+        format: FORMAT,
+        version: CURRENT_VERSION.title.to_string(),
+        created: Utc::now().timestamp(),
+        pastas: pastas.len(),
+        collections: collections.len(),
+        attachments: keys.len(),
+        files,
+    };
+    let data = serde_json::to_vec_pretty(&manifest)?;
+    append(&mut archive, &mut BTreeMap::new(), MANIFEST, data.len() as u64, data.as_slice())?;
+
+    archive.into_inner()?.finish()?;
+    Ok(manifest)
+}
+
+/// Attachments are only ever unpacked under the directories the store uses,
+/// so an archive can not write anywhere else in the data directory.
+fn is_attachment(path: &str) -> bool {
+    (path.starts_with("blobs/") || path.starts_with("attachments/"))
+        && Path::new(path)
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)))
+}
+
+/// Restores an archive written by `export` into the current database and
+/// attachment store, which must both be empty. Nothing is written to the
+/// database before every entry of the archive matched its checksum.
+pub fn import(db: &Database, reader: impl Read) -> io::Result<Manifest> {
+    if !db::read_all(db).is_empty() || !db::read_all_collections(db).is_empty() {
+        return Err(io::Error::other(
+            "the database is not empty, backups can only be restored into an empty data directory",
+        ));
+    }
+
+    let staging = store::local_path(STAGING);
+    if staging.exists() {
+        fs::remove_dir_all(&staging)?;
+    }
+
+    let result = unpack(reader, &staging).and_then(|(manifest, records)| {
+        for key in manifest.files.keys().filter(|path| is_attachment(path)) {
+            store::put(db, key, &staging.join(key))?;
+        }
+        restore(db, records)?;
+        Ok(manifest)
+    });
+    let _ = fs::remove_dir_all(&staging);
+    result
+}
+
+fn unpack(reader: impl Read, staging: &Path) -> io::Result<(Manifest, BTreeMap<String, Vec<u8>>)> {
+    let mut archive = tar::Archive::new(GzDecoder::new(reader));
+    let mut files = BTreeMap::new();
+    let mut records = BTreeMap::new();
+    let mut manifest: Option<Manifest> = None;
+
+    for entry in archive.entries()? {
+        let entry = entry?;
+        let path = entry.path()?.to_string_lossy().to_string();
+
+        if path == MANIFEST {
+            manifest = Some(serde_json::from_reader(entry)?);
+        } else if [PASTAS, COLLECTIONS, SETTING_CHANGES, ADMIN_TOTP].contains(&path.as_str()) {
+            let mut reader = HashingReader::new(entry);
+            let mut data = Vec::new();
+            reader.read_to_end(&mut data)?;
+            files.insert(path.clone(), reader.checksum());
+            records.insert(path, data);
+        } else if is_attachment(&path) {
+            let target = staging.join(&path);
+            fs::create_dir_all(target.parent().unwrap())?;
+            let mut reader = HashingReader::new(entry);
+            io::copy(&mut reader, &mut File::create(&target)?)?;
+            files.insert(path, reader.checksum());
+        } else {
+            return Err(invalid(format!("unexpected entry {} in the archive", path)));
+        }
+    }
+
+    let manifest = manifest.ok_or_else(|| invalid(String::from("the archive has no manifest")))?;
+    if manifest.format != FORMAT {
+        return Err(invalid(format!(
+            "the archive has format version {}, this version of MicroBin reads {}",
+            manifest.format, FORMAT
+        )));
+    }
+    for (path, checksum) in &manifest.files {
+        match files.get(path) {
+            None => return Err(invalid(format!("{} is missing from the archive", path))),
+            Some(found) if found != checksum => {
+                return Err(invalid(format!("checksum mismatch for {}", path)))
+            }
+            Some(_) => {}
+        }
+    }
+    if let Some(path) = files.keys().find(|path| !manifest.files.contains_key(*path)) {
+        return Err(invalid(format!("{} is not listed in the manifest", path)));
+    }
+
+    Ok((manifest, records))
+}
+
+fn restore(db: &Database, records: BTreeMap<String, Vec<u8>>) -> io::Result<()> {
+    let read = |path: &str| records.get(path).map(Vec::as_slice).unwrap_or(b"null");
+
+    let pastas: Vec<Pasta> = serde_json::from_slice(read(PASTAS))?;
+    let collections: Vec<Collection> =
+        serde_json::from_slice::<Option<_>>(read(COLLECTIONS))?.unwrap_or_default();
+    let setting_changes: Vec<SettingChange> =
+        serde_json::from_slice::<Option<_>>(read(SETTING_CHANGES))?.unwrap_or_default();
+    let admin_totp: Option<AdminTotp> = serde_json::from_slice(read(ADMIN_TOTP))?;
+
+    db::insert_all(db, &pastas);
+    for collection in &collections {
+        db::insert_collection(db, Some(&collections), Some(collection));
+    }
+    for change in &setting_changes {
+        db::insert_setting_change(db, Some(&setting_changes), Some(change));
+    }
+    if admin_totp.is_some() {
+        db::update_admin_totp(db, admin_totp.as_ref());
+    }
+    Ok(())
+}
+
+/// Runs `microbin export`, reading everything from the database.
+pub fn export_to_file(db: &Database, path: &str) -> io::Result<Manifest> {
+    let file = File::create(path)?;
+    export(
+        db,
+        file,
+        &db::read_all(db),
+        &db::read_all_collections(db),
+        &db::read_setting_changes(db),
+        db::read_admin_totp(db).as_ref(),
+    )
+}
+
+/// Runs `microbin import`.
+pub fn import_from_file(db: &Database, path: &str) -> io::Result<Manifest> {
+    import(db, File::open(path)?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_export_unpack() {
+        let mut archive = Vec::new();
+        let manifest = export(&Database::default(), &mut archive, &[], &[], &[], None).unwrap();
+        assert_eq!(manifest.files.len(), 4);
+
+        let staging = std::env::temp_dir().join("microbin-test-backup");
+        let (unpacked, records) = unpack(archive.as_slice(), &staging).unwrap();
+        assert_eq!(unpacked.files, manifest.files);
+        assert_eq!(records[PASTAS], b"[]");
+
+        // a flipped byte in the middle of the archive fails the restore
+        let mut damaged = Vec::new();
+        let mut tar = tar::Builder::new(GzEncoder::new(&mut damaged, Compression::default()));
+        let mut files = BTreeMap::new();
+        append(&mut tar, &mut files, PASTAS, 2, &b"[]"[..]).unwrap();
+        files.get_mut(PASTAS).unwrap().sha256 = String::from("0");
+        let data = serde_json::to_vec(&Manifest { files, ..manifest }).unwrap();
+        append(&mut tar, &mut BTreeMap::new(), MANIFEST, data.len() as u64, data.as_slice()).unwrap();
+        tar.into_inner().unwrap().finish().unwrap();
+        assert!(unpack(damaged.as_slice(), &staging).is_err());
+    }
+
+    #[test]
+    fn test_is_attachment() {
+        assert!(is_attachment("blobs/2c/2cf24dba"));
+        assert!(is_attachment("attachments/cat-dog/data.enc"));
+        assert!(!is_attachment("blobs/../../etc/passwd"));
+        assert!(!is_attachment("microbin.toml"));
+    }
+}
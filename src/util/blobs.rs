@@ -0,0 +1,121 @@
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::Path;
+
+use sha2::{Digest, Sha256};
+
+use crate::util::db::{self, update, Database};
+use crate::util::store;
+use crate::Pasta;
+
+/// Unencrypted attachments are stored once per distinct content under
+/// `blobs/<first two digits of the hash>/<SHA-256 hash>`. The pastas in memory
+/// are the reference counts: a blob is removed when the last pasta pointing
+/// to its hash goes away. A shared database is asked as well, as pastas of
+/// other instances may not be in memory yet.
+pub fn blob_key(sha256: &str) -> String {
+    format!("blobs/{}/{}", &sha256[..2], sha256)
+}
+
+/// Moves a freshly uploaded file into the blob store. If an identical blob is
+/// already stored the upload is dropped instead.
+pub fn store(db: &Database, path: &Path, sha256: &str) -> io::Result<()> {
+    let key = blob_key(sha256);
+    if store::exists(db, &key)? {
+        fs::remove_file(path)?;
+    } else {
+        store::put(db, &key, path)?;
+    }
+
+    // the upload directory only ever holds this one file
+    if let Some(directory) = path.parent() {
+        let _ = fs::remove_dir(directory);
+    }
+    Ok(())
+}
+
+/// Number of pastas, other than the one with the given id, that reference
+/// the blob.
+pub fn references(pastas: &[Pasta], sha256: &str, except_id: u64) -> usize {
+    pastas
+        .iter()
+        .filter(|p| p.id != except_id)
+        .filter_map(|p| p.file.as_ref())
+        .filter(|file| file.sha256.as_deref() == Some(sha256))
+        .count()
+}
+
+/// Key of the blob of the pasta if no other pasta references it anymore, so
+/// it is to be removed along with the pasta.
+pub fn release(db: &Database, pasta: &Pasta, pastas: &[Pasta]) -> Option<String> {
+    let sha256 = pasta.file.as_ref()?.sha256.as_deref()?;
+
+    if references(pastas, sha256, pasta.id) > 0 || db::blob_referenced(db, sha256, pasta.id) {
+        return None;
+    }
+    Some(blob_key(sha256))
+}
+
+pub fn hash_file(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = [0; 64 * 1024];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Moves the unencrypted attachments uploaded before the blob store existed
+/// into it, so they are deduplicated as well.
+pub fn migrate(db: &Database, pastas: &mut Vec<Pasta>) {
+    for i in 0..pastas.len() {
+        let pasta = &pastas[i];
+        let Some(file) = &pasta.file else {
+            continue;
+        };
+        if file.sha256.is_some() || pasta.encrypt_server {
+            continue;
+        }
+
+        let path = store::local_path(&format!(
+            "attachments/{}/{}",
+            pasta.id_as_animals(),
+            file.name()
+        ));
+        let sha256 = match hash_file(&path).and_then(|sha256| store(db, &path, &sha256).map(|_| sha256)) {
+            Ok(sha256) => sha256,
+            Err(e) => {
+                log::error!("Failed to move {} into the blob store: {:?}", path.display(), e);
+                continue;
+            }
+        };
+
+        pastas[i].file.as_mut().unwrap().sha256 = Some(sha256);
+        update(db, Some(&*pastas), Some(&pastas[i]));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+
+    use tempfile::NamedTempFile;
+
+    use super::*;
+
+    #[test]
+    fn test_hash_file() {
+        let mut tmpfile = NamedTempFile::new().unwrap();
+        tmpfile.write_all(b"hello").unwrap();
+
+        assert_eq!(
+            hash_file(tmpfile.path()).unwrap(),
+            "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+        );
+    }
+}
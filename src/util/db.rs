@@ -1,57 +1,584 @@
-use crate::{args::ARGS, pasta::Pasta};
+use std::future::Future;
+use std::sync::{LockResult, Mutex, MutexGuard};
+
+use actix_web::error::BlockingError;
+use actix_web::web;
+use futures::future::{ready, Either};
+
+use crate::{
+    args::ARGS,
+    collection::Collection,
+    pasta::Pasta,
+    setting::SettingChange,
+    util::read_stats::ReadCount,
+    util::search::{self, SearchHit},
+    util::totp::AdminTotp,
+    view::View,
+};
+
+/// Whether the database may be shared with other instances, in which case
+/// the records in memory are not authoritative.
+pub fn is_shared() -> bool {
+    ARGS.get().database_url.is_some()
+}
+
+/// Connections to the database. It is opened once in `main` and kept in the
+/// application state, which passes it to everything that reads or writes the
+/// database. The JSON database keeps no connections.
+#[derive(Clone, Default)]
+pub struct Database {
+    #[cfg(feature = "default")]
+    sqlite: Option<super::db_sqlite::Pool>,
+    #[cfg(feature = "postgres")]
+    postgres: Option<super::db_postgres::Pool>,
+}
+
+impl Database {
+    /// Opens the configured database and brings its schema up to date.
+    /// Exits if the database can not be used.
+    pub fn open() -> Database {
+        #[cfg(feature = "postgres")]
+        if ARGS.get().database_url.is_some() {
+            return Database {
+                postgres: Some(super::db_postgres::open().unwrap_or_else(|e| {
+                    log::error!("Can not use the PostgreSQL database: {}", e);
+                    std::process::exit(1);
+                })),
+                #[cfg(feature = "default")]
+                sqlite: None,
+            };
+        }
+        if ARGS.get().json_db {
+            return Database::default();
+        }
+
+        #[cfg(feature = "default")]
+        return Database {
+            sqlite: Some(super::db_sqlite::open().unwrap_or_else(|e| {
+                log::error!("Can not use the SQLite database: {}", e);
+                std::process::exit(1);
+            })),
+            #[cfg(feature = "postgres")]
+            postgres: None,
+        };
+        #[cfg(not(feature = "default"))]
+        panic!("{}", PANIC_MSG);
+    }
+
+    #[cfg(feature = "default")]
+    pub fn sqlite(&self) -> &super::db_sqlite::Pool {
+        self.sqlite.as_ref().expect("The SQLite database is not open")
+    }
+
+    #[cfg(feature = "postgres")]
+    pub fn postgres(&self) -> &super::db_postgres::Pool {
+        self.postgres.as_ref().expect("The PostgreSQL database is not open")
+    }
+}
+
+/// Records kept in memory between requests. With a shared database other
+/// instances may have changed them since, so requests `reload` them before
+/// they lock them, or `reload_id` the single pasta they are about.
+pub struct Records<T> {
+    records: Mutex<Vec<T>>,
+    db: Database,
+    read_all: fn(&Database) -> Vec<T>,
+}
+
+impl<T: Send + 'static> Records<T> {
+    pub fn new(db: &Database, read_all: fn(&Database) -> Vec<T>) -> Records<T> {
+        Records {
+            records: Mutex::new(read_all(db)),
+            db: db.clone(),
+            read_all,
+        }
+    }
+
+    pub fn lock(&self) -> LockResult<MutexGuard<'_, Vec<T>>> {
+        self.records.lock()
+    }
+
+    /// Reloads the records from a shared database. The query runs on the
+    /// blocking thread pool before the records are locked, so the caller must
+    /// not hold the lock on any records while waiting for it.
+    pub async fn reload(&self) {
+        if !is_shared() {
+            return;
+        }
+        let db = self.db.clone();
+        let read_all = self.read_all;
+        match web::block(move || read_all(&db)).await {
+            Ok(records) => *self.records.lock().unwrap() = records,
+            Err(e) => log::error!("Failed to reload the records from the database: {}", e),
+        }
+    }
+}
+
+impl Records<Pasta> {
+    /// Reloads only the pasta with the given id from a shared database, for
+    /// requests about that pasta, see `reload`.
+    pub async fn reload_id(&self, id: u64) {
+        if !is_shared() {
+            return;
+        }
+        let db = self.db.clone();
+        let pasta = match web::block(move || read_one(&db, id)).await {
+            Ok(pasta) => pasta,
+            Err(e) => {
+                log::error!("Failed to reload the pasta from the database: {}", e);
+                return;
+            }
+        };
+
+        let mut pastas = self.records.lock().unwrap();
+        let index = pastas.iter().position(|pasta| pasta.id == id);
+        match (index, pasta) {
+            (Some(index), Some(pasta)) => pastas[index] = pasta,
+            (Some(index), None) => {
+                pastas.remove(index);
+            }
+            (None, Some(pasta)) => pastas.push(pasta),
+            (None, None) => {}
+        }
+    }
+}
+
+/// Makes a write of a request, given the lock on the records it changed. A
+/// shared database does not need the records, so the lock is released first
+/// and the write is made on the blocking thread pool by the returned future.
+/// The other databases are written right away, under the lock, as the JSON
+/// database writes the records along.
+pub fn write<T>(
+    db: &Database,
+    records: MutexGuard<'_, Vec<T>>,
+    write: impl FnOnce(&Database, Option<&Vec<T>>) + Send + 'static,
+) -> impl Future<Output = Result<(), BlockingError>> {
+    if !is_shared() {
+        write(db, Some(&records));
+        return Either::Left(ready(Ok(())));
+    }
+    drop(records);
+    let db = db.clone();
+    Either::Right(web::block(move || write(&db, None)))
+}
 
 #[cfg(not(feature = "default"))]
 const PANIC_MSG: &'static str = "Can not run without argument json-db, this version of microbin was compiled without rusqlite support. Make sure you do not pass in no-default-features during compilation";
 
 #[cfg(feature = "default")]
-pub fn read_all() -> Vec<Pasta> {
-    if ARGS.json_db {
+pub fn read_all(db: &Database) -> Vec<Pasta> {
+    #[cfg(feature = "postgres")]
+    if ARGS.get().database_url.is_some() {
+        return super::db_postgres::read_all(db.postgres());
+    }
+    if ARGS.get().json_db {
         super::db_json::read_all()
     } else {
-        super::db_sqlite::read_all()
+        super::db_sqlite::read_all(db.sqlite())
     }
 }
 
 #[cfg(not(feature = "default"))]
-pub fn read_all() -> Vec<Pasta> {
-    if ARGS.json_db {
+#[allow(unused)]
+pub fn read_all(db: &Database) -> Vec<Pasta> {
+    #[cfg(feature = "postgres")]
+    if ARGS.get().database_url.is_some() {
+        return super::db_postgres::read_all(db.postgres());
+    }
+    if ARGS.get().json_db {
         super::db_json::read_all()
     } else {
         panic!("{}", PANIC_MSG);
     }
 }
 
+/// The pasta with the given id.
 #[allow(unused)]
-pub fn insert(pastas: Option<&Vec<Pasta>>, pasta: Option<&Pasta>) {
-    if ARGS.json_db {
-        super::db_json::update_all(pastas.expect("Called insert() without passing Pasta vector"));
+pub fn read_one(db: &Database, id: u64) -> Option<Pasta> {
+    #[cfg(feature = "postgres")]
+    if ARGS.get().database_url.is_some() {
+        return super::db_postgres::read_one(db.postgres(), id);
+    }
+    read_all(db).into_iter().find(|pasta| pasta.id == id)
+}
+
+#[allow(unused)]
+pub fn insert(db: &Database, pastas: Option<&Vec<Pasta>>, pasta: Option<&Pasta>) {
+    #[cfg(feature = "postgres")]
+    if ARGS.get().database_url.is_some() {
+        return super::db_postgres::insert(db.postgres(), pasta.expect("Called insert() without passing new Pasta"));
+    }
+    if ARGS.get().json_db {
+        super::db_json::upsert(pasta.expect("Called insert() without passing new Pasta"), pastas);
     } else {
         #[cfg(feature = "default")]
-        super::db_sqlite::insert(pasta.expect("Called insert() without passing new Pasta"));
+        super::db_sqlite::insert(db.sqlite(), pasta.expect("Called insert() without passing new Pasta"));
         #[cfg(not(feature = "default"))]
         panic!();
     }
 }
 
+/// Inserts the pastas of a restored backup, writing the JSON database once.
+pub fn insert_all(db: &Database, pastas: &Vec<Pasta>) {
+    if ARGS.get().json_db {
+        super::db_json::update_all(pastas);
+    } else {
+        for pasta in pastas {
+            insert(db, None, Some(pasta));
+        }
+    }
+}
+
+#[allow(unused)]
+pub fn update(db: &Database, pastas: Option<&Vec<Pasta>>, pasta: Option<&Pasta>) {
+    #[cfg(feature = "postgres")]
+    if ARGS.get().database_url.is_some() {
+        return super::db_postgres::update(db.postgres(), pasta.expect("Called update() without passing Pasta to update"));
+    }
+    if ARGS.get().json_db {
+        super::db_json::upsert(pasta.expect("Called update() without passing Pasta to update"), pastas);
+    } else {
+        #[cfg(feature = "default")]
+        super::db_sqlite::update(db.sqlite(), pasta.expect("Called insert() without passing Pasta to update"));
+        #[cfg(not(feature = "default"))]
+        panic!("{}", PANIC_MSG);
+    }
+}
+
+/// Counts a read of the pasta in a shared database right away. Returns the
+/// new read count, or None if the pasta is gone or burned.
+#[allow(unused)]
+pub fn record_read(db: &Database, id: u64, timestamp: i64) -> Option<u64> {
+    #[cfg(feature = "postgres")]
+    if ARGS.get().database_url.is_some() {
+        return super::db_postgres::record_read(db.postgres(), id, timestamp);
+    }
+    unreachable!("Only a shared database counts reads right away")
+}
+
+/// Stores an admin session in a shared database, so that the other instances
+/// know it too. The other databases leave the sessions in memory.
+#[allow(unused)]
+pub fn insert_admin_session(db: &Database, token_sha256: &str, expires: i64, timenow: i64) {
+    #[cfg(feature = "postgres")]
+    if ARGS.get().database_url.is_some() {
+        return super::db_postgres::insert_admin_session(db.postgres(), token_sha256, expires, timenow);
+    }
+    unreachable!("Only a shared database stores admin sessions")
+}
+
+#[allow(unused)]
+pub fn admin_session_valid(db: &Database, token_sha256: &str, timenow: i64) -> bool {
+    #[cfg(feature = "postgres")]
+    if ARGS.get().database_url.is_some() {
+        return super::db_postgres::admin_session_valid(db.postgres(), token_sha256, timenow);
+    }
+    unreachable!("Only a shared database stores admin sessions")
+}
+
+#[allow(unused)]
+pub fn delete_admin_session(db: &Database, token_sha256: &str) {
+    #[cfg(feature = "postgres")]
+    if ARGS.get().database_url.is_some() {
+        return super::db_postgres::delete_admin_session(db.postgres(), token_sha256);
+    }
+    unreachable!("Only a shared database stores admin sessions")
+}
+
+#[allow(unused)]
+pub fn delete(db: &Database, pastas: Option<&Vec<Pasta>>, id: Option<u64>) {
+    #[cfg(feature = "postgres")]
+    if ARGS.get().database_url.is_some() {
+        return super::db_postgres::delete_by_id(db.postgres(), id.expect("Called delete() without passing Pasta id"));
+    }
+    if ARGS.get().json_db {
+        super::db_json::delete(id.expect("Called delete() without passing Pasta id"), pastas);
+    } else {
+        #[cfg(feature = "default")]
+        super::db_sqlite::delete_by_id(db.sqlite(), id.expect("Called delete() without passing Pasta id"));
+        #[cfg(not(feature = "default"))]
+        panic!("{}", PANIC_MSG);
+    }
+}
+
+/// Deletes a pasta found expired in memory. A shared database only deletes
+/// it if it is still expired there, as another instance may have removed or
+/// changed it in the meantime. Returns whether it was deleted.
+#[allow(unused)]
+pub fn delete_expired(db: &Database, id: u64, timenow: i64, gc_days: u16) -> bool {
+    #[cfg(feature = "postgres")]
+    if ARGS.get().database_url.is_some() {
+        return super::db_postgres::delete_expired(db.postgres(), id, timenow, gc_days);
+    }
+    delete(db, None, Some(id));
+    true
+}
+
+/// Whether a pasta other than `except_id` that is not in memory references
+/// the blob, which is only possible with a shared database.
+#[allow(unused)]
+pub fn blob_referenced(db: &Database, sha256: &str, except_id: u64) -> bool {
+    #[cfg(feature = "postgres")]
+    if ARGS.get().database_url.is_some() {
+        return super::db_postgres::blob_referenced(db.postgres(), sha256, except_id);
+    }
+    false
+}
+
+#[allow(unused)]
+pub fn read_all_collections(db: &Database) -> Vec<Collection> {
+    #[cfg(feature = "postgres")]
+    if ARGS.get().database_url.is_some() {
+        return super::db_postgres::read_all_collections(db.postgres());
+    }
+    if ARGS.get().json_db {
+        super::db_json::read_all_collections()
+    } else {
+        #[cfg(feature = "default")]
+        return super::db_sqlite::read_all_collections(db.sqlite());
+        #[cfg(not(feature = "default"))]
+        panic!("{}", PANIC_MSG);
+    }
+}
+
+#[allow(unused)]
+pub fn insert_collection(db: &Database, collections: Option<&Vec<Collection>>, collection: Option<&Collection>) {
+    #[cfg(feature = "postgres")]
+    if ARGS.get().database_url.is_some() {
+        return super::db_postgres::insert_collection(
+            db.postgres(),
+            collection.expect("Called insert_collection() without passing new Collection"),
+        );
+    }
+    if ARGS.get().json_db {
+        super::db_json::update_all_collections(
+            collections.expect("Called insert_collection() without passing Collection vector"),
+        );
+    } else {
+        #[cfg(feature = "default")]
+        super::db_sqlite::insert_collection(
+            db.sqlite(),
+            collection.expect("Called insert_collection() without passing new Collection"),
+        );
+        #[cfg(not(feature = "default"))]
+        panic!("{}", PANIC_MSG);
+    }
+}
+
+#[allow(unused)]
+pub fn delete_collection(db: &Database, collections: Option<&Vec<Collection>>, id: Option<u64>) {
+    #[cfg(feature = "postgres")]
+    if ARGS.get().database_url.is_some() {
+        return super::db_postgres::delete_collection_by_id(
+            db.postgres(),
+            id.expect("Called delete_collection() without passing Collection id"),
+        );
+    }
+    if ARGS.get().json_db {
+        super::db_json::update_all_collections(
+            collections.expect("Called delete_collection() without passing Collection vector"),
+        );
+    } else {
+        #[cfg(feature = "default")]
+        super::db_sqlite::delete_collection_by_id(
+            db.sqlite(),
+            id.expect("Called delete_collection() without passing Collection id"),
+        );
+        #[cfg(not(feature = "default"))]
+        panic!("{}", PANIC_MSG);
+    }
+}
+
+#[allow(unused)]
+pub fn read_setting_changes(db: &Database) -> Vec<SettingChange> {
+    #[cfg(feature = "postgres")]
+    if ARGS.get().database_url.is_some() {
+        return super::db_postgres::read_setting_changes(db.postgres());
+    }
+    if ARGS.get().json_db {
+        super::db_json::read_setting_changes()
+    } else {
+        #[cfg(feature = "default")]
+        return super::db_sqlite::read_setting_changes(db.sqlite());
+        #[cfg(not(feature = "default"))]
+        panic!("{}", PANIC_MSG);
+    }
+}
+
+#[allow(unused)]
+pub fn insert_setting_change(db: &Database, changes: Option<&Vec<SettingChange>>, change: Option<&SettingChange>) {
+    #[cfg(feature = "postgres")]
+    if ARGS.get().database_url.is_some() {
+        return super::db_postgres::insert_setting_change(
+            db.postgres(),
+            change.expect("Called insert_setting_change() without passing new SettingChange"),
+        );
+    }
+    if ARGS.get().json_db {
+        super::db_json::update_all_setting_changes(
+            changes.expect("Called insert_setting_change() without passing SettingChange vector"),
+        );
+    } else {
+        #[cfg(feature = "default")]
+        super::db_sqlite::insert_setting_change(
+            db.sqlite(),
+            change.expect("Called insert_setting_change() without passing new SettingChange"),
+        );
+        #[cfg(not(feature = "default"))]
+        panic!("{}", PANIC_MSG);
+    }
+}
+
+/// Raises the read counters and last read times of pastas, leaving the rest
+/// of them alone.
 #[allow(unused)]
-pub fn update(pastas: Option<&Vec<Pasta>>, pasta: Option<&Pasta>) {
-    if ARGS.json_db {
-        super::db_json::update_all(pastas.expect("Called update() without passing Pasta vector"));
+pub fn update_read_counts(db: &Database, counts: &[ReadCount], pastas: Option<&Vec<Pasta>>) {
+    #[cfg(feature = "postgres")]
+    if ARGS.get().database_url.is_some() {
+        return super::db_postgres::update_read_counts(db.postgres(), counts);
+    }
+    if ARGS.get().json_db {
+        super::db_json::update_read_counts(counts, pastas);
     } else {
         #[cfg(feature = "default")]
-        super::db_sqlite::update(pasta.expect("Called insert() without passing Pasta to update"));
+        super::db_sqlite::update_read_counts(db.sqlite(), counts);
         #[cfg(not(feature = "default"))]
         panic!("{}", PANIC_MSG);
     }
 }
 
 #[allow(unused)]
-pub fn delete(pastas: Option<&Vec<Pasta>>, id: Option<u64>) {
-    if ARGS.json_db {
-        super::db_json::update_all(pastas.expect("Called delete() without passing Pasta vector"));
+pub fn insert_views(db: &Database, views: &[View]) {
+    #[cfg(feature = "postgres")]
+    if ARGS.get().database_url.is_some() {
+        return super::db_postgres::insert_views(db.postgres(), views);
+    }
+    if ARGS.get().json_db {
+        super::db_json::insert_views(views);
+    } else {
+        #[cfg(feature = "default")]
+        super::db_sqlite::insert_views(db.sqlite(), views);
+        #[cfg(not(feature = "default"))]
+        panic!("{}", PANIC_MSG);
+    }
+}
+
+/// Views of the pasta, newest first.
+#[allow(unused)]
+pub fn read_views(db: &Database, pasta_id: u64) -> Vec<View> {
+    #[cfg(feature = "postgres")]
+    if ARGS.get().database_url.is_some() {
+        return super::db_postgres::read_views(db.postgres(), pasta_id);
+    }
+    if ARGS.get().json_db {
+        super::db_json::read_views(pasta_id)
+    } else {
+        #[cfg(feature = "default")]
+        return super::db_sqlite::read_views(db.sqlite(), pasta_id);
+        #[cfg(not(feature = "default"))]
+        panic!("{}", PANIC_MSG);
+    }
+}
+
+#[allow(unused)]
+pub fn delete_views_before(db: &Database, timestamp: i64) {
+    #[cfg(feature = "postgres")]
+    if ARGS.get().database_url.is_some() {
+        return super::db_postgres::delete_views_before(db.postgres(), timestamp);
+    }
+    if ARGS.get().json_db {
+        super::db_json::delete_views_before(timestamp);
+    } else {
+        #[cfg(feature = "default")]
+        super::db_sqlite::delete_views_before(db.sqlite(), timestamp);
+        #[cfg(not(feature = "default"))]
+        panic!("{}", PANIC_MSG);
+    }
+}
+
+#[allow(unused)]
+pub fn read_admin_totp(db: &Database) -> Option<AdminTotp> {
+    #[cfg(feature = "postgres")]
+    if ARGS.get().database_url.is_some() {
+        return super::db_postgres::read_admin_totp(db.postgres());
+    }
+    if ARGS.get().json_db {
+        super::db_json::read_admin_totp()
+    } else {
+        #[cfg(feature = "default")]
+        return super::db_sqlite::read_admin_totp(db.sqlite());
+        #[cfg(not(feature = "default"))]
+        panic!("{}", PANIC_MSG);
+    }
+}
+
+#[allow(unused)]
+pub fn update_admin_totp(db: &Database, admin_totp: Option<&AdminTotp>) {
+    #[cfg(feature = "postgres")]
+    if ARGS.get().database_url.is_some() {
+        return super::db_postgres::update_admin_totp(db.postgres(), admin_totp);
+    }
+    if ARGS.get().json_db {
+        super::db_json::update_admin_totp(admin_totp);
+    } else {
+        #[cfg(feature = "default")]
+        super::db_sqlite::update_admin_totp(db.sqlite(), admin_totp);
+        #[cfg(not(feature = "default"))]
+        panic!("{}", PANIC_MSG);
+    }
+}
+
+/// Reads the admin TOTP settings and writes them back if `modify` returns
+/// true, without other logins getting in between. Returns false if TOTP is
+/// not set up.
+#[allow(unused)]
+pub fn modify_admin_totp(db: &Database, modify: impl FnOnce(&mut AdminTotp) -> bool) -> bool {
+    #[cfg(feature = "postgres")]
+    if ARGS.get().database_url.is_some() {
+        return super::db_postgres::modify_admin_totp(db.postgres(), modify);
+    }
+    if ARGS.get().json_db {
+        super::db_json::modify_admin_totp(modify)
+    } else {
+        #[cfg(feature = "default")]
+        return super::db_sqlite::modify_admin_totp(db.sqlite(), modify);
+        #[cfg(not(feature = "default"))]
+        panic!("{}", PANIC_MSG);
+    }
+}
+
+/// Checks that the database can be read, for the readiness probe.
+#[allow(unused)]
+pub fn check(db: &Database) -> Result<(), String> {
+    #[cfg(feature = "postgres")]
+    if ARGS.get().database_url.is_some() {
+        return super::db_postgres::check(db.postgres());
+    }
+    if ARGS.get().json_db {
+        super::db_json::check()
+    } else {
+        #[cfg(feature = "default")]
+        return super::db_sqlite::check(db.sqlite());
+        #[cfg(not(feature = "default"))]
+        panic!("{}", PANIC_MSG);
+    }
+}
+
+/// Searches the content, attachment names and text files of searchable
+/// pastas. The JSON and PostgreSQL databases have no index, so the pastas in
+/// memory are scanned instead.
+#[allow(unused)]
+pub fn search(db: &Database, pastas: &[Pasta], query: &str) -> Vec<SearchHit> {
+    let terms = search::terms(query);
+    if terms.is_empty() {
+        return Vec::new();
+    }
+
+    if ARGS.get().json_db || is_shared() {
+        search::scan(pastas, &terms)
     } else {
         #[cfg(feature = "default")]
-        super::db_sqlite::delete_by_id(id.expect("Called delete() without passing Pasta id"));
+        return super::db_sqlite::search(db.sqlite(), &search::fts_query(&terms));
         #[cfg(not(feature = "default"))]
         panic!("{}", PANIC_MSG);
     }
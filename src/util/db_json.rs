@@ -1,26 +1,345 @@
-use std::fs::File;
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
 use std::io;
-use std::io::{BufReader, BufWriter};
-use std::path::Path;
-use std::sync::Once;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, Once};
 
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
+use crate::args::ARGS;
+use crate::collection::Collection;
+use crate::setting::SettingChange;
+use crate::util::read_stats::ReadCount;
+use crate::util::totp::AdminTotp;
+use crate::view::View;
 use crate::Pasta;
 
-fn database_path() -> &'static Path {
-    Path::new("pasta_data/database.json")
+/// Files of the JSON database, which releases before this one kept in
+/// `pasta_data` in the working directory, whatever the data directory was.
+const FILES: [&str; 5] = [
+    "database.json",
+    "database.journal",
+    "admin_totp.json",
+    "collections.json",
+    "setting_changes.json",
+];
+const LEGACY_DIRECTORY: &str = "pasta_data";
+
+/// The journal is folded into database.json once it has this many entries,
+/// or as many as there are pastas if that is more.
+const COMPACT_AFTER: usize = 1000;
+
+/// Entries appended to the journal since it was last compacted.
+static JOURNAL_ENTRIES: AtomicUsize = AtomicUsize::new(0);
+
+/// Held while the admin TOTP settings are read and written back, so that
+/// two logins can not use the same code.
+static ADMIN_TOTP: Mutex<()> = Mutex::new(());
+
+/// One line of the journal. Every change of a pasta appends one, so a view
+/// writes a single pasta instead of the whole database.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Entry<P> {
+    Upsert { pasta: P },
+    Delete { id: u64 },
+    Read { id: u64, read_count: u64, last_read: i64 },
+}
+
+fn path(name: &str) -> PathBuf {
+    static MOVE_LEGACY_FILES: Once = Once::new();
+    MOVE_LEGACY_FILES.call_once(move_legacy_files);
+
+    PathBuf::from(format!("{}/{}", ARGS.get().data_dir, name))
+}
+
+fn database_path() -> PathBuf {
+    path("database.json")
+}
+
+fn journal_path() -> PathBuf {
+    path("database.journal")
+}
+
+fn admin_totp_path() -> PathBuf {
+    path("admin_totp.json")
+}
+
+fn collections_path() -> PathBuf {
+    path("collections.json")
+}
+
+fn setting_changes_path() -> PathBuf {
+    path("setting_changes.json")
+}
+
+fn views_path() -> PathBuf {
+    path("views.jsonl")
+}
+
+/// Moves the files of older releases into the data directory. Starting on
+/// an empty database instead would look like all pastas were lost, so a
+/// failed move stops MicroBin.
+fn move_legacy_files() {
+    for name in FILES {
+        let legacy = Path::new(LEGACY_DIRECTORY).join(name);
+        let target = PathBuf::from(format!("{}/{}", ARGS.get().data_dir, name));
+        if !legacy.exists() || target.exists() {
+            continue;
+        }
+
+        let moved = fs::rename(&legacy, &target)
+            .or_else(|_| fs::copy(&legacy, &target).and_then(|_| fs::remove_file(&legacy)));
+        if let Err(e) = moved {
+            log::error!(
+                "Failed to move {} to {}: {}",
+                legacy.display(),
+                target.display(),
+                e
+            );
+            std::process::exit(1);
+        }
+        log::info!("Moved {} to {}", legacy.display(), target.display());
+    }
+    let _ = fs::remove_dir(LEGACY_DIRECTORY);
+}
+
+/// Creates the data directory, which is not created at startup when only a
+/// command runs.
+pub fn create_directory() -> io::Result<()> {
+    fs::create_dir_all(&ARGS.get().data_dir)
 }
 
 pub fn read_all() -> Vec<Pasta> {
-    load_from_file(database_path()).expect("Failed to load pastas from JSON")
+    match load_from_file(&database_path(), &journal_path()) {
+        Ok(pastas) => pastas,
+        Err(e) => {
+            log::error!("Can not use {}: {}", database_path().display(), e);
+            std::process::exit(1);
+        }
+    }
 }
 
+/// Writes all pastas to database.json and empties the journal.
 pub fn update_all(pastas: &Vec<Pasta>) {
-    save_to_file(database_path(), pastas);
+    compact_database(pastas);
+}
+
+/// Stores a new or changed pasta. `pastas` are all pastas including this
+/// one, for compacting the journal once it grew long enough.
+pub fn upsert(pasta: &Pasta, pastas: Option<&Vec<Pasta>>) {
+    append(&Entry::Upsert { pasta }, pastas);
+}
+
+pub fn delete(id: u64, pastas: Option<&Vec<Pasta>>) {
+    append(&Entry::<&Pasta>::Delete { id }, pastas);
+}
+
+/// Journals read counters. `pastas` are all pastas, for compacting the
+/// journal once it grew long enough.
+pub fn update_read_counts(counts: &[ReadCount], pastas: Option<&Vec<Pasta>>) {
+    for count in counts {
+        append(
+            &Entry::Read {
+                id: count.id,
+                read_count: count.read_count,
+                last_read: count.last_read,
+            },
+            pastas,
+        );
+    }
+}
+
+fn append(entry: &Entry<&Pasta>, pastas: Option<&Vec<Pasta>>) {
+    let mut line = serde_json::to_vec(entry).expect("Should be able to serialize journal entry");
+    line.push(b'\n');
+
+    // a single write per entry, so a crash can only cut off the last line
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(journal_path())
+        .and_then(|mut journal| journal.write_all(&line))
+        .expect("Could not append to the database journal");
+
+    let entries = JOURNAL_ENTRIES.fetch_add(1, Ordering::Relaxed) + 1;
+    if let Some(pastas) = pastas {
+        if entries >= COMPACT_AFTER.max(pastas.len()) {
+            compact_database(pastas);
+        }
+    }
+}
+
+/// Compacts the journal and drops the views of pastas deleted since, which
+/// are left behind by `delete` so that it does not rewrite the view log.
+fn compact_database(pastas: &Vec<Pasta>) {
+    compact(&database_path(), &journal_path(), pastas);
+    let ids: HashSet<u64> = pastas.iter().map(|pasta| pasta.id).collect();
+    retain_views(|view| ids.contains(&view.pasta_id));
+}
+
+/// database.json is replaced as a whole before the journal is removed. A
+/// crash in between replays the journal onto a database that already has its
+/// changes, which changes nothing.
+fn compact(path: &Path, journal: &Path, pastas: &Vec<Pasta>) {
+    save_pastas(path, pastas);
+    if journal.exists() {
+        fs::remove_file(journal).expect("Could not remove the database journal");
+    }
+    JOURNAL_ENTRIES.store(0, Ordering::Relaxed);
+}
+
+pub fn read_all_collections() -> Vec<Collection> {
+    let Ok(file) = File::open(collections_path()) else {
+        return Vec::new();
+    };
+    match serde_json::from_reader(BufReader::new(file)) {
+        Ok(collections) => collections,
+        Err(e) => {
+            log::error!("Failed to read collections: {:?}", e);
+            Vec::new()
+        }
+    }
+}
+
+pub fn update_all_collections(collections: &Vec<Collection>) {
+    save_to_file(&collections_path(), collections);
+}
+
+pub fn read_setting_changes() -> Vec<SettingChange> {
+    let Ok(file) = File::open(setting_changes_path()) else {
+        return Vec::new();
+    };
+    match serde_json::from_reader(BufReader::new(file)) {
+        Ok(changes) => changes,
+        Err(e) => {
+            log::error!("Failed to read setting changes: {:?}", e);
+            Vec::new()
+        }
+    }
+}
+
+pub fn update_all_setting_changes(changes: &Vec<SettingChange>) {
+    save_to_file(&setting_changes_path(), changes);
+}
+
+/// Views are appended to views.jsonl, one per line, and the file is only
+/// rewritten when views are removed.
+pub fn insert_views(views: &[View]) {
+    let mut lines = Vec::new();
+    for view in views {
+        serde_json::to_writer(&mut lines, view).expect("Should be able to serialize view");
+        lines.push(b'\n');
+    }
+
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(views_path())
+        .and_then(|mut file| file.write_all(&lines))
+        .expect("Could not append to the view log");
 }
 
-fn save_to_file(path: &Path, pasta_data: &Vec<Pasta>) {
+fn read_all_views() -> Vec<View> {
+    let Ok(file) = File::open(views_path()) else {
+        return Vec::new();
+    };
+    BufReader::new(file)
+        .lines()
+        .map_while(Result::ok)
+        .filter_map(|line| serde_json::from_str(&line).ok())
+        .collect()
+}
+
+/// Views of the pasta, newest first.
+pub fn read_views(pasta_id: u64) -> Vec<View> {
+    let mut views: Vec<View> = read_all_views()
+        .into_iter()
+        .filter(|view| view.pasta_id == pasta_id)
+        .collect();
+    views.reverse();
+    views
+}
+
+pub fn delete_views_before(timestamp: i64) {
+    retain_views(|view| view.viewed_at >= timestamp);
+}
+
+fn retain_views(keep: impl Fn(&View) -> bool) {
+    if !views_path().exists() {
+        return;
+    }
+    let views = read_all_views();
+    let kept: Vec<&View> = views.iter().filter(|view| keep(view)).collect();
+    if kept.len() == views.len() {
+        return;
+    }
+
+    let tmp_file_path = views_path().with_extension("tmp");
+    let mut writer = BufWriter::new(
+        File::create(&tmp_file_path).expect("failed to create temporary view log file for writing"),
+    );
+    for view in kept {
+        serde_json::to_writer(&mut writer, view).expect("Should be able to write out view log");
+        writer.write_all(b"\n").expect("Should be able to write out view log");
+    }
+    writer.flush().expect("Should be able to write out view log");
+    drop(writer);
+    fs::rename(tmp_file_path, views_path()).expect("Could not update view log");
+}
+
+pub fn read_admin_totp() -> Option<AdminTotp> {
+    let file = File::open(admin_totp_path()).ok()?;
+    match serde_json::from_reader(BufReader::new(file)) {
+        Ok(admin_totp) => Some(admin_totp),
+        Err(e) => {
+            log::error!("Failed to read admin TOTP settings: {:?}", e);
+            None
+        }
+    }
+}
+
+pub fn update_admin_totp(admin_totp: Option<&AdminTotp>) {
+    match admin_totp {
+        Some(admin_totp) => save_to_file(&admin_totp_path(), admin_totp),
+        None => {
+            if admin_totp_path().exists() {
+                fs::remove_file(admin_totp_path()).expect("Could not remove admin TOTP file");
+            }
+        }
+    }
+}
+
+pub fn modify_admin_totp(modify: impl FnOnce(&mut AdminTotp) -> bool) -> bool {
+    let _lock = ADMIN_TOTP.lock().unwrap_or_else(|e| e.into_inner());
+    let Some(mut admin_totp) = read_admin_totp() else {
+        return false;
+    };
+    if !modify(&mut admin_totp) {
+        return false;
+    }
+    save_to_file(&admin_totp_path(), &admin_totp);
+    true
+}
+
+/// The database file is only written after the first upload, so the probe
+/// checks that its directory exists and that the file, if any, is readable.
+pub fn check() -> Result<(), String> {
+    let path = database_path();
+    let directory = path.parent().unwrap_or(Path::new("."));
+    if !directory.is_dir() {
+        return Err(format!("{} is not a directory", directory.display()));
+    }
+    if path.exists() {
+        File::open(&path).map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+fn save_to_file<T: Serialize + ?Sized>(path: &Path, data: &T) {
     // This uses a two stage write. First we write to a new file, if this fails
     // only the new pasta's are lost. Then we replace the current database with
     // the new file. This either succeeds or fails. The database is never left
@@ -32,61 +351,154 @@ fn save_to_file(path: &Path, pasta_data: &Vec<Pasta>) {
     ));
 
     let writer = BufWriter::new(tmp_file);
-    serde_json::to_writer(writer, &pasta_data)
+    serde_json::to_writer(writer, data)
         .expect("Should be able to write out data to database file");
-    std::fs::rename(tmp_file_path, path).expect("Could not update database");
+    fs::rename(tmp_file_path, path).expect("Could not update database");
 }
 
-fn migrate(path: &Path) {
-    let Ok(file) = File::open(path) else {
-        return;
-    };
+/// Migrations of the pastas in database.json, applied in order. The file
+/// records the number of steps applied to it in its `version` field. Released
+/// steps must never change, new ones are appended.
+const MIGRATIONS: &[fn(&mut Value)] = &[add_missing_fields];
+
+/// Newest version of database.json this build knows.
+pub const SCHEMA_VERSION: u64 = MIGRATIONS.len() as u64;
+
+#[derive(Serialize)]
+struct Database<'a> {
+    version: u64,
+    pastas: &'a Vec<Pasta>,
+}
 
-    let reader = BufReader::new(file);
-    let mut partially_deserialized: Value = serde_json::from_reader(reader).unwrap();
-    let data = partially_deserialized
-        .as_array_mut()
-        .expect("should be vec");
+/// Version 1: the releases before the version field wrote a bare array of
+/// pastas, each missing the fields added after it was stored.
+fn add_missing_fields(pasta: &mut Value) {
+    let pasta = pasta.as_object_mut().expect("should be pasta struct");
+    pasta.entry("hide_read_count").or_insert(Value::Bool(false));
+    pasta.entry("tags").or_insert(Value::Array(Vec::new()));
+    pasta.entry("files").or_insert(Value::Array(Vec::new()));
+    pasta.entry("uploader").or_insert(Value::Null);
+    pasta.entry("uploader_ip").or_insert(Value::Null);
+}
+
+/// Splits database.json into its version and pastas. Refuses files written
+/// by a newer build.
+fn split(data: Value) -> io::Result<(u64, Vec<Value>)> {
+    let (version, pastas) = match data {
+        Value::Array(pastas) => (0, pastas),
+        Value::Object(mut database) => (
+            database.get("version").and_then(Value::as_u64).unwrap_or(0),
+            match database.remove("pastas") {
+                Some(Value::Array(pastas)) => pastas,
+                _ => Vec::new(),
+            },
+        ),
+        _ => (SCHEMA_VERSION, Vec::new()),
+    };
 
-    for pasta in data {
-        let pasta = pasta.as_object_mut().expect("should be pasta struct");
-        // add migrations here
-        pasta.entry("hide_read_count").or_insert(Value::Bool(false));
+    if version > SCHEMA_VERSION {
+        return Err(io::Error::other(format!(
+            "the database has version {}, but this version of MicroBin only knows up to {}. Upgrade MicroBin or restore a backup",
+            version, SCHEMA_VERSION
+        )));
     }
-    let pasta_data: Vec<Pasta> =
-        serde_json::from_value(partially_deserialized).expect("missing fields where added");
-    save_to_file(path, &pasta_data)
+    Ok((version, pastas))
 }
 
-fn load_from_file(path: &Path) -> io::Result<Vec<Pasta>> {
-    static INIT_JSON_DB: Once = Once::new();
-    INIT_JSON_DB.call_once(|| {
-        // lets not migrate every read
-        // read happens before any update therefore
-        // its safe to only migrate here
-        migrate(path);
-    });
+/// Applies the journal to the pastas of database.json and returns the number
+/// of entries. The journal is written at the version of database.json, so
+/// its pastas are migrated along with the others afterwards.
+fn replay(journal: &Path, pastas: &mut Vec<Value>) -> io::Result<usize> {
+    let Ok(file) = File::open(journal) else {
+        return Ok(0);
+    };
+
+    let mut entries = 0;
+    for line in BufReader::new(file).lines() {
+        let Ok(entry) = serde_json::from_str::<Entry<Value>>(&line?) else {
+            log::warn!("Ignoring the incomplete last entry of {}", journal.display());
+            break;
+        };
+        match entry {
+            Entry::Upsert { pasta } => {
+                match pastas.iter().position(|p| p["id"] == pasta["id"]) {
+                    Some(index) => pastas[index] = pasta,
+                    None => pastas.push(pasta),
+                }
+            }
+            Entry::Delete { id } => pastas.retain(|p| p["id"] != id),
+            Entry::Read {
+                id,
+                read_count,
+                last_read,
+            } => {
+                if let Some(pasta) = pastas.iter_mut().find(|p| p["id"] == id) {
+                    if pasta["read_count"].as_u64().unwrap_or(0) < read_count {
+                        pasta["read_count"] = read_count.into();
+                    }
+                    if pasta["last_read"].as_i64().unwrap_or(0) < last_read {
+                        pasta["last_read"] = last_read.into();
+                    }
+                }
+            }
+        }
+        entries += 1;
+    }
+    Ok(entries)
+}
 
+fn load_from_file(path: &Path, journal: &Path) -> io::Result<Vec<Pasta>> {
     let file = File::open(path);
     match file {
         Ok(file) => {
             let reader = BufReader::new(file);
-            let data: Vec<Pasta> = match serde_json::from_reader(reader) {
+            let data: Value = match serde_json::from_reader(reader) {
                 Ok(t) => t,
-                _ => Vec::new(),
+                _ => return Ok(Vec::new()),
             };
-            Ok(data)
+            let (version, mut values) = split(data)?;
+            let entries = replay(journal, &mut values)?;
+            for migration in &MIGRATIONS[version as usize..] {
+                values.iter_mut().for_each(migration);
+            }
+            let pastas: Vec<Pasta> = serde_json::from_value(Value::Array(values))
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+            // a journal without complete entries is rewritten as well, the
+            // next entry would otherwise be appended to its cut off line
+            if version < SCHEMA_VERSION || entries > 0 || journal.exists() {
+                compact(path, journal, &pastas);
+            }
+            if version < SCHEMA_VERSION {
+                log::info!(
+                    "Migrated {} from version {} to {}",
+                    path.display(),
+                    version,
+                    SCHEMA_VERSION
+                );
+            }
+            Ok(pastas)
         }
         Err(_) => {
             log::info!("Database file {} not found!", path.display());
-            save_to_file(path, &Vec::<Pasta>::new());
+            save_pastas(path, &Vec::new());
 
             log::info!("Database file {} created.", path.display());
-            load_from_file(path)
+            load_from_file(path, journal)
         }
     }
 }
 
+fn save_pastas(path: &Path, pastas: &Vec<Pasta>) {
+    save_to_file(
+        path,
+        &Database {
+            version: SCHEMA_VERSION,
+            pastas,
+        },
+    );
+}
+
 #[cfg(test)]
 mod test {
     use serde::{Deserialize, Serialize};
@@ -144,7 +556,84 @@ mod test {
             .write(&serde_json::to_vec(&old_db).unwrap())
             .unwrap();
 
-        let migrated_db = load_from_file(tmpfile.path()).unwrap();
+        let journal = tmpfile.path().with_extension("journal");
+        let migrated_db = load_from_file(tmpfile.path(), &journal).unwrap();
         assert_eq!(migrated_db[0].hide_read_count, false);
+        assert!(migrated_db[0].tags.is_empty());
+        assert!(migrated_db[0].files.is_empty());
+
+        // the migrated file is written back with its version
+        let saved: Value =
+            serde_json::from_reader(File::open(tmpfile.path()).unwrap()).unwrap();
+        assert_eq!(saved["version"], SCHEMA_VERSION);
+        assert_eq!(saved["pastas"][0]["content"], "test content");
+    }
+
+    #[test]
+    fn test_refuse_newer_version() {
+        let mut tmpfile = NamedTempFile::new().unwrap();
+        write!(tmpfile, r#"{{"version": {}, "pastas": []}}"#, SCHEMA_VERSION + 1).unwrap();
+
+        let journal = tmpfile.path().with_extension("journal");
+        assert!(load_from_file(tmpfile.path(), &journal).is_err());
+    }
+
+    #[test]
+    fn test_journal() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("database.json");
+        let journal = directory.path().join("database.journal");
+
+        let old_pasta = |id: u64, content: &str| {
+            let mut pasta = serde_json::json!({
+                "id": id, "content": content, "file": null, "extension": "", "private": false,
+                "readonly": false, "editable": false, "encrypt_server": false,
+                "encrypt_client": false, "encrypted_key": null, "created": 42, "expiration": 0,
+                "last_read": 42, "read_count": 0, "burn_after_reads": 0, "pasta_type": "text"
+            });
+            add_missing_fields(&mut pasta);
+            pasta
+        };
+        fs::write(
+            &path,
+            serde_json::to_vec(&serde_json::json!({
+                "version": SCHEMA_VERSION,
+                "pastas": [old_pasta(1, "one"), old_pasta(2, "two")]
+            }))
+            .unwrap(),
+        )
+        .unwrap();
+
+        let mut lines = String::new();
+        for entry in [
+            Entry::Upsert { pasta: old_pasta(3, "three") },
+            Entry::Upsert { pasta: old_pasta(1, "one, edited") },
+            Entry::Delete { id: 2 },
+            Entry::Read { id: 3, read_count: 5, last_read: 50 },
+            // flushed after the newer count above, it does not lower it
+            Entry::Read { id: 3, read_count: 4, last_read: 45 },
+            Entry::Read { id: 2, read_count: 1, last_read: 50 },
+        ] {
+            lines.push_str(&serde_json::to_string(&entry).unwrap());
+            lines.push('\n');
+        }
+        // cut off by a crash in the middle of a write
+        lines.push_str(r#"{"op":"delete","#);
+        fs::write(&journal, lines).unwrap();
+
+        let pastas = load_from_file(&path, &journal).unwrap();
+        let contents: Vec<&str> = pastas.iter().map(|p| p.content.as_str()).collect();
+        assert_eq!(contents, ["one, edited", "three"]);
+        assert_eq!((pastas[1].read_count, pastas[1].last_read), (5, 50));
+
+        // the journal was folded into the database
+        assert!(!journal.exists());
+        let reloaded = load_from_file(&path, &journal).unwrap();
+        assert_eq!(reloaded.len(), 2);
+
+        // a journal holding nothing but a cut off entry is removed too
+        fs::write(&journal, r#"{"op":"delete","#).unwrap();
+        assert_eq!(load_from_file(&path, &journal).unwrap().len(), 2);
+        assert!(!journal.exists());
     }
 }
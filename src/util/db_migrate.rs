@@ -0,0 +1,116 @@
+use std::io;
+
+use crate::args::{Backend, ARGS};
+use crate::collection::Collection;
+use crate::setting::SettingChange;
+use crate::util::totp::AdminTotp;
+use crate::util::db_sqlite::{self, Pool};
+use crate::util::db_json;
+use crate::Pasta;
+
+/// Everything a database backend keeps. Attachments are not copied, the
+/// filesystem and S3 stores work with either backend. Those kept in the
+/// SQLite database can not be used with the JSON one, so `run` refuses that
+/// migration. Neither is the view log copied, which only holds analytics.
+struct Contents {
+    pastas: Vec<Pasta>,
+    collections: Vec<Collection>,
+    setting_changes: Vec<SettingChange>,
+    admin_totp: Option<AdminTotp>,
+}
+
+impl Contents {
+    fn is_empty(&self) -> bool {
+        self.pastas.is_empty()
+            && self.collections.is_empty()
+            && self.setting_changes.is_empty()
+            && self.admin_totp.is_none()
+    }
+}
+
+fn read(backend: Backend, pool: &Pool) -> io::Result<Contents> {
+    Ok(match backend {
+        Backend::Json => {
+            db_json::create_directory()?;
+            Contents {
+                pastas: db_json::read_all(),
+                collections: db_json::read_all_collections(),
+                setting_changes: db_json::read_setting_changes(),
+                admin_totp: db_json::read_admin_totp(),
+            }
+        }
+        Backend::Sqlite => Contents {
+            pastas: db_sqlite::read_all(pool),
+            collections: db_sqlite::read_all_collections(pool),
+            setting_changes: db_sqlite::read_setting_changes(pool),
+            admin_totp: db_sqlite::read_admin_totp(pool),
+        },
+    })
+}
+
+/// Replaces the contents of the backend.
+fn write(backend: Backend, pool: &Pool, contents: &Contents) {
+    match backend {
+        Backend::Json => {
+            db_json::update_all(&contents.pastas);
+            db_json::update_all_collections(&contents.collections);
+            db_json::update_all_setting_changes(&contents.setting_changes);
+            db_json::update_admin_totp(contents.admin_totp.as_ref());
+        }
+        Backend::Sqlite => db_sqlite::replace_all(
+            pool,
+            &contents.pastas,
+            &contents.collections,
+            &contents.setting_changes,
+            contents.admin_totp.as_ref(),
+        ),
+    }
+}
+
+/// Copies the contents of one backend into the other and reads them back to
+/// check that nothing was lost. Returns the number of pastas copied.
+pub fn run(from: Backend, to: Backend, force: bool) -> io::Result<usize> {
+    if from == to {
+        return Err(io::Error::other("the source and target database are the same"));
+    }
+    if to == Backend::Json && ARGS.get().attachment_store == "sqlite" {
+        return Err(io::Error::other(
+            "the attachments are kept in the SQLite database, which the JSON database can not use; export a backup and import it with json_db and another attachment_store instead",
+        ));
+    }
+
+    // creates and migrates the SQLite tables, whichever side they are on
+    let pool = db_sqlite::open().map_err(io::Error::other)?;
+    let mut source = read(from, &pool)?;
+    let target = read(to, &pool)?;
+    if !target.is_empty() && !force {
+        return Err(io::Error::other(format!(
+            "the target database already holds {} pastas and {} collections, pass --force to replace them",
+            target.pastas.len(),
+            target.collections.len()
+        )));
+    }
+
+    write(to, &pool, &source);
+
+    // every field of every pasta has to survive the round trip
+    let mut copied = read(to, &pool)?;
+    source.pastas.sort_by_key(|pasta| pasta.id);
+    copied.pastas.sort_by_key(|pasta| pasta.id);
+    if serde_json::to_value(&copied.pastas)? != serde_json::to_value(&source.pastas)?
+        || copied.collections.len() != source.collections.len()
+        || copied.setting_changes.len() != source.setting_changes.len()
+        || copied.admin_totp != source.admin_totp
+    {
+        return Err(io::Error::other(format!(
+            "verification failed, the target database holds {} of {} pastas, {} of {} collections and {} of {} setting changes",
+            copied.pastas.len(),
+            source.pastas.len(),
+            copied.collections.len(),
+            source.collections.len(),
+            copied.setting_changes.len(),
+            source.setting_changes.len()
+        )));
+    }
+    Ok(source.pastas.len())
+}
@@ -0,0 +1,1063 @@
+use std::future::Future;
+use std::time::Duration;
+
+use bytesize::ByteSize;
+use deadpool_postgres::{Client, Manager, ManagerConfig, RecyclingMethod, Runtime};
+use lazy_static::lazy_static;
+use tokio_postgres::{NoTls, Row};
+
+use crate::{
+    args::ARGS, collection::Collection, pasta::PastaFile, setting::SettingChange,
+    util::read_stats::ReadCount, util::totp::AdminTotp, view::View, Pasta,
+};
+
+/// Connections to the PostgreSQL database, shared by all requests and by the
+/// other instances using the same database.
+pub type Pool = deadpool_postgres::Pool;
+
+type Error = Box<dyn std::error::Error + Send + Sync>;
+
+/// Migrations of the PostgreSQL schema, applied in order. The schema version
+/// of a database is the number of steps applied to it. Released steps must
+/// never change, new ones are appended.
+const MIGRATIONS: &[&str] = &[
+    CREATE_SCHEMA,
+    CREATE_INDEXES,
+    CREATE_VIEW_TABLE,
+    CREATE_ADMIN_SESSION_TABLE,
+    ADD_TOTP_LAST_STEP,
+];
+
+/// Newest schema version this build knows.
+pub const SCHEMA_VERSION: usize = MIGRATIONS.len();
+
+/// Key of the advisory lock that serializes schema changes of instances
+/// starting at the same time.
+const SCHEMA_LOCK: i64 = 0x6d6963726f62696e;
+
+/// How long a request waits for a connection from the pool, and for a new
+/// connection to be established.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+lazy_static! {
+    /// Drives the I/O of the connections. Queries are made from synchronous
+    /// code on the server's threads, which can not run a runtime of their
+    /// own, so they wait for the queries to finish on this one instead.
+    static ref RUNTIME: tokio::runtime::Runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(2)
+        .thread_name("microbin-postgres")
+        .enable_all()
+        .build()
+        .expect("Failed to start the PostgreSQL runtime!");
+}
+
+/// Version 1: the schema of the releases before versioned migrations. Their
+/// databases have no version yet and may lack any of the columns added over
+/// time, so this step only adds what is missing.
+const CREATE_SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS pasta (
+        id BIGINT PRIMARY KEY,
+        content TEXT NOT NULL,
+        file_name TEXT,
+        file_size BIGINT,
+        extension TEXT NOT NULL,
+        read_only BOOLEAN NOT NULL,
+        private BOOLEAN NOT NULL,
+        editable BOOLEAN NOT NULL,
+        encrypt_server BOOLEAN NOT NULL,
+        encrypt_client BOOLEAN NOT NULL,
+        encrypted_key TEXT,
+        created BIGINT NOT NULL,
+        expiration BIGINT NOT NULL,
+        last_read BIGINT NOT NULL,
+        read_count BIGINT NOT NULL,
+        burn_after_reads BIGINT NOT NULL,
+        pasta_type TEXT NOT NULL
+    );
+
+    ALTER TABLE pasta ADD COLUMN IF NOT EXISTS hide_read_count BOOLEAN NOT NULL DEFAULT FALSE;
+    ALTER TABLE pasta ADD COLUMN IF NOT EXISTS tags TEXT NOT NULL DEFAULT '';
+    ALTER TABLE pasta ADD COLUMN IF NOT EXISTS files TEXT NOT NULL DEFAULT '[]';
+    ALTER TABLE pasta ADD COLUMN IF NOT EXISTS uploader TEXT;
+    ALTER TABLE pasta ADD COLUMN IF NOT EXISTS uploader_ip TEXT;
+    ALTER TABLE pasta ADD COLUMN IF NOT EXISTS file_sha256 TEXT;
+
+    CREATE TABLE IF NOT EXISTS admin_totp (
+        id INTEGER PRIMARY KEY CHECK (id = 0),
+        secret TEXT NOT NULL,
+        recovery_codes TEXT NOT NULL
+    );
+
+    CREATE TABLE IF NOT EXISTS collection (
+        id BIGINT PRIMARY KEY,
+        name TEXT NOT NULL,
+        pasta_ids TEXT NOT NULL,
+        created BIGINT NOT NULL
+    );
+
+    CREATE TABLE IF NOT EXISTS setting_change (
+        id BIGSERIAL PRIMARY KEY,
+        key TEXT NOT NULL,
+        value TEXT,
+        changed_by TEXT NOT NULL,
+        changed_at BIGINT NOT NULL
+    );";
+
+/// Version 2: indexes on the columns the pastas and collections are sorted
+/// and expired by, and on the blob hash that instances check before
+/// removing a blob.
+const CREATE_INDEXES: &str = "
+    CREATE INDEX IF NOT EXISTS pasta_created ON pasta (created);
+    CREATE INDEX IF NOT EXISTS pasta_expiration ON pasta (expiration);
+    CREATE INDEX IF NOT EXISTS pasta_file_sha256 ON pasta (file_sha256);
+    CREATE INDEX IF NOT EXISTS collection_created ON collection (created);";
+
+/// Version 3: the view log.
+const CREATE_VIEW_TABLE: &str = "
+    CREATE TABLE IF NOT EXISTS pasta_view (
+        id BIGSERIAL PRIMARY KEY,
+        pasta_id BIGINT NOT NULL,
+        viewed_at BIGINT NOT NULL,
+        referrer TEXT,
+        agent TEXT NOT NULL,
+        ip TEXT
+    );
+
+    CREATE INDEX IF NOT EXISTS pasta_view_pasta ON pasta_view (pasta_id, viewed_at);
+    CREATE INDEX IF NOT EXISTS pasta_view_viewed_at ON pasta_view (viewed_at);";
+
+/// Version 4: admin sessions, so that an admin logged in on one instance is
+/// logged in on all of them. Only a hash of the session token is stored.
+const CREATE_ADMIN_SESSION_TABLE: &str = "
+    CREATE TABLE IF NOT EXISTS admin_session (
+        token_sha256 TEXT PRIMARY KEY,
+        expires BIGINT NOT NULL
+    );";
+
+/// Version 5: the time step of the last accepted TOTP code, so that a code
+/// can not be used twice.
+const ADD_TOTP_LAST_STEP: &str = "
+    ALTER TABLE admin_totp ADD COLUMN IF NOT EXISTS last_step BIGINT NOT NULL DEFAULT 0;";
+
+/// Connects to the database in the database_url argument and brings its
+/// schema up to date.
+pub fn open() -> Result<Pool, String> {
+    let config = ARGS
+        .get()
+        .database_url
+        .as_deref()
+        .unwrap_or("")
+        .parse()
+        .map_err(|e| format!("invalid PostgreSQL connection URL: {}", e))?;
+    let pool = connect(config, ARGS.get().threads.max(1) as usize + 1)?;
+    migrate(&pool)?;
+    Ok(pool)
+}
+
+fn connect(config: tokio_postgres::Config, max_size: usize) -> Result<Pool, String> {
+    let manager = Manager::from_config(
+        config,
+        NoTls,
+        ManagerConfig {
+            recycling_method: RecyclingMethod::Fast,
+        },
+    );
+    Pool::builder(manager)
+        .max_size(max_size)
+        .runtime(Runtime::Tokio1)
+        .wait_timeout(Some(TIMEOUT))
+        .create_timeout(Some(TIMEOUT))
+        .build()
+        .map_err(|e| e.to_string())
+}
+
+/// Waits for the query on the runtime of the connections.
+fn block_on<T>(query: impl Future<Output = Result<T, Error>>) -> Result<T, String> {
+    let _runtime = RUNTIME.enter();
+    futures::executor::block_on(query).map_err(|e| e.to_string())
+}
+
+/// Runs the query with a connection from the pool and waits for it.
+fn with_client<T, F, Q>(pool: &Pool, query: Q) -> Result<T, String>
+where
+    F: Future<Output = Result<T, tokio_postgres::Error>>,
+    Q: FnOnce(Client) -> F,
+{
+    block_on(async {
+        let client = pool.get().await?;
+        Ok(query(client).await?)
+    })
+}
+
+/// Brings the schema up to `SCHEMA_VERSION`, in a single transaction under an
+/// advisory lock so that instances starting at the same time migrate it
+/// once. Refuses databases written by a newer build, whose schema this one
+/// does not know.
+fn migrate(pool: &Pool) -> Result<(), String> {
+    let version = block_on(async {
+        let mut client = pool.get().await?;
+        let transaction = client.transaction().await?;
+        transaction
+            .execute("SELECT pg_advisory_xact_lock($1)", &[&SCHEMA_LOCK])
+            .await?;
+        // the steps skip what already exists, which is not worth a notice
+        transaction
+            .batch_execute("SET LOCAL client_min_messages TO WARNING")
+            .await?;
+        transaction
+            .batch_execute("CREATE TABLE IF NOT EXISTS schema_version (version INTEGER NOT NULL)")
+            .await?;
+        let version = transaction
+            .query_opt("SELECT version FROM schema_version", &[])
+            .await?
+            .map_or(0, |row| row.get::<_, i32>(0) as usize);
+
+        if version > SCHEMA_VERSION {
+            return Err(format!(
+                "the database has schema version {}, but this version of MicroBin only knows up to {}. Upgrade MicroBin or restore a backup",
+                version, SCHEMA_VERSION
+            )
+            .into());
+        }
+
+        for (step, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+            transaction
+                .batch_execute(migration)
+                .await
+                .map_err(|e| format!("migration to schema version {} failed: {}", step + 1, e))?;
+        }
+        transaction.execute("DELETE FROM schema_version", &[]).await?;
+        transaction
+            .execute(
+                "INSERT INTO schema_version (version) VALUES ($1)",
+                &[&(SCHEMA_VERSION as i32)],
+            )
+            .await?;
+        transaction.commit().await?;
+        Ok(version)
+    })?;
+
+    if version < SCHEMA_VERSION {
+        log::info!(
+            "Migrated the PostgreSQL database from schema version {} to {}",
+            version,
+            SCHEMA_VERSION
+        );
+    }
+    Ok(())
+}
+
+fn pasta_from_row(row: &Row) -> Pasta {
+    let file_name: Option<String> = row.get("file_name");
+    let file_size: Option<i64> = row.get("file_size");
+
+    Pasta {
+        id: row.get::<_, i64>("id") as u64,
+        content: row.get("content"),
+        file: match (file_name, file_size) {
+            (Some(name), Some(size)) if !name.is_empty() && size != 0 => Some(PastaFile {
+                name,
+                size: ByteSize::b(size as u64),
+                sha256: row.get("file_sha256"),
+            }),
+            _ => None,
+        },
+        extension: row.get("extension"),
+        readonly: row.get("read_only"),
+        private: row.get("private"),
+        editable: row.get("editable"),
+        encrypt_server: row.get("encrypt_server"),
+        encrypt_client: row.get("encrypt_client"),
+        encrypted_key: row.get("encrypted_key"),
+        created: row.get("created"),
+        expiration: row.get("expiration"),
+        last_read: row.get("last_read"),
+        read_count: row.get::<_, i64>("read_count") as u64,
+        burn_after_reads: row.get::<_, i64>("burn_after_reads") as u64,
+        pasta_type: row.get("pasta_type"),
+        hide_read_count: row.get("hide_read_count"),
+        tags: row
+            .get::<_, String>("tags")
+            .split_whitespace()
+            .map(String::from)
+            .collect(),
+        files: serde_json::from_str(row.get("files")).unwrap_or_else(|e| {
+            log::error!("Failed to read text files of pasta: {}", e);
+            Vec::new()
+        }),
+        uploader: row.get("uploader"),
+        uploader_ip: row.get("uploader_ip"),
+    }
+}
+
+pub fn read_all(pool: &Pool) -> Vec<Pasta> {
+    with_client(pool, |client| async move {
+        let rows = client.query("SELECT * FROM pasta ORDER BY created ASC", &[]).await?;
+        Ok(rows.iter().map(pasta_from_row).collect())
+    })
+    .expect("Failed to select Pastas from PostgreSQL database.")
+}
+
+/// The pasta with the given id, for requests about a single pasta, which
+/// need not reload all of them.
+pub fn read_one(pool: &Pool, id: u64) -> Option<Pasta> {
+    with_client(pool, |client| async move {
+        let statement = client.prepare_cached("SELECT * FROM pasta WHERE id = $1").await?;
+        let row = client.query_opt(&statement, &[&(id as i64)]).await?;
+        Ok(row.as_ref().map(pasta_from_row))
+    })
+    .expect("Failed to select Pasta from PostgreSQL database.")
+}
+
+pub fn insert(pool: &Pool, pasta: &Pasta) {
+    with_client(pool, |client| async move {
+        client
+            .execute(
+                "INSERT INTO pasta (
+                    id,
+                    content,
+                    file_name,
+                    file_size,
+                    extension,
+                    read_only,
+                    private,
+                    editable,
+                    encrypt_server,
+                    encrypt_client,
+                    encrypted_key,
+                    created,
+                    expiration,
+                    last_read,
+                    read_count,
+                    burn_after_reads,
+                    pasta_type,
+                    hide_read_count,
+                    tags,
+                    files,
+                    uploader,
+                    uploader_ip,
+                    file_sha256
+                ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18, $19, $20, $21, $22, $23)",
+                &[
+                    &(pasta.id as i64),
+                    &pasta.content,
+                    &pasta.file.as_ref().map_or("", |f| f.name.as_str()),
+                    &pasta.file.as_ref().map_or(0, |f| f.size.as_u64() as i64),
+                    &pasta.extension,
+                    &pasta.readonly,
+                    &pasta.private,
+                    &pasta.editable,
+                    &pasta.encrypt_server,
+                    &pasta.encrypt_client,
+                    &pasta.encrypted_key,
+                    &pasta.created,
+                    &pasta.expiration,
+                    &pasta.last_read,
+                    &(pasta.read_count as i64),
+                    &(pasta.burn_after_reads as i64),
+                    &pasta.pasta_type,
+                    &pasta.hide_read_count,
+                    &pasta.tags.join(" "),
+                    &serde_json::to_string(&pasta.files).expect("Failed to serialize text files"),
+                    &pasta.uploader,
+                    &pasta.uploader_ip,
+                    &pasta.file.as_ref().and_then(|f| f.sha256.as_deref()),
+                ],
+            )
+            .await
+    })
+    .expect("Failed to insert pasta.");
+}
+
+/// Writes the pasta except for its read count and last read time, which
+/// other instances raise with `record_read` in the meantime.
+pub fn update(pool: &Pool, pasta: &Pasta) {
+    with_client(pool, |client| async move {
+        let statement = client
+            .prepare_cached(
+                "UPDATE pasta SET
+                    content = $2,
+                    file_name = $3,
+                    file_size = $4,
+                    extension = $5,
+                    read_only = $6,
+                    private = $7,
+                    editable = $8,
+                    encrypt_server = $9,
+                    encrypt_client = $10,
+                    encrypted_key = $11,
+                    created = $12,
+                    expiration = $13,
+                    burn_after_reads = $14,
+                    pasta_type = $15,
+                    hide_read_count = $16,
+                    tags = $17,
+                    files = $18,
+                    file_sha256 = $19
+                WHERE id = $1",
+            )
+            .await?;
+        client
+            .execute(
+                &statement,
+                &[
+                    &(pasta.id as i64),
+                    &pasta.content,
+                    &pasta.file.as_ref().map_or("", |f| f.name.as_str()),
+                    &pasta.file.as_ref().map_or(0, |f| f.size.as_u64() as i64),
+                    &pasta.extension,
+                    &pasta.readonly,
+                    &pasta.private,
+                    &pasta.editable,
+                    &pasta.encrypt_server,
+                    &pasta.encrypt_client,
+                    &pasta.encrypted_key,
+                    &pasta.created,
+                    &pasta.expiration,
+                    &(pasta.burn_after_reads as i64),
+                    &pasta.pasta_type,
+                    &pasta.hide_read_count,
+                    &pasta.tags.join(" "),
+                    &serde_json::to_string(&pasta.files).expect("Failed to serialize text files"),
+                    &pasta.file.as_ref().and_then(|f| f.sha256.as_deref()),
+                ],
+            )
+            .await
+    })
+    .expect("Failed to update pasta.");
+}
+
+/// Counts a read of the pasta in a single statement, so that reads on all
+/// instances are counted. Returns the new read count, or None if the pasta
+/// is gone or has been read as often as it may be before it burns.
+pub fn record_read(pool: &Pool, id: u64, timestamp: i64) -> Option<u64> {
+    with_client(pool, |client| async move {
+        let statement = client
+            .prepare_cached(
+                "UPDATE pasta SET read_count = read_count + 1, last_read = $2
+                WHERE id = $1 AND (burn_after_reads = 0 OR read_count < burn_after_reads)
+                RETURNING read_count",
+            )
+            .await?;
+        let row = client.query_opt(&statement, &[&(id as i64), &timestamp]).await?;
+        Ok(row.map(|row| row.get::<_, i64>(0) as u64))
+    })
+    .expect("Failed to count read of pasta.")
+}
+
+pub fn delete_by_id(pool: &Pool, id: u64) {
+    with_client(pool, |mut client| async move {
+        let transaction = client.transaction().await?;
+        transaction
+            .execute("DELETE FROM pasta WHERE id = $1", &[&(id as i64)])
+            .await?;
+        transaction
+            .execute("DELETE FROM pasta_view WHERE pasta_id = $1", &[&(id as i64)])
+            .await?;
+        transaction.commit().await
+    })
+    .expect("Failed to delete pasta.");
+}
+
+/// Deletes the pasta if it is still expired, burned or unread for `gc_days`
+/// in the database. Another instance may have removed it already or changed
+/// it since it was loaded, in which case it is left alone and false is
+/// returned.
+pub fn delete_expired(pool: &Pool, id: u64, timenow: i64, gc_days: u16) -> bool {
+    with_client(pool, |mut client| async move {
+        let transaction = client.transaction().await?;
+        let deleted = transaction
+            .execute(
+                "DELETE FROM pasta WHERE id = $1 AND (
+                    (expiration <> 0 AND expiration <= $2)
+                    OR (burn_after_reads <> 0 AND read_count >= burn_after_reads)
+                    OR ($3::BIGINT <> 0 AND last_read <= $2 - $3::BIGINT * 86400)
+                )",
+                &[&(id as i64), &timenow, &(gc_days as i64)],
+            )
+            .await?;
+        if deleted > 0 {
+            transaction
+                .execute("DELETE FROM pasta_view WHERE pasta_id = $1", &[&(id as i64)])
+                .await?;
+        }
+        transaction.commit().await?;
+        Ok(deleted > 0)
+    })
+    .expect("Failed to delete expired pasta.")
+}
+
+/// Whether a pasta other than the one with the given id references the blob.
+pub fn blob_referenced(pool: &Pool, sha256: &str, except_id: u64) -> bool {
+    with_client(pool, |client| async move {
+        let row = client
+            .query_one(
+                "SELECT EXISTS (SELECT 1 FROM pasta WHERE file_sha256 = $1 AND id <> $2)",
+                &[&sha256, &(except_id as i64)],
+            )
+            .await?;
+        Ok(row.get(0))
+    })
+    .expect("Failed to look up blob references.")
+}
+
+pub fn read_all_collections(pool: &Pool) -> Vec<Collection> {
+    with_client(pool, |client| async move {
+        let rows = client
+            .query(
+                "SELECT id, name, pasta_ids, created FROM collection ORDER BY created ASC",
+                &[],
+            )
+            .await?;
+        Ok(rows
+            .iter()
+            .map(|row| Collection {
+                id: row.get::<_, i64>(0) as u64,
+                name: row.get(1),
+                pasta_ids: row
+                    .get::<_, &str>(2)
+                    .split_whitespace()
+                    .filter_map(|id| id.parse().ok())
+                    .collect(),
+                created: row.get(3),
+            })
+            .collect())
+    })
+    .expect("Failed to select Collections from PostgreSQL database.")
+}
+
+pub fn insert_collection(pool: &Pool, collection: &Collection) {
+    let pasta_ids: Vec<String> = collection.pasta_ids.iter().map(|id| id.to_string()).collect();
+
+    with_client(pool, |client| async move {
+        client
+            .execute(
+                "INSERT INTO collection (id, name, pasta_ids, created) VALUES ($1, $2, $3, $4)",
+                &[
+                    &(collection.id as i64),
+                    &collection.name,
+                    &pasta_ids.join(" "),
+                    &collection.created,
+                ],
+            )
+            .await
+    })
+    .expect("Failed to insert collection.");
+}
+
+pub fn delete_collection_by_id(pool: &Pool, id: u64) {
+    with_client(pool, |client| async move {
+        client
+            .execute("DELETE FROM collection WHERE id = $1", &[&(id as i64)])
+            .await
+    })
+    .expect("Failed to delete collection.");
+}
+
+pub fn read_setting_changes(pool: &Pool) -> Vec<SettingChange> {
+    with_client(pool, |client| async move {
+        let rows = client
+            .query(
+                "SELECT key, value, changed_by, changed_at FROM setting_change ORDER BY id ASC",
+                &[],
+            )
+            .await?;
+        Ok(rows
+            .iter()
+            .map(|row| SettingChange {
+                key: row.get(0),
+                value: row.get(1),
+                changed_by: row.get(2),
+                changed_at: row.get(3),
+            })
+            .collect())
+    })
+    .expect("Failed to select setting changes from PostgreSQL database.")
+}
+
+pub fn insert_setting_change(pool: &Pool, change: &SettingChange) {
+    with_client(pool, |client| async move {
+        client
+            .execute(
+                "INSERT INTO setting_change (key, value, changed_by, changed_at) VALUES ($1, $2, $3, $4)",
+                &[&change.key, &change.value, &change.changed_by, &change.changed_at],
+            )
+            .await
+    })
+    .expect("Failed to insert setting change.");
+}
+
+pub fn update_read_counts(pool: &Pool, counts: &[ReadCount]) {
+    with_client(pool, |mut client| async move {
+        let transaction = client.transaction().await?;
+        let statement = transaction
+            .prepare(
+                "UPDATE pasta SET read_count = GREATEST(read_count, $2), last_read = GREATEST(last_read, $3)
+                WHERE id = $1",
+            )
+            .await?;
+        for count in counts {
+            transaction
+                .execute(
+                    &statement,
+                    &[&(count.id as i64), &(count.read_count as i64), &count.last_read],
+                )
+                .await?;
+        }
+        transaction.commit().await
+    })
+    .expect("Failed to update read counts.");
+}
+
+pub fn insert_views(pool: &Pool, views: &[View]) {
+    with_client(pool, |mut client| async move {
+        let transaction = client.transaction().await?;
+        let statement = transaction
+            .prepare(
+                "INSERT INTO pasta_view (pasta_id, viewed_at, referrer, agent, ip) VALUES ($1, $2, $3, $4, $5)",
+            )
+            .await?;
+        for view in views {
+            transaction
+                .execute(
+                    &statement,
+                    &[
+                        &(view.pasta_id as i64),
+                        &view.viewed_at,
+                        &view.referrer,
+                        &view.agent,
+                        &view.ip,
+                    ],
+                )
+                .await?;
+        }
+        transaction.commit().await
+    })
+    .expect("Failed to insert views.");
+}
+
+/// Views of the pasta, newest first.
+pub fn read_views(pool: &Pool, pasta_id: u64) -> Vec<View> {
+    with_client(pool, |client| async move {
+        let rows = client
+            .query(
+                "SELECT pasta_id, viewed_at, referrer, agent, ip FROM pasta_view
+                WHERE pasta_id = $1 ORDER BY viewed_at DESC, id DESC",
+                &[&(pasta_id as i64)],
+            )
+            .await?;
+        Ok(rows
+            .iter()
+            .map(|row| View {
+                pasta_id: row.get::<_, i64>(0) as u64,
+                viewed_at: row.get(1),
+                referrer: row.get(2),
+                agent: row.get(3),
+                ip: row.get(4),
+            })
+            .collect())
+    })
+    .expect("Failed to select views from PostgreSQL database.")
+}
+
+pub fn delete_views_before(pool: &Pool, timestamp: i64) {
+    with_client(pool, |client| async move {
+        client
+            .execute("DELETE FROM pasta_view WHERE viewed_at < $1", &[&timestamp])
+            .await
+    })
+    .expect("Failed to delete old views.");
+}
+
+const SELECT_ADMIN_TOTP: &str = "SELECT secret, recovery_codes, last_step FROM admin_totp WHERE id = 0";
+
+fn admin_totp_from_row(row: &Row) -> AdminTotp {
+    AdminTotp {
+        secret: row.get(0),
+        recovery_codes: row
+            .get::<_, &str>(1)
+            .split_whitespace()
+            .map(String::from)
+            .collect(),
+        last_step: row.get::<_, i64>(2) as u64,
+    }
+}
+
+pub fn read_admin_totp(pool: &Pool) -> Option<AdminTotp> {
+    with_client(pool, |client| async move {
+        let row = client.query_opt(SELECT_ADMIN_TOTP, &[]).await?;
+        Ok(row.as_ref().map(admin_totp_from_row))
+    })
+    .expect("Failed to read admin TOTP settings.")
+}
+
+pub fn update_admin_totp(pool: &Pool, admin_totp: Option<&AdminTotp>) {
+    with_client(pool, |client| async move {
+        match admin_totp {
+            Some(admin_totp) => {
+                client
+                    .execute(
+                        "INSERT INTO admin_totp (id, secret, recovery_codes, last_step) VALUES (0, $1, $2, $3)
+                        ON CONFLICT (id) DO UPDATE SET secret = $1, recovery_codes = $2, last_step = $3",
+                        &[
+                            &admin_totp.secret,
+                            &admin_totp.recovery_codes.join(" "),
+                            &(admin_totp.last_step as i64),
+                        ],
+                    )
+                    .await
+            }
+            None => client.execute("DELETE FROM admin_totp", &[]).await,
+        }
+    })
+    .expect("Failed to update admin TOTP settings.");
+}
+
+/// The row stays locked until the transaction ends, so that logins on other
+/// instances wait instead of using the same code.
+pub fn modify_admin_totp(pool: &Pool, modify: impl FnOnce(&mut AdminTotp) -> bool) -> bool {
+    with_client(pool, |mut client| async move {
+        let transaction = client.transaction().await?;
+        let Some(row) = transaction
+            .query_opt(&format!("{} FOR UPDATE", SELECT_ADMIN_TOTP), &[])
+            .await?
+        else {
+            return Ok(false);
+        };
+        let mut admin_totp = admin_totp_from_row(&row);
+        if !modify(&mut admin_totp) {
+            return Ok(false);
+        }
+
+        transaction
+            .execute(
+                "UPDATE admin_totp SET recovery_codes = $1, last_step = $2 WHERE id = 0",
+                &[&admin_totp.recovery_codes.join(" "), &(admin_totp.last_step as i64)],
+            )
+            .await?;
+        transaction.commit().await?;
+        Ok(true)
+    })
+    .expect("Failed to update admin TOTP settings.")
+}
+
+/// Stores a new admin session, and removes the ones that have expired.
+pub fn insert_admin_session(pool: &Pool, token_sha256: &str, expires: i64, timenow: i64) {
+    with_client(pool, |mut client| async move {
+        let transaction = client.transaction().await?;
+        transaction
+            .execute("DELETE FROM admin_session WHERE expires <= $1", &[&timenow])
+            .await?;
+        transaction
+            .execute(
+                "INSERT INTO admin_session (token_sha256, expires) VALUES ($1, $2)",
+                &[&token_sha256, &expires],
+            )
+            .await?;
+        transaction.commit().await
+    })
+    .expect("Failed to store admin session.");
+}
+
+pub fn admin_session_valid(pool: &Pool, token_sha256: &str, timenow: i64) -> bool {
+    with_client(pool, |client| async move {
+        let row = client
+            .query_opt(
+                "SELECT 1 FROM admin_session WHERE token_sha256 = $1 AND expires > $2",
+                &[&token_sha256, &timenow],
+            )
+            .await?;
+        Ok(row.is_some())
+    })
+    .expect("Failed to read admin session.")
+}
+
+pub fn delete_admin_session(pool: &Pool, token_sha256: &str) {
+    with_client(pool, |client| async move {
+        client
+            .execute("DELETE FROM admin_session WHERE token_sha256 = $1", &[&token_sha256])
+            .await
+    })
+    .expect("Failed to delete admin session.");
+}
+
+/// Takes a connection from the pool and runs a trivial query on it, for the
+/// readiness probe.
+pub fn check(pool: &Pool) -> Result<(), String> {
+    with_client(pool, |client| async move {
+        client
+            .query_one("SELECT COUNT(*) FROM pasta", &[])
+            .await
+            .map(|_| ())
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Tables of the first release with PostgreSQL support, before migrations
+    /// were versioned.
+    const FIRST_RELEASE: &str = "
+        CREATE TABLE pasta (
+            id BIGINT PRIMARY KEY,
+            content TEXT NOT NULL,
+            file_name TEXT,
+            file_size BIGINT,
+            extension TEXT NOT NULL,
+            read_only BOOLEAN NOT NULL,
+            private BOOLEAN NOT NULL,
+            editable BOOLEAN NOT NULL,
+            encrypt_server BOOLEAN NOT NULL,
+            encrypt_client BOOLEAN NOT NULL,
+            encrypted_key TEXT,
+            created BIGINT NOT NULL,
+            expiration BIGINT NOT NULL,
+            last_read BIGINT NOT NULL,
+            read_count BIGINT NOT NULL,
+            burn_after_reads BIGINT NOT NULL,
+            pasta_type TEXT NOT NULL,
+            hide_read_count BOOLEAN NOT NULL,
+            tags TEXT NOT NULL DEFAULT '',
+            files TEXT NOT NULL DEFAULT '[]',
+            uploader TEXT,
+            uploader_ip TEXT,
+            file_sha256 TEXT
+        );
+        CREATE TABLE admin_totp (
+            id INTEGER PRIMARY KEY CHECK (id = 0),
+            secret TEXT NOT NULL,
+            recovery_codes TEXT NOT NULL
+        );
+        CREATE TABLE collection (
+            id BIGINT PRIMARY KEY,
+            name TEXT NOT NULL,
+            pasta_ids TEXT NOT NULL,
+            created BIGINT NOT NULL
+        );
+        CREATE TABLE setting_change (
+            id BIGSERIAL PRIMARY KEY,
+            key TEXT NOT NULL,
+            value TEXT,
+            changed_by TEXT NOT NULL,
+            changed_at BIGINT NOT NULL
+        );
+        INSERT INTO pasta VALUES
+            (1, 'first', 'a.txt', 6, 'txt', FALSE, FALSE, FALSE, FALSE, FALSE, NULL, 10, 0, 10, 3, 0, 'text',
+             FALSE, 'a b', '[]', NULL, NULL, NULL);";
+
+    /// The release that added the view log, still without a schema version.
+    const VIEW_LOG_RELEASE: &str = "
+        CREATE TABLE pasta_view (
+            id BIGSERIAL PRIMARY KEY,
+            pasta_id BIGINT NOT NULL,
+            viewed_at BIGINT NOT NULL,
+            referrer TEXT,
+            agent TEXT NOT NULL,
+            ip TEXT
+        );
+        CREATE INDEX pasta_view_pasta ON pasta_view (pasta_id, viewed_at);
+        CREATE INDEX pasta_view_viewed_at ON pasta_view (viewed_at);
+        INSERT INTO pasta_view (pasta_id, viewed_at, agent) VALUES (1, 20, 'curl');";
+
+    /// A schema of its own in the database given by MICROBIN_TEST_DATABASE_URL,
+    /// dropped again when the test is done. None if no database is given, in
+    /// which case the tests are skipped.
+    struct TestDatabase {
+        pool: Pool,
+        admin: Pool,
+        schema: String,
+    }
+
+    impl TestDatabase {
+        fn new(name: &str) -> Option<TestDatabase> {
+            let Ok(url) = std::env::var("MICROBIN_TEST_DATABASE_URL") else {
+                eprintln!("MICROBIN_TEST_DATABASE_URL is not set, skipping {}", name);
+                return None;
+            };
+            let mut config: tokio_postgres::Config = url.parse().unwrap();
+            let schema = format!("microbin_test_{}_{}", name, std::process::id());
+
+            let admin = connect(config.clone(), 1).unwrap();
+            let statement = format!("DROP SCHEMA IF EXISTS {0} CASCADE; CREATE SCHEMA {0}", schema);
+            with_client(&admin, |client| async move { client.batch_execute(&statement).await }).unwrap();
+
+            config.options(format!("-c search_path={}", schema));
+            Some(TestDatabase {
+                pool: connect(config, 2).unwrap(),
+                admin,
+                schema,
+            })
+        }
+
+        fn execute(&self, statements: &str) {
+            with_client(&self.pool, |client| async move { client.batch_execute(statements).await }).unwrap();
+        }
+
+        fn version(&self) -> usize {
+            with_client(&self.pool, |client| async move {
+                let row = client.query_one("SELECT version FROM schema_version", &[]).await?;
+                Ok(row.get::<_, i32>(0) as usize)
+            })
+            .unwrap()
+        }
+    }
+
+    impl Drop for TestDatabase {
+        fn drop(&mut self) {
+            let statement = format!("DROP SCHEMA {} CASCADE", self.schema);
+            let _ = with_client(&self.admin, |client| async move { client.batch_execute(&statement).await });
+        }
+    }
+
+    fn pasta(id: u64, burn_after_reads: u64) -> Pasta {
+        Pasta {
+            id,
+            content: String::from("content"),
+            file: None,
+            extension: String::from("txt"),
+            private: false,
+            readonly: false,
+            editable: true,
+            hide_read_count: false,
+            encrypt_server: false,
+            encrypt_client: false,
+            encrypted_key: None,
+            created: 100,
+            expiration: 0,
+            last_read: 100,
+            read_count: 0,
+            burn_after_reads,
+            pasta_type: String::from("text"),
+            tags: Vec::new(),
+            files: Vec::new(),
+            uploader: None,
+            uploader_ip: None,
+        }
+    }
+
+    #[test]
+    fn test_migrate_previous_releases() {
+        for (name, fixtures) in [
+            ("first", vec![FIRST_RELEASE]),
+            ("view_log", vec![FIRST_RELEASE, VIEW_LOG_RELEASE]),
+        ] {
+            let Some(db) = TestDatabase::new(&format!("migrate_{}", name)) else {
+                return;
+            };
+            for fixture in fixtures {
+                db.execute(fixture);
+            }
+
+            migrate(&db.pool).unwrap();
+            assert_eq!(db.version(), SCHEMA_VERSION);
+
+            let pastas = read_all(&db.pool);
+            assert_eq!(pastas.len(), 1);
+            assert_eq!(pastas[0].id, 1);
+            assert_eq!(pastas[0].read_count, 3);
+            assert_eq!(pastas[0].tags, vec!["a", "b"]);
+            assert_eq!(pastas[0].file.as_ref().map(|f| f.name()), Some("a.txt"));
+            assert_eq!(read_views(&db.pool, 1).len(), (name == "view_log") as usize);
+
+            // running again on a current database changes nothing
+            migrate(&db.pool).unwrap();
+            assert_eq!(db.version(), SCHEMA_VERSION);
+            assert_eq!(read_all(&db.pool).len(), 1);
+        }
+    }
+
+    #[test]
+    fn test_refuse_newer_schema() {
+        let Some(db) = TestDatabase::new("newer_schema") else {
+            return;
+        };
+        migrate(&db.pool).unwrap();
+        db.execute(&format!("UPDATE schema_version SET version = {}", SCHEMA_VERSION + 1));
+
+        assert!(migrate(&db.pool).is_err());
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let Some(db) = TestDatabase::new("round_trip") else {
+            return;
+        };
+        migrate(&db.pool).unwrap();
+
+        insert(&db.pool, &pasta(1, 2));
+        insert(&db.pool, &pasta(2, 0));
+        assert_eq!(read_one(&db.pool, 1).unwrap().content, "content");
+        assert!(read_one(&db.pool, 3).is_none());
+
+        // reads are counted in the database until the pasta burns
+        assert_eq!(record_read(&db.pool, 1, 200), Some(1));
+        assert_eq!(record_read(&db.pool, 1, 300), Some(2));
+        assert_eq!(record_read(&db.pool, 1, 400), None);
+        assert_eq!(record_read(&db.pool, 3, 400), None);
+
+        // updates from an instance that loaded the pasta earlier keep the count
+        let mut stale = pasta(1, 2);
+        stale.content = String::from("edited");
+        update(&db.pool, &stale);
+        let pasta_1 = read_one(&db.pool, 1).unwrap();
+        assert_eq!(pasta_1.content, "edited");
+        assert_eq!((pasta_1.read_count, pasta_1.last_read), (2, 300));
+
+        // only pastas that are still expired in the database are deleted
+        assert!(!delete_expired(&db.pool, 2, 1000, 0));
+        assert!(delete_expired(&db.pool, 1, 1000, 0));
+        assert!(!delete_expired(&db.pool, 1, 1000, 0));
+
+        // deleting a pasta deletes its views
+        insert_views(
+            &db.pool,
+            &[View {
+                pasta_id: 2,
+                viewed_at: 500,
+                referrer: None,
+                agent: String::from("curl"),
+                ip: None,
+            }],
+        );
+        assert_eq!(read_views(&db.pool, 2).len(), 1);
+        delete_by_id(&db.pool, 2);
+        assert!(read_one(&db.pool, 2).is_none());
+        assert!(read_views(&db.pool, 2).is_empty());
+        assert!(read_all(&db.pool).is_empty());
+    }
+
+    #[test]
+    fn test_modify_admin_totp() {
+        let Some(db) = TestDatabase::new("admin_totp") else {
+            return;
+        };
+        migrate(&db.pool).unwrap();
+
+        assert!(!modify_admin_totp(&db.pool, |_| true));
+        update_admin_totp(
+            &db.pool,
+            Some(&AdminTotp {
+                secret: String::from("secret"),
+                recovery_codes: vec![String::from("a"), String::from("b")],
+                last_step: 1,
+            }),
+        );
+
+        // nothing is written when the change is refused
+        assert!(!modify_admin_totp(&db.pool, |admin_totp| {
+            admin_totp.last_step = 5;
+            false
+        }));
+        assert_eq!(read_admin_totp(&db.pool).unwrap().last_step, 1);
+
+        assert!(modify_admin_totp(&db.pool, |admin_totp| {
+            admin_totp.recovery_codes.remove(0);
+            admin_totp.last_step = 2;
+            true
+        }));
+        let admin_totp = read_admin_totp(&db.pool).unwrap();
+        assert_eq!(admin_totp.recovery_codes, vec!["b"]);
+        assert_eq!(admin_totp.last_step, 2);
+    }
+}
@@ -1,27 +1,130 @@
-use std::sync::Once;
+use std::time::Duration;
 
 use bytesize::ByteSize;
-use rusqlite::{params, Connection};
+use r2d2::PooledConnection;
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::{
+    params, Connection, OptionalExtension, Params, Row, Transaction, TransactionBehavior,
+};
 
-use crate::{args::ARGS, pasta::PastaFile, Pasta};
+use crate::{
+    args::ARGS,
+    collection::Collection,
+    pasta::PastaFile,
+    setting::SettingChange,
+    util::read_stats::ReadCount,
+    util::search::{highlight, SearchHit, MATCH_END, MATCH_START, SEARCH_LIMIT},
+    util::totp::AdminTotp,
+    view::View,
+    Pasta,
+};
 
-pub fn read_all() -> Vec<Pasta> {
-    static INIT_SQLITE: Once = Once::new();
-    INIT_SQLITE.call_once(|| {
-        // lets not migrate every read
-        // read happens before any update therefore 
-        // its safe to only migrate here
-        create_table();
-        migrate();
-    });
-    select_all_from_db()
+/// Migrations of the SQLite schema, applied in order and each in a
+/// transaction of its own. The schema version of a database is the number of
+/// steps applied to it. Released steps must never change, new ones are
+/// appended.
+const MIGRATIONS: &[fn(&Transaction) -> rusqlite::Result<()>] = &[
+    create_schema,
+    create_indexes,
+    create_attachment_tables,
+    create_view_table,
+    exclude_burned_from_search,
+    index_text_files,
+    add_totp_last_step,
+];
+
+/// Newest schema version this build knows.
+pub const SCHEMA_VERSION: usize = MIGRATIONS.len();
+
+/// How long a statement waits for the write lock held by another
+/// connection before it fails.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Prepared statements kept per connection.
+const STATEMENT_CACHE_SIZE: usize = 32;
+
+/// Connections to database.sqlite, shared by all requests. In WAL mode
+/// readers are not blocked by a write, and writers wait for each other for
+/// up to `BUSY_TIMEOUT`.
+pub type Pool = r2d2::Pool<SqliteConnectionManager>;
+
+/// Opens database.sqlite in the data directory and brings its schema up to
+/// date.
+pub fn open() -> Result<Pool, String> {
+    let manager = SqliteConnectionManager::file(format!("{}/database.sqlite", ARGS.get().data_dir))
+        .with_init(|conn| {
+            conn.pragma_update(None, "journal_mode", "WAL")?;
+            conn.pragma_update(None, "synchronous", "NORMAL")?;
+            conn.busy_timeout(BUSY_TIMEOUT)?;
+            conn.set_prepared_statement_cache_capacity(STATEMENT_CACHE_SIZE);
+            Ok(())
+        });
+    let pool = Pool::builder()
+        .max_size(ARGS.get().threads.max(1) as u32 + 1)
+        .build(manager)
+        .map_err(|e| e.to_string())?;
+    migrate(&mut connection(&pool))?;
+    Ok(pool)
 }
 
-fn create_table() {
-    let conn = Connection::open(format!("{}/database.sqlite", ARGS.data_dir))
-        .expect("Failed to open SQLite database!");
+pub fn connection(pool: &Pool) -> PooledConnection<SqliteConnectionManager> {
+    pool.get().expect("Failed to open SQLite database!")
+}
+
+/// Runs a statement through the statement cache of the connection.
+fn execute(conn: &Connection, sql: &str, params: impl Params) -> rusqlite::Result<usize> {
+    conn.prepare_cached(sql)?.execute(params)
+}
 
+pub fn read_all(pool: &Pool) -> Vec<Pasta> {
+    select_all(&connection(pool))
+}
+
+/// Brings the schema up to `SCHEMA_VERSION`. Refuses databases written by a
+/// newer build, whose schema this one does not know.
+fn migrate(conn: &mut Connection) -> Result<(), String> {
     conn.execute(
+        "CREATE TABLE IF NOT EXISTS schema_version (version INTEGER NOT NULL)",
+        params![],
+    )
+    .map_err(|e| e.to_string())?;
+    let version: usize = conn
+        .query_row("SELECT version FROM schema_version", params![], |row| row.get(0))
+        .optional()
+        .map_err(|e| e.to_string())?
+        .unwrap_or(0);
+
+    if version > SCHEMA_VERSION {
+        return Err(format!(
+            "the database has schema version {}, but this version of MicroBin only knows up to {}. Upgrade MicroBin or restore a backup",
+            version, SCHEMA_VERSION
+        ));
+    }
+
+    for (step, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let run = |tx: &Transaction| {
+            migration(tx)?;
+            tx.execute("DELETE FROM schema_version", params![])?;
+            tx.execute(
+                "INSERT INTO schema_version (version) VALUES (?1)",
+                params![step + 1],
+            )?;
+            Ok(())
+        };
+        let tx = conn.transaction().map_err(|e| e.to_string())?;
+        run(&tx)
+            .and_then(|()| tx.commit())
+            .map_err(|e: rusqlite::Error| format!("migration to schema version {} failed: {}", step + 1, e))?;
+        log::info!("Migrated the SQLite database to schema version {}", step + 1);
+    }
+    Ok(())
+}
+
+/// Version 1: the schema of the releases before versioned migrations. Their
+/// databases have no version yet and may lack any of the columns and tables
+/// added over time, so this step only adds what is missing.
+fn create_schema(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute(
         "
         CREATE TABLE IF NOT EXISTS pasta (
             id INTEGER PRIMARY KEY,
@@ -41,39 +144,310 @@ fn create_table() {
             read_count INTEGER NOT NULL,
             burn_after_reads INTEGER NOT NULL,
             pasta_type TEXT NOT NULL,
-            hide_read_count INTEGER NOT NULL
+            hide_read_count INTEGER NOT NULL,
+            tags TEXT NOT NULL DEFAULT '',
+            files TEXT NOT NULL DEFAULT '[]',
+            uploader TEXT,
+            uploader_ip TEXT,
+            file_sha256 TEXT
+        );",
+        params![],
+    )?;
+
+    tx.execute(
+        "
+        CREATE TABLE IF NOT EXISTS admin_totp (
+            id INTEGER PRIMARY KEY CHECK (id = 0),
+            secret TEXT NOT NULL,
+            recovery_codes TEXT NOT NULL
+        );",
+        params![],
+    )?;
+
+    tx.execute(
+        "
+        CREATE TABLE IF NOT EXISTS collection (
+            id INTEGER PRIMARY KEY,
+            name TEXT NOT NULL,
+            pasta_ids TEXT NOT NULL,
+            created INTEGER NOT NULL
+        );",
+        params![],
+    )?;
+
+    tx.execute(
+        "
+        CREATE TABLE IF NOT EXISTS setting_change (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            key TEXT NOT NULL,
+            value TEXT,
+            changed_by TEXT NOT NULL,
+            changed_at INTEGER NOT NULL
         );",
         params![],
+    )?;
+
+    add_missing_columns(tx)?;
+    create_search_index(tx)
+}
+
+
+/// Version 2: indexes on the columns the pastas and collections are sorted
+/// and expired by.
+fn create_indexes(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "
+        CREATE INDEX IF NOT EXISTS pasta_created ON pasta (created);
+        CREATE INDEX IF NOT EXISTS pasta_expiration ON pasta (expiration);
+        CREATE INDEX IF NOT EXISTS collection_created ON collection (created);",
     )
-    .expect("Failed to create SQLite table for Pasta!");
 }
 
-fn migrate() {
-    let conn = Connection::open(format!("{}/database.sqlite", ARGS.data_dir))
-        .expect("Failed to open SQLite database!");
+/// Version 3: attachments kept in the database by the sqlite attachment
+/// store, split into chunks.
+fn create_attachment_tables(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "
+        CREATE TABLE IF NOT EXISTS attachment (
+            key TEXT PRIMARY KEY,
+            size INTEGER NOT NULL
+        );
 
-    let res = conn.execute(
-        "ALTER TABLE pasta 
-ADD hide_read_count INTEGER NOT NULL DEFAULT 0",
-        // In the future add more migrations here
-        params![],
-    );
+        CREATE TABLE IF NOT EXISTS attachment_chunk (
+            key TEXT NOT NULL,
+            seq INTEGER NOT NULL,
+            data BLOB NOT NULL,
+            PRIMARY KEY (key, seq)
+        );",
+    )
+}
+
+/// Version 4: the view log.
+fn create_view_table(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "
+        CREATE TABLE IF NOT EXISTS pasta_view (
+            id INTEGER PRIMARY KEY,
+            pasta_id INTEGER NOT NULL,
+            viewed_at INTEGER NOT NULL,
+            referrer TEXT,
+            agent TEXT NOT NULL,
+            ip TEXT
+        );
+
+        CREATE INDEX IF NOT EXISTS pasta_view_pasta ON pasta_view (pasta_id, viewed_at);
+        CREATE INDEX IF NOT EXISTS pasta_view_viewed_at ON pasta_view (viewed_at);",
+    )
+}
+
+/// Version 5: burn-after-read pastas are left out of the search index, and
+/// the index is only updated when a column it depends on changes, not on
+/// every read count. Removing the old entry and adding the new one happen in
+/// one trigger, SQLite gives no order between triggers on the same event.
+fn exclude_burned_from_search(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "
+        DROP TRIGGER IF EXISTS pasta_fts_insert;
+        DROP TRIGGER IF EXISTS pasta_fts_delete;
+        DROP TRIGGER IF EXISTS pasta_fts_update_old;
+        DROP TRIGGER IF EXISTS pasta_fts_update_new;
+
+        CREATE TRIGGER pasta_fts_insert AFTER INSERT ON pasta
+        WHEN new.private = 0 AND new.encrypt_server = 0 AND new.encrypt_client = 0
+            AND new.burn_after_reads = 0
+        BEGIN
+            INSERT INTO pasta_fts (rowid, content, file_name)
+            VALUES (new.id, new.content, new.file_name);
+        END;
+
+        CREATE TRIGGER pasta_fts_delete AFTER DELETE ON pasta
+        WHEN old.private = 0 AND old.encrypt_server = 0 AND old.encrypt_client = 0
+            AND old.burn_after_reads = 0
+        BEGIN
+            INSERT INTO pasta_fts (pasta_fts, rowid, content, file_name)
+            VALUES ('delete', old.id, old.content, old.file_name);
+        END;
+
+        CREATE TRIGGER pasta_fts_update
+        AFTER UPDATE OF content, file_name, private, encrypt_server, encrypt_client, burn_after_reads ON pasta
+        BEGIN
+            INSERT INTO pasta_fts (pasta_fts, rowid, content, file_name)
+            SELECT 'delete', old.id, old.content, old.file_name
+            WHERE old.private = 0 AND old.encrypt_server = 0 AND old.encrypt_client = 0
+                AND old.burn_after_reads = 0;
+            INSERT INTO pasta_fts (rowid, content, file_name)
+            SELECT new.id, new.content, new.file_name
+            WHERE new.private = 0 AND new.encrypt_server = 0 AND new.encrypt_client = 0
+                AND new.burn_after_reads = 0;
+        END;
+
+        INSERT INTO pasta_fts (pasta_fts) VALUES ('delete-all');
+        INSERT INTO pasta_fts (rowid, content, file_name)
+        SELECT id, content, file_name FROM pasta
+        WHERE private = 0 AND encrypt_server = 0 AND encrypt_client = 0
+            AND burn_after_reads = 0;",
+    )
+}
+
+/// Version 6: the text files of multi-file pastas are indexed as well. The
+/// index keeps a copy of the text it matches, names and contents of the
+/// files joined by line breaks, instead of reading the JSON of the files
+/// column back for snippets.
+fn index_text_files(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(&format!(
+        "
+        DROP TRIGGER IF EXISTS pasta_fts_insert;
+        DROP TRIGGER IF EXISTS pasta_fts_delete;
+        DROP TRIGGER IF EXISTS pasta_fts_update;
+        DROP TABLE IF EXISTS pasta_fts;
+
+        CREATE VIRTUAL TABLE pasta_fts USING fts5(content, file_name, files);
+
+        CREATE TRIGGER pasta_fts_insert AFTER INSERT ON pasta
+        WHEN {new_searchable}
+        BEGIN
+            INSERT INTO pasta_fts (rowid, content, file_name, files)
+            VALUES (new.id, new.content, new.file_name, {new_files});
+        END;
+
+        CREATE TRIGGER pasta_fts_delete AFTER DELETE ON pasta
+        BEGIN
+            DELETE FROM pasta_fts WHERE rowid = old.id;
+        END;
+
+        CREATE TRIGGER pasta_fts_update
+        AFTER UPDATE OF content, file_name, files, private, encrypt_server, encrypt_client, burn_after_reads ON pasta
+        BEGIN
+            DELETE FROM pasta_fts WHERE rowid = old.id;
+            INSERT INTO pasta_fts (rowid, content, file_name, files)
+            SELECT new.id, new.content, new.file_name, {new_files}
+            WHERE {new_searchable};
+        END;
+
+        INSERT INTO pasta_fts (rowid, content, file_name, files)
+        SELECT id, content, file_name, {files} FROM pasta
+        WHERE {searchable};",
+        searchable = searchable_condition(""),
+        new_searchable = searchable_condition("new."),
+        files = files_text(""),
+        new_files = files_text("new."),
+    ))
+}
 
-    if let Err(e) = res {
-        if e.to_string().contains("duplicate column name:") {
-            return;
-        } else {
-            panic!("error while migrating sqlite table: {e}")
+/// SQL condition matching the pastas `search::searchable` accepts, on the
+/// columns of `row` ("new." in triggers).
+fn searchable_condition(row: &str) -> String {
+    format!(
+        "{row}private = 0 AND {row}encrypt_server = 0 AND {row}encrypt_client = 0 AND {row}burn_after_reads = 0",
+        row = row
+    )
+}
+
+/// SQL expression for the text of the files of a pasta, the name and
+/// content of each file joined by line breaks.
+fn files_text(row: &str) -> String {
+    format!(
+        "(SELECT group_concat(json_extract(value, '$.name') || char(10) || json_extract(value, '$.content'), char(10))
+            FROM json_each({row}files))",
+        row = row
+    )
+}
+
+/// Columns added to the pasta table by releases before versioned migrations,
+/// in the order they were added.
+const LEGACY_COLUMNS: [(&str, &str); 6] = [
+    ("hide_read_count", "INTEGER NOT NULL DEFAULT 0"),
+    ("tags", "TEXT NOT NULL DEFAULT ''"),
+    ("files", "TEXT NOT NULL DEFAULT '[]'"),
+    ("uploader", "TEXT"),
+    ("uploader_ip", "TEXT"),
+    ("file_sha256", "TEXT"),
+];
+
+/// Version 7: the time step of the last accepted TOTP code, so that a code
+/// can not be used twice.
+fn add_totp_last_step(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch("ALTER TABLE admin_totp ADD COLUMN last_step INTEGER NOT NULL DEFAULT 0;")
+}
+
+fn add_missing_columns(tx: &Transaction) -> rusqlite::Result<()> {
+    let columns = tx
+        .prepare("SELECT name FROM pragma_table_info('pasta')")?
+        .query_map(params![], |row| row.get::<_, String>(0))?
+        .collect::<rusqlite::Result<Vec<String>>>()?;
+
+    for (name, definition) in LEGACY_COLUMNS {
+        if !columns.iter().any(|column| column == name) {
+            tx.execute(&format!("ALTER TABLE pasta ADD {} {}", name, definition), params![])?;
         }
     }
+    Ok(())
 }
 
-fn select_all_from_db() -> Vec<Pasta> {
-    let conn = Connection::open(format!("{}/database.sqlite", ARGS.data_dir))
-        .expect("Failed to open SQLite database!");
+/// Full-text index over content and attachment names of the searchable
+/// pastas, kept up to date by triggers. Private and encrypted pastas are
+/// never indexed.
+fn create_search_index(conn: &Connection) -> rusqlite::Result<()> {
+    let exists: bool = conn
+        .query_row(
+            "SELECT count(*) > 0 FROM sqlite_master WHERE name = 'pasta_fts'",
+            params![],
+            |row| row.get(0),
+        )?;
+
+    conn.execute_batch(
+        "
+        CREATE VIRTUAL TABLE IF NOT EXISTS pasta_fts USING fts5(
+            content,
+            file_name,
+            content = 'pasta',
+            content_rowid = 'id'
+        );
+
+        CREATE TRIGGER IF NOT EXISTS pasta_fts_insert AFTER INSERT ON pasta
+        WHEN new.private = 0 AND new.encrypt_server = 0 AND new.encrypt_client = 0
+        BEGIN
+            INSERT INTO pasta_fts (rowid, content, file_name)
+            VALUES (new.id, new.content, new.file_name);
+        END;
 
+        CREATE TRIGGER IF NOT EXISTS pasta_fts_delete AFTER DELETE ON pasta
+        WHEN old.private = 0 AND old.encrypt_server = 0 AND old.encrypt_client = 0
+        BEGIN
+            INSERT INTO pasta_fts (pasta_fts, rowid, content, file_name)
+            VALUES ('delete', old.id, old.content, old.file_name);
+        END;
+
+        CREATE TRIGGER IF NOT EXISTS pasta_fts_update_old AFTER UPDATE ON pasta
+        WHEN old.private = 0 AND old.encrypt_server = 0 AND old.encrypt_client = 0
+        BEGIN
+            INSERT INTO pasta_fts (pasta_fts, rowid, content, file_name)
+            VALUES ('delete', old.id, old.content, old.file_name);
+        END;
+
+        CREATE TRIGGER IF NOT EXISTS pasta_fts_update_new AFTER UPDATE ON pasta
+        WHEN new.private = 0 AND new.encrypt_server = 0 AND new.encrypt_client = 0
+        BEGIN
+            INSERT INTO pasta_fts (rowid, content, file_name)
+            VALUES (new.id, new.content, new.file_name);
+        END;",
+    )?;
+
+    // index the pastas that were stored before the index existed
+    if !exists {
+        conn.execute(
+            "INSERT INTO pasta_fts (rowid, content, file_name)
+            SELECT id, content, file_name FROM pasta
+            WHERE private = 0 AND encrypt_server = 0 AND encrypt_client = 0",
+            params![],
+        )?;
+    }
+    Ok(())
+}
+
+fn select_all(conn: &Connection) -> Vec<Pasta> {
     let mut stmt = conn
-        .prepare("SELECT * FROM pasta ORDER BY created ASC")
+        .prepare_cached("SELECT * FROM pasta ORDER BY created ASC")
         .expect("Failed to prepare SQL statement to load pastas");
 
     let pasta_iter = stmt
@@ -87,6 +461,7 @@ fn select_all_from_db() -> Vec<Pasta> {
                         Some(PastaFile {
                             name: file_name,
                             size: ByteSize::b(file_size),
+                            sha256: row.get(22)?,
                         })
                     } else {
                         None
@@ -108,6 +483,17 @@ fn select_all_from_db() -> Vec<Pasta> {
                 burn_after_reads: row.get(15)?,
                 pasta_type: row.get(16)?,
                 hide_read_count: row.get(17)?,
+                tags: row
+                    .get::<_, String>(18)?
+                    .split_whitespace()
+                    .map(String::from)
+                    .collect(),
+                files: serde_json::from_str(&row.get::<_, String>(19)?).unwrap_or_else(|e| {
+                    log::error!("Failed to read text files of pasta: {}", e);
+                    Vec::new()
+                }),
+                uploader: row.get(20)?,
+                uploader_ip: row.get(21)?,
             })
         })
         .expect("Failed to select Pastas from SQLite database.");
@@ -117,37 +503,13 @@ fn select_all_from_db() -> Vec<Pasta> {
         .collect::<Vec<Pasta>>()
 }
 
-pub fn insert(pasta: &Pasta) {
-    let conn = Connection::open(format!("{}/database.sqlite", ARGS.data_dir))
-        .expect("Failed to open SQLite database!");
-
-    conn.execute(
-        "
-        CREATE TABLE IF NOT EXISTS pasta (
-            id INTEGER PRIMARY KEY,
-            content TEXT NOT NULL,
-            file_name TEXT,
-            file_size INTEGER,
-            extension TEXT NOT NULL,
-            read_only INTEGER NOT NULL,
-            private INTEGER NOT NULL,
-            editable INTEGER NOT NULL,
-            encrypt_server INTEGER NOT NULL,
-            encrypt_client INTEGER NOT NULL,
-            encrypted_key TEXT,
-            created INTEGER NOT NULL,
-            expiration INTEGER NOT NULL,
-            last_read INTEGER NOT NULL,
-            read_count INTEGER NOT NULL,
-            burn_after_reads INTEGER NOT NULL,
-            pasta_type TEXT NOT NULL,
-            hide_read_count INTEGER NOT NULL
-        );",
-        params![],
-    )
-    .expect("Failed to create SQLite table for Pasta!");
+pub fn insert(pool: &Pool, pasta: &Pasta) {
+    insert_pasta(&connection(pool), pasta).expect("Failed to insert pasta.");
+}
 
-    conn.execute(
+fn insert_pasta(conn: &Connection, pasta: &Pasta) -> rusqlite::Result<usize> {
+    execute(
+        conn,
         "INSERT INTO pasta (
                 id,
                 content,
@@ -166,8 +528,13 @@ pub fn insert(pasta: &Pasta) {
                 read_count,
                 burn_after_reads,
                 pasta_type,
-                hide_read_count
-            ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18)",
+                hide_read_count,
+                tags,
+                files,
+                uploader,
+                uploader_ip,
+                file_sha256
+            ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18, ?19, ?20, ?21, ?22, ?23)",
         params![
             pasta.id,
             pasta.content,
@@ -187,16 +554,20 @@ pub fn insert(pasta: &Pasta) {
             pasta.burn_after_reads,
             pasta.pasta_type,
             pasta.hide_read_count,
+            pasta.tags.join(" "),
+            serde_json::to_string(&pasta.files).expect("Failed to serialize text files"),
+            pasta.uploader.as_deref(),
+            pasta.uploader_ip.as_deref(),
+            pasta.file.as_ref().and_then(|f| f.sha256.as_deref()),
         ],
     )
-    .expect("Failed to insert pasta.");
 }
 
-pub fn update(pasta: &Pasta) {
-    let conn = Connection::open(format!("{}/database.sqlite", ARGS.data_dir))
-        .expect("Failed to open SQLite database!");
+pub fn update(pool: &Pool, pasta: &Pasta) {
+    let conn = connection(pool);
 
-    conn.execute(
+    execute(
+        &conn,
         "UPDATE pasta SET
             content = ?2,
             file_name = ?3,
@@ -214,7 +585,10 @@ pub fn update(pasta: &Pasta) {
             read_count = ?15,
             burn_after_reads = ?16,
             pasta_type = ?17,
-            hide_read_count = ?18
+            hide_read_count = ?18,
+            tags = ?19,
+            files = ?20,
+            file_sha256 = ?21
         WHERE id = ?1;",
         params![
             pasta.id,
@@ -235,19 +609,461 @@ pub fn update(pasta: &Pasta) {
             pasta.burn_after_reads,
             pasta.pasta_type,
             pasta.hide_read_count,
+            pasta.tags.join(" "),
+            serde_json::to_string(&pasta.files).expect("Failed to serialize text files"),
+            pasta.file.as_ref().and_then(|f| f.sha256.as_deref()),
         ],
     )
     .expect("Failed to update pasta.");
 }
 
-pub fn delete_by_id(id: u64) {
-    let conn = Connection::open(format!("{}/database.sqlite", ARGS.data_dir))
-        .expect("Failed to open SQLite database!");
+pub fn delete_by_id(pool: &Pool, id: u64) {
+    let conn = connection(pool);
 
-    conn.execute(
+    execute(
+        &conn,
         "DELETE FROM pasta 
         WHERE id = ?1;",
         params![id],
     )
     .expect("Failed to delete pasta.");
+    execute(&conn, "DELETE FROM pasta_view WHERE pasta_id = ?1", params![id])
+        .expect("Failed to delete views of pasta.");
+}
+
+/// Empties every table, for `microbin migrate-db --force`.
+/// Replaces everything in the database, in one transaction so that a copy
+/// that fails halfway leaves the previous contents. The view log is emptied,
+/// its pastas are gone.
+pub fn replace_all(
+    pool: &Pool,
+    pastas: &[Pasta],
+    collections: &[Collection],
+    setting_changes: &[SettingChange],
+    admin_totp: Option<&AdminTotp>,
+) {
+    let mut conn = connection(pool);
+
+    let replace = |tx: &Transaction| {
+        tx.execute_batch(
+            "DELETE FROM pasta;
+            DELETE FROM collection;
+            DELETE FROM setting_change;
+            DELETE FROM pasta_view;",
+        )?;
+        for pasta in pastas {
+            insert_pasta(tx, pasta)?;
+        }
+        for collection in collections {
+            insert_collection_row(tx, collection)?;
+        }
+        for change in setting_changes {
+            insert_setting_change_row(tx, change)?;
+        }
+        replace_admin_totp(tx, admin_totp)?;
+        Ok(())
+    };
+    conn.transaction()
+        .and_then(|tx| replace(&tx).and_then(|()| tx.commit()))
+        .expect("Failed to replace the database.");
+}
+
+pub fn read_all_collections(pool: &Pool) -> Vec<Collection> {
+    let conn = connection(pool);
+
+    let mut stmt = conn
+        .prepare_cached("SELECT id, name, pasta_ids, created FROM collection ORDER BY created ASC")
+        .expect("Failed to prepare SQL statement to load collections");
+
+    let collection_iter = stmt
+        .query_map([], |row| {
+            let pasta_ids: String = row.get(2)?;
+            Ok(Collection {
+                id: row.get(0)?,
+                name: row.get(1)?,
+                pasta_ids: pasta_ids
+                    .split_whitespace()
+                    .filter_map(|id| id.parse().ok())
+                    .collect(),
+                created: row.get(3)?,
+            })
+        })
+        .expect("Failed to select Collections from SQLite database.");
+
+    collection_iter
+        .map(|r| r.expect("Failed to get collection"))
+        .collect::<Vec<Collection>>()
+}
+
+pub fn insert_collection(pool: &Pool, collection: &Collection) {
+    insert_collection_row(&connection(pool), collection).expect("Failed to insert collection.");
+}
+
+fn insert_collection_row(conn: &Connection, collection: &Collection) -> rusqlite::Result<usize> {
+    let pasta_ids: Vec<String> = collection.pasta_ids.iter().map(|id| id.to_string()).collect();
+
+    execute(
+        conn,
+        "INSERT INTO collection (id, name, pasta_ids, created) VALUES (?1, ?2, ?3, ?4)",
+        params![
+            collection.id,
+            collection.name,
+            pasta_ids.join(" "),
+            collection.created
+        ],
+    )
+}
+
+pub fn delete_collection_by_id(pool: &Pool, id: u64) {
+    let conn = connection(pool);
+
+    execute(&conn, "DELETE FROM collection WHERE id = ?1;", params![id])
+        .expect("Failed to delete collection.");
+}
+
+pub fn read_setting_changes(pool: &Pool) -> Vec<SettingChange> {
+    let conn = connection(pool);
+
+    let mut stmt = conn
+        .prepare_cached("SELECT key, value, changed_by, changed_at FROM setting_change ORDER BY id ASC")
+        .expect("Failed to prepare SQL statement to load setting changes");
+
+    let change_iter = stmt
+        .query_map([], |row| {
+            Ok(SettingChange {
+                key: row.get(0)?,
+                value: row.get(1)?,
+                changed_by: row.get(2)?,
+                changed_at: row.get(3)?,
+            })
+        })
+        .expect("Failed to select setting changes from SQLite database.");
+
+    change_iter
+        .map(|r| r.expect("Failed to get setting change"))
+        .collect::<Vec<SettingChange>>()
+}
+
+pub fn insert_setting_change(pool: &Pool, change: &SettingChange) {
+    insert_setting_change_row(&connection(pool), change).expect("Failed to insert setting change.");
+}
+
+fn insert_setting_change_row(conn: &Connection, change: &SettingChange) -> rusqlite::Result<usize> {
+    execute(
+        conn,
+        "INSERT INTO setting_change (key, value, changed_by, changed_at) VALUES (?1, ?2, ?3, ?4)",
+        params![change.key, change.value, change.changed_by, change.changed_at],
+    )
+}
+
+pub fn update_read_counts(pool: &Pool, counts: &[ReadCount]) {
+    let mut conn = connection(pool);
+
+    let update = |tx: &Transaction| {
+        for count in counts {
+            execute(
+                tx,
+                "UPDATE pasta SET read_count = MAX(read_count, ?2), last_read = MAX(last_read, ?3) WHERE id = ?1",
+                params![count.id, count.read_count, count.last_read],
+            )?;
+        }
+        Ok(())
+    };
+    conn.transaction()
+        .and_then(|tx| update(&tx).and_then(|()| tx.commit()))
+        .expect("Failed to update read counts.");
+}
+
+pub fn insert_views(pool: &Pool, views: &[View]) {
+    let mut conn = connection(pool);
+
+    let insert = |tx: &Transaction| {
+        for view in views {
+            execute(
+                tx,
+                "INSERT INTO pasta_view (pasta_id, viewed_at, referrer, agent, ip) VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![view.pasta_id, view.viewed_at, view.referrer, view.agent, view.ip],
+            )?;
+        }
+        Ok(())
+    };
+    conn.transaction()
+        .and_then(|tx| insert(&tx).and_then(|()| tx.commit()))
+        .expect("Failed to insert views.");
+}
+
+/// Views of the pasta, newest first.
+pub fn read_views(pool: &Pool, pasta_id: u64) -> Vec<View> {
+    let conn = connection(pool);
+
+    let mut stmt = conn
+        .prepare_cached(
+            "SELECT pasta_id, viewed_at, referrer, agent, ip FROM pasta_view
+            WHERE pasta_id = ?1 ORDER BY viewed_at DESC, id DESC",
+        )
+        .expect("Failed to prepare SQL statement to load views");
+
+    let view_iter = stmt
+        .query_map(params![pasta_id], |row| {
+            Ok(View {
+                pasta_id: row.get(0)?,
+                viewed_at: row.get(1)?,
+                referrer: row.get(2)?,
+                agent: row.get(3)?,
+                ip: row.get(4)?,
+            })
+        })
+        .expect("Failed to select views from SQLite database.");
+
+    view_iter
+        .map(|r| r.expect("Failed to get view"))
+        .collect::<Vec<View>>()
+}
+
+pub fn delete_views_before(pool: &Pool, timestamp: i64) {
+    let conn = connection(pool);
+
+    execute(&conn, "DELETE FROM pasta_view WHERE viewed_at < ?1", params![timestamp])
+        .expect("Failed to delete old views.");
+}
+
+const SELECT_ADMIN_TOTP: &str = "SELECT secret, recovery_codes, last_step FROM admin_totp WHERE id = 0";
+
+fn admin_totp_from_row(row: &Row) -> rusqlite::Result<AdminTotp> {
+    let recovery_codes: String = row.get(1)?;
+    Ok(AdminTotp {
+        secret: row.get(0)?,
+        recovery_codes: recovery_codes.split_whitespace().map(String::from).collect(),
+        last_step: row.get(2)?,
+    })
+}
+
+pub fn read_admin_totp(pool: &Pool) -> Option<AdminTotp> {
+    let conn = connection(pool);
+
+    conn.query_row(SELECT_ADMIN_TOTP, params![], admin_totp_from_row)
+        .optional()
+        .expect("Failed to read admin TOTP settings.")
+}
+
+pub fn update_admin_totp(pool: &Pool, admin_totp: Option<&AdminTotp>) {
+    replace_admin_totp(&connection(pool), admin_totp).expect("Failed to update admin TOTP settings.");
+}
+
+fn replace_admin_totp(conn: &Connection, admin_totp: Option<&AdminTotp>) -> rusqlite::Result<usize> {
+    match admin_totp {
+        Some(admin_totp) => conn.execute(
+            "INSERT OR REPLACE INTO admin_totp (id, secret, recovery_codes, last_step) VALUES (0, ?1, ?2, ?3)",
+            params![admin_totp.secret, admin_totp.recovery_codes.join(" "), admin_totp.last_step],
+        ),
+        None => conn.execute("DELETE FROM admin_totp", params![]),
+    }
+}
+
+/// The transaction takes the write lock before reading, so that logins on
+/// other connections wait instead of using the same code.
+pub fn modify_admin_totp(pool: &Pool, modify: impl FnOnce(&mut AdminTotp) -> bool) -> bool {
+    let mut conn = connection(pool);
+
+    let tx = conn
+        .transaction_with_behavior(TransactionBehavior::Immediate)
+        .expect("Failed to update admin TOTP settings.");
+    let Some(mut admin_totp) = tx
+        .query_row(SELECT_ADMIN_TOTP, params![], admin_totp_from_row)
+        .optional()
+        .expect("Failed to read admin TOTP settings.")
+    else {
+        return false;
+    };
+    if !modify(&mut admin_totp) {
+        return false;
+    }
+
+    execute(
+        &tx,
+        "UPDATE admin_totp SET recovery_codes = ?1, last_step = ?2 WHERE id = 0",
+        params![admin_totp.recovery_codes.join(" "), admin_totp.last_step],
+    )
+    .and_then(|_| tx.commit())
+    .expect("Failed to update admin TOTP settings.");
+    true
+}
+
+/// Full-text search using the FTS5 index, best matches first.
+pub fn search(pool: &Pool, fts_query: &str) -> Vec<SearchHit> {
+    let conn = connection(pool);
+
+    let mut stmt = conn
+        .prepare(
+            "SELECT rowid, snippet(pasta_fts, -1, ?2, ?3, '…', 24) FROM pasta_fts
+            WHERE pasta_fts MATCH ?1
+            ORDER BY rank
+            LIMIT ?4",
+        )
+        .expect("Failed to prepare SQL statement to search pastas");
+
+    let hits = stmt.query_map(
+        params![
+            fts_query,
+            MATCH_START.to_string(),
+            MATCH_END.to_string(),
+            SEARCH_LIMIT
+        ],
+        |row| {
+            let snippet: String = row.get(1)?;
+            Ok(SearchHit {
+                id: row.get(0)?,
+                snippet: highlight(&snippet),
+            })
+        },
+    );
+
+    match hits.and_then(|hits| hits.collect()) {
+        Ok(hits) => hits,
+        Err(e) => {
+            log::error!("Failed to search pastas: {}", e);
+            Vec::new()
+        }
+    }
+}
+
+/// Opens the database and runs a trivial query on it, for the readiness probe.
+pub fn check(pool: &Pool) -> Result<(), String> {
+    let conn = pool.get().map_err(|e| e.to_string())?;
+
+    conn.query_row("SELECT COUNT(*) FROM pasta", params![], |row| {
+        row.get::<_, i64>(0)
+    })
+    .map(|_| ())
+    .map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Pasta table of the first releases, before any migration existed.
+    const ORIGINAL_RELEASE: &str = "
+        CREATE TABLE pasta (
+            id INTEGER PRIMARY KEY,
+            content TEXT NOT NULL,
+            file_name TEXT,
+            file_size INTEGER,
+            extension TEXT NOT NULL,
+            read_only INTEGER NOT NULL,
+            private INTEGER NOT NULL,
+            editable INTEGER NOT NULL,
+            encrypt_server INTEGER NOT NULL,
+            encrypt_client INTEGER NOT NULL,
+            encrypted_key TEXT,
+            created INTEGER NOT NULL,
+            expiration INTEGER NOT NULL,
+            last_read INTEGER NOT NULL,
+            read_count INTEGER NOT NULL,
+            burn_after_reads INTEGER NOT NULL,
+            pasta_type TEXT NOT NULL
+        );
+        INSERT INTO pasta VALUES
+            (1, 'original', 'a.txt', 6, 'txt', 0, 0, 0, 0, 0, NULL, 10, 0, 10, 3, 0, 'text');";
+
+    /// The releases that created the table with hide_read_count.
+    const HIDE_READ_COUNT_RELEASE: &str = "
+        CREATE TABLE pasta (
+            id INTEGER PRIMARY KEY,
+            content TEXT NOT NULL,
+            file_name TEXT,
+            file_size INTEGER,
+            extension TEXT NOT NULL,
+            read_only INTEGER NOT NULL,
+            private INTEGER NOT NULL,
+            editable INTEGER NOT NULL,
+            encrypt_server INTEGER NOT NULL,
+            encrypt_client INTEGER NOT NULL,
+            encrypted_key TEXT,
+            created INTEGER NOT NULL,
+            expiration INTEGER NOT NULL,
+            last_read INTEGER NOT NULL,
+            read_count INTEGER NOT NULL,
+            burn_after_reads INTEGER NOT NULL,
+            pasta_type TEXT NOT NULL,
+            hide_read_count INTEGER NOT NULL
+        );
+        INSERT INTO pasta VALUES
+            (2, 'hidden', NULL, NULL, 'txt', 0, 0, 1, 0, 0, NULL, 20, 0, 20, 5, 0, 'text', 1);";
+
+    fn version(conn: &Connection) -> usize {
+        conn.query_row("SELECT version FROM schema_version", params![], |row| row.get(0))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_migrate_previous_releases() {
+        for (fixture, id) in [(ORIGINAL_RELEASE, 1), (HIDE_READ_COUNT_RELEASE, 2)] {
+            let mut conn = Connection::open_in_memory().unwrap();
+            conn.execute_batch(fixture).unwrap();
+
+            migrate(&mut conn).unwrap();
+            assert_eq!(version(&conn), SCHEMA_VERSION);
+
+            let pastas = select_all(&conn);
+            assert_eq!(pastas.len(), 1);
+            assert_eq!(pastas[0].id, id);
+            assert!(pastas[0].tags.is_empty());
+            assert!(pastas[0].files.is_empty());
+            assert_eq!(pastas[0].hide_read_count, id == 2);
+            assert_eq!(pastas[0].file.as_ref().map(|f| f.name()), (id == 1).then_some("a.txt"));
+
+            // the pastas stored before the search index get indexed
+            let indexed: i64 = conn
+                .query_row("SELECT count(*) FROM pasta_fts WHERE pasta_fts MATCH 'original OR hidden'", params![], |row| row.get(0))
+                .unwrap();
+            assert_eq!(indexed, 1);
+
+            // burn-after-read pastas drop out of the index, read counts
+            // alone do not put them back
+            conn.execute("UPDATE pasta SET burn_after_reads = 1", params![]).unwrap();
+            conn.execute("UPDATE pasta SET read_count = read_count + 1", params![]).unwrap();
+            let indexed: i64 = conn
+                .query_row("SELECT count(*) FROM pasta_fts WHERE pasta_fts MATCH 'original OR hidden'", params![], |row| row.get(0))
+                .unwrap();
+            assert_eq!(indexed, 0);
+
+            // edits of indexed pastas replace their entry
+            conn.execute("UPDATE pasta SET burn_after_reads = 0, content = 'edited'", params![]).unwrap();
+            conn.execute("UPDATE pasta SET content = 'edited twice'", params![]).unwrap();
+            let indexed: i64 = conn
+                .query_row("SELECT count(*) FROM pasta_fts WHERE pasta_fts MATCH 'edited AND twice'", params![], |row| row.get(0))
+                .unwrap();
+            assert_eq!(indexed, 1);
+            let stale: i64 = conn
+                .query_row("SELECT count(*) FROM pasta_fts WHERE pasta_fts MATCH 'original OR hidden'", params![], |row| row.get(0))
+                .unwrap();
+            assert_eq!(stale, 0);
+
+            // text files are indexed by name and content
+            conn.execute(
+                "UPDATE pasta SET files = '[{\"name\":\"notes.md\",\"content\":\"gadget\"}]'",
+                params![],
+            )
+            .unwrap();
+            let indexed: i64 = conn
+                .query_row("SELECT count(*) FROM pasta_fts WHERE pasta_fts MATCH 'notes AND gadget'", params![], |row| row.get(0))
+                .unwrap();
+            assert_eq!(indexed, 1);
+
+            // running again on a current database changes nothing
+            migrate(&mut conn).unwrap();
+            assert_eq!(select_all(&conn).len(), 1);
+        }
+    }
+
+    #[test]
+    fn test_refuse_newer_schema() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        conn.execute("UPDATE schema_version SET version = ?1", params![SCHEMA_VERSION + 1])
+            .unwrap();
+
+        assert!(migrate(&mut conn).is_err());
+    }
 }
@@ -0,0 +1,232 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use argon2::{Argon2, PasswordHash, PasswordVerifier};
+use lazy_static::lazy_static;
+use sha2::{Digest, Sha256};
+
+use crate::args::ARGS;
+
+/// What a basic auth user is allowed to do once logged in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    /// Can view pastas but not create, edit or remove them.
+    Read,
+    /// Full access, the default when a line has no permission field.
+    Upload,
+}
+
+#[derive(Debug)]
+struct User {
+    hash: String,
+    permission: Permission,
+}
+
+#[derive(Default)]
+struct Htpasswd {
+    modified: Option<SystemTime>,
+    users: HashMap<String, User>,
+    // Credentials that already passed verification. bcrypt and argon2 are
+    // slow on purpose and the browser sends the credentials with every
+    // request, so we only want to pay that cost once per login. Keyed on a
+    // digest so no plain text passwords are kept around.
+    verified: HashMap<[u8; 32], Permission>,
+}
+
+lazy_static! {
+    static ref HTPASSWD: Mutex<Htpasswd> = Mutex::new(Htpasswd::default());
+}
+
+/// The htpasswd file, relative paths are resolved inside the data directory.
+pub fn htpasswd_path() -> Option<PathBuf> {
+    let args = ARGS.get();
+    let file = args.auth_basic_htpasswd.as_ref()?.trim();
+    if file.is_empty() {
+        return None;
+    }
+
+    let path = Path::new(file);
+    if path.is_absolute() {
+        Some(path.to_path_buf())
+    } else {
+        Some(Path::new(&ARGS.get().data_dir).join(path))
+    }
+}
+
+pub fn enabled() -> bool {
+    htpasswd_path().is_some()
+}
+
+/// Checks the credentials against the htpasswd file, reloading it first if
+/// it changed on disk. Returns the permission of the user if they are valid.
+pub fn verify(username: &str, password: &str) -> Option<Permission> {
+    let path = htpasswd_path()?;
+    let key = credentials_digest(username, password);
+
+    let hash = {
+        let mut htpasswd = HTPASSWD.lock().unwrap();
+        htpasswd.reload_if_changed(&path);
+
+        if let Some(permission) = htpasswd.verified.get(&key) {
+            return Some(*permission);
+        }
+
+        htpasswd.users.get(username)?.hash.to_owned()
+    };
+
+    // do not hold the lock while hashing, that would serialize all logins
+    if !verify_hash(password, &hash) {
+        return None;
+    }
+
+    let mut htpasswd = HTPASSWD.lock().unwrap();
+    // the file might have been reloaded in the meantime
+    let user = htpasswd.users.get(username)?;
+    if user.hash != hash {
+        return None;
+    }
+    let permission = user.permission;
+    htpasswd.verified.insert(key, permission);
+    Some(permission)
+}
+
+impl Htpasswd {
+    fn reload_if_changed(&mut self, path: &Path) {
+        let modified = fs::metadata(path).and_then(|m| m.modified()).ok();
+        if modified.is_some() && modified == self.modified {
+            return;
+        }
+
+        self.modified = modified;
+        self.verified.clear();
+        self.users = match fs::read_to_string(path) {
+            Ok(contents) => {
+                let users = parse(&contents);
+                log::info!(
+                    "Loaded {} user(s) from htpasswd file {}",
+                    users.len(),
+                    path.display()
+                );
+                users
+            }
+            Err(e) => {
+                log::error!("Failed to read htpasswd file {}: {:?}", path.display(), e);
+                HashMap::new()
+            }
+        };
+    }
+}
+
+/// Parses lines of the form `username:hash[:permission]`. Empty lines and
+/// lines starting with `#` are ignored.
+fn parse(contents: &str) -> HashMap<String, User> {
+    let mut users = HashMap::new();
+
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let mut fields = line.splitn(3, ':');
+        let (Some(username), Some(hash)) = (fields.next(), fields.next()) else {
+            log::warn!("Ignoring malformed htpasswd line {}", number + 1);
+            continue;
+        };
+
+        if !hash.starts_with("$2") && !hash.starts_with("$argon2") {
+            log::warn!(
+                "Ignoring htpasswd user {}: only bcrypt and argon2 hashes are supported",
+                username
+            );
+            continue;
+        }
+
+        let permission = match fields.next().map(str::trim) {
+            None | Some("") | Some("upload") => Permission::Upload,
+            Some("read") => Permission::Read,
+            Some(other) => {
+                // fall back to the least privileged option on typos
+                log::warn!(
+                    "Unknown permission {} for htpasswd user {}, using read",
+                    other,
+                    username
+                );
+                Permission::Read
+            }
+        };
+
+        users.insert(
+            username.to_string(),
+            User {
+                hash: hash.to_string(),
+                permission,
+            },
+        );
+    }
+
+    users
+}
+
+fn verify_hash(password: &str, hash: &str) -> bool {
+    if hash.starts_with("$argon2") {
+        PasswordHash::new(hash)
+            .map(|parsed| {
+                Argon2::default()
+                    .verify_password(password.as_bytes(), &parsed)
+                    .is_ok()
+            })
+            .unwrap_or(false)
+    } else {
+        bcrypt::verify(password, hash).unwrap_or(false)
+    }
+}
+
+fn credentials_digest(username: &str, password: &str) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(username.as_bytes());
+    hasher.update([0]);
+    hasher.update(password.as_bytes());
+    hasher.finalize().into()
+}
+
+#[cfg(test)]
+mod test {
+    use argon2::password_hash::rand_core::OsRng;
+    use argon2::password_hash::SaltString;
+    use argon2::PasswordHasher;
+
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let bcrypt_hash = bcrypt::hash("hunter2", 4).unwrap();
+        let contents = format!(
+            "# comment\n\nalice:{bcrypt_hash}\nbob:{bcrypt_hash}:read\ncarol:plaintext\nbroken\n"
+        );
+
+        let users = parse(&contents);
+        assert_eq!(users.len(), 2);
+        assert_eq!(users["alice"].permission, Permission::Upload);
+        assert_eq!(users["bob"].permission, Permission::Read);
+        assert!(!users.contains_key("carol"));
+    }
+
+    #[test]
+    fn test_verify_hash() {
+        let bcrypt_hash = bcrypt::hash("hunter2", 4).unwrap();
+        assert!(verify_hash("hunter2", &bcrypt_hash));
+        assert!(!verify_hash("hunter3", &bcrypt_hash));
+
+        let salt = SaltString::generate(&mut OsRng);
+        let argon2_hash = Argon2::default()
+            .hash_password(b"hunter2", &salt)
+            .unwrap()
+            .to_string();
+        assert!(verify_hash("hunter2", &argon2_hash));
+        assert!(!verify_hash("hunter3", &argon2_hash));
+    }
+}
@@ -0,0 +1,332 @@
+use bytesize::ByteSize;
+use chrono::{Local, NaiveDate, TimeZone};
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+
+use crate::pasta::Pasta;
+
+const DEFAULT_PER_PAGE: usize = 50;
+const MAX_PER_PAGE: usize = 500;
+
+/// Filter, sort and page parameters of the upload lists, taken from the query
+/// string so that a filtered view can be bookmarked. Every field is a plain
+/// string because empty form inputs are submitted as empty values; anything
+/// empty or unparsable is ignored.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(default)]
+pub struct ListQuery {
+    /// text, url or file
+    #[serde(rename = "type", skip_serializing_if = "String::is_empty")]
+    pub pasta_type: String,
+    /// public or private
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub privacy: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub tag: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub extension: String,
+    /// Sizes like "100", "10KB" or "1.5 MiB"
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub min_size: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub max_size: String,
+    /// Dates as YYYY-MM-DD, both ends inclusive
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub created_from: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub created_to: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub expires_from: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub expires_to: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub min_reads: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub max_reads: String,
+    /// created, expiration, size, reads or last_read
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub sort: String,
+    /// asc or desc
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub order: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub page: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub per_page: String,
+}
+
+/// One page of the filtered and sorted uploads.
+pub struct Page<'a> {
+    pub pastas: Vec<&'a Pasta>,
+    /// 1-based number of this page
+    pub number: usize,
+    pub count: usize,
+    /// Number of uploads matching the filters over all pages
+    pub total: usize,
+}
+
+impl Page<'_> {
+    pub fn has_previous(&self) -> bool {
+        self.number > 1
+    }
+
+    pub fn has_next(&self) -> bool {
+        self.number < self.count
+    }
+}
+
+fn parse_size(size: &str) -> Option<u64> {
+    size.trim().parse::<ByteSize>().ok().map(|s| s.as_u64())
+}
+
+fn parse_date(date: &str) -> Option<NaiveDate> {
+    NaiveDate::parse_from_str(date.trim(), "%Y-%m-%d").ok()
+}
+
+fn start_of_day(date: NaiveDate) -> i64 {
+    date.and_hms_opt(0, 0, 0)
+        .and_then(|time| Local.from_local_datetime(&time).earliest())
+        .map(|time| time.timestamp())
+        .unwrap_or(0)
+}
+
+/// Timestamp of the first second after the given day.
+fn end_of_day(date: NaiveDate) -> i64 {
+    date.succ_opt().map(start_of_day).unwrap_or(i64::MAX)
+}
+
+/// Expiration timestamp where "never" sorts after any date.
+fn expiration_key(pasta: &Pasta) -> i64 {
+    if pasta.expiration == 0 {
+        i64::MAX
+    } else {
+        pasta.expiration
+    }
+}
+
+impl ListQuery {
+    /// Whether any filter is set, as opposed to only sorting or paging.
+    pub fn is_filtered(&self) -> bool {
+        !(self.pasta_type.is_empty()
+            && self.privacy.is_empty()
+            && self.tag.is_empty()
+            && self.extension.is_empty()
+            && self.min_size.is_empty()
+            && self.max_size.is_empty()
+            && self.created_from.is_empty()
+            && self.created_to.is_empty()
+            && self.expires_from.is_empty()
+            && self.expires_to.is_empty()
+            && self.min_reads.is_empty()
+            && self.max_reads.is_empty())
+    }
+
+    /// Query string of the same view on another page.
+    pub fn page_url(&self, number: usize) -> String {
+        let mut query = self.clone();
+        query.page = number.to_string();
+        serde_urlencoded::to_string(&query).unwrap_or_default()
+    }
+
+    fn per_page(&self) -> usize {
+        self.per_page
+            .parse()
+            .unwrap_or(DEFAULT_PER_PAGE)
+            .clamp(1, MAX_PER_PAGE)
+    }
+
+    fn matches(&self, pasta: &Pasta) -> bool {
+        let type_matches = match self.pasta_type.as_str() {
+            "text" | "url" => pasta.pasta_type == self.pasta_type,
+            "file" => pasta.has_file(),
+            _ => true,
+        };
+
+        let privacy_matches = match self.privacy.as_str() {
+            "public" => !pasta.private,
+            "private" => pasta.private,
+            _ => true,
+        };
+
+        let tag_matches = self.tag.is_empty() || pasta.tags.contains(&self.tag);
+
+        let extension = self.extension.trim().trim_start_matches('.');
+        let extension_matches = extension.is_empty()
+            || pasta.extension.eq_ignore_ascii_case(extension)
+            || pasta.file.as_ref().is_some_and(|file| {
+                file.name()
+                    .rsplit_once('.')
+                    .is_some_and(|(_, ext)| ext.eq_ignore_ascii_case(extension))
+            });
+
+        let size = pasta.total_size();
+        let size_matches = parse_size(&self.min_size).map_or(true, |min| size >= min)
+            && parse_size(&self.max_size).map_or(true, |max| size <= max);
+
+        let created_matches = parse_date(&self.created_from)
+            .map_or(true, |date| pasta.created >= start_of_day(date))
+            && parse_date(&self.created_to).map_or(true, |date| pasta.created < end_of_day(date));
+
+        let expiration = expiration_key(pasta);
+        let expiration_matches = parse_date(&self.expires_from)
+            .map_or(true, |date| expiration >= start_of_day(date))
+            && parse_date(&self.expires_to).map_or(true, |date| expiration < end_of_day(date));
+
+        let reads_matches = self
+            .min_reads
+            .parse()
+            .map_or(true, |min: u64| pasta.read_count >= min)
+            && self
+                .max_reads
+                .parse()
+                .map_or(true, |max: u64| pasta.read_count <= max);
+
+        type_matches
+            && privacy_matches
+            && tag_matches
+            && extension_matches
+            && size_matches
+            && created_matches
+            && expiration_matches
+            && reads_matches
+    }
+
+    fn compare(&self, a: &Pasta, b: &Pasta) -> Ordering {
+        let ordering = match self.sort.as_str() {
+            "expiration" => expiration_key(a).cmp(&expiration_key(b)),
+            "size" => a.total_size().cmp(&b.total_size()),
+            "reads" => a.read_count.cmp(&b.read_count),
+            "last_read" => a.last_read.cmp(&b.last_read),
+            _ => a.created.cmp(&b.created),
+        };
+
+        // newest, biggest and most read first unless asked otherwise
+        if self.order == "asc" {
+            ordering
+        } else {
+            ordering.reverse()
+        }
+    }
+
+    /// Filters and sorts the given uploads and cuts out the requested page.
+    pub fn apply<'a>(&self, pastas: impl Iterator<Item = &'a Pasta>) -> Page<'a> {
+        let mut matching: Vec<&Pasta> = pastas.filter(|pasta| self.matches(pasta)).collect();
+        matching.sort_by(|a, b| self.compare(a, b));
+
+        let per_page = self.per_page();
+        let total = matching.len();
+        let count = total.div_ceil(per_page).max(1);
+        let number = self.page.parse().unwrap_or(1).clamp(1, count);
+
+        Page {
+            pastas: matching
+                .into_iter()
+                .skip((number - 1) * per_page)
+                .take(per_page)
+                .collect(),
+            number,
+            count,
+            total,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pasta::PastaFile;
+
+    fn pasta(id: u64, content: &str, created: i64) -> Pasta {
+        Pasta {
+            id,
+            content: String::from(content),
+            file: None,
+            extension: String::from(""),
+            private: false,
+            readonly: false,
+            editable: false,
+            hide_read_count: false,
+            encrypt_server: false,
+            encrypt_client: false,
+            encrypted_key: None,
+            created,
+            expiration: 0,
+            last_read: created,
+            read_count: 0,
+            burn_after_reads: 0,
+            pasta_type: String::from("text"),
+            tags: Vec::new(),
+            files: Vec::new(),
+            uploader: None,
+            uploader_ip: None,
+        }
+    }
+
+    #[test]
+    fn test_filter_sort_and_page() {
+        let mut pastas: Vec<Pasta> = (1..=5).map(|i| pasta(i, "hello", i as i64)).collect();
+        pastas[1].file = Some(PastaFile {
+            name: String::from("notes.TXT"),
+            size: ByteSize::kb(2),
+            sha256: None,
+        });
+        pastas[3].pasta_type = String::from("url");
+        pastas[2].tags = vec![String::from("rust")];
+
+        let query = ListQuery {
+            per_page: String::from("2"),
+            ..Default::default()
+        };
+        let page = query.apply(pastas.iter());
+        assert_eq!(page.total, 5);
+        assert_eq!(page.count, 3);
+        assert_eq!(page.pastas.iter().map(|p| p.id).collect::<Vec<_>>(), [5, 4]);
+        assert!(page.has_next() && !page.has_previous());
+
+        let query = ListQuery {
+            order: String::from("asc"),
+            page: String::from("99"),
+            per_page: String::from("2"),
+            ..Default::default()
+        };
+        let page = query.apply(pastas.iter());
+        assert_eq!(page.number, 3);
+        assert_eq!(page.pastas.iter().map(|p| p.id).collect::<Vec<_>>(), [5]);
+
+        let query = ListQuery {
+            pasta_type: String::from("file"),
+            extension: String::from(".txt"),
+            min_size: String::from("1KB"),
+            ..Default::default()
+        };
+        assert!(query.is_filtered());
+        let page = query.apply(pastas.iter());
+        assert_eq!(page.pastas.iter().map(|p| p.id).collect::<Vec<_>>(), [2]);
+
+        let query = ListQuery {
+            tag: String::from("rust"),
+            ..Default::default()
+        };
+        let page = query.apply(pastas.iter());
+        assert_eq!(page.pastas.iter().map(|p| p.id).collect::<Vec<_>>(), [3]);
+
+        let query = ListQuery {
+            pasta_type: String::from("url"),
+            min_reads: String::from(""),
+            ..Default::default()
+        };
+        let page = query.apply(pastas.iter());
+        assert_eq!(page.pastas.iter().map(|p| p.id).collect::<Vec<_>>(), [4]);
+    }
+
+    #[test]
+    fn test_page_url_keeps_filters() {
+        let query = ListQuery {
+            pasta_type: String::from("text"),
+            sort: String::from("size"),
+            page: String::from("3"),
+            ..Default::default()
+        };
+        assert_eq!(query.page_url(4), "type=text&sort=size&page=4");
+    }
+}
@@ -0,0 +1,255 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use lazy_static::lazy_static;
+
+use crate::pasta::Pasta;
+
+/// Upper bounds in seconds of the request latency histogram buckets.
+const LATENCY_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+#[derive(Default)]
+struct Histogram {
+    /// Observations per bucket, not cumulative. The last one counts the
+    /// observations above the largest bound.
+    buckets: [u64; LATENCY_BUCKETS.len() + 1],
+    sum: f64,
+    count: u64,
+}
+
+#[derive(Default)]
+struct Metrics {
+    created: Mutex<BTreeMap<(String, String), u64>>,
+    reads: AtomicU64,
+    removed: Mutex<BTreeMap<&'static str, u64>>,
+    failed_auth: Mutex<BTreeMap<&'static str, u64>>,
+    latencies: Mutex<BTreeMap<(String, String), Histogram>>,
+}
+
+lazy_static! {
+    static ref METRICS: Metrics = Metrics::default();
+}
+
+/// Privacy level of the pasta as chosen in the upload form.
+pub fn privacy(pasta: &Pasta) -> &'static str {
+    if pasta.encrypt_client {
+        "secret"
+    } else if pasta.encrypt_server {
+        "private"
+    } else if pasta.readonly {
+        "readonly"
+    } else if pasta.private {
+        "unlisted"
+    } else {
+        "public"
+    }
+}
+
+pub fn pasta_created(pasta: &Pasta) {
+    let pasta_type = if pasta.file.is_some() {
+        "file"
+    } else {
+        pasta.pasta_type.as_str()
+    };
+    *METRICS
+        .created
+        .lock()
+        .unwrap()
+        .entry((pasta_type.to_string(), privacy(pasta).to_string()))
+        .or_insert(0) += 1;
+}
+
+pub fn pasta_read() {
+    METRICS.reads.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Counts a pasta removed by `remove_expired` or evicted to stay within the
+/// storage limit. The reason is one of "expired", "burned", "gc" or
+/// "evicted".
+pub fn pasta_removed(reason: &'static str) {
+    *METRICS.removed.lock().unwrap().entry(reason).or_insert(0) += 1;
+}
+
+/// Counts a rejected password. The kind is one of "basic", "admin",
+/// "uploader", "pasta" or "metrics".
+pub fn failed_auth(kind: &'static str) {
+    *METRICS.failed_auth.lock().unwrap().entry(kind).or_insert(0) += 1;
+}
+
+pub fn observe_request(method: &str, route: &str, duration: Duration) {
+    let seconds = duration.as_secs_f64();
+    let bucket = LATENCY_BUCKETS
+        .iter()
+        .position(|bound| seconds <= *bound)
+        .unwrap_or(LATENCY_BUCKETS.len());
+
+    let mut latencies = METRICS.latencies.lock().unwrap();
+    let histogram = latencies
+        .entry((method.to_string(), route.to_string()))
+        .or_default();
+    histogram.buckets[bucket] += 1;
+    histogram.sum += seconds;
+    histogram.count += 1;
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// Renders all metrics in the Prometheus text exposition format. The gauges
+/// are computed from the pastas currently in memory.
+pub fn render(pastas: &[Pasta]) -> String {
+    let mut out = String::new();
+
+    header(
+        &mut out,
+        "microbin_pastas_created_total",
+        "counter",
+        "Pastas created, by type and privacy.",
+    );
+    for ((pasta_type, privacy), count) in METRICS.created.lock().unwrap().iter() {
+        let _ = writeln!(
+            out,
+            "microbin_pastas_created_total{{type=\"{}\",privacy=\"{}\"}} {}",
+            escape(pasta_type),
+            privacy,
+            count
+        );
+    }
+
+    header(
+        &mut out,
+        "microbin_pasta_reads_total",
+        "counter",
+        "Pasta views and raw reads.",
+    );
+    let _ = writeln!(
+        out,
+        "microbin_pasta_reads_total {}",
+        METRICS.reads.load(Ordering::Relaxed)
+    );
+
+    header(
+        &mut out,
+        "microbin_pastas_removed_total",
+        "counter",
+        "Pastas removed because they expired, were burned, were garbage collected or were evicted.",
+    );
+    for (reason, count) in METRICS.removed.lock().unwrap().iter() {
+        let _ = writeln!(
+            out,
+            "microbin_pastas_removed_total{{reason=\"{}\"}} {}",
+            reason, count
+        );
+    }
+
+    header(
+        &mut out,
+        "microbin_failed_auth_total",
+        "counter",
+        "Rejected passwords, by kind of login.",
+    );
+    for (kind, count) in METRICS.failed_auth.lock().unwrap().iter() {
+        let _ = writeln!(
+            out,
+            "microbin_failed_auth_total{{kind=\"{}\"}} {}",
+            kind, count
+        );
+    }
+
+    header(
+        &mut out,
+        "microbin_pastas",
+        "gauge",
+        "Pastas currently stored.",
+    );
+    let _ = writeln!(out, "microbin_pastas {}", pastas.len());
+
+    header(
+        &mut out,
+        "microbin_attachment_bytes",
+        "gauge",
+        "Total size of the stored attachments in bytes.",
+    );
+    let attachment_bytes: u64 = pastas
+        .iter()
+        .filter_map(|pasta| pasta.file.as_ref())
+        .map(|file| file.size.as_u64())
+        .sum();
+    let _ = writeln!(out, "microbin_attachment_bytes {}", attachment_bytes);
+
+    header(
+        &mut out,
+        "microbin_request_duration_seconds",
+        "histogram",
+        "Request latencies, by method and route.",
+    );
+    for ((method, route), histogram) in METRICS.latencies.lock().unwrap().iter() {
+        let labels = format!("method=\"{}\",route=\"{}\"", escape(method), escape(route));
+        let mut cumulative = 0;
+        for (bound, count) in LATENCY_BUCKETS.iter().zip(histogram.buckets.iter()) {
+            cumulative += count;
+            let _ = writeln!(
+                out,
+                "microbin_request_duration_seconds_bucket{{{},le=\"{}\"}} {}",
+                labels, bound, cumulative
+            );
+        }
+        let _ = writeln!(
+            out,
+            "microbin_request_duration_seconds_bucket{{{},le=\"+Inf\"}} {}",
+            labels, histogram.count
+        );
+        let _ = writeln!(
+            out,
+            "microbin_request_duration_seconds_sum{{{}}} {}",
+            labels, histogram.sum
+        );
+        let _ = writeln!(
+            out,
+            "microbin_request_duration_seconds_count{{{}}} {}",
+            labels, histogram.count
+        );
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_histogram_buckets_are_cumulative() {
+        observe_request("GET", "/test/{id}", Duration::from_millis(20));
+        observe_request("GET", "/test/{id}", Duration::from_secs(20));
+
+        let out = render(&[]);
+        assert!(out.contains(
+            "microbin_request_duration_seconds_bucket{method=\"GET\",route=\"/test/{id}\",le=\"0.01\"} 0"
+        ));
+        assert!(out.contains(
+            "microbin_request_duration_seconds_bucket{method=\"GET\",route=\"/test/{id}\",le=\"0.025\"} 1"
+        ));
+        assert!(out.contains(
+            "microbin_request_duration_seconds_bucket{method=\"GET\",route=\"/test/{id}\",le=\"10\"} 1"
+        ));
+        assert!(out.contains(
+            "microbin_request_duration_seconds_bucket{method=\"GET\",route=\"/test/{id}\",le=\"+Inf\"} 2"
+        ));
+        assert!(out.contains("microbin_pastas 0"));
+    }
+}
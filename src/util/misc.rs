@@ -2,69 +2,152 @@ use crate::args::ARGS;
 use linkify::{LinkFinder, LinkKind};
 use magic_crypt::{new_magic_crypt, MagicCryptTrait};
 use qrcode_generator::QrCodeEcc;
+use rand::Rng;
 use std::fs::{self, File};
-use std::io::{BufReader, Read, Write};
+use std::io::{self, BufReader, Read, Write};
 use std::path::Path;
 use std::time::{SystemTime, UNIX_EPOCH};
 
 use crate::Pasta;
 
-use super::db::delete;
+use super::blobs;
+use super::db::{self, delete_expired, Database};
+use super::metrics;
+use super::store;
+use super::webhooks::{self, Event};
 
-pub fn remove_expired(pastas: &mut Vec<Pasta>) {
+const MAX_TAGS: usize = 10;
+const MAX_TAG_LENGTH: usize = 32;
+
+/// Draws of a 16-bit id before a new id is drawn from the whole 64-bit range.
+const SHORT_ID_ATTEMPTS: usize = 16;
+
+pub fn remove_expired(db: &Database, pastas: &mut Vec<Pasta>) {
+    let timenow = timenow();
+    let removed = take_expired(pastas, timenow);
+
+    // a shared database is swept by `sweep_expired` in the background, so
+    // requests only leave out the expired pastas they have in memory
+    if db::is_shared() {
+        return;
+    }
+    delete_expired_pastas(db, removed, pastas, timenow);
+}
+
+/// Removes the expired pastas from a shared database, by the same rules as
+/// `remove_expired`. Runs on the blocking thread pool with every flush.
+pub fn sweep_expired(db: &Database) {
+    let timenow = timenow();
+    let mut pastas = db::read_all(db);
+    let removed = take_expired(&mut pastas, timenow);
+    delete_expired_pastas(db, removed, &pastas, timenow);
+}
+
+fn timenow() -> i64 {
     // get current time - this will be needed to check which pastas have expired
-    let timenow: i64 = match SystemTime::now().duration_since(UNIX_EPOCH) {
+    (match SystemTime::now().duration_since(UNIX_EPOCH) {
         Ok(n) => n.as_secs(),
         Err(_) => {
             log::error!("SystemTime before UNIX EPOCH!");
             0
         }
-    } as i64;
-
-    pastas.retain(|p| {
-        // keep if:
-        //  expiration is `never` or not reached
-        //  AND
-        //  read count is less than burn limit, or no limit set
-        //  AND
-        //  has been read in the last N days where N is the arg --gc-days OR N is 0 (no GC)
-        if (p.expiration == 0 || p.expiration > timenow)
-            && (p.read_count < p.burn_after_reads || p.burn_after_reads == 0)
-            && (p.last_read_days_ago() < ARGS.gc_days || ARGS.gc_days == 0)
-        {
-            // keep
-            true
+    }) as i64
+}
+
+/// Takes the expired pastas out of `pastas`.
+fn take_expired(pastas: &mut Vec<Pasta>, timenow: i64) -> Vec<Pasta> {
+    let gc_days = ARGS.get().gc_days;
+    let (kept, removed): (Vec<Pasta>, Vec<Pasta>) = std::mem::take(pastas)
+        .into_iter()
+        .partition(|p| {
+            // keep if:
+            //  expiration is `never` or not reached
+            //  AND
+            //  read count is less than burn limit, or no limit set
+            //  AND
+            //  has been read in the last N days where N is the arg --gc-days OR N is 0 (no GC)
+            (p.expiration == 0 || p.expiration > timenow)
+                && (p.read_count < p.burn_after_reads || p.burn_after_reads == 0)
+                && (p.last_read_days_ago() < gc_days || gc_days == 0)
+        });
+    *pastas = kept;
+    removed
+}
+
+/// Deletes the expired pastas taken out of `pastas` from the database, along
+/// with their attachments.
+fn delete_expired_pastas(db: &Database, removed: Vec<Pasta>, pastas: &[Pasta], timenow: i64) {
+    let gc_days = ARGS.get().gc_days;
+    for p in removed {
+        // remove from database, unless another instance sharing it has
+        // removed or changed the pasta in the meantime
+        if !delete_expired(db, p.id, timenow, gc_days) {
+            continue;
+        }
+
+        let burned = p.burn_after_reads != 0 && p.read_count >= p.burn_after_reads;
+        metrics::pasta_removed(if p.expiration != 0 && p.expiration <= timenow {
+            "expired"
+        } else if burned {
+            "burned"
         } else {
-            // remove from database
-            delete(None, Some(p.id));
-
-            // remove the file itself
-            if let Some(file) = &p.file {
-                if fs::remove_file(format!(
-                    "{}/attachments/{}/{}",
-                    ARGS.data_dir,
-                    p.id_as_animals(),
-                    file.name()
-                ))
-                .is_err()
-                {
-                    log::error!("Failed to delete file {}!", file.name())
-                }
-
-                // and remove the containing directory
-                if fs::remove_dir(format!(
-                    "{}/attachments/{}/",
-                    ARGS.data_dir,
-                    p.id_as_animals()
-                ))
-                .is_err()
-                {
-                    log::error!("Failed to delete directory {}!", file.name())
-                }
-            }
-            false
+            "gc"
+        });
+        // pastas removed for not being read in a while count as expired too
+        webhooks::notify(if burned { Event::Burned } else { Event::Expired }, &p);
+
+        // remove the file itself, once all expired pastas are gone so shared
+        // blobs are released with their last reference
+        remove_attachment(db, &p, pastas);
+    }
+}
+
+/// Deletes the attachment of the pasta from the attachment store, if it has one.
+/// Attachments in the blob store are only deleted if none of the other
+/// `pastas` references them. Callers hold the pastas locked and the store may
+/// be remote, so the attachment is deleted on the blocking thread pool.
+pub fn remove_attachment(db: &Database, pasta: &Pasta, pastas: &[Pasta]) {
+    let is_blob = pasta.file.as_ref().is_some_and(|file| file.sha256.is_some());
+    let key = if is_blob {
+        blobs::release(db, pasta, pastas)
+    } else {
+        store::attachment_key(pasta)
+    };
+    let Some(key) = key else {
+        return;
+    };
+
+    let db = db.clone();
+    let remove = move || match store::remove(&db, &key) {
+        // pastas removed together may share a blob that is already gone
+        Err(e) if is_blob && e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => log::error!("Failed to delete attachment {}: {:?}", key, e),
+        Ok(()) => {}
+    };
+    match tokio::runtime::Handle::try_current() {
+        Ok(runtime) => drop(runtime.spawn_blocking(remove)),
+        // outside of the server, as in tests
+        Err(_) => remove(),
+    }
+}
+
+/// Random id for a new pasta or collection that `is_taken` accepts. Ids are
+/// drawn from 16 bits first, as they make the short animal names in links,
+/// and from 64 bits once the short ids get crowded.
+pub fn unused_id(is_taken: impl Fn(u64) -> bool) -> u64 {
+    let mut rng = rand::thread_rng();
+    for _ in 0..SHORT_ID_ATTEMPTS {
+        let id = rng.gen::<u16>() as u64;
+        if !is_taken(id) {
+            return id;
+        }
+    }
+    loop {
+        let id = rng.gen::<u64>();
+        if !is_taken(id) {
+            return id;
         }
-    });
+    }
 }
 
 pub fn string_to_qr_svg(str: &str) -> String {
@@ -77,6 +160,29 @@ pub fn is_valid_url(url: &str) -> bool {
     spans[0].as_str() == url && Some(&LinkKind::Url) == spans[0].kind()
 }
 
+/// Turns user input like "Rust, web-dev  #example" into a list of tags.
+/// Tags are lowercase, may only contain letters, digits, '-' and '_', and are
+/// limited in length and number.
+pub fn parse_tags(input: &str) -> Vec<String> {
+    let mut tags: Vec<String> = Vec::new();
+
+    for tag in input.split(|c: char| c == ',' || c.is_whitespace()) {
+        let tag: String = tag
+            .chars()
+            .filter(|c| c.is_alphanumeric() || *c == '-' || *c == '_')
+            .flat_map(char::to_lowercase)
+            .take(MAX_TAG_LENGTH)
+            .collect();
+
+        if !tag.is_empty() && !tags.contains(&tag) {
+            tags.push(tag);
+        }
+    }
+
+    tags.truncate(MAX_TAGS);
+    tags
+}
+
 pub fn encrypt(text_str: &str, key_str: &str) -> String {
     if text_str.is_empty() {
         return String::from("");
@@ -130,7 +236,7 @@ pub fn encrypt_file(
 
 pub fn decrypt_file(
     passphrase: &str,
-    input_file: &File,
+    input_file: impl Read,
 ) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
     // Read the input file into memory
     let mut reader = BufReader::new(input_file);
@@ -148,3 +254,26 @@ pub fn decrypt_file(
 
     Ok(res.unwrap())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_tags() {
+        assert_eq!(
+            parse_tags("Rust, web-dev  #example,rust,, a/b"),
+            ["rust", "web-dev", "example", "ab"]
+        );
+        assert!(parse_tags(" , ").is_empty());
+        assert_eq!(parse_tags(&"t ".repeat(20)).len(), 1);
+        assert_eq!(parse_tags(&"x".repeat(100))[0].len(), MAX_TAG_LENGTH);
+    }
+
+    #[test]
+    fn test_unused_id() {
+        assert!(unused_id(|id| id == 7) != 7);
+        // with every short id taken the id is drawn from 64 bits
+        assert!(unused_id(|id| id <= u16::MAX as u64) > u16::MAX as u64);
+    }
+}
@@ -0,0 +1,279 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::args::ARGS;
+use crate::pasta::Pasta;
+use crate::util::db::{delete, Database};
+use crate::util::metrics;
+use crate::util::misc::remove_attachment;
+use crate::util::webhooks::{self, Event};
+
+const MB: u64 = 1024 * 1024;
+
+fn sha256(pasta: &Pasta) -> Option<&str> {
+    pasta.file.as_ref()?.sha256.as_deref()
+}
+
+fn attachment_size(pasta: &Pasta) -> u64 {
+    pasta.file.as_ref().map_or(0, |file| file.size.as_u64())
+}
+
+/// Bytes the pastas take in storage. An attachment in the blob store is
+/// counted once, however many of the pastas reference it.
+fn stored_size<'a>(pastas: impl IntoIterator<Item = &'a Pasta>) -> u64 {
+    let mut blobs = HashSet::new();
+    pastas
+        .into_iter()
+        .map(|pasta| match sha256(pasta) {
+            Some(sha256) if !blobs.insert(sha256) => pasta.total_size() - attachment_size(pasta),
+            _ => pasta.total_size(),
+        })
+        .sum()
+}
+
+/// Stored size of the pastas `covers` accepts together with `new_pasta`.
+fn size_with(pastas: &[Pasta], new_pasta: &Pasta, covers: impl Fn(&Pasta) -> bool) -> u64 {
+    stored_size(pastas.iter().filter(|p| covers(p)).chain(std::iter::once(new_pasta)))
+}
+
+/// Ids of the expiring pastas to evict to free at least `needed` bytes, in
+/// the order of the eviction policy. A blob is only freed along with the last
+/// pasta referencing it. None when evicting every expiring pasta would not be
+/// enough, or eviction is disabled.
+fn evictions(pastas: &[Pasta], new_pasta: &Pasta, needed: u64, policy: &str) -> Option<Vec<u64>> {
+    let mut candidates: Vec<&Pasta> = pastas.iter().filter(|p| evictable(p, policy)).collect();
+    match policy {
+        "oldest" => candidates.sort_by_key(|p| p.created),
+        "least_read" => candidates.sort_by_key(|p| (p.read_count, p.created)),
+        _ => return None,
+    }
+
+    let mut references: HashMap<&str, usize> = HashMap::new();
+    for sha256 in pastas.iter().chain(std::iter::once(new_pasta)).filter_map(sha256) {
+        *references.entry(sha256).or_default() += 1;
+    }
+
+    let mut freed = 0;
+    let mut ids = Vec::new();
+    for pasta in candidates {
+        if freed >= needed {
+            break;
+        }
+        freed += pasta.total_size() - attachment_size(pasta);
+        let last_reference = sha256(pasta).map_or(true, |sha256| {
+            let count = references.get_mut(sha256).unwrap();
+            *count -= 1;
+            *count == 0
+        });
+        if last_reference {
+            freed += attachment_size(pasta);
+        }
+        ids.push(pasta.id);
+    }
+
+    if freed >= needed {
+        Some(ids)
+    } else {
+        None
+    }
+}
+
+fn evictable(pasta: &Pasta, policy: &str) -> bool {
+    pasta.expiration != 0 && matches!(policy, "oldest" | "least_read")
+}
+
+/// A storage quota of the uploader of a pasta: the pastas it covers may
+/// take `bytes` together.
+struct Quota<'a> {
+    bytes: u64,
+    covers: Box<dyn Fn(&Pasta) -> bool + 'a>,
+    message: String,
+}
+
+fn quotas(new_pasta: &Pasta) -> Vec<Quota<'_>> {
+    let mut quotas = Vec::new();
+
+    if let (Some(uploader), quota) = (&new_pasta.uploader, ARGS.get().quota_per_user_mb) {
+        if quota > 0 {
+            quotas.push(Quota {
+                bytes: quota * MB,
+                covers: Box::new(move |p: &Pasta| p.uploader.as_ref() == Some(uploader)),
+                message: format!(
+                    "Upload rejected: it would exceed your storage quota of {} MB.",
+                    quota
+                ),
+            });
+        }
+    }
+
+    if let (Some(uploader_ip), quota) = (&new_pasta.uploader_ip, ARGS.get().quota_per_ip_mb) {
+        if quota > 0 {
+            quotas.push(Quota {
+                bytes: quota * MB,
+                covers: Box::new(move |p: &Pasta| p.uploader_ip.as_ref() == Some(uploader_ip)),
+                message: format!(
+                    "Upload rejected: it would exceed the storage quota of {} MB for your address.",
+                    quota
+                ),
+            });
+        }
+    }
+
+    quotas
+}
+
+const STORAGE_LIMIT_MESSAGE: &str = "Upload rejected: the storage limit of this server has been reached.";
+
+/// How many more bytes `new_pasta` can grow by before a quota of its
+/// uploader or the storage limit rejects it, with the message to reject it
+/// with. Checked while an attachment streams in, which counts in full as it
+/// is not known yet whether the blob store already has it. None without
+/// limits.
+pub fn room(pastas: &[Pasta], new_pasta: &Pasta) -> Option<(u64, String)> {
+    let mut rooms: Vec<(u64, String)> = quotas(new_pasta)
+        .into_iter()
+        .map(|quota| {
+            let used = size_with(pastas, new_pasta, &quota.covers);
+            (quota.bytes.saturating_sub(used), quota.message)
+        })
+        .collect();
+
+    if ARGS.get().storage_limit_mb > 0 {
+        // pastas that can be evicted make room for this one
+        let policy = &ARGS.get().eviction;
+        let kept = size_with(pastas, new_pasta, |p| !evictable(p, policy));
+        rooms.push((
+            (ARGS.get().storage_limit_mb * MB).saturating_sub(kept),
+            String::from(STORAGE_LIMIT_MESSAGE),
+        ));
+    }
+
+    rooms.into_iter().min_by_key(|(bytes, _)| *bytes)
+}
+
+/// Checks the quotas of the uploader and the storage limit of the server
+/// before `new_pasta` is stored. When the storage limit would be exceeded,
+/// expiring pastas are evicted according to the eviction policy: they are
+/// taken out of `pastas` and returned, to be deleted with `delete_evicted`
+/// along with the write of the new pasta. Returns the message for the
+/// uploader if the pasta can not be stored.
+pub fn make_room(pastas: &mut Vec<Pasta>, new_pasta: &Pasta) -> Result<Vec<Pasta>, String> {
+    for quota in quotas(new_pasta) {
+        if size_with(pastas, new_pasta, &quota.covers) > quota.bytes {
+            return Err(quota.message);
+        }
+    }
+
+    if ARGS.get().storage_limit_mb == 0 {
+        return Ok(Vec::new());
+    }
+
+    let used = size_with(pastas, new_pasta, |_| true);
+    let limit = ARGS.get().storage_limit_mb * MB;
+    if used <= limit {
+        return Ok(Vec::new());
+    }
+
+    let Some(ids) = evictions(pastas, new_pasta, used - limit, &ARGS.get().eviction) else {
+        return Err(String::from(STORAGE_LIMIT_MESSAGE));
+    };
+
+    let mut evicted = Vec::new();
+    for id in ids {
+        let Some(index) = pastas.iter().position(|p| p.id == id) else {
+            continue;
+        };
+        log::info!(
+            "Evicting pasta {} to stay within the storage limit",
+            pastas[index].id_as_animals()
+        );
+        webhooks::notify(Event::Evicted, &pastas[index]);
+        evicted.push(pastas.remove(index));
+        metrics::pasta_removed("evicted");
+    }
+
+    Ok(evicted)
+}
+
+/// Deletes the pastas evicted by `make_room` from the database, along with
+/// their attachments.
+pub fn delete_evicted(db: &Database, evicted: &[Pasta], pastas: Option<&Vec<Pasta>>) {
+    for pasta in evicted {
+        delete(db, pastas, Some(pasta.id));
+        remove_attachment(db, pasta, pastas.map_or(&[], Vec::as_slice));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pasta::PastaFile;
+    use bytesize::ByteSize;
+
+    fn pasta(id: u64, size: usize, created: i64, read_count: u64, expiration: i64) -> Pasta {
+        Pasta {
+            id,
+            content: "x".repeat(size),
+            file: None,
+            extension: String::from(""),
+            private: false,
+            readonly: false,
+            editable: false,
+            hide_read_count: false,
+            encrypt_server: false,
+            encrypt_client: false,
+            encrypted_key: None,
+            created,
+            expiration,
+            last_read: created,
+            read_count,
+            burn_after_reads: 0,
+            pasta_type: String::from("text"),
+            tags: Vec::new(),
+            files: Vec::new(),
+            uploader: None,
+            uploader_ip: None,
+        }
+    }
+
+    #[test]
+    fn test_evictions_follow_policy() {
+        let pastas = vec![
+            pasta(1, 10, 1, 5, 100),
+            pasta(2, 10, 2, 0, 100),
+            // never expires, so it is never evicted
+            pasta(3, 100, 0, 0, 0),
+            pasta(4, 10, 3, 1, 100),
+        ];
+
+        let new_pasta = pasta(5, 10, 4, 0, 100);
+        assert_eq!(evictions(&pastas, &new_pasta, 15, "oldest"), Some(vec![1, 2]));
+        assert_eq!(evictions(&pastas, &new_pasta, 15, "least_read"), Some(vec![2, 4]));
+        assert_eq!(evictions(&pastas, &new_pasta, 31, "oldest"), None);
+        assert_eq!(evictions(&pastas, &new_pasta, 5, "none"), None);
+    }
+
+    #[test]
+    fn test_shared_blobs_count_once() {
+        let with_blob = |id: u64, created: i64| {
+            let mut pasta = pasta(id, 1, created, 0, 100);
+            pasta.file = Some(PastaFile {
+                name: String::from("a.bin"),
+                size: ByteSize::b(100),
+                sha256: Some(String::from("aa")),
+            });
+            pasta
+        };
+        let pastas = vec![with_blob(1, 1), with_blob(2, 2), pasta(3, 10, 3, 0, 100)];
+
+        assert_eq!(stored_size(&pastas), 112);
+
+        // the blob is only freed once both pastas referencing it are evicted
+        let new_pasta = pasta(4, 10, 4, 0, 100);
+        assert_eq!(evictions(&pastas, &new_pasta, 2, "oldest"), Some(vec![1, 2]));
+        assert_eq!(evictions(&pastas, &new_pasta, 3, "oldest"), Some(vec![1, 2]));
+        assert_eq!(evictions(&pastas, &new_pasta, 103, "oldest"), Some(vec![1, 2, 3]));
+
+        // a new pasta with the same blob keeps it stored
+        assert_eq!(evictions(&pastas, &with_blob(4, 4), 103, "oldest"), None);
+    }
+}
@@ -0,0 +1,116 @@
+use std::collections::HashSet;
+use std::future::Future;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use actix_web::web;
+use futures::future::{ready, Either};
+use lazy_static::lazy_static;
+
+use crate::util::db::{self, update, Database, Records};
+use crate::util::metrics;
+use crate::util::webhooks::{self, Event};
+use crate::Pasta;
+
+/// How often the read counters collected in memory are written to the
+/// database.
+pub const FLUSH_INTERVAL: Duration = Duration::from_secs(30);
+
+lazy_static! {
+    /// Ids of the pastas read since the last flush.
+    static ref PENDING: Mutex<HashSet<u64>> = Mutex::new(HashSet::new());
+}
+
+/// Counts a read of the pasta and updates its last read time. The change is
+/// written with the next flush instead of right away, except for pastas
+/// that burn after a number of reads, whose count must survive a crash, and
+/// for a shared database, which counts the reads of all instances. A shared
+/// database counts the read on the blocking thread pool, without the lock on
+/// the pastas, so the lock is taken again and returned with the index of the
+/// pasta. Returns None if the pasta may not be read anymore, because another
+/// instance sharing the database burned or removed it.
+pub fn record<'a>(
+    db: &Database,
+    records: &'a Records<Pasta>,
+    mut pastas: MutexGuard<'a, Vec<Pasta>>,
+    index: usize,
+) -> impl Future<Output = Option<(MutexGuard<'a, Vec<Pasta>>, usize)>> + 'a {
+    let timenow: i64 = match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(n) => n.as_secs(),
+        Err(_) => {
+            log::error!("SystemTime before UNIX EPOCH!");
+            0
+        }
+    } as i64;
+
+    if !db::is_shared() {
+        pastas[index].read_count += 1;
+        pastas[index].last_read = timenow;
+        if pastas[index].burn_after_reads != 0 {
+            update(db, Some(&pastas), Some(&pastas[index]));
+        } else {
+            PENDING.lock().unwrap().insert(pastas[index].id);
+        }
+        return Either::Left(ready(Some(counted(pastas, index))));
+    }
+
+    let id = pastas[index].id;
+    drop(pastas);
+    let db = db.clone();
+    Either::Right(async move {
+        let read_count = match web::block(move || db::record_read(&db, id, timenow)).await {
+            Ok(read_count) => read_count?,
+            Err(e) => {
+                log::error!("Failed to count the read of pasta {}: {}", id, e);
+                return None;
+            }
+        };
+
+        let mut pastas = records.lock().unwrap();
+        let index = pastas.iter().position(|pasta| pasta.id == id)?;
+        pastas[index].read_count = read_count;
+        pastas[index].last_read = timenow;
+        Some(counted(pastas, index))
+    })
+}
+
+fn counted(pastas: MutexGuard<'_, Vec<Pasta>>, index: usize) -> (MutexGuard<'_, Vec<Pasta>>, usize) {
+    metrics::pasta_read();
+    if pastas[index].read_count == 1 {
+        webhooks::notify(Event::FirstRead, &pastas[index]);
+    }
+    (pastas, index)
+}
+
+/// Read counter of a pasta, copied out of the pastas so it can be written
+/// without holding their lock.
+pub struct ReadCount {
+    pub id: u64,
+    pub read_count: u64,
+    pub last_read: i64,
+}
+
+/// Takes the read counters of all pastas read since the last flush. Pastas
+/// that were removed in the meantime are skipped.
+pub fn take_pending(pastas: &[Pasta]) -> Vec<ReadCount> {
+    let pending = std::mem::take(&mut *PENDING.lock().unwrap());
+    pastas
+        .iter()
+        .filter(|pasta| pending.contains(&pasta.id))
+        .map(|pasta| ReadCount {
+            id: pasta.id,
+            read_count: pasta.read_count,
+            last_read: pasta.last_read,
+        })
+        .collect()
+}
+
+/// Writes the read counters taken by `take_pending`. Only the counters are
+/// written, and never lowered, so an edit or a read of the pasta written in
+/// the meantime is kept. `pastas` are all pastas when they are locked, which
+/// the JSON database needs to compact its journal.
+pub fn flush(db: &Database, counts: &[ReadCount], pastas: Option<&Vec<Pasta>>) {
+    if !counts.is_empty() {
+        db::update_read_counts(db, counts, pastas);
+    }
+}
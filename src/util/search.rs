@@ -0,0 +1,234 @@
+use crate::pasta::Pasta;
+
+/// Maximum number of results returned for one search.
+pub const SEARCH_LIMIT: usize = 50;
+
+/// Markers around matched terms in raw snippets. They can not appear in
+/// uploads typed into a browser, so they survive HTML escaping unambiguously.
+pub const MATCH_START: char = '\u{2}';
+pub const MATCH_END: char = '\u{3}';
+
+/// Number of characters of context shown on either side of the first match.
+const SNIPPET_CONTEXT: usize = 60;
+
+pub struct SearchHit {
+    pub id: u64,
+    /// HTML excerpt of the upload with the matched terms in `<mark>` tags
+    pub snippet: String,
+}
+
+/// Only uploads that could be listed publicly are searchable. Encrypted
+/// uploads are left out as well, their content is not readable anyway, and
+/// so are burn-after-read uploads, whose content must not be readable
+/// without counting as a read.
+pub fn searchable(pasta: &Pasta) -> bool {
+    !pasta.private && !pasta.encrypt_server && !pasta.encrypt_client && pasta.burn_after_reads == 0
+}
+
+/// Splits the search box input into lowercase terms.
+pub fn terms(query: &str) -> Vec<String> {
+    query
+        .split_whitespace()
+        .map(|term| term.to_lowercase())
+        .collect()
+}
+
+/// Turns the search terms into an FTS5 query that matches uploads containing
+/// every term as a prefix. Each term is quoted so user input can not be
+/// interpreted as FTS5 syntax.
+pub fn fts_query(terms: &[String]) -> String {
+    terms
+        .iter()
+        .map(|term| format!("\"{}\"*", term.replace('"', "\"\"")))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+/// Escapes a raw snippet and turns the match markers into `<mark>` tags.
+pub fn highlight(snippet: &str) -> String {
+    html_escape::encode_text(snippet)
+        .replace(MATCH_START, "<mark>")
+        .replace(MATCH_END, "</mark>")
+}
+
+/// Lowercases every character on its own, so that positions in the folded
+/// text are the same as in the original.
+fn fold(text: &str) -> Vec<char> {
+    text.chars()
+        .map(|c| c.to_lowercase().next().unwrap_or(c))
+        .collect()
+}
+
+fn find(haystack: &[char], needle: &[char]) -> Option<usize> {
+    if needle.is_empty() || haystack.len() < needle.len() {
+        return None;
+    }
+    (0..=haystack.len() - needle.len()).find(|&i| haystack[i..].starts_with(needle))
+}
+
+/// Excerpt of `text` around the first matching term, with every match
+/// wrapped in the match markers. None if no term occurs in the text.
+fn snippet(text: &str, terms: &[Vec<char>]) -> Option<String> {
+    let chars: Vec<char> = text.chars().collect();
+    let folded = fold(text);
+
+    let first = terms.iter().filter_map(|t| find(&folded, t)).min()?;
+    let start = first.saturating_sub(SNIPPET_CONTEXT);
+    let end = (first + SNIPPET_CONTEXT * 2).min(chars.len());
+
+    let mut result = String::new();
+    if start > 0 {
+        result.push('…');
+    }
+
+    let mut i = start;
+    while i < end {
+        match terms
+            .iter()
+            .filter(|t| folded[i..].starts_with(t))
+            .map(|t| t.len())
+            .max()
+        {
+            Some(len) => {
+                result.push(MATCH_START);
+                result.extend(&chars[i..(i + len).min(chars.len())]);
+                result.push(MATCH_END);
+                i += len;
+            }
+            None => {
+                result.push(chars[i]);
+                i += 1;
+            }
+        }
+    }
+
+    if end < chars.len() {
+        result.push('…');
+    }
+    Some(result)
+}
+
+/// The texts of a pasta that are searched: its content, the name of its
+/// attachment and the names and contents of its text files.
+fn texts(pasta: &Pasta) -> impl Iterator<Item = &str> {
+    std::iter::once(pasta.content.as_str())
+        .chain(pasta.file.as_ref().map(|f| f.name()))
+        .chain(
+            pasta
+                .files
+                .iter()
+                .flat_map(|f| [f.name.as_str(), f.content.as_str()]),
+        )
+}
+
+/// Plain scan over the uploads in memory, used instead of a full-text index
+/// by the JSON and PostgreSQL databases. Matches uploads whose texts contain
+/// every term, newest first.
+pub fn scan(pastas: &[Pasta], terms: &[String]) -> Vec<SearchHit> {
+    let terms: Vec<Vec<char>> = terms.iter().map(|t| fold(t)).collect();
+    if terms.is_empty() {
+        return Vec::new();
+    }
+
+    let mut matching: Vec<&Pasta> = pastas
+        .iter()
+        .filter(|pasta| searchable(pasta))
+        .filter(|pasta| {
+            let texts: Vec<Vec<char>> = texts(pasta).map(fold).collect();
+            terms
+                .iter()
+                .all(|t| texts.iter().any(|text| find(text, t).is_some()))
+        })
+        .collect();
+    matching.sort_by_key(|pasta| std::cmp::Reverse(pasta.created));
+
+    matching
+        .into_iter()
+        .take(SEARCH_LIMIT)
+        .map(|pasta| {
+            let raw = texts(pasta)
+                .find_map(|text| snippet(text, &terms))
+                .unwrap_or_default();
+            SearchHit {
+                id: pasta.id,
+                snippet: highlight(&raw),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pasta::TextFile;
+
+    fn pasta(id: u64, content: &str) -> Pasta {
+        Pasta {
+            id,
+            content: String::from(content),
+            file: None,
+            extension: String::from(""),
+            private: false,
+            readonly: false,
+            editable: false,
+            hide_read_count: false,
+            encrypt_server: false,
+            encrypt_client: false,
+            encrypted_key: None,
+            created: id as i64,
+            expiration: 0,
+            last_read: id as i64,
+            read_count: 0,
+            burn_after_reads: 0,
+            pasta_type: String::from("text"),
+            tags: Vec::new(),
+            files: Vec::new(),
+            uploader: None,
+            uploader_ip: None,
+        }
+    }
+
+    #[test]
+    fn test_scan_skips_unlisted() {
+        let mut pastas: Vec<Pasta> = (1..=4).map(|i| pasta(i, "secret plans")).collect();
+        pastas[1].private = true;
+        pastas[2].encrypt_server = true;
+        pastas[3].burn_after_reads = 1;
+
+        let hits = scan(&pastas, &terms("plans"));
+        assert_eq!(hits.iter().map(|hit| hit.id).collect::<Vec<_>>(), [1]);
+    }
+
+    #[test]
+    fn test_scan_matches_text_files() {
+        let mut pastas = vec![pasta(1, "")];
+        pastas[0].files = vec![TextFile {
+            name: String::from("main.rs"),
+            content: String::from("fn main() {}"),
+        }];
+
+        let hits = scan(&pastas, &terms("main fn"));
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].snippet, "<mark>main</mark>.rs");
+        assert!(scan(&pastas, &terms("struct")).is_empty());
+    }
+
+    #[test]
+    fn test_fts_query_quotes_terms() {
+        assert_eq!(
+            fts_query(&terms("Hello wor\"ld")),
+            "\"hello\"* \"wor\"\"ld\"*"
+        );
+    }
+
+    #[test]
+    fn test_snippet_marks_matches() {
+        let terms = vec![fold("rust")];
+        let raw = snippet("I like <b>Rust</b> and rust", &terms).unwrap();
+        assert_eq!(
+            highlight(&raw),
+            "I like &lt;b&gt;<mark>Rust</mark>&lt;/b&gt; and <mark>rust</mark>"
+        );
+        assert!(snippet("nothing here", &terms).is_none());
+    }
+}
@@ -0,0 +1,133 @@
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use actix_web::{Error, HttpRequest, HttpResponse};
+
+use crate::args::ARGS;
+use crate::util::blobs;
+use crate::util::db::Database;
+use crate::Pasta;
+
+/// Attachments are addressed by keys relative to the data directory, like
+/// `blobs/ab/ab12...` or `attachments/<slug>/data.enc`. Uploads are staged at
+/// that path in the data directory before they are put into the store.
+pub fn local_path(key: &str) -> PathBuf {
+    PathBuf::from(format!("{}/{}", ARGS.get().data_dir, key))
+}
+
+/// Key of the attachment of the pasta, if it has one. Unencrypted attachments
+/// are in the blob store, encrypted ones in the directory of the pasta.
+pub fn attachment_key(pasta: &Pasta) -> Option<String> {
+    let file = pasta.file.as_ref()?;
+    Some(if let Some(sha256) = &file.sha256 {
+        blobs::blob_key(sha256)
+    } else if pasta.encrypt_server && !pasta.readonly {
+        format!("attachments/{}/data.enc", pasta.id_as_animals())
+    } else {
+        format!("attachments/{}/{}", pasta.id_as_animals(), file.name())
+    })
+}
+
+fn is_s3() -> bool {
+    ARGS.get().attachment_store == "s3"
+}
+
+#[cfg(feature = "default")]
+fn is_sqlite() -> bool {
+    ARGS.get().attachment_store == "sqlite"
+}
+
+/// Moves the staged file at `path` into the store under `key`.
+#[allow(unused)]
+pub fn put(db: &Database, key: &str, path: &Path) -> io::Result<()> {
+    #[cfg(feature = "default")]
+    if is_sqlite() {
+        return super::store_sqlite::put(db.sqlite(), key, path);
+    }
+    if is_s3() {
+        super::store_s3::put(&super::store_s3::bucket(), key, path)
+    } else {
+        super::store_fs::put(key, path)
+    }
+}
+
+#[allow(unused)]
+pub fn exists(db: &Database, key: &str) -> io::Result<bool> {
+    #[cfg(feature = "default")]
+    if is_sqlite() {
+        return super::store_sqlite::exists(db.sqlite(), key);
+    }
+    if is_s3() {
+        super::store_s3::exists(&super::store_s3::bucket(), key)
+    } else {
+        super::store_fs::exists(key)
+    }
+}
+
+/// Opens the attachment as a local file, downloading it first if the store
+/// is remote.
+#[allow(unused)]
+pub fn open(db: &Database, key: &str) -> io::Result<File> {
+    #[cfg(feature = "default")]
+    if is_sqlite() {
+        return super::store_sqlite::open(db.sqlite(), key);
+    }
+    if is_s3() {
+        super::store_s3::open(&super::store_s3::bucket(), key)
+    } else {
+        super::store_fs::open(key)
+    }
+}
+
+#[allow(unused)]
+pub fn read(db: &Database, key: &str) -> io::Result<Vec<u8>> {
+    #[cfg(feature = "default")]
+    if is_sqlite() {
+        return super::store_sqlite::read(db.sqlite(), key);
+    }
+    if is_s3() {
+        super::store_s3::read(&super::store_s3::bucket(), key)
+    } else {
+        super::store_fs::read(key)
+    }
+}
+
+/// Removes the attachment. Removing a missing one fails with `NotFound` on
+/// the filesystem and succeeds on S3 and SQLite.
+#[allow(unused)]
+pub fn remove(db: &Database, key: &str) -> io::Result<()> {
+    #[cfg(feature = "default")]
+    if is_sqlite() {
+        return super::store_sqlite::remove(db.sqlite(), key);
+    }
+    if is_s3() {
+        super::store_s3::remove(&super::store_s3::bucket(), key)
+    } else {
+        super::store_fs::remove(key)
+    }
+}
+
+/// Streams the attachment as a download named `name`, honouring the Range
+/// header of the request.
+#[allow(unused)]
+pub async fn serve(db: &Database, req: &HttpRequest, key: &str, name: &str) -> Result<HttpResponse, Error> {
+    #[cfg(feature = "default")]
+    if is_sqlite() {
+        return super::store_sqlite::serve(db.sqlite(), req, key, name).await;
+    }
+    if is_s3() {
+        super::store_s3::serve(&super::store_s3::bucket(), req, key, name).await
+    } else {
+        super::store_fs::serve(req, key, name)
+    }
+}
+
+/// Checks that the store can be reached, for the readiness probe.
+pub fn check() -> Result<(), String> {
+    if is_s3() {
+        super::store_s3::check(&super::store_s3::bucket())
+    } else {
+        Ok(())
+    }
+}
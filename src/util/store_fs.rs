@@ -0,0 +1,52 @@
+use std::fs::{self, File};
+use std::io;
+use std::path::Path;
+
+use actix_web::http::header;
+use actix_web::{Error, HttpRequest, HttpResponse};
+
+use super::store::local_path;
+
+pub fn put(key: &str, path: &Path) -> io::Result<()> {
+    let target = local_path(key);
+    if path == target {
+        return Ok(());
+    }
+
+    fs::create_dir_all(target.parent().unwrap())?;
+    fs::rename(path, target)
+}
+
+pub fn exists(key: &str) -> io::Result<bool> {
+    Ok(local_path(key).exists())
+}
+
+pub fn open(key: &str) -> io::Result<File> {
+    File::open(local_path(key))
+}
+
+pub fn read(key: &str) -> io::Result<Vec<u8>> {
+    fs::read(local_path(key))
+}
+
+/// Removes the file and its directory, once it is empty.
+pub fn remove(key: &str) -> io::Result<()> {
+    let path = local_path(key);
+    fs::remove_file(&path)?;
+    let _ = fs::remove_dir(path.parent().unwrap());
+    Ok(())
+}
+
+pub fn serve(req: &HttpRequest, key: &str, name: &str) -> Result<HttpResponse, Error> {
+    // This will stream the file and set the content type based on the file
+    // name
+    let file = File::open(local_path(key))?;
+    let file_reponse = actix_files::NamedFile::from_file(file, name)?;
+    let file_reponse = file_reponse.set_content_disposition(header::ContentDisposition {
+        disposition: header::DispositionType::Attachment,
+        parameters: vec![header::DispositionParam::Filename(name.to_string())],
+    });
+    // This takes care of streaming/seeking using the Range header in the
+    // request.
+    Ok(file_reponse.into_response(req))
+}
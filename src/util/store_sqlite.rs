@@ -0,0 +1,206 @@
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use actix_files::HttpRange;
+use actix_web::http::header;
+use actix_web::web::{self, Bytes};
+use actix_web::{Error, HttpRequest, HttpResponse};
+use rusqlite::{params, OptionalExtension};
+
+use crate::util::db_sqlite::{connection, Pool};
+use crate::util::store::local_path;
+
+/// Attachments are split into rows of this size, so that neither storing
+/// nor serving one holds the whole file in memory.
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Moves the staged file into the database and removes it from the data
+/// directory.
+pub fn put(pool: &Pool, key: &str, path: &Path) -> io::Result<()> {
+    let mut file = File::open(path)?;
+    let mut conn = connection(pool);
+    let tx = conn.transaction().map_err(io::Error::other)?;
+
+    tx.execute("DELETE FROM attachment_chunk WHERE key = ?1", params![key])
+        .map_err(io::Error::other)?;
+    let mut size = 0;
+    for seq in 0.. {
+        let mut chunk = Vec::with_capacity(CHUNK_SIZE);
+        (&mut file).take(CHUNK_SIZE as u64).read_to_end(&mut chunk)?;
+        if chunk.is_empty() {
+            break;
+        }
+        size += chunk.len();
+        tx.execute(
+            "INSERT INTO attachment_chunk (key, seq, data) VALUES (?1, ?2, ?3)",
+            params![key, seq, chunk],
+        )
+        .map_err(io::Error::other)?;
+    }
+    tx.execute(
+        "INSERT OR REPLACE INTO attachment (key, size) VALUES (?1, ?2)",
+        params![key, size],
+    )
+    .map_err(io::Error::other)?;
+    tx.commit().map_err(io::Error::other)?;
+
+    fs::remove_file(path)?;
+    if let Some(directory) = path.parent() {
+        let _ = fs::remove_dir(directory);
+    }
+    Ok(())
+}
+
+fn size(pool: &Pool, key: &str) -> io::Result<Option<u64>> {
+    connection(pool)
+        .prepare_cached("SELECT size FROM attachment WHERE key = ?1")
+        .and_then(|mut stmt| stmt.query_row(params![key], |row| row.get(0)).optional())
+        .map_err(io::Error::other)
+}
+
+fn chunk(pool: &Pool, key: &str, seq: u64) -> io::Result<Vec<u8>> {
+    connection(pool)
+        .prepare_cached("SELECT data FROM attachment_chunk WHERE key = ?1 AND seq = ?2")
+        .and_then(|mut stmt| stmt.query_row(params![key, seq], |row| row.get(0)))
+        .map_err(io::Error::other)
+}
+
+fn not_found(key: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("{} is not in the database", key))
+}
+
+pub fn exists(pool: &Pool, key: &str) -> io::Result<bool> {
+    Ok(size(pool, key)?.is_some())
+}
+
+/// Writes the attachment into a temporary file in the data directory, which
+/// is unlinked right away.
+pub fn open(pool: &Pool, key: &str) -> io::Result<File> {
+    let size = size(pool, key)?.ok_or_else(|| not_found(key))?;
+    let path = local_path(&format!(".download-{}", rand::random::<u64>()));
+    let mut file = File::options()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(&path)?;
+    let _ = fs::remove_file(&path);
+
+    for seq in 0..size.div_ceil(CHUNK_SIZE as u64) {
+        file.write_all(&chunk(pool, key, seq)?)?;
+    }
+    file.seek(SeekFrom::Start(0))?;
+    Ok(file)
+}
+
+pub fn read(pool: &Pool, key: &str) -> io::Result<Vec<u8>> {
+    let size = size(pool, key)?.ok_or_else(|| not_found(key))?;
+    let mut data = Vec::with_capacity(size as usize);
+    for seq in 0..size.div_ceil(CHUNK_SIZE as u64) {
+        data.extend(chunk(pool, key, seq)?);
+    }
+    Ok(data)
+}
+
+pub fn remove(pool: &Pool, key: &str) -> io::Result<()> {
+    let mut conn = connection(pool);
+    let tx = conn.transaction().map_err(io::Error::other)?;
+    tx.execute("DELETE FROM attachment_chunk WHERE key = ?1", params![key])
+        .map_err(io::Error::other)?;
+    tx.execute("DELETE FROM attachment WHERE key = ?1", params![key])
+        .map_err(io::Error::other)?;
+    tx.commit().map_err(io::Error::other)
+}
+
+/// Streams the attachment chunk by chunk, or the part of it asked for in the
+/// Range header. Chunks are read on the blocking thread pool.
+pub async fn serve(pool: &Pool, req: &HttpRequest, key: &str, name: &str) -> Result<HttpResponse, Error> {
+    let (pool, key) = (pool.clone(), key.to_string());
+    let size = {
+        let (pool, key) = (pool.clone(), key.clone());
+        web::block(move || size(&pool, &key)).await??
+    };
+    let Some(size) = size else {
+        return Ok(HttpResponse::NotFound().finish());
+    };
+
+    let mut builder = HttpResponse::Ok();
+    builder
+        .insert_header((header::ACCEPT_RANGES, "bytes"))
+        .insert_header((
+            header::CONTENT_TYPE,
+            mime_guess::from_path(name)
+                .first_or_octet_stream()
+                .to_string(),
+        ))
+        .insert_header(header::ContentDisposition {
+            disposition: header::DispositionType::Attachment,
+            parameters: vec![header::DispositionParam::Filename(name.to_string())],
+        });
+
+    let range = req
+        .headers()
+        .get(header::RANGE)
+        .and_then(|value| value.to_str().ok());
+    let (start, length) = match range.map(|range| HttpRange::parse(range, size)) {
+        None => (0, size),
+        Some(Ok(ranges)) if !ranges.is_empty() => {
+            let range = ranges[0];
+            builder.status(actix_web::http::StatusCode::PARTIAL_CONTENT);
+            builder.insert_header((
+                header::CONTENT_RANGE,
+                format!(
+                    "bytes {}-{}/{}",
+                    range.start,
+                    range.start + range.length - 1,
+                    size
+                ),
+            ));
+            (range.start, range.length)
+        }
+        Some(_) => {
+            return Ok(HttpResponse::RangeNotSatisfiable()
+                .insert_header((header::CONTENT_RANGE, format!("bytes */{}", size)))
+                .finish());
+        }
+    };
+    builder.no_chunking(length);
+
+    let end = start + length;
+    let body = futures::stream::unfold(start, move |offset| {
+        let (pool, key) = (pool.clone(), key.clone());
+        async move {
+            if offset >= end {
+                return None;
+            }
+            let seq = offset / CHUNK_SIZE as u64;
+            let chunk_start = seq * CHUNK_SIZE as u64;
+            let to = (end - chunk_start).min(CHUNK_SIZE as u64) as usize;
+            let from = (offset - chunk_start) as usize;
+            let data = {
+                let key = key.clone();
+                web::block(move || chunk(&pool, &key, seq))
+                    .await
+                    .map_err(io::Error::other)
+                    .and_then(|data| data)
+            };
+            // a chunk shorter than the size of the attachment says would
+            // otherwise end the download early without an error
+            match data {
+                Ok(data) if data.len() >= to => Some((
+                    Ok(Bytes::from(data).slice(from..to)),
+                    chunk_start + to as u64,
+                )),
+                Ok(_) => Some((
+                    Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        format!("Chunk {} of {} is shorter than the attachment", seq, key),
+                    )),
+                    end,
+                )),
+                Err(e) => Some((Err(e), end)),
+            }
+        }
+    });
+    Ok(builder.streaming(body))
+}
@@ -27,7 +27,7 @@ pub fn start_telemetry_thread() {
 
 fn send_telemetry() -> Result<(), reqwest::Error> {
     // Convert the telemetry object to JSON
-    let json_body = json!(ARGS.to_owned().without_secrets().to_owned()).to_string();
+    let json_body = json!(ARGS.get().as_ref().clone().without_secrets()).to_string();
 
     // Send the telemetry data to the API
     crate::util::http_client::new()
@@ -0,0 +1,182 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rand::distributions::{Alphanumeric, DistString};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use totp_rs::{Algorithm, Secret, TOTP};
+
+use crate::args::ARGS;
+use crate::util::db::{modify_admin_totp, read_admin_totp, Database};
+
+const RECOVERY_CODE_COUNT: usize = 8;
+
+const STEP_SECONDS: u64 = 30;
+
+/// Codes of this many time steps before and after the current one are
+/// accepted too, for clocks that are a little off.
+const SKEW_STEPS: u64 = 1;
+
+/// TOTP enrollment of the admin account as it is kept in the database.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct AdminTotp {
+    /// Base32 encoded shared secret
+    pub secret: String,
+    /// SHA-256 hashes of the unused recovery codes
+    pub recovery_codes: Vec<String>,
+    /// Time step of the last accepted code. Codes of this step and older
+    /// ones are rejected, so that a code can not be used twice.
+    #[serde(default)]
+    pub last_step: u64,
+}
+
+pub fn enabled(db: &Database) -> bool {
+    read_admin_totp(db).is_some()
+}
+
+/// A fresh 160 bit secret, base32 encoded like authenticator apps expect it.
+pub fn new_secret() -> String {
+    let bytes: [u8; 20] = rand::thread_rng().gen();
+    match Secret::Raw(bytes.to_vec()).to_encoded() {
+        Secret::Encoded(secret) => secret,
+        Secret::Raw(_) => unreachable!("to_encoded always returns an encoded secret"),
+    }
+}
+
+fn totp(secret: &str) -> Option<TOTP> {
+    let secret = Secret::Encoded(secret.to_string()).to_bytes().ok()?;
+    TOTP::new(
+        Algorithm::SHA1,
+        6,
+        0,
+        STEP_SECONDS,
+        secret,
+        Some(String::from("MicroBin")),
+        ARGS.get().auth_admin_username.replace(':', "_"),
+    )
+    .ok()
+}
+
+/// The otpauth:// URL authenticator apps scan from the QR code.
+pub fn otpauth_url(secret: &str) -> String {
+    totp(secret).map(|t| t.get_url()).unwrap_or_default()
+}
+
+/// Returns the time step of the code if it is current and newer than
+/// `last_step`, the step of the last accepted code.
+pub fn check_code(secret: &str, code: &str, last_step: u64) -> Option<u64> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs();
+    check_code_at(secret, code, last_step, now)
+}
+
+fn check_code_at(secret: &str, code: &str, last_step: u64, now: u64) -> Option<u64> {
+    let code: String = code.chars().filter(|c| !c.is_whitespace()).collect();
+    let totp = totp(secret)?;
+    let current = now / STEP_SECONDS;
+    (current.saturating_sub(SKEW_STEPS)..=current + SKEW_STEPS)
+        .filter(|step| *step > last_step)
+        .find(|step| totp.check(&code, step * STEP_SECONDS))
+}
+
+/// Returns the plain recovery codes to show the admin once, and their hashes
+/// to store.
+pub fn new_recovery_codes() -> (Vec<String>, Vec<String>) {
+    let mut rng = rand::thread_rng();
+    let codes: Vec<String> = (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            let code = Alphanumeric.sample_string(&mut rng, 10).to_lowercase();
+            format!("{}-{}", &code[..5], &code[5..])
+        })
+        .collect();
+    let hashes = codes.iter().map(|c| hash_recovery_code(c)).collect();
+    (codes, hashes)
+}
+
+fn hash_recovery_code(code: &str) -> String {
+    let normalized: String = code
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+
+    Sha256::digest(normalized.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Checks the second factor of an admin login. Accepts either a current TOTP
+/// code that was not used before or an unused recovery code, which is then
+/// used up. Always succeeds when TOTP is not set up.
+pub fn verify_login(db: &Database, code: &str) -> bool {
+    if !enabled(db) {
+        return true;
+    }
+
+    let hash = hash_recovery_code(code);
+    let mut recovery_codes_left = None;
+    let verified = modify_admin_totp(db, |admin_totp| {
+        if let Some(step) = check_code(&admin_totp.secret, code, admin_totp.last_step) {
+            admin_totp.last_step = step;
+            return true;
+        }
+
+        let Some(index) = admin_totp.recovery_codes.iter().position(|c| *c == hash) else {
+            return false;
+        };
+        admin_totp.recovery_codes.remove(index);
+        recovery_codes_left = Some(admin_totp.recovery_codes.len());
+        true
+    });
+
+    if let Some(left) = recovery_codes_left {
+        log::warn!("Admin logged in with a recovery code, {} left", left);
+    }
+    verified
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_check_code() {
+        let secret = new_secret();
+        let code = totp(&secret).unwrap().generate_current().unwrap();
+        let step = check_code(&secret, &code, 0).unwrap();
+        assert_eq!(
+            check_code(&secret, &format!(" {} {} ", &code[..3], &code[3..]), 0),
+            Some(step)
+        );
+        assert_eq!(check_code(&secret, "abcdef", 0), None);
+    }
+
+    #[test]
+    fn test_check_code_rejects_used_steps() {
+        let secret = new_secret();
+        let now = 1_700_000_000;
+        let step = now / STEP_SECONDS;
+        let code = totp(&secret).unwrap().generate(now);
+
+        assert_eq!(check_code_at(&secret, &code, step - 1, now), Some(step));
+        // the same code again, or after a newer one was accepted
+        assert_eq!(check_code_at(&secret, &code, step, now), None);
+        assert_eq!(check_code_at(&secret, &code, step + 1, now), None);
+
+        // the code of the previous step is still accepted while it is unused
+        let previous = totp(&secret).unwrap().generate(now - STEP_SECONDS);
+        assert_eq!(check_code_at(&secret, &previous, 0, now), Some(step - 1));
+        assert_eq!(check_code_at(&secret, &previous, step - 1, now), None);
+    }
+
+    #[test]
+    fn test_recovery_code_hash_ignores_formatting() {
+        let (codes, hashes) = new_recovery_codes();
+        assert_eq!(codes.len(), RECOVERY_CODE_COUNT);
+        assert_eq!(hash_recovery_code(&codes[0]), hashes[0]);
+        assert_eq!(
+            hash_recovery_code(&codes[0].to_uppercase().replace('-', " ")),
+            hashes[0]
+        );
+    }
+}
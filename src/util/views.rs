@@ -0,0 +1,244 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Mutex;
+
+use actix_web::http::header;
+use actix_web::HttpRequest;
+use chrono::{Days, Local, NaiveDate, TimeZone};
+use lazy_static::lazy_static;
+
+use crate::args::ARGS;
+use crate::util::db::{self, Database};
+use crate::view::View;
+use crate::Pasta;
+
+/// Days shown in the chart of the views page.
+pub const CHART_DAYS: u64 = 30;
+
+/// How often views older than the retention are removed, in seconds.
+const PRUNE_INTERVAL: i64 = 60 * 60;
+
+lazy_static! {
+    /// Views recorded since the last flush.
+    static ref PENDING: Mutex<Vec<View>> = Mutex::new(Vec::new());
+}
+
+static LAST_PRUNE: AtomicI64 = AtomicI64::new(0);
+
+/// Views of one day, for the chart of the views page.
+pub struct Day {
+    pub date: String,
+    pub count: usize,
+    /// Count relative to the busiest day shown
+    pub percent: usize,
+}
+
+/// Logs a view of the pasta, unless the view log is disabled. Like the read
+/// counters, views are written with the next flush.
+pub fn record(req: &HttpRequest, pasta: &Pasta) {
+    if ARGS.get().disable_view_log {
+        return;
+    }
+
+    let header = |name| {
+        req.headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+    };
+    let view = View {
+        pasta_id: pasta.id,
+        viewed_at: Local::now().timestamp(),
+        referrer: header(header::REFERER).and_then(referrer_host),
+        agent: String::from(coarse_agent(header(header::USER_AGENT).unwrap_or(""))),
+        ip: if ARGS.get().view_log_ip {
+            req.connection_info().realip_remote_addr().and_then(anonymize)
+        } else {
+            None
+        },
+    };
+    PENDING.lock().unwrap().push(view);
+}
+
+/// Takes the views recorded since the last flush, except those of pastas
+/// removed in the meantime.
+pub fn take_pending(pastas: &[Pasta]) -> Vec<View> {
+    std::mem::take(&mut *PENDING.lock().unwrap())
+        .into_iter()
+        .filter(|view| pastas.iter().any(|pasta| pasta.id == view.pasta_id))
+        .collect()
+}
+
+/// Writes the views taken by `take_pending` and removes views older than the
+/// retention once an hour.
+pub fn flush(db: &Database, views: &[View]) {
+    if !views.is_empty() {
+        db::insert_views(db, views);
+    }
+
+    let now = Local::now().timestamp();
+    if ARGS.get().view_log_retention_days > 0
+        && now - LAST_PRUNE.load(Ordering::Relaxed) >= PRUNE_INTERVAL
+    {
+        LAST_PRUNE.store(now, Ordering::Relaxed);
+        db::delete_views_before(db, now - ARGS.get().view_log_retention_days as i64 * 24 * 60 * 60);
+    }
+}
+
+/// Views of the pasta, newest first, including those not flushed yet.
+pub fn read(db: &Database, pasta_id: u64) -> Vec<View> {
+    let mut views: Vec<View> = PENDING
+        .lock()
+        .unwrap()
+        .iter()
+        .rev()
+        .filter(|view| view.pasta_id == pasta_id)
+        .cloned()
+        .collect();
+    views.extend(db::read_views(db, pasta_id));
+    views
+}
+
+/// Counts the views of each of the last `CHART_DAYS` days, oldest first.
+pub fn per_day(views: &[View], today: NaiveDate) -> Vec<Day> {
+    let dates: Vec<NaiveDate> = (0..CHART_DAYS)
+        .rev()
+        .filter_map(|days| today.checked_sub_days(Days::new(days)))
+        .collect();
+    let counts: Vec<usize> = dates
+        .iter()
+        .map(|date| {
+            views
+                .iter()
+                .filter(|view| {
+                    Local
+                        .timestamp_opt(view.viewed_at, 0)
+                        .earliest()
+                        .map(|viewed| viewed.date_naive() == *date)
+                        .unwrap_or(false)
+                })
+                .count()
+        })
+        .collect();
+    let busiest = counts.iter().copied().max().unwrap_or(0).max(1);
+
+    dates
+        .iter()
+        .zip(counts)
+        .map(|(date, count)| Day {
+            date: date.format("%Y-%m-%d").to_string(),
+            count,
+            percent: count * 100 / busiest,
+        })
+        .collect()
+}
+
+/// Host of the Referer header, without scheme, credentials, port or path.
+/// Anything that is not a host name or address is dropped, as it ends up on
+/// the views page as it is.
+fn referrer_host(referrer: &str) -> Option<String> {
+    let rest = referrer.split_once("://").map_or(referrer, |(_, rest)| rest);
+    let authority = rest.split(['/', '?', '#']).next()?;
+    let host = authority.rsplit('@').next()?;
+    let host = match host.strip_prefix('[') {
+        Some(ipv6) => ipv6.split(']').next()?,
+        None => host.split(':').next()?,
+    };
+    if host.is_empty()
+        || !host
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == ':')
+    {
+        None
+    } else {
+        Some(host.to_lowercase())
+    }
+}
+
+/// Reduces a User-Agent header to the browser or client family. Order
+/// matters, as most browsers also claim to be the ones they are based on.
+fn coarse_agent(user_agent: &str) -> &'static str {
+    let user_agent = user_agent.to_lowercase();
+    let families = [
+        ("bot", "Bot"),
+        ("crawler", "Bot"),
+        ("spider", "Bot"),
+        ("curl/", "curl"),
+        ("wget/", "Wget"),
+        ("edg/", "Edge"),
+        ("opr/", "Opera"),
+        ("firefox/", "Firefox"),
+        ("chrome/", "Chrome"),
+        ("chromium/", "Chrome"),
+        ("safari/", "Safari"),
+    ];
+    families
+        .iter()
+        .find(|(marker, _)| user_agent.contains(marker))
+        .map(|(_, family)| *family)
+        .unwrap_or(if user_agent.is_empty() { "Unknown" } else { "Other" })
+}
+
+/// Zeroes the host part of an address: the last byte of IPv4 addresses and
+/// everything after the /48 prefix of IPv6 addresses.
+fn anonymize(address: &str) -> Option<String> {
+    let ip = address
+        .parse::<IpAddr>()
+        .or_else(|_| address.parse::<SocketAddr>().map(|socket| socket.ip()))
+        .ok()?;
+    Some(match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            Ipv4Addr::new(a, b, c, 0).to_string()
+        }
+        IpAddr::V6(ip) => {
+            let [a, b, c, ..] = ip.segments();
+            Ipv6Addr::new(a, b, c, 0, 0, 0, 0, 0).to_string()
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_anonymized_fields() {
+        assert_eq!(referrer_host("https://user@Example.com:8080/a?b").as_deref(), Some("example.com"));
+        assert_eq!(referrer_host("http://[::1]:80/").as_deref(), Some("::1"));
+        assert_eq!(referrer_host(""), None);
+        assert_eq!(referrer_host("https://<script>/"), None);
+
+        assert_eq!(coarse_agent("Mozilla/5.0 (X11; Linux x86_64; rv:131.0) Gecko/20100101 Firefox/131.0"), "Firefox");
+        assert_eq!(coarse_agent("Mozilla/5.0 AppleWebKit/537.36 Chrome/130.0 Safari/537.36 Edg/130.0"), "Edge");
+        assert_eq!(coarse_agent("Googlebot/2.1"), "Bot");
+        assert_eq!(coarse_agent(""), "Unknown");
+
+        assert_eq!(anonymize("203.0.113.42:51234").as_deref(), Some("203.0.113.0"));
+        assert_eq!(anonymize("2001:db8:1:2::5").as_deref(), Some("2001:db8:1::"));
+        assert_eq!(anonymize("unknown"), None);
+    }
+
+    #[test]
+    fn test_per_day() {
+        let today = NaiveDate::from_ymd_opt(2024, 3, 10).unwrap();
+        let at = |day, hour| View {
+            pasta_id: 1,
+            viewed_at: Local
+                .with_ymd_and_hms(2024, 3, day, hour, 0, 0)
+                .earliest()
+                .unwrap()
+                .timestamp(),
+            referrer: None,
+            agent: String::from("Other"),
+            ip: None,
+        };
+        let views = [at(10, 9), at(10, 23), at(9, 0), at(1, 12)];
+
+        let days = per_day(&views, today);
+        assert_eq!(days.len(), CHART_DAYS as usize);
+        assert_eq!(days.last().unwrap().date, "2024-03-10");
+        let counts: Vec<(usize, usize)> = days[days.len() - 2..].iter().map(|day| (day.count, day.percent)).collect();
+        assert_eq!(counts, [(1, 50), (2, 100)]);
+        assert_eq!(days.iter().map(|day| day.count).sum::<usize>(), 4);
+    }
+}
@@ -0,0 +1,310 @@
+use std::collections::VecDeque;
+use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use chrono::{Local, TimeZone};
+use hmac::{Hmac, Mac};
+use lazy_static::lazy_static;
+use serde_json::{json, Value};
+use sha2::Sha256;
+
+use crate::args::ARGS;
+use crate::Pasta;
+
+/// Waits before the retries of a failed delivery. A delivery is given up
+/// after the last one.
+const RETRY_DELAYS: [Duration; 3] = [
+    Duration::from_secs(5),
+    Duration::from_secs(30),
+    Duration::from_secs(120),
+];
+
+/// Time a webhook has to answer a delivery.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Deliveries kept for the admin page.
+const LOG_SIZE: usize = 100;
+
+/// Deliveries waiting for the worker. Events beyond this are dropped rather
+/// than piling up while a webhook is slow.
+const QUEUE_SIZE: usize = 1000;
+
+lazy_static! {
+    /// Most recent deliveries, newest first.
+    static ref LOG: Mutex<VecDeque<Delivery>> = Mutex::new(VecDeque::new());
+    /// Deliveries for the worker thread, which is started with the first one.
+    static ref QUEUE: SyncSender<Job> = {
+        let (sender, receiver) = sync_channel(QUEUE_SIZE);
+        thread::spawn(move || work(receiver));
+        sender
+    };
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Event {
+    Created,
+    Edited,
+    FirstRead,
+    Burned,
+    Expired,
+    Evicted,
+    Deleted,
+}
+
+impl Event {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Event::Created => "created",
+            Event::Edited => "edited",
+            Event::FirstRead => "first_read",
+            Event::Burned => "burned",
+            Event::Expired => "expired",
+            Event::Evicted => "evicted",
+            Event::Deleted => "deleted",
+        }
+    }
+}
+
+/// Outcome of sending one event to one webhook, for the admin page.
+#[derive(Clone, Debug)]
+pub struct Delivery {
+    pub event: &'static str,
+    pub pasta: String,
+    pub url: String,
+    pub attempts: usize,
+    /// HTTP status or error of the last attempt
+    pub result: String,
+    pub success: bool,
+    pub delivered_at: i64,
+}
+
+impl Delivery {
+    pub fn delivered_at_as_string(&self) -> String {
+        Local
+            .timestamp_opt(self.delivered_at, 0)
+            .earliest()
+            .map(|date| date.format("%Y-%m-%d %H:%M:%S").to_string())
+            .unwrap_or_default()
+    }
+}
+
+/// One event on its way to one webhook.
+struct Job {
+    event: Event,
+    slug: String,
+    url: String,
+    body: String,
+    signature: String,
+    attempts: usize,
+}
+
+/// The configured webhook URLs.
+pub fn urls() -> Vec<String> {
+    ARGS.get().webhook_urls
+        .as_deref()
+        .unwrap_or("")
+        .split(',')
+        .map(str::trim)
+        .filter(|url| !url.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// Deliveries since MicroBin started, newest first.
+pub fn deliveries() -> Vec<Delivery> {
+    LOG.lock().unwrap().iter().cloned().collect()
+}
+
+/// Sends the event to every configured webhook. The payload is built right
+/// away and the deliveries are queued for the worker thread.
+pub fn notify(event: Event, pasta: &Pasta) {
+    let urls = urls();
+    if urls.is_empty() {
+        return;
+    }
+
+    let body = payload(event, pasta, Local::now().timestamp()).to_string();
+    let signature = sign(ARGS.get().webhook_secret.as_deref().unwrap_or(""), &body);
+
+    for url in urls {
+        let job = Job {
+            event,
+            slug: pasta.id_as_animals(),
+            url,
+            body: body.clone(),
+            signature: signature.clone(),
+            attempts: 0,
+        };
+        if let Err(TrySendError::Full(job)) = QUEUE.try_send(job) {
+            log::warn!(
+                "Dropping {} event of {} for {}, too many webhook deliveries are waiting",
+                job.event.as_str(),
+                job.slug,
+                job.url
+            );
+        }
+    }
+}
+
+/// Sends the queued deliveries one at a time. A failed one is retried after
+/// its delay while the worker goes on with the others, so a webhook that is
+/// down holds up the rest for no longer than the timeout of an attempt.
+fn work(queue: Receiver<Job>) {
+    let client = crate::util::http_client::new();
+    let mut retries: Vec<(Instant, Job)> = Vec::new();
+    loop {
+        let next_retry = retries
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, (due, _))| *due)
+            .map(|(index, (due, _))| (index, *due));
+        let job = match next_retry {
+            Some((index, due)) if due <= Instant::now() => retries.swap_remove(index).1,
+            Some((_, due)) => match queue.recv_timeout(due.saturating_duration_since(Instant::now())) {
+                Ok(job) => job,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => return,
+            },
+            None => match queue.recv() {
+                Ok(job) => job,
+                Err(_) => return,
+            },
+        };
+        if let Some(retry) = attempt(&client, job) {
+            retries.push(retry);
+        }
+    }
+}
+
+/// Sends the delivery once. Returns it with the time of its next attempt if
+/// it failed and has retries left, otherwise logs the outcome.
+fn attempt(client: &reqwest::blocking::Client, mut job: Job) -> Option<(Instant, Job)> {
+    job.attempts += 1;
+    let response = client
+        .post(&job.url)
+        .timeout(TIMEOUT)
+        .header("Content-Type", "application/json")
+        .header("X-MicroBin-Event", job.event.as_str())
+        .header("X-MicroBin-Signature", format!("sha256={}", job.signature))
+        .body(job.body.clone())
+        .send();
+    let (success, result) = match response {
+        Ok(response) => (response.status().is_success(), response.status().to_string()),
+        Err(e) => (false, e.to_string()),
+    };
+
+    if !success && job.attempts <= RETRY_DELAYS.len() {
+        return Some((Instant::now() + RETRY_DELAYS[job.attempts - 1], job));
+    }
+    if !success {
+        log::warn!(
+            "Failed to deliver {} event of {} to {}: {}",
+            job.event.as_str(),
+            job.slug,
+            job.url,
+            result
+        );
+    }
+    let mut log = LOG.lock().unwrap();
+    log.push_front(Delivery {
+        event: job.event.as_str(),
+        pasta: job.slug,
+        url: job.url,
+        attempts: job.attempts,
+        result,
+        success,
+        delivered_at: Local::now().timestamp(),
+    });
+    log.truncate(LOG_SIZE);
+    None
+}
+
+/// HMAC-SHA256 of the body with the webhook secret, hex encoded. Receivers
+/// compute the same over the raw body to check that it came from this
+/// instance, and compare the timestamp in it to reject replays.
+fn sign(secret: &str, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any size");
+    mac.update(body.as_bytes());
+    format!("{:x}", mac.finalize().into_bytes())
+}
+
+/// Metadata of the pasta. The content is only included when the pasta is
+/// not encrypted.
+fn payload(event: Event, pasta: &Pasta, timestamp: i64) -> Value {
+    let encrypted = pasta.encrypt_server || pasta.encrypt_client;
+    json!({
+        "event": event.as_str(),
+        "timestamp": timestamp,
+        "pasta": {
+            "id": pasta.id_as_animals(),
+            "url": format!("{}/upload/{}", ARGS.get().public_path_as_str(), pasta.id_as_animals()),
+            "type": pasta.pasta_type,
+            "extension": pasta.extension,
+            "created": pasta.created,
+            "expiration": pasta.expiration,
+            "read_count": pasta.read_count,
+            "burn_after_reads": pasta.burn_after_reads,
+            "private": pasta.private,
+            "readonly": pasta.readonly,
+            "encrypted": encrypted,
+            "tags": pasta.tags,
+            "file": pasta.file.as_ref().map(|file| json!({
+                "name": file.name,
+                "size": file.size.as_u64(),
+            })),
+            "content": if encrypted { None } else { Some(&pasta.content) },
+        },
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_sign() {
+        // RFC 4231, test case 2
+        assert_eq!(
+            sign("Jefe", "what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn test_payload_without_encrypted_content() {
+        let mut pasta = Pasta {
+            id: 1,
+            content: String::from("secret"),
+            file: None,
+            extension: String::from(""),
+            private: true,
+            readonly: false,
+            editable: false,
+            hide_read_count: false,
+            encrypt_server: true,
+            encrypt_client: false,
+            encrypted_key: None,
+            created: 0,
+            expiration: 0,
+            last_read: 0,
+            read_count: 0,
+            burn_after_reads: 0,
+            pasta_type: String::from("text"),
+            tags: Vec::new(),
+            files: Vec::new(),
+            uploader: None,
+            uploader_ip: None,
+        };
+
+        let encrypted = payload(Event::Created, &pasta, 42);
+        assert_eq!(encrypted["event"], "created");
+        assert_eq!(encrypted["pasta"]["content"], Value::Null);
+
+        pasta.encrypt_server = false;
+        let plain = payload(Event::Deleted, &pasta, 42);
+        assert_eq!(plain["pasta"]["content"], "secret");
+    }
+}
@@ -0,0 +1,44 @@
+use chrono::{Datelike, Local, TimeZone, Timelike};
+use serde::{Deserialize, Serialize};
+
+/// One view of a pasta, kept for its owner unless the view log is disabled.
+/// Nothing in it identifies the reader: the agent is only the browser family
+/// and the address is only kept when enabled, and then anonymized.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct View {
+    pub pasta_id: u64,
+    pub viewed_at: i64,
+    /// Host of the page that linked to the pasta
+    pub referrer: Option<String>,
+    /// Browser or client family, like "Firefox" or "curl"
+    pub agent: String,
+    /// Address of the reader with the host part zeroed
+    pub ip: Option<String>,
+}
+
+impl View {
+    pub fn viewed_at_as_string(&self) -> String {
+        Local
+            .timestamp_opt(self.viewed_at, 0)
+            .map(|date| {
+                format!(
+                    "{}-{:02}-{:02} {:02}:{:02}",
+                    date.year(),
+                    date.month(),
+                    date.day(),
+                    date.hour(),
+                    date.minute(),
+                )
+            })
+            .earliest()
+            .unwrap_or_default()
+    }
+
+    pub fn referrer_as_str(&self) -> &str {
+        self.referrer.as_deref().unwrap_or("-")
+    }
+
+    pub fn ip_as_str(&self) -> &str {
+        self.ip.as_deref().unwrap_or("-")
+    }
+}